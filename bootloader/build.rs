@@ -0,0 +1,27 @@
+//! Computes the CRC32 of the loadable segments of the embedded kernel image and
+//! hands it to the bootloader through the `KERNEL_CRC32` environment variable.
+//!
+//! The build fails if the kernel image is missing or can't be parsed, so a
+//! bootloader is never built without the checksum of the image it embeds.
+
+use std::path::Path;
+
+/// Path of the kernel image which gets embedded, see `src/embedded.rs`
+const KERNEL_IMAGE: &str = "../kernel/target/kernel.bin";
+
+fn main() {
+    println!("cargo::rerun-if-changed={KERNEL_IMAGE}");
+
+    let image = std::fs::read(Path::new(KERNEL_IMAGE)).unwrap_or_else(|err| {
+        panic!("Couldn't read the kernel image {KERNEL_IMAGE}: {err}")
+    });
+
+    // Checksum the image the same way the bootloader verifies it
+    let crc = elf_parser::Elf::parse(&image)
+        .and_then(|elf| elf.crc32())
+        .unwrap_or_else(|err| {
+            panic!("Couldn't checksum the kernel image {KERNEL_IMAGE}: {err:?}")
+        });
+
+    println!("cargo::rustc-env=KERNEL_CRC32={crc:08X}");
+}
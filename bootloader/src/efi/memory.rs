@@ -152,9 +152,19 @@ impl From<u32> for MemoryType {
 /// The larger your system memory, the more descriptors should be expected.
 const N_MEM_DESC: usize = 2048;
 
+/// The free memory retrieved from the UEFI memory map
+pub struct FreeMemory {
+    /// The memory free to use
+    pub ranges: RangeSet,
+
+    /// Number of bytes of free memory left out because the map had more
+    /// separate free regions than `ranges` can hold
+    pub dropped_bytes: u64,
+}
+
 /// Get a memory map of [`MemoryDescriptor`]s and exit the boot services
 pub unsafe fn memory_map_exit(sys: SystemTablePtr, image: BootloaderImagePtr)
-        -> Result<RangeSet, Error> {
+        -> Result<FreeMemory, Error> {
     // Get the pointer to the services required
     let boot_svc = unsafe { (*sys.0).boot_svc };
     let get_memory_map = boot_svc.get_memory_map;
@@ -193,6 +203,7 @@ pub unsafe fn memory_map_exit(sys: SystemTablePtr, image: BootloaderImagePtr)
 
     // Now, only retain the memory that we are free to use in a memory allocator
     let mut free_memory = RangeSet::new();
+    let mut dropped_bytes = 0u64;
     for desc in memory_map.iter() {
         // Skip all regions that will become invalid post boot services exit
         if !desc.mem_type.available_post_boot_svc_exit() { continue; }
@@ -203,11 +214,17 @@ pub unsafe fn memory_map_exit(sys: SystemTablePtr, image: BootloaderImagePtr)
         let end = desc.phys_addr.checked_add(offset - 1)
             .ok_or(Error::MemoryMapOverflow)?;
 
-        // Write the memory down. This will only ever return errors if the UEFI
-        // sabotages us and gives us corrupted information. At that point it is
-        // safer to just panic instead of handling the errors.
-        free_memory.insert(Range::new(desc.phys_addr as u64, end as u64)
-            .unwrap()).unwrap();
+        // Write the memory down. Adjacent descriptors get coalesced, but a
+        // fragmented map can still have more free regions than the set can
+        // hold, in which case the smallest regions are left unused rather
+        // than failing the boot. Other errors will only ever be returned if
+        // the UEFI sabotages us and gives us corrupted information. At that
+        // point it is safer to just panic instead of handling the errors.
+        let dropped = free_memory.insert_or_drop_smallest(
+            Range::new(desc.phys_addr as u64, end as u64).unwrap()).unwrap();
+        if let Some(dropped) = dropped {
+            dropped_bytes += dropped.end() - dropped.start() + 1;
+        }
     }
 
     // Reserve the first page to avoid writing into legacy structures
@@ -218,5 +235,5 @@ pub unsafe fn memory_map_exit(sys: SystemTablePtr, image: BootloaderImagePtr)
     free_memory.remove(Range::new(0xA0000, 0xFFFFF).unwrap()).unwrap();
 
     // Return the memory
-    Ok(free_memory)
+    Ok(FreeMemory { ranges: free_memory, dropped_bytes })
 }
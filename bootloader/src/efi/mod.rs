@@ -5,4 +5,4 @@ pub mod acpi;
 
 pub use efi::*;
 pub use status::*;
-pub use memory::memory_map_exit;
+pub use memory::{memory_map_exit, FreeMemory};
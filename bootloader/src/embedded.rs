@@ -11,6 +11,15 @@
 pub static INITIAL_KERNEL_IMAGE: &'static [u8] = include_bytes!(
     "../../kernel/target/kernel.bin");
 
+/// CRC32 of the loadable segments of the embedded kernel image, which is
+/// verified before the image is loaded. It's computed by `build.rs` from the
+/// same image file that's embedded.
+pub const INITIAL_KERNEL_IMAGE_CRC32: u32 =
+    match u32::from_str_radix(env!("KERNEL_CRC32"), 16) {
+        Ok(crc) => crc,
+        Err(_)  => panic!("KERNEL_CRC32 is not a hexadecimal u32"),
+    };
+
 #[unsafe(no_mangle)]
 #[unsafe(link_section = ".trmpln")]
 pub static TRAMPOLINE: &'static [u8] =
@@ -10,7 +10,7 @@ pub mod mm;
 pub mod trampoline;
 
 mod embedded;
-pub use embedded::INITIAL_KERNEL_IMAGE;
+pub use embedded::{INITIAL_KERNEL_IMAGE, INITIAL_KERNEL_IMAGE_CRC32};
 pub use embedded::TRAMPOLINE;
 
 /// Data shared between the bootloader and the kernel
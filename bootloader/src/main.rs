@@ -5,8 +5,7 @@ use core::sync::atomic::{AtomicBool, Ordering};
 use bootloader::{efi, mm, trampoline, SHARED, println};
 use serial::SerialDriver;
 use page_table::{
-    VirtAddr, PageTable, MapRequest, PageType, Permissions,
-    PAGE_PRESENT, PAGE_WRITE, PAGE_SIZE};
+    VirtAddr, PhysAddr, PageTable, MapRequest, PageType, Permissions};
 use shared_data::{
     KERNEL_STACK_SIZE_PADDED, KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE,
     BootloaderState};
@@ -37,8 +36,14 @@ fn init_setup(image_handle: efi::BootloaderImagePtr,
     // Save the serial driver
     *SHARED.serial.lock() = Some(serial);
 
+    // Let the user know if a fragmented memory map cost us some memory
+    if map.dropped_bytes != 0 {
+        println!("Too many free memory regions, {} bytes of memory left unused",
+            map.dropped_bytes);
+    }
+
     // Initialize the memory manager
-    mm::init(map);
+    mm::init(map.ranges);
 
     // Map in the trampoline into the bootloader memory space
     trampoline::map_once();
@@ -92,8 +97,14 @@ fn load_kernel() {
         println!("No kernel image found. Using the embedded one from now on.");
 
         // Parse the embedded kernel
-        *kernel = Some(elf_parser::Elf::parse(bootloader::INITIAL_KERNEL_IMAGE)
-            .expect("Couldn't parse embedded kernel image."));
+        let elf = elf_parser::Elf::parse(bootloader::INITIAL_KERNEL_IMAGE)
+            .expect("Couldn't parse embedded kernel image.");
+
+        // Make sure the image isn't corrupted
+        elf.verify_crc32(bootloader::INITIAL_KERNEL_IMAGE_CRC32)
+            .expect("Embedded kernel image is corrupted.");
+
+        *kernel = Some(elf);
     }
 
     // Get exclusive access to physical memory so we can write the kernel
@@ -102,9 +113,11 @@ fn load_kernel() {
     let pmem = pmem.as_mut().expect("Memory not still uninitialized.");
     let mut pmem = mm::PhysicalMemory(pmem);
 
-    // Create the page table for the kernel
+    // Create the page table for the kernel. The trampoline switches to it
+    // without touching CR4, so it must use as many levels as ours
+    let levels = SHARED.bootloader().get().page_table.levels();
     let mut kernel_table = SHARED.kernel_pt().lock();
-    *kernel_table = Some(PageTable::new(&mut pmem)
+    *kernel_table = Some(PageTable::with_levels(&mut pmem, levels)
         .expect("Failed to create the kernel page table."));
     let table = kernel_table.as_mut().unwrap();
 
@@ -117,7 +130,7 @@ fn load_kernel() {
             .expect("Segment failed while creating the kernel page table.");
 
         println!("\n{:?}", segment.permissions);
-        println!(" ├ Vaddr:  {:X?}", segment.vaddr);
+        println!(" ├ Vaddr:  {}", segment.vaddr);
         println!(" ├ Vsize:  0x{:X?}", segment.vsize);
         println!(" └ Offset: 0x{:X?}", segment.offset);
 
@@ -129,17 +142,14 @@ fn load_kernel() {
 
         // Create the mapping request
         let request = MapRequest::new(segment.vaddr, PageType::Page4K,
-            segment.offset + segment.vsize, perms)
+            segment.map_len(), perms)
         .expect("Error while requesting a map in");
 
         // Map in the request, initializing it to the kernel bytes at the
         // correct offset
-        table.map_init(&mut pmem, request, Some(|mem_offset| {
-            if mem_offset >= segment.offset {
-                segment.bytes.get((mem_offset - segment.offset) as usize)
-                    .copied().unwrap_or(0)
-            } else { 0 }
-        }));
+        table.map_init(&mut pmem, request,
+            Some(|mem_offset| segment.byte_at(mem_offset)))
+            .expect("Couldn't map in a kernel segment");
     }
     println!();
 
@@ -166,7 +176,7 @@ fn load_kernel() {
     // Map in the physical memory window
     //
     // First, get CPU features to know which pages we can use
-    let features = cpu::Features::get();
+    let features = cpu::Features::get_cached();
 
     // Determine the corresponding page type
     let page_type = if features.gbyte_pages {
@@ -177,25 +187,25 @@ fn load_kernel() {
         PageType::Page4K
     };
 
-    // Get the raw page table entry mask for this entry
-    let page_mask = 0 | PAGE_WRITE | PAGE_PRESENT | match page_type {
-        PageType::Page4K => 0,
-        _ => PAGE_SIZE,
-    };
-
     // Map the window in!
-    for paddr in (0..KERNEL_PHYS_WINDOW_SIZE).step_by(page_type as usize) {
-        let virt = VirtAddr(KERNEL_PHYS_WINDOW_BASE + paddr);
-        unsafe {
-            table.map_raw(&mut pmem, virt, page_type, paddr | page_mask)
-                .unwrap();
-        }
+    unsafe {
+        table.map_range(&mut pmem, VirtAddr(KERNEL_PHYS_WINDOW_BASE),
+                        PhysAddr(0), KERNEL_PHYS_WINDOW_SIZE, page_type,
+                        Permissions::new(true, true, false))
+            .unwrap();
     }
 }
 
 /// Sets up a trampoline for jumping into the kernel from the bootloader and
 /// jumps to the kernel!
+///
+/// The trampoline bytes and the jump arguments are validated once per kernel
+/// launch, right before the jump, so a corrupted trampoline or a bogus
+/// argument results in a panic rather than a wild jump.
 unsafe fn jump_to_kernel(stack: VirtAddr) {
+    // Make sure the trampoline hasn't been corrupted since it was mapped
+    assert!(trampoline::verify(), "The mapped trampoline is corrupted");
+
     // Get the pointer to the trampoline
     let trampoline = unsafe { shared_data::get_trampoline() };
 
@@ -204,9 +214,17 @@ unsafe fn jump_to_kernel(stack: VirtAddr) {
     let table = SHARED.kernel_pt().lock().as_ref().unwrap().clone();
     let shared = page_table::PhysAddr(&SHARED as *const _ as u64);
 
+    // Validate the arguments of the jump
+    let canonical = |addr: VirtAddr| addr.is_canonical_for(table.levels());
+    assert!(canonical(entry), "Kernel entry {entry} is not canonical");
+    assert!(canonical(stack) && stack.is_aligned(16),
+        "Kernel stack {stack} is not canonical or aligned");
+    assert!(table.addr().is_aligned_to_page(PageType::Page4K),
+        "Kernel page table {} is not page aligned", table.addr());
+
     println!("ENTERING KERNEL ────────────────────────────────────────────");
 
-    unsafe { trampoline(entry, stack, table, shared); }
+    unsafe { trampoline(entry, stack, table.addr(), shared); }
 }
 
 /// Maps in a new stack into the kernel's memory and return the base where it's
@@ -236,7 +254,7 @@ unsafe fn map_kernel_stack() -> VirtAddr {
 
     // Return the base which should be put into RSP. This addition shouldn't
     // fail because it's checked in `get_next_stack()`.
-    VirtAddr(stack_base.0.checked_add(KERNEL_STACK_SIZE_PADDED).unwrap())
+    stack_base.checked_add(KERNEL_STACK_SIZE_PADDED).unwrap()
 }
 
 /// This is the entry point for both the bootloader itself (the one that UEFI
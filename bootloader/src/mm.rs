@@ -36,6 +36,16 @@ impl<'a> PhysMem for PhysicalMemory<'a> {
                 .expect("Failed to allocate physical memory")? as u64
         ))
     }
+
+    fn free_phys(&mut self, paddr: PhysAddr, layout: Layout) {
+        // Zero sized allocations don't hold any memory
+        if layout.size() == 0 { return; }
+
+        // Return the memory back to the rangeset
+        let end = paddr.0 + (layout.size() as u64 - 1);
+        self.0.insert(Range::new(paddr.0, end).unwrap())
+            .expect("Failed to free physical memory");
+    }
 }
 
 /// Initialize the global memory allocator using `memory` as the physical memory
@@ -6,10 +6,7 @@ pub struct Serial;
 
 impl Write for Serial {
     fn write_str(&mut self, string: &str) -> core::fmt::Result {
-        let mut serial = SHARED.serial.lock();
-        if let Some(serial) = &mut *serial {
-            serial.write(string.as_bytes());
-        }
+        SHARED.serial_write(string.as_bytes());
         Ok(())
     }
 }
@@ -61,3 +61,19 @@ pub fn map_once() {
         .page.expect("Couldn't get the raw page entry for the trampoline").2;
     RAW_PT_ENTRY.store(raw, Ordering::SeqCst);
 }
+
+/// Verifies that the trampoline mapped in the current page table still
+/// matches the embedded trampoline bytes.
+///
+/// Returns `false` if the trampoline hasn't been mapped yet.
+pub fn verify() -> bool {
+    if RAW_PT_ENTRY.load(Ordering::SeqCst) == 0 { return false; }
+
+    // Compare the mapped bytes against the embedded ones
+    let trampoline = crate::TRAMPOLINE;
+    let mapped = unsafe {
+        core::slice::from_raw_parts(
+            TRAMPOLINE_ADDR as *const u8, trampoline.len())
+    };
+    mapped == trampoline
+}
@@ -4,17 +4,22 @@ use alloc::vec::Vec;
 use core::mem::size_of;
 use core::ptr::read_unaligned;
 
+use const_assert::const_assert;
 use page_table::PhysAddr;
+use acpi_tables::Invalid;
 
-use crate::acpi::{Error, Madt, Srat};
+use crate::acpi::{
+    Error, Madt, Srat, Mcfg, Fadt, register_local_apics, register_power_off,
+    dsdt_addr};
 use crate::apic;
+use crate::pci;
 use crate::mm::{phys_ptr, register_numa};
 
 /// Flag showing that a table entry is enabled
 pub const ENABLED: u32 = 1 << 0;
 
 /// Types of tables recognized by this lib -- used for error handling
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Table {
     /// Multiple APIC description table
     Madt,
@@ -22,6 +27,18 @@ pub enum Table {
     /// System resource affinity table
     Srat,
 
+    /// PCI express memory mapped configuration space base address table
+    Mcfg,
+
+    /// Fixed ACPI description table
+    Fadt,
+
+    /// High precision event timer table
+    Hpet,
+
+    /// Differentiated system description table
+    Dsdt,
+
     /// Unknown system table
     Unknown([u8; 4]),
 }
@@ -32,9 +49,26 @@ impl Table {
         match signature {
             b"APIC"  => Self::Madt,
             b"SRAT"  => Self::Srat,
+            b"MCFG"  => Self::Mcfg,
+            b"FACP"  => Self::Fadt,
+            b"HPET"  => Self::Hpet,
+            b"DSDT"  => Self::Dsdt,
             _unknown => Self::Unknown(*signature),
         }
     }
+
+    /// Returns the signature of this table type
+    pub fn signature(&self) -> [u8; 4] {
+        match self {
+            Self::Madt => *b"APIC",
+            Self::Srat => *b"SRAT",
+            Self::Mcfg => *b"MCFG",
+            Self::Fadt => *b"FACP",
+            Self::Hpet => *b"HPET",
+            Self::Dsdt => *b"DSDT",
+            Self::Unknown(signature) => *signature,
+        }
+    }
 }
 
 /// Header present in all SDTs
@@ -70,14 +104,14 @@ pub struct SdtHeader {
     /// Revision of utility that created the table
     pub creator_revision: u32,
 }
+const_assert!(size_of::<SdtHeader>() == acpi_tables::HEADER_LEN);
 
 impl SdtHeader {
     /// Checks the checksum of the header _AND_ the connected table
     pub fn checksum_valid(&self) -> bool {
         let bytes = unsafe { core::slice::from_raw_parts(
                 self as *const Self as *const u8, self.length as usize) };
-        let sum = bytes.iter().fold(0u8, |x, &byte| x.wrapping_add(byte));
-        sum == 0
+        acpi_tables::checksum_valid(bytes)
     }
 }
 
@@ -132,18 +166,13 @@ pub unsafe fn parse_table_entries(hdr_ptr: *const SdtHeader, offset: usize)
     Ok(entries)
 }
 
-/// Initialize the ACPI tables
-pub unsafe fn init() -> Result<(), Error> {
-    // Parsed table information
-    let mut madt: Option<Madt> = None;
-    let mut srat: Option<Srat> = None;
-
+/// Returns an iterator over the headers of all SDTs given to us by UEFI
+fn sdt_headers() -> impl Iterator<Item = *const SdtHeader> {
     // Get the physical pointer to the SDTs and offset it into our phys window
     let sdt_table = *core!().shared.acpi().get();
     let base = phys_ptr(sdt_table.base).0;
 
-    // Go through each SDT and parse it
-    for entry in 0..sdt_table.n_entries {
+    (0..sdt_table.n_entries).map(move |entry| {
         // Get the pointer to the table
         let offset = entry.checked_mul(size_of::<u64>())
             .expect("Overflow when offseting into physical window");
@@ -156,30 +185,77 @@ pub unsafe fn init() -> Result<(), Error> {
         };
 
         // Offset the pointer to our physical window
-        let table_ptr = phys_ptr(PhysAddr(table_ptr as u64));
-        let table_ptr = table_ptr.0 as *const SdtHeader;
+        phys_ptr(PhysAddr(table_ptr as u64)).0 as *const SdtHeader
+    })
+}
 
-        // Get the signature for the table
-        let signature = unsafe { read_unaligned(table_ptr as *const [u8; 4]) };
+/// Make sure that the table at `hdr` is of type `which`, that it spans its
+/// header and that its checksum is valid, returning its raw bytes, including
+/// the header
+fn validate_table(hdr: &'static SdtHeader, which: Table)
+        -> Result<&'static [u8], Error> {
+    // Only the header is known to be there until its length is validated
+    let len = (hdr.length as usize).max(size_of::<SdtHeader>());
+    let bytes = unsafe {
+        core::slice::from_raw_parts(hdr as *const SdtHeader as *const u8, len)
+    };
+
+    acpi_tables::validate(bytes, &which.signature()).map_err(|err| match err {
+        Invalid::Signature => Error::SignatureMismatch(which),
+        Invalid::Length    => Error::SizeMismatch(which),
+        Invalid::Checksum  => Error::ChecksumMismatch(which),
+    })
+}
+
+/// Find the first table of type `which` whose checksum is valid and return
+/// its raw bytes, including the header. The DSDT isn't listed in the RSDT, so
+/// it's looked up through the FADT
+///
+/// Returns `Error::ChecksumMismatch` if there are tables of type `which`, but
+/// none of them is valid
+pub fn find_table(which: Table) -> Result<Option<&'static [u8]>, Error> {
+    // Get the DSDT from the address in the FADT
+    if which == Table::Dsdt {
+        let Some(dsdt) = find_table(Table::Fadt)?.and_then(dsdt_addr) else {
+            return Ok(None);
+        };
+        let hdr = unsafe { &*(phys_ptr(dsdt).0 as *const SdtHeader) };
+        return validate_table(hdr, which).map(Some);
+    }
+
+    let mut found = Ok(None);
+    for hdr in sdt_headers().map(|table_ptr| unsafe { &*table_ptr }) {
+        match validate_table(hdr, which) {
+            Ok(table) => return Ok(Some(table)),
+            Err(Error::SignatureMismatch(_)) => {},
+            Err(err) => found = Err(err),
+        }
+    }
+    found
+}
 
-        // Print out the table that we're reading right now
+/// Initialize the ACPI tables
+pub unsafe fn init() -> Result<(), Error> {
+    // Print out the tables that we have
+    for table_ptr in sdt_headers() {
+        let signature = unsafe { read_unaligned(table_ptr as *const [u8; 4]) };
         if let Ok(sig) = core::str::from_utf8(&signature) {
             let table = Table::from_sig(&signature);
             println!("Got ACPI table: {sig} | {table:?}");
         }
-
-        // Parse the table
-        match Table::from_sig(&signature) {
-            Table::Madt => {
-                madt = unsafe { Some(Madt::parse(table_ptr)?) };
-            },
-            Table::Srat => {
-                srat = unsafe { Some(Srat::parse(table_ptr)?) };
-            },
-            _ => continue,
-        }
     }
 
+    // Parse the tables we're interested in
+    let hdr = |table: &'static [u8]| table.as_ptr() as *const SdtHeader;
+    let madt = find_table(Table::Madt)?
+        .map(|x| unsafe { Madt::parse(hdr(x)) }).transpose()?;
+    let srat = find_table(Table::Srat)?
+        .map(|x| unsafe { Srat::parse(hdr(x)) }).transpose()?;
+    let mcfg = find_table(Table::Mcfg)?
+        .map(|x| unsafe { Mcfg::parse(hdr(x)) }).transpose()?;
+    let fadt = find_table(Table::Fadt)?
+        .map(|x| unsafe { Fadt::parse(hdr(x)) }).transpose()?;
+
     // Store the maximum APIC ID we have found
     let max_id = madt.as_ref()
         .map(|x| x.apics.iter().fold(0, |x, &y| x.max(y)))
@@ -191,8 +267,21 @@ pub unsafe fn init() -> Result<(), Error> {
         unsafe { register_numa(srat.apic_to_domain, srat.domain_to_ranges); }
     }
 
+    // Let the PCI layer use the memory mapped configuration space
+    if let Some(mcfg) = mcfg {
+        unsafe { pci::register_ecam(&mcfg.entries); }
+    }
+
+    // Save the power off state, reporting where the S5 sleep types came from
+    if let Some(fadt) = fadt {
+        let source = if fadt.s5_from_dsdt { "DSDT" } else { "fallback" };
+        println!("ACPI S5 sleep types: {:?} ({source})", fadt.s5);
+        register_power_off(fadt);
+    }
+
     // Initialize the APIC states on the system and bring up the other cores
-    if let Some(madt) = madt {
+    if let Some(mut madt) = madt {
+        register_local_apics(core::mem::take(&mut madt.local_apics));
         // apic::ioapic::init(madt.io_apics, madt.isa_overrides);
         apic::init_system(madt.apics);
     }
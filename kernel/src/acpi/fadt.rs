@@ -0,0 +1,193 @@
+//! FADT implementation and ACPI power off
+
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+use oncelock::OnceLock;
+use page_table::PhysAddr;
+
+use crate::acpi::{SdtHeader, Error, Table};
+use crate::mm::phys_ptr;
+
+/// The sleep enable bit of the PM1 control registers
+const SLP_EN: u16 = 1 << 13;
+
+/// Shift of the sleep type field in the PM1 control registers
+const SLP_TYP_SHIFT: u16 = 10;
+
+/// The SCI enable bit of the PM1 control registers. Set once the firmware has
+/// switched the system to ACPI mode
+const SCI_EN: u16 = 1 << 0;
+
+/// The `SLP_TYPa` and `SLP_TYPb` values used for S5 when the DSDT doesn't
+/// describe them. This is what QEMU and Bochs use
+const FALLBACK_S5: (u8, u8) = (0, 0);
+
+/// The power off state found by `acpi::init()`
+static POWER_OFF: OnceLock<Fadt> = OnceLock::new();
+
+/// Information returned when parsing the FADT table
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// I/O port of the system management interrupt command register, `0` if
+    /// the system only supports ACPI mode
+    pub smi_cmd: u16,
+
+    /// Value written to `smi_cmd` to switch the system to ACPI mode
+    pub acpi_enable: u8,
+
+    /// I/O port of the PM1a control register block
+    pub pm1a_cnt: u16,
+
+    /// I/O port of the PM1b control register block, `0` if not supported
+    pub pm1b_cnt: u16,
+
+    /// The `SLP_TYPa` and `SLP_TYPb` values of the S5 (soft off) state
+    pub s5: (u8, u8),
+
+    /// Whether `s5` was found in the DSDT, rather than being the fallback
+    pub s5_from_dsdt: bool,
+}
+
+impl Fadt {
+    pub unsafe fn parse(hdr_ptr: *const SdtHeader) -> Result<Self, Error> {
+        // Get a usable rust reference to the header
+        let hdr = unsafe { &*hdr_ptr };
+
+        // Make sure the table is valid
+        if !hdr.checksum_valid() {
+            return Err(Error::ChecksumMismatch(Table::Fadt));
+        }
+
+        // Make sure the table holds all the fields up to the PM1 control
+        // blocks
+        let len = hdr.length as usize;
+        if len < 72 {
+            return Err(Error::SizeMismatch(Table::Fadt));
+        }
+
+        let ptr = hdr_ptr as *const u8;
+        let read_u32 = |offset: usize| unsafe {
+            read_unaligned(ptr.add(offset) as *const u32)
+        };
+
+        // Find the S5 sleep types in the DSDT, falling back to the usual ones
+        let table = unsafe { core::slice::from_raw_parts(ptr, len) };
+        let s5 = dsdt_addr(table).and_then(|dsdt| unsafe { find_s5(dsdt) });
+
+        Ok(Self {
+            smi_cmd:      read_u32(48) as u16,
+            acpi_enable:  unsafe { *ptr.add(52) },
+            pm1a_cnt:     read_u32(64) as u16,
+            pm1b_cnt:     read_u32(68) as u16,
+            s5:           s5.unwrap_or(FALLBACK_S5),
+            s5_from_dsdt: s5.is_some(),
+        })
+    }
+}
+
+/// Get the physical address of the DSDT from the raw bytes of the `fadt`,
+/// preferring the 64-bit `X_DSDT` of ACPI 2.0+ tables over `DSDT`. Returns
+/// `None` if neither is set
+pub fn dsdt_addr(fadt: &[u8]) -> Option<PhysAddr> {
+    let read = |range: core::ops::Range<usize>| {
+        fadt.get(range).map(|bytes| {
+            bytes.iter().rev().fold(0u64, |acc, &byte| acc << 8 | byte as u64)
+        })
+    };
+
+    read(140..148).filter(|&x| x != 0)
+        .or_else(|| read(40..44))
+        .filter(|&x| x != 0)
+        .map(PhysAddr)
+}
+
+/// Find the `SLP_TYPa` and `SLP_TYPb` values of the `\_S5_` package in the
+/// DSDT at `dsdt`.
+///
+/// There's no AML interpreter, so this only finds the package when it's
+/// defined directly by a `Name` with constant byte values, which is how most
+/// firmware defines it. Returns `None` if the package couldn't be found this
+/// way
+unsafe fn find_s5(dsdt: PhysAddr) -> Option<(u8, u8)> {
+    /// AML opcodes found around the package
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    // Get the DSDT and make sure it's valid
+    if dsdt.0 == 0 { return None; }
+    let hdr = unsafe { &*(phys_ptr(dsdt).0 as *const SdtHeader) };
+    if hdr.signature != Table::Dsdt.signature() || !hdr.checksum_valid() {
+        return None;
+    }
+    let aml = unsafe {
+        core::slice::from_raw_parts(
+            hdr as *const SdtHeader as *const u8, hdr.length as usize)
+    }.get(size_of::<SdtHeader>()..)?;
+
+    // Find the name of the package, defined either as `_S5_` or `\_S5_`
+    let idx = aml.windows(4).enumerate()
+        .filter(|(_, name)| *name == b"_S5_")
+        .map(|(idx, _)| idx)
+        .find(|&idx| {
+            let name_op = match idx.checked_sub(1).map(|x| aml[x]) {
+                Some(b'\\') => idx.checked_sub(2).map(|x| aml[x]),
+                x => x,
+            };
+            name_op == Some(NAME_OP) && aml.get(idx + 4) == Some(&PACKAGE_OP)
+        })?;
+
+    // Skip the package length, whose first byte holds the number of bytes
+    // that follow it in its top two bits, and the number of elements
+    let pkg_len = aml.get(idx + 5)?;
+    let mut ptr = idx + 5 + ((pkg_len >> 6) as usize + 1) + 1;
+
+    // Read the two sleep types. `Zero` and `One` are encoded as their values
+    let mut read = || {
+        if *aml.get(ptr)? == BYTE_PREFIX { ptr += 1; }
+        let val = *aml.get(ptr)?;
+        ptr += 1;
+        Some(val)
+    };
+    Some((read()?, read()?))
+}
+
+/// Register the power off state described by the `fadt`
+pub fn register_power_off(fadt: Fadt) {
+    POWER_OFF.set(fadt);
+}
+
+/// Power off the machine by entering the ACPI S5 (soft off) sleep state.
+///
+/// The S5 sleep types are read from the DSDT if possible. Otherwise the values
+/// QEMU and Bochs use are assumed, which may not power off other machines. If
+/// the machine doesn't power off, the core is halted
+pub fn shutdown() -> ! {
+    if let Some(fadt) = POWER_OFF.try_get() {
+        unsafe {
+            cpu::disable_interrupts();
+
+            // Switch to ACPI mode if the firmware hasn't done so already
+            if cpu::in16(fadt.pm1a_cnt) & SCI_EN == 0 && fadt.smi_cmd != 0 {
+                cpu::out8(fadt.smi_cmd, fadt.acpi_enable);
+                while cpu::in16(fadt.pm1a_cnt) & SCI_EN == 0 {
+                    core::hint::spin_loop();
+                }
+            }
+
+            // Write the sleep type and enable the sleep, preserving the rest
+            // of the control registers
+            let (typ_a, typ_b) = fadt.s5;
+            let ports = [(fadt.pm1a_cnt, typ_a), (fadt.pm1b_cnt, typ_b)];
+            for (port, typ) in ports.into_iter().filter(|&(x, _)| x != 0) {
+                let cnt = cpu::in16(port) & !(7 << SLP_TYP_SHIFT);
+                cpu::out16(port,
+                    cnt | ((typ as u16 & 7) << SLP_TYP_SHIFT) | SLP_EN);
+            }
+        }
+    }
+
+    println!("ACPI power off failed, halting");
+    cpu::halt();
+}
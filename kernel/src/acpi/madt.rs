@@ -3,13 +3,36 @@
 use alloc::vec::Vec;
 use alloc::collections::BTreeMap;
 
+use oncelock::OnceLock;
+
 // use page_table::PhysAddr;
 //
 // use crate::apic::ioapic::Uninitialized;
-use crate::acpi::{SdtHeader, Error, Table, ENABLED, parse_table_entries};
+use crate::acpi::{SdtHeader, Error, Table};
+
+pub use acpi_tables::madt::LocalApicEntry;
+
+/// All local APICs described by the MADT, set by `acpi::init()`
+static LOCAL_APICS: OnceLock<Vec<LocalApicEntry>> = OnceLock::new();
+
+/// Get all local APICs described by the MADT, including the disabled ones.
+///
+/// Returns an empty slice if the MADT hasn't been parsed yet
+pub fn local_apics() -> &'static [LocalApicEntry] {
+    LOCAL_APICS.try_get().map_or(&[], |apics| &apics[..])
+}
+
+/// Get the number of cores on the system which are enabled or can be enabled,
+/// as described by the MADT
+pub fn core_count() -> usize {
+    local_apics().iter().filter(|apic| apic.usable()).count()
+}
 
-/// Flag showing that an APIC is online capable
-const ONLINE_CAPABLE: u32 = 1 << 1;
+/// Save the local APICs parsed from the MADT such that they can be queried by
+/// `local_apics()`
+pub(in crate::acpi) fn register_local_apics(apics: Vec<LocalApicEntry>) {
+    LOCAL_APICS.set(apics);
+}
 
 /// Source -> (GSI, flags)
 pub type IsaSourceOverrides = BTreeMap<u8, (u32, u16)>;
@@ -19,6 +42,9 @@ pub struct Madt {
     /// ID vector of all usable APICs
     pub apics: Vec<u32>,
 
+    /// All local APIC entries, including the disabled ones
+    pub local_apics: Vec<LocalApicEntry>,
+
     // /// Vector of all IO APICs that have yet to be initialized
     // pub io_apics: Vec<Uninitialized>,
 
@@ -28,14 +54,16 @@ pub struct Madt {
 
 impl Madt {
     pub unsafe fn parse(hdr_ptr: *const SdtHeader) -> Result<Self, Error> {
-        // Get the entries from the table
-        let entries = unsafe {
-            parse_table_entries(hdr_ptr, 2 * size_of::<u32>())?
+        // Get the bytes of the table
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                hdr_ptr as *const u8, (*hdr_ptr).length as usize)
         };
 
         // Create the info struct that will be returned
         let mut madt = Self {
             apics: Vec::new(),
+            local_apics: Vec::new(),
             // io_apics: Vec::new(),
             // isa_overrides: BTreeMap::new(),
         };
@@ -44,21 +72,20 @@ impl Madt {
         let mismatch_err = Err(Error::SizeMismatch(Table::Madt));
 
         // Go through each entry and save the IDs of functional APICs
-        for entry in entries {
+        for entry in acpi_tables::madt::entries(bytes) {
+            let Ok(entry) = entry else { return mismatch_err; };
             match entry.typ {
-                // Local APIC
-                0 => {
-                    // Validate the length
-                    if entry.len != 8 { return mismatch_err; }
-
-                    // Read the APIC ID and flags
-                    let id    = entry.read::<u8>(3) as u32;
-                    let flags = entry.read::<u32>(4);
+                // Local APIC and local x2APIC
+                0 | 9 => {
+                    // Validate the length and read the UID, APIC ID and flags
+                    let Ok(Some(apic)) = LocalApicEntry::from_entry(&entry)
+                        else { return mismatch_err; };
 
                     // If the CPU is enabled, or can be enabled, save the ID
-                    if (flags & ENABLED) != 0 || (flags & ONLINE_CAPABLE) != 0 {
-                        madt.apics.push(id);
+                    if apic.usable() {
+                        madt.apics.push(apic.apic_id);
                     }
+                    madt.local_apics.push(apic);
                 },
                 // // IO APIC
                 // 1 => {
@@ -93,20 +120,6 @@ impl Madt {
                 //         }
                 //     }
                 // },
-                // Local x2APIC
-                9 => {
-                    // Validate the length
-                    if entry.len != 16 { return mismatch_err; }
-
-                    // Read the APIC ID and flags
-                    let id    = entry.read::<u32>(4);
-                    let flags = entry.read::<u32>(8);
-
-                    // If the CPU is enabled, or can be enabled, save the ID
-                    if (flags & ENABLED) != 0 || (flags & ONLINE_CAPABLE) != 0 {
-                        madt.apics.push(id);
-                    }
-                },
                 _ => {},
             }
         }
@@ -0,0 +1,90 @@
+//! MCFG implementation
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+use page_table::PhysAddr;
+
+use crate::acpi::{SdtHeader, Error, Table};
+
+/// Size of a single configuration space allocation entry in the MCFG
+const ENTRY_SIZE: usize = 16;
+
+/// A PCIe enhanced configuration space allocation
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    /// Physical base address of the enhanced configuration mechanism. This
+    /// always corresponds to bus 0 of the segment, even if `start_bus` isn't 0
+    pub base: PhysAddr,
+
+    /// PCI segment group number
+    pub segment: u16,
+
+    /// First PCI bus number decoded by this host bridge
+    pub start_bus: u8,
+
+    /// Last PCI bus number decoded by this host bridge
+    pub end_bus: u8,
+}
+
+/// Information returned when parsing the MCFG table
+pub struct Mcfg {
+    /// All enhanced configuration space allocations
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    pub unsafe fn parse(hdr_ptr: *const SdtHeader) -> Result<Self, Error> {
+        // Get a usable rust reference to the header
+        let hdr = unsafe { &*hdr_ptr };
+
+        // Make sure the table is valid
+        if !hdr.checksum_valid() {
+            return Err(Error::ChecksumMismatch(Table::Mcfg));
+        }
+
+        // The entries start after the header and 8 reserved bytes
+        let offset = size_of::<SdtHeader>() + size_of::<u64>();
+
+        // Make sure the entries fill the rest of the table exactly
+        let entries_len = (hdr.length as usize).checked_sub(offset)
+            .ok_or(Error::SizeMismatch(Table::Mcfg))?;
+        if entries_len % ENTRY_SIZE != 0 {
+            return Err(Error::SizeMismatch(Table::Mcfg));
+        }
+
+        // Create the info struct that will be returned
+        let mut mcfg = Self {
+            entries: Vec::with_capacity(entries_len / ENTRY_SIZE),
+        };
+
+        // Go through each entry and save it
+        let base = unsafe { (hdr_ptr as *const u8).add(offset) };
+        for idx in 0..entries_len / ENTRY_SIZE {
+            let ptr = unsafe { base.add(idx * ENTRY_SIZE) };
+
+            // Read the fields
+            let (addr, segment, start_bus, end_bus) = unsafe {(
+                read_unaligned(ptr.add(0)  as *const u64),
+                read_unaligned(ptr.add(8)  as *const u16),
+                *ptr.add(10),
+                *ptr.add(11),
+            )};
+
+            // Make sure the bus range makes sense
+            if start_bus > end_bus {
+                return Err(Error::SizeMismatch(Table::Mcfg));
+            }
+
+            mcfg.entries.push(McfgEntry {
+                base: PhysAddr(addr),
+                segment,
+                start_bus,
+                end_bus,
+            });
+        }
+
+        Ok(mcfg)
+    }
+}
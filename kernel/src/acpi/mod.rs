@@ -3,8 +3,12 @@
 mod acpi;
 mod madt;
 mod srat;
+mod mcfg;
+mod fadt;
 
 pub use srat::*;
+pub use mcfg::*;
+pub use fadt::*;
 pub use madt::*;
 pub use acpi::*;
 
@@ -5,6 +5,7 @@
 use core::sync::atomic::Ordering;
 
 use const_assert::const_assert;
+use cpu::Msr;
 use page_table::{
     PageType, PAGE_NXE, PAGE_WRITE, PAGE_CACHE_DISABLE, PAGE_PRESENT};
 
@@ -16,13 +17,31 @@ const IA32_APIC_BASE_EXTD: u64 = 1 << 10;
 /// The global enable bit in the `IA32_APIC_BASE` MSR
 const IA32_APIC_BASE_EN: u64 = 1 << 11;
 
-/// The intel specified APIC MSR
-const IA32_APIC_BASE: u32 = 0x1B;
-
 /// The physical address we want the local APIC to be mapped at. This should be
 /// the standard base unless someone relocated it..
 const APIC_BASE: u64 = 0xFEE0_0000;
 
+/// ICR delivery mode of fixed interrupts
+const ICR_DELIVERY_FIXED: u32 = 0 << 8;
+
+/// ICR delivery mode of NMIs
+const ICR_DELIVERY_NMI: u32 = 4 << 8;
+
+/// ICR delivery mode of INIT requests
+const ICR_DELIVERY_INIT: u32 = 5 << 8;
+
+/// ICR delivery mode of start-up IPIs
+const ICR_DELIVERY_STARTUP: u32 = 6 << 8;
+
+/// ICR level assert bit. Must be set for all IPIs but INIT level de-asserts
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// ICR destination shorthand targeting all cores including this one
+const ICR_ALL_INCLUDING_SELF: u32 = 2 << 18;
+
+/// ICR destination shorthand targeting all cores excluding this one
+const ICR_ALL_EXCLUDING_SELF: u32 = 3 << 18;
+
 // Validate the APIC base at compile time
 const_assert!(
     APIC_BASE > 0 && APIC_BASE == (APIC_BASE & 0x0000_000f_ffff_f000));
@@ -57,7 +76,7 @@ impl LocalApic {
                     core::ptr::read_volatile(&mapping[offset / 4])
                 },
                 ApicMode::X2Apic => {
-                    cpu::rdmsr(0x800 + (offset as u32 / 16)) as u32
+                    Msr::Raw(0x800 + (offset as u32 / 16)).read() as u32
                 },
             }
         }
@@ -73,7 +92,7 @@ impl LocalApic {
                     core::ptr::write_volatile(&mut mapping[offset / 4], value);
                 },
                 ApicMode::X2Apic => {
-                    cpu::wrmsr(0x800 + (offset as u32 / 16), value as u64);
+                    Msr::Raw(0x800 + (offset as u32 / 16)).write(value as u64);
                 }
             }
         }
@@ -94,6 +113,50 @@ impl LocalApic {
         unsafe { self.write_icr(((dest_id as u64) << 32) | ipi as u64); }
     }
 
+    /// Send an NMI to a specific APIC ID
+    pub unsafe fn send_nmi(&mut self, apic_id: u32) {
+        unsafe { self.ipi(apic_id, ICR_LEVEL_ASSERT | ICR_DELIVERY_NMI); }
+    }
+
+    /// Send an INIT request to a specific APIC ID, resetting the core into its
+    /// wait-for-SIPI state.
+    ///
+    /// When starting up a core, the INIT must be followed by a 10 millisecond
+    /// delay before the first SIPI is sent
+    pub unsafe fn send_init(&mut self, apic_id: u32) {
+        unsafe { self.ipi(apic_id, ICR_LEVEL_ASSERT | ICR_DELIVERY_INIT); }
+    }
+
+    /// Send a start-up IPI to a specific APIC ID. The core will start
+    /// executing in real mode at physical address `vector * 0x1000`.
+    ///
+    /// The SIPI must be preceded by an INIT (see `send_init()`). It should be
+    /// sent twice, with a 200 microsecond delay between the two, as the first
+    /// one may be lost on some systems. A core which has already started up
+    /// ignores the second SIPI
+    pub unsafe fn send_sipi(&mut self, apic_id: u32, vector: u8) {
+        unsafe {
+            self.ipi(apic_id,
+                ICR_LEVEL_ASSERT | ICR_DELIVERY_STARTUP | vector as u32);
+        }
+    }
+
+    /// Send a fixed interrupt with `vector` to all cores on the system,
+    /// including this one if `include_self` is set
+    pub unsafe fn broadcast_fixed(&mut self, vector: u8, include_self: bool) {
+        let shorthand = if include_self {
+            ICR_ALL_INCLUDING_SELF
+        } else {
+            ICR_ALL_EXCLUDING_SELF
+        };
+
+        // The destination is ignored when a shorthand is used
+        unsafe {
+            self.ipi(0, shorthand | ICR_LEVEL_ASSERT | ICR_DELIVERY_FIXED
+                | vector as u32);
+        }
+    }
+
     /// Write a value to the APIC's ICR
     unsafe fn write_icr(&mut self, val: u64) {
         unsafe {
@@ -109,7 +172,7 @@ impl LocalApic {
                 }
                 ApicMode::X2Apic => {
                     // Write the entire 64-bit value in one shot
-                    cpu::wrmsr(0x830, val);
+                    Msr::Raw(0x830).write(val);
                 }
             }
         }
@@ -246,7 +309,7 @@ impl LocalApic {
         let apic_mode = if let ApicMode::X2Apic = self.mode {
             IA32_APIC_BASE_EXTD
         } else { 0 };
-        cpu::wrmsr(IA32_APIC_BASE, self.orig.ia32_apic_base | apic_mode);
+        Msr::ApicBase.write(self.orig.ia32_apic_base | apic_mode);
 
         // Reload the PIC's original state
         cpu::out8(0xA1, self.orig.pic_a1);
@@ -255,7 +318,8 @@ impl LocalApic {
     }
 
     /// Enable the APIC timer which is used to check the serial port
-    /// periodically to see if the user wants to issue a soft reboot
+    /// periodically to see if the user wants to issue a soft reboot, and to
+    /// service the timers scheduled by `time::after()`
     pub unsafe fn enable_reboot_timer(&mut self) {
         const PERIODIC_MODE: u32 = 1 << 17;
 
@@ -276,7 +340,7 @@ impl LocalApic {
 
             // Program the APIC
             self.write(Register::LvtTimer,
-                PERIODIC_MODE | (InterruptId::SoftRebootTimer as u8 as u32));
+                PERIODIC_MODE | u8::from(InterruptId::SoftRebootTimer) as u32);
 
             // Enable the timer by setting the initial count
             self.write(Register::InitialCount, 100_000);
@@ -406,7 +470,7 @@ pub unsafe fn init() {
     assert!(cur_apic.is_none(), "APIC already initialized!");
 
     // Get the CPU features
-    let cpu_features = cpu::Features::get();
+    let cpu_features = cpu::Features::get_cached();
 
     // APIC must be supported
     assert!(cpu_features.apic, "APIC is not available on this system.");
@@ -414,7 +478,7 @@ pub unsafe fn init() {
     // Enable the APIC base
     let (orig_ia32_apic_base, orig_pic_a1, orig_pic_21) = unsafe {
         // Load the IA32_APIC_BASE
-        let orig_ia32_apic_base = cpu::rdmsr(IA32_APIC_BASE);
+        let orig_ia32_apic_base = Msr::ApicBase.read();
 
         // The APIC must be globally enabled as re-enabling a disabled APIC is
         // not always supported.
@@ -440,7 +504,7 @@ pub unsafe fn init() {
         cpu::out8(0x21, 0xFF);
 
         // Reprogram the APIC with our new settings.
-        cpu::wrmsr(IA32_APIC_BASE, apic_base);
+        Msr::ApicBase.write(apic_base);
 
         // Return out the original configuration
         (orig_ia32_apic_base, orig_pic_a1, orig_pic_21)
@@ -64,6 +64,13 @@ pub fn check_in() {
     }
 }
 
+/// Get the total number of cores on the system as reported by the MADT
+///
+/// Returns `None` if ACPI hasn't been parsed yet
+pub fn total_cores() -> Option<u32> {
+    TOTAL_CORES.try_get().copied()
+}
+
 /// Set the current execution state of a given APIC ID
 #[track_caller]
 pub fn set_core_state(id: u32, state: ApicState) {
@@ -169,11 +176,13 @@ pub fn init_system(apics: Vec<u32>) {
         set_core_state(id, ApicState::Launched);
 
         // INIT-SIPI-SIPI; launch the core
-        let entry = (ENTRY_ADDR / 0x1000) as u32;
+        let entry = (ENTRY_ADDR / 0x1000) as u8;
         unsafe {
-            apic.ipi(id, 0x4500);
-            apic.ipi(id, 0x4600 + entry);
-            apic.ipi(id, 0x4600 + entry);
+            apic.send_init(id);
+            crate::time::sleep(10_000);
+            apic.send_sipi(id, entry);
+            crate::time::sleep(200);
+            apic.send_sipi(id, entry);
         }
 
         // Wait for the core to come online
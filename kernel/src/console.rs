@@ -0,0 +1,114 @@
+//! A minimal console over the serial port
+//!
+//! The serial port is polled by the APIC timer of the BSP, and every byte
+//! received is handled as a single command:
+//!
+//! | Byte | Command                                             |
+//! |------|-----------------------------------------------------|
+//! | `H`  | Halt the kernel                                     |
+//! | `r`  | Soft reboot the kernel, also accepted after a panic |
+//! | `S`  | Same as `r`, kept from before the console existed   |
+//! | `n`  | Print the statistics of the network devices         |
+//! | `m`  | Print the physical memory statistics                |
+//! | `i`  | Print the number of received interrupts             |
+//!
+//! The diagnostic commands (`n`, `m` and `i`) are only available with the
+//! `debug_console` feature, which is enabled by default. Unknown bytes are
+//! ignored.
+//!
+//! The commands run in interrupt context, so the diagnostics are best-effort
+//! and skip anything whose lock is held.
+
+use core::sync::atomic::Ordering;
+
+/// Command halting the kernel
+pub const HALT: u8 = b'H';
+
+/// Command soft rebooting the kernel
+pub const REBOOT: u8 = b'r';
+
+/// The original soft reboot command, still accepted as an alias of `REBOOT`
+pub const REBOOT_ALIAS: u8 = b'S';
+
+/// Returns whether `byte` is a soft reboot command
+pub fn is_reboot(byte: u8) -> bool {
+    byte == REBOOT || byte == REBOOT_ALIAS
+}
+
+/// Handle the command `byte` received over the serial port
+pub fn handle(byte: u8) {
+    match byte {
+        HALT => panic!("Halt requested by the user"),
+        REBOOT | REBOOT_ALIAS => {
+            // Mark the kernel as rebooting and panic
+            core!().shared.rebooting.store(true, Ordering::SeqCst);
+            panic!("Soft reboot requested by the user");
+        }
+
+        #[cfg(feature = "debug_console")]
+        b'n' => diagnostics::net(),
+        #[cfg(feature = "debug_console")]
+        b'm' => diagnostics::memory(),
+        #[cfg(feature = "debug_console")]
+        b'i' => diagnostics::interrupts(),
+
+        _ => {}
+    }
+}
+
+/// The diagnostics printed by the console commands
+#[cfg(feature = "debug_console")]
+mod diagnostics {
+    use crate::net::NetDevice;
+    use crate::interrupts::{InterruptId, interrupt_count};
+
+    /// Print the link and packet pool statistics of all network devices
+    pub fn net() {
+        let devices = NetDevice::all();
+        if devices.is_empty() {
+            println!("No network devices");
+            return;
+        }
+
+        for dev in devices {
+            let stats = dev.packet_stats();
+            let link = match (dev.link_up(), dev.link_speed_mbps()) {
+                (false, _)         => "down".into(),
+                (true, Some(mbps)) => alloc::format!("up, {mbps} Mb/s"),
+                (true, None)       => "up".into(),
+            };
+
+            println!("net{} {:X?} link {link}", dev.id(), dev.mac().0);
+            let traffic = dev.stats();
+            println!(" ├ RX: {} packets, {} bytes, {} errors",
+                     traffic.rx_packets, traffic.rx_bytes, traffic.rx_errors);
+            println!(" ├ TX: {} packets, {} bytes",
+                     traffic.tx_packets, traffic.tx_bytes);
+            println!(" └ Packets: {} allocated, {} released, {} dropped, \
+                      {} in flight", stats.allocated, stats.released,
+                      stats.dropped_on_release, stats.in_flight);
+        }
+    }
+
+    /// Print the amount of free physical memory and the free list refills of
+    /// this core
+    pub fn memory() {
+        match crate::mm::free_bytes() {
+            Some(free) => println!("Free memory: {} MiB", free / (1024 * 1024)),
+            None       => println!("Free memory: busy"),
+        }
+        println!("Remote refills of core {}: {}",
+                 core!().id, core!().remote_refills());
+    }
+
+    /// Print the number of times each interrupt has been received
+    pub fn interrupts() {
+        for vector in 0..=u8::MAX {
+            let id = InterruptId::from(vector);
+            let count = interrupt_count(id);
+            if count != 0 {
+                println!("{vector:#04X} {id:?}: {count}");
+            }
+        }
+    }
+}
@@ -6,13 +6,15 @@ use core::alloc::Layout;
 
 use page_table::VirtAddr;
 use shared_data::Shared;
-use spinlock::{SpinLock, InterruptState, DummyInterruptState};
+use spinlock::{
+    SpinLock, InterruptState, DummyInterruptState, InterruptDisables};
 use oncelock::OnceLock;
 use autorefcount::{AutoRefCount, AutoRefCountGuard};
 
-use crate::mm::FreeList;
+use crate::mm::{FreeList, PhysWindow};
 use crate::interrupts::Interrupts;
 use crate::apic::LocalApic;
+use crate::time::TimerWheel;
 
 /// The cumulative variable used for allocating core IDs
 static NEXT_CORE_ID: AtomicU32 = AtomicU32::new(0);
@@ -32,8 +34,28 @@ impl InterruptState for InterruptLock {
     fn in_exception() -> bool { core!().in_exception() }
     fn enter_lock()           { unsafe { core!().disable_interrupts(); } }
     fn exit_lock()            { unsafe { core!().enable_interrupts(); } }
+
+    #[cfg(feature = "lock_hold_time")]
+    fn long_hold(location: &'static core::panic::Location<'static>,
+                 cycles: u64) {
+        use core::fmt::Write;
+
+        // This core may be holding the print lock already, e.g. when the lock
+        // released was taken in the middle of a print, so the report is
+        // dropped rather than deadlocking
+        let Some(_lock) = core!().shared.print_lock.try_lock() else { return; };
+        let _ = writeln!(crate::print::Serial,
+            "Lock taken at {location} held for {} us ({cycles} cycles)",
+            cycles / crate::time::tsc_mhz());
+    }
 }
 
+/// Guard which keeps the interrupts on its core disabled while it's alive.
+///
+/// Created by `CoreLocals::interrupts_disabled()`. The guards nest the same way
+/// as `disable_interrupts()` and `enable_interrupts()` do
+pub type InterruptGuard = spinlock::InterruptGuard<InterruptLock>;
+
 /// Core local data
 #[allow(dead_code)]
 #[repr(C)]
@@ -59,7 +81,7 @@ pub struct CoreLocals {
     /// The number of requests to have interrupts disabled.
     ///
     /// While this value is non-zero, interrupts will be disabled.
-    interrupt_disable_requests: AtomicUsize,
+    interrupt_disable_requests: InterruptDisables,
 
     /// Current level of interrupt nesting.
     interrupt_depth: AutoRefCount,
@@ -78,6 +100,13 @@ pub struct CoreLocals {
     /// Free lists for each power-of-two size.
     /// The free list size is `(1 << (idx + 3))`
     free_lists: [SpinLock<FreeList, InterruptLock>; 61],
+
+    /// Number of times a free list of this core had to be refilled with memory
+    /// from outside of this core's NUMA node
+    remote_refills: AtomicUsize,
+
+    /// Timers pending on this core, serviced from the APIC timer interrupt
+    timers: SpinLock<TimerWheel, InterruptLock>,
 }
 
 impl CoreLocals {
@@ -104,6 +133,18 @@ impl CoreLocals {
         &self.free_lists[idx as usize - 3]
     }
 
+    /// Record that a free list of this core has been refilled with memory
+    /// from outside of this core's NUMA node
+    pub fn record_remote_refill(&self) {
+        self.remote_refills.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the number of times the free lists of this core have been refilled
+    /// with memory from outside of this core's NUMA node
+    pub fn remote_refills(&self) -> usize {
+        self.remote_refills.load(Ordering::Relaxed)
+    }
+
     /// Returns whether this core is the bootstrap processor
     pub fn is_bsp(&self) -> bool {
         self.id == 0
@@ -114,6 +155,11 @@ impl CoreLocals {
         &self.interrupts
     }
 
+    /// Get access to the timers pending on this core
+    pub fn timers(&self) -> &SpinLock<TimerWheel, InterruptLock> {
+        &self.timers
+    }
+
     /// Get access to the local APIC
     pub fn apic(&self) -> &SpinLock<Option<LocalApic>, InterruptLock> {
         &self.apic
@@ -148,6 +194,17 @@ impl CoreLocals {
         self.interrupt_depth.count() != 0
     }
 
+    /// Make sure this core is neither in an interrupt nor an exception. A
+    /// panic here means that an interrupt or exception guard has been leaked.
+    ///
+    /// This is meant to be called from points where no interrupt or exception
+    /// can be in progress
+    #[track_caller]
+    pub fn assert_quiescent(&self) {
+        self.interrupt_depth.assert_zero("Leaked interrupt depth guard");
+        self.exception_depth.assert_zero("Leaked exception depth guard");
+    }
+
     /// Set that we're currently in an exception
     pub fn enter_exception(&self) -> AutoRefCountGuard {
         self.exception_depth.increment()
@@ -158,6 +215,16 @@ impl CoreLocals {
         self.exception_depth.count() != 0
     }
 
+    /// Disable interrupts in a nesting manner until the returned guard is
+    /// dropped.
+    ///
+    /// Prefer this over `disable_interrupts()` for scoped critical sections, as
+    /// the interrupts are restored on every path out of the scope.
+    #[track_caller]
+    pub fn interrupts_disabled(&self) -> InterruptGuard {
+        InterruptGuard::new()
+    }
+
     /// Disable interrupts in a nesting manner.
     ///
     /// The "nesting manner" here means that if multiple `disable_interrupts()`
@@ -165,8 +232,7 @@ impl CoreLocals {
     /// interrupts are enabled again.
     #[track_caller]
     pub unsafe fn disable_interrupts(&self) {
-        let x = self.interrupt_disable_requests.fetch_add(1, Ordering::SeqCst);
-        x.checked_add(1).expect("Overflow on disable interrupts outstanding");
+        self.interrupt_disable_requests.disable();
         unsafe { cpu::disable_interrupts(); }
     }
 
@@ -176,8 +242,7 @@ impl CoreLocals {
     /// `disable_interrupts()` called.
     #[track_caller]
     pub unsafe fn enable_interrupts(&self) {
-        let x = self.interrupt_disable_requests.fetch_sub(1, Ordering::SeqCst);
-        x.checked_sub(1).expect("Overflow on enable interrupts outstanding");
+        let last = self.interrupt_disable_requests.enable();
 
         // Since it's possible interrupts can be enabled when we enter an
         // interrupt, if we acquire a lock in an interrupt and release it, it
@@ -185,7 +250,7 @@ impl CoreLocals {
         // Thus, we never allow enabling interrupts from an interrupt handler.
         // This means interrupts will correctly get re-enabled in this case when
         // the IRET loads the old interrupt flag.
-        if !core!().in_interrupt() && x == 1 {
+        if !core!().in_interrupt() && last {
             unsafe { cpu::enable_interrupts(); }
         }
     }
@@ -210,19 +275,37 @@ pub fn init(shared: page_table::PhysAddr) {
     // Allocate an ID for this core
     let core_id = NEXT_CORE_ID.fetch_add(1, Ordering::SeqCst);
 
-    // Offset the SHARED pointer into our physical window and get its reference
+    // Offset the SHARED pointer into our physical window
     let shared = crate::mm::phys_ptr(shared);
+
+    // Make sure the bootloader was built with the same layout of the shared
+    // data as we were. Nothing else can be trusted otherwise, including the
+    // serial driver the panic handler would print with, so the error is
+    // printed through a new one
+    if !unsafe { shared_data::shared_abi_matches(shared.0 as *const u8) } {
+        unsafe {
+            serial::SerialDriver::init().write(b"Shared data ABI mismatch: \
+                the bootloader and the kernel were built from different \
+                sources\n");
+        }
+        cpu::halt();
+    }
+
+    // Get the reference to the shared data
     let shared = unsafe { &*(shared.0 as *const Shared<DummyInterruptState>) };
 
-    // Allocate space for the core locals
+    // Allocate space for the core locals. The NUMA shards can't be locked
+    // without them, so they come from the global free memory, which keeps room
+    // for them once NUMA is registered (see `mm::register_numa()`)
     let core_locals_ptr = {
         let mut pmem = shared.free_memory().lock();
         let pmem = pmem.as_mut().unwrap();
 
-        pmem.allocate(
+        let paddr = pmem.allocate(
             core::mem::size_of::<CoreLocals>() as u64,
             core::mem::align_of::<CoreLocals>() as u64
-        ).unwrap().unwrap() + shared_data::KERNEL_PHYS_WINDOW_BASE
+        ).unwrap().unwrap();
+        page_table::PhysAddr(paddr).to_virt().0
     };
 
     macro_rules! generate_freelists {
@@ -274,9 +357,12 @@ pub fn init(shared: page_table::PhysAddr) {
         interrupts: SpinLock::new_no_preempt(None),
         exception_depth: AutoRefCount::new(0),
         interrupt_depth: AutoRefCount::new(0),
-        interrupt_disable_requests: AtomicUsize::new(0),
+        interrupt_disable_requests: InterruptDisables::new(),
 
         free_lists,
+        remote_refills: AtomicUsize::new(0),
+
+        timers: SpinLock::new_no_preempt(TimerWheel::new()),
     };
 
     unsafe {
@@ -16,7 +16,8 @@
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use alloc::vec;
-use core::mem::ManuallyDrop;
+
+use page_table::VirtAddr;
 
 /// A 64-bit task state segment structure
 #[repr(C, packed)]
@@ -31,6 +32,29 @@ pub struct Tss {
     iopb_offset: u16,
 }
 
+impl Tss {
+    /// Set the stack which is switched to when an interrupt whose IDT entry
+    /// uses the IST `index` fires. Valid indices are `1..=7`, as `0` in the IDT
+    /// entry means no stack switch.
+    pub fn set_ist(&mut self, index: usize, stack_top: VirtAddr) {
+        assert!((1..=7).contains(&index), "Invalid TSS IST index");
+        self.ist[index - 1] = stack_top.0;
+    }
+
+    /// Get the stack set for the IST `index`, if any
+    pub fn ist(&self, index: usize) -> Option<VirtAddr> {
+        assert!((1..=7).contains(&index), "Invalid TSS IST index");
+        let ist = self.ist;
+        (ist[index - 1] != 0).then_some(VirtAddr(ist[index - 1]))
+    }
+
+    /// Set the stack which is switched to when an interrupt fires while
+    /// running in ring 3
+    pub fn set_rsp0(&mut self, stack_top: VirtAddr) {
+        self.rsp[0] = stack_top.0;
+    }
+}
+
 /// The kernel GDT
 #[repr(transparent)]
 pub struct Gdt {
@@ -41,7 +65,8 @@ pub struct Gdt {
 impl Gdt {
     /// Create a new GDT, placing the long mode descriptors to where the CS and
     /// DS currently point (such that we don't have to change them when loading
-    /// this GDT) and append a TSS with a critical stack at IST[0].
+    /// this GDT) and append an empty TSS. The stacks of the TSS have to be set
+    /// before they are used by any interrupts.
     ///
     /// Returns the GDT, the TSS and the offset of the TSS into GDT.
     pub fn new() -> (Self, Box<Tss>, u16) {
@@ -61,12 +86,7 @@ impl Gdt {
         gdt[ds_idx] = 0x0000920000000000; // 64-bit, present, data
 
         // Create a new TSS
-        let mut tss: Box<Tss> = Box::default();
-
-        // Create a 32 KiB critical stack for #DF, #MC and NMI
-        let crit_stack: ManuallyDrop<Vec<u8>> = ManuallyDrop::new(
-            Vec::with_capacity(32 * 1024));
-        tss.ist[0] = crit_stack.as_ptr() as u64 + crit_stack.capacity() as u64;
+        let tss: Box<Tss> = Box::default();
 
         // Create the task pointer in the GDT
         let tss_base = &*tss as *const Tss as u64;
@@ -2,11 +2,12 @@
 
 #![allow(unused_variables)]
 
-use core::sync::atomic::Ordering;
+use page_table::VirtAddr;
 
-use crate::interrupts::InterruptArgs;
+use crate::interrupts::{InterruptArgs, InterruptFrame, PageFaultError};
 use crate::panic::bsp_in_panic;
-use crate::apic::{set_core_state, ApicState};
+use crate::mm::VirtWindow;
+use crate::apic::{set_core_state, total_cores, ApicState};
 
 /// NMI handler
 ///
@@ -26,40 +27,81 @@ pub unsafe fn nmi(args: InterruptArgs) -> bool {
     if core!().is_bsp() {
         panic!("Panic occured on another core");
     } else {
-        // Set that we're halted and halt forever
+        // Set that we're halted
         set_core_state(core!().apic_id().unwrap(), ApicState::Halted);
+
+        // Park at the barrier until the BSP confirms all cores have parked.
+        // The BSP only releases the barrier when it soft reboots, so don't
+        // spin at it on a plain panic
+        if core!().shared.is_rebooting() {
+            if let Some(total) = total_cores() {
+                core!().shared.rendezvous(total as u64);
+            }
+        }
+
+        // Halt forever
+        unsafe { cpu::disable_interrupts(); }
         cpu::halt();
     }
 }
 
 /// Page Fault handler
+///
+/// Page faults aren't recovered from. This only describes the fault before it's
+/// reported as unhandled
 pub unsafe fn page_fault(args: InterruptArgs) -> bool {
+    let error = PageFaultError(args.error);
+    let cr2 = cpu::read_cr2();
+
+    // Note faults in the physical window, which are likely caused by a bad
+    // physical address rather than a bad mapping
+    let in_window = VirtAddr(cr2).try_to_phys().is_some();
+    let note = if in_window { " (in the physical window)" } else { "" };
+
+    println!("Page fault: {error} at {cr2:#X}{note}");
     false
 }
 
+/// Breakpoint and overflow handler
+///
+/// Dumps the state of the core and continues execution, so `cpu::int3()` can
+/// be used to inspect the state at any point in the kernel. Both exceptions
+/// are traps, so the RIP in the frame already points past the instruction
+/// which raised them and returning resumes right after it
+pub unsafe fn breakpoint(args: InterruptArgs) -> bool {
+    let InterruptFrame { rip, rsp, rflags, .. } = *args.frame;
+    let regs = *args.regs;
+    let (rax, rcx, rdx, rbx) = (regs.rax, regs.rcx, regs.rdx, regs.rbx);
+    let (rbp, rsi, rdi)      = (regs.rbp, regs.rsi, regs.rdi);
+    let (r8, r9, r10, r11)   = (regs.r8, regs.r9, regs.r10, regs.r11);
+    let (r12, r13, r14, r15) = (regs.r12, regs.r13, regs.r14, regs.r15);
+
+    println!(r#"{:?} on core <{}>
+ ├ rax {rax:016X} rcx {rcx:016X} rdx {rdx:016X} rbx {rbx:016X}
+ ├ rsp {rsp:016X} rbp {rbp:016X} rsi {rsi:016X} rdi {rdi:016X}
+ ├ r8  {r8:016X} r9  {r9:016X} r10 {r10:016X} r11 {r11:016X}
+ ├ r12 {r12:016X} r13 {r13:016X} r14 {r14:016X} r15 {r15:016X}
+ └ rip {rip:016X} rfl {rflags:016X}"#, args.id, core!().id);
+    true
+}
+
 /// Soft Reboot Timer handler
 ///
 /// The soft reboot timer is an APIC timer that causes us to periodically check
-/// the serial port to see if the user wants to issue a soft reboot
+/// the serial port to see if the user wants to issue a soft reboot. It also
+/// runs the expired timers of the core
 pub unsafe fn soft_reboot_timer(_args: InterruptArgs) -> bool {
+    // Run the timers of this core
+    crate::time::service_timers();
+
     // Only allow soft reboot attempts from the BSP
     if !core!().is_bsp() { return true; }
 
-    // Attempt to get a byte from the serial port
+    // Attempt to get a byte from the serial port and handle it as a console
+    // command
     let byte = { core!().shared.serial.lock().as_mut().unwrap().read_byte() };
-
-    // TODO: I don't like the fact that these are hardcoded here
-
-    // Halt request
-    if let Some(b'H') = byte {
-        panic!("Halt requested by the user");
-    }
-
-    // Soft reboot request. This is also hardcoded in panic.rs :(
-    if let Some(b'S') = byte {
-        // Mark the kernel as rebooting and panic
-        core!().shared.rebooting.store(true, Ordering::SeqCst);
-        panic!("Soft reboot requested by the user");
+    if let Some(byte) = byte {
+        crate::console::handle(byte);
     }
 
     true
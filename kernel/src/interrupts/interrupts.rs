@@ -2,13 +2,28 @@
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::arch::asm;
 
 use crate::interrupts::{
     handler, INT_HANDLERS, AllRegs, Gdt, Tss, get_selector_indices};
 use crate::apic::LocalApic;
 
+pub use vectors::InterruptId;
+use vectors::DispatchTable;
+
+/// Size of each of the critical interrupt stacks
+const CRITICAL_STACK_SIZE: u64 = 32 * 1024;
+
+/// IST index of the stack used by NMIs
+const NMI_IST: usize = 1;
+
+/// IST index of the stack used by double faults
+const DOUBLE_FAULT_IST: usize = 2;
+
+/// IST index of the stack used by machine checks
+const MACHINE_CHECK_IST: usize = 3;
+
 /// Indicates whether the interrupt number at index into this array requires an
 /// EOI when handled
 pub static EOI_REQUIRED: [AtomicBool; 256] =
@@ -20,6 +35,10 @@ pub static EOI_REQUIRED: [AtomicBool; 256] =
 /// interrupts before we shut down the APIC and the kernel.
 pub static DRAINING_EOIS: AtomicBool = AtomicBool::new(false);
 
+/// Number of times each interrupt has been received, over all cores
+static INTERRUPT_COUNTS: [AtomicU64; 256] =
+    [const { AtomicU64::new(0) }; 256];
+
 /// Indicates whether this interrupt is supposed to get handled even when we're
 /// draining EOIs
 static DRAIN_PRECEDENCE: [AtomicBool; 256] =
@@ -43,6 +62,58 @@ pub unsafe fn eoi_bitmask() -> [u128; 2] {
     bitmask
 }
 
+/// The error code pushed by the CPU on a page fault
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PageFaultError(pub u64);
+
+impl PageFaultError {
+    /// The fault was caused by a protection violation on a present page, as
+    /// opposed to a non-present page
+    pub fn present(&self) -> bool {
+        (self.0 & (1 << 0)) != 0
+    }
+
+    /// The fault was caused by a write, as opposed to a read
+    pub fn write(&self) -> bool {
+        (self.0 & (1 << 1)) != 0
+    }
+
+    /// The fault was caused by an access from ring 3
+    pub fn user(&self) -> bool {
+        (self.0 & (1 << 2)) != 0
+    }
+
+    /// A reserved bit was set in one of the paging structures
+    pub fn reserved(&self) -> bool {
+        (self.0 & (1 << 3)) != 0
+    }
+
+    /// The fault was caused by an instruction fetch
+    pub fn instruction_fetch(&self) -> bool {
+        (self.0 & (1 << 4)) != 0
+    }
+}
+
+impl core::fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let access = match (self.instruction_fetch(), self.write()) {
+            (true, _)     => "instruction fetch from",
+            (false, true) => "write to",
+            _             => "read from",
+        };
+        let page = if self.present() { "present" } else { "non-present" };
+        let mode = if self.user() { "user" } else { "kernel" };
+
+        write!(f, "{mode} {access} {page} page")?;
+        if self.reserved() {
+            write!(f, " with a reserved bit set in the page tables")?;
+        }
+
+        Ok(())
+    }
+}
+
 /// The interrupt information passed to all interrupt handlers
 #[derive(Clone, Copy, Debug)]
 pub struct InterruptArgs<'a> {
@@ -69,7 +140,7 @@ impl<'a> InterruptArgs<'a> {
 
     /// Returns whether this interrupt is an exception
     pub fn is_exception(&self) -> bool {
-        (self.id as u8) < 32
+        self.id.is_exception()
     }
 }
 
@@ -80,7 +151,7 @@ type InterruptDispatch = unsafe fn(InterruptArgs) -> bool;
 
 /// Structure to hold different dispatch routines for interrupts
 pub struct Interrupts {
-    dispatch: [Option<InterruptDispatch>; 256],
+    dispatch: DispatchTable<InterruptDispatch>,
     pub tss: Box<Tss>,
     pub idt: Vec<IdtEntry>,
     pub gdt: Gdt,
@@ -91,18 +162,16 @@ impl Interrupts {
     #[track_caller]
     pub fn register(&mut self, id: InterruptId, handler: InterruptDispatch,
             eoi: bool) {
-        let idx = id as usize;
+        let idx = usize::from(id);
 
         // Do not register any handler for reserved interrupts
-        assert!(id < InterruptId::Reserved || id > InterruptId::LastReserved,
+        assert!(!id.is_reserved(),
             "Can't register handler for reserved interrupts.");
 
-        // Re-registering an interrupt handler at runtime is undefined behavior
-        assert!(self.dispatch[idx].is_none(),
-            "Interrupt handler already installed for {:?}", id);
-
-        // Register the handler
-        self.dispatch[idx] = Some(handler);
+        // Register the handler. Re-registering an interrupt handler at runtime
+        // is undefined behavior
+        let installed = self.dispatch.insert(id, handler);
+        assert!(installed, "Interrupt handler already installed for {:?}", id);
 
         // Register whether EOI is required when handling this interrupt
         EOI_REQUIRED[idx].store(eoi, Ordering::SeqCst);
@@ -116,15 +185,55 @@ impl Interrupts {
         self.register(id, handler, eoi);
 
         // Register that this interrupt gets handled even during EOI draining
-        DRAIN_PRECEDENCE[id as usize].store(true, Ordering::SeqCst);
+        DRAIN_PRECEDENCE[usize::from(id)].store(true, Ordering::SeqCst);
+    }
+
+    /// Allocate a free dynamic vector on this core and register `handler` for
+    /// it.
+    ///
+    /// Returns the allocated interrupt, or `None` if all of the dynamic vectors
+    /// are in use already.
+    pub fn allocate(&mut self, handler: InterruptDispatch, eoi: bool)
+            -> Option<InterruptId> {
+        // Find the first dynamic vector without a handler
+        let vector = (InterruptId::FIRST_DYNAMIC..=InterruptId::LAST_DYNAMIC)
+            .find(|&vector| !self.is_registered(InterruptId::Dynamic(vector)))?;
+
+        // Register the handler for it
+        let id = InterruptId::Dynamic(vector);
+        self.register(id, handler, eoi);
+
+        Some(id)
     }
 
     /// Unregister an interrupt handler
     pub fn unregister(&mut self, id: InterruptId) {
-        let idx = id as usize;
-        self.dispatch[idx] = None;
+        let idx = usize::from(id);
+        self.dispatch.remove(id);
         EOI_REQUIRED[idx].store(false, Ordering::SeqCst);
     }
+
+    /// Returns whether a handler is registered for `id`
+    pub fn is_registered(&self, id: InterruptId) -> bool {
+        self.dispatch.get(id).is_some()
+    }
+
+    /// Returns the handler registered for `id`, if any
+    pub fn handler_for(&self, id: InterruptId) -> Option<InterruptDispatch> {
+        self.dispatch.get(id)
+    }
+
+    /// Set the handler which is called for interrupts without a registered
+    /// handler, replacing the previous one.
+    ///
+    /// Interrupts which the default handler doesn't handle either still cause
+    /// a panic. Handlers registered for a specific interrupt are never
+    /// overridden by the default, even if they don't handle it. EOIs are only
+    /// sent for interrupts registered with `eoi` set, so a default handler for
+    /// APIC interrupts has to EOI them itself.
+    pub fn set_default(&mut self, handler: InterruptDispatch) {
+        self.dispatch.set_default(handler);
+    }
 }
 
 /// Shape of a raw 64-bit interrupt frame
@@ -189,15 +298,24 @@ impl TablePtr {
     }
 }
 
-/// Switch to a kernel-based GDT, load a TSS with a critical stack for #DF, #MC
+/// Switch to a kernel-based GDT, load a TSS with critical stacks for #DF, #MC
 /// and NMI interrupts and setup an IDT.
+///
+/// Each core gets its own critical stacks, and each of the critical interrupts
+/// gets a dedicated one, so an NMI arriving during a #DF or #MC doesn't reuse
+/// the stack the other handler is running on.
 pub fn init() {
     // Get access to the interrupts. Don't reinitialize them
     let mut interrupts = core!().interrupts().lock();
     assert!(interrupts.is_none(), "Interrupts already initialized!");
 
     // Create the GDT
-    let (gdt, tss, tss_entry) = Gdt::new();
+    let (gdt, mut tss, tss_entry) = Gdt::new();
+
+    // Map in the critical stacks
+    for ist in [NMI_IST, DOUBLE_FAULT_IST, MACHINE_CHECK_IST] {
+        tss.set_ist(ist, crate::mm::map_kernel_stack(CRITICAL_STACK_SIZE));
+    }
 
     // Create a pointer to the GDT for lgdt to load
     let gdt_ptr = TablePtr::new(
@@ -230,7 +348,9 @@ pub fn init() {
     for (id, &handler) in INT_HANDLERS.iter().enumerate().take(idt.capacity()) {
         let ist = match id {
             // NMI, #DF, #MC use the IST
-            2 | 8 | 18 => { 1 },
+            2  => { NMI_IST },
+            8  => { DOUBLE_FAULT_IST },
+            18 => { MACHINE_CHECK_IST },
 
             // The rest uses the existing stack
             _ => { 0 },
@@ -243,7 +363,7 @@ pub fn init() {
         idt.push(IdtEntry::new(
             cs,                 // Kernel code segment in the GDT
             handler as usize,   // Address of the handler for all interrupts
-            ist,                // IST index
+            ist as u32,         // IST index
             X64_INTERRUPT_GATE, // Type (interrupt gate)
             0                   // DPL
         ));
@@ -252,6 +372,9 @@ pub fn init() {
     // Make sure the entire IDT is present fully
     assert!(core::mem::size_of_val(&idt[..]) == 4096);
 
+    // Make sure the critical interrupts don't run on a null stack
+    assert!(tss.ist(NMI_IST).is_some(), "NMI IST stack not set!");
+
     // Load the idt
     let idt_ptr = TablePtr::new(0xFFF, idt.as_ptr() as u64);
     unsafe {
@@ -262,11 +385,20 @@ pub fn init() {
     }
 
     // Create the interrupts structure and register our handlers
-    let mut ints = Interrupts { dispatch: [None; 256], gdt, idt, tss };
+    let mut ints = Interrupts {
+        dispatch: DispatchTable::new(),
+        gdt,
+        idt,
+        tss,
+    };
     ints.register_precedent(
         InterruptId::NonMaskableInterrupt, handler::nmi, false);
     ints.register_precedent(
         InterruptId::PageFault, handler::page_fault, false);
+    ints.register_precedent(
+        InterruptId::Breakpoint, handler::breakpoint, false);
+    ints.register_precedent(
+        InterruptId::Overflow, handler::breakpoint, false);
 
     *interrupts = Some(ints);
 }
@@ -274,14 +406,17 @@ pub fn init() {
 /// This is the entry point for all interrupts
 #[unsafe(no_mangle)]
 unsafe extern "sysv64" fn interrupt_entry(
-    id: InterruptId,
+    vector: u8,
     frame: &InterruptFrame,
     error: u64,
     regs: &AllRegs,
 ) {
     // Get the arguments for this interrupt
-    let args = InterruptArgs::new(id, frame, error, regs);
-    let idx = id as usize;
+    let args = InterruptArgs::new(vector.into(), frame, error, regs);
+    let idx = vector as usize;
+
+    // Count the interrupt
+    INTERRUPT_COUNTS[idx].fetch_add(1, Ordering::Relaxed);
 
     // Increment the refcount for this interrupt. Gets decremented on scope end
     let _depth = if args.is_exception() {
@@ -295,17 +430,15 @@ unsafe extern "sysv64" fn interrupt_entry(
     let draining_eois = DRAINING_EOIS.load(Ordering::SeqCst);
     let precedent = DRAIN_PRECEDENCE[idx].load(Ordering::SeqCst);
 
-    // If we're not draining interrupts, attempt to handle it
+    // If we're not draining interrupts, attempt to handle it, falling back
+    // to the default handler if there's no handler registered for it
     let handled = if !draining_eois || precedent {
-        unsafe {
-            core!()
-                .interrupts()
-                .lock()
-                .as_ref()
-                .unwrap()
-                .dispatch[idx]
-                .is_some_and(|handler| handler(args))
-        }
+        let handler = {
+            let interrupts = core!().interrupts().lock();
+            let interrupts = interrupts.as_ref().unwrap();
+            interrupts.dispatch.lookup(args.id)
+        };
+        unsafe { handler.is_some_and(|handler| handler(args)) }
     } else {
         false
     };
@@ -322,6 +455,12 @@ unsafe extern "sysv64" fn interrupt_entry(
     if !handled { unhandled(args); }
 }
 
+/// Returns the number of times the interrupt `id` has been received, over all
+/// cores
+pub fn interrupt_count(id: InterruptId) -> u64 {
+    INTERRUPT_COUNTS[usize::from(id)].load(Ordering::Relaxed)
+}
+
 #[inline(always)]
 fn unhandled(args: InterruptArgs) -> ! {
     /// Macro to copy unaligned fields from a packed struct.
@@ -364,74 +503,3 @@ Unhandled interrupt <{id:X?}>, error code <{error:#X}> on core <{core_id}>
  └ xmm14 {xmm14:032X} xmm15 {xmm15:032X}
 "#);
 }
-
-/// Legacy ISA interrupt identifiers
-#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Copy, Clone)]
-#[repr(u8)]
-pub enum InterruptId {
-    DivideBy0 = 0x00,
-    // Reserved = 0x01,
-    NonMaskableInterrupt = 0x02,
-    Breakpoint,
-    Overflow,
-    BoundsRangeExceeded,
-    InvalidOpcode,
-    DeviceNotAvailable,
-    DoubleFault,
-    CoprocessorSegmentOverrun,
-    InvalidTSS,
-    SegmentNotPresent,
-    StackSegmentFault,
-    GeneralProtectionFault,
-    PageFault,
-    // Reserved = 0x0F,
-    X87FPUError = 0x10,
-    AlignmentCheck,
-    MachineCheck,
-    SIMDFloatingPointException,
-
-    // Reserved hole
-    Reserved = 0x14,
-    LastReserved = 0x1F,
-
-    // Kernel definable interrupts start at 0x20
-    SoftRebootTimer = 0x20,
-}
-
-impl From<u8> for InterruptId {
-    fn from(val: u8) -> Self {
-        match val {
-            // Well defined IDT entries
-            0x00 => Self::DivideBy0,
-            0x02 => Self::NonMaskableInterrupt,
-            0x03 => Self::Breakpoint,
-            0x04 => Self::Overflow,
-            0x05 => Self::BoundsRangeExceeded,
-            0x06 => Self::InvalidOpcode,
-            0x07 => Self::DeviceNotAvailable,
-            0x08 => Self::DoubleFault,
-            0x09 => Self::CoprocessorSegmentOverrun,
-            0x0A => Self::InvalidTSS,
-            0x0B => Self::SegmentNotPresent,
-            0x0C => Self::StackSegmentFault,
-            0x0D => Self::GeneralProtectionFault,
-            0x0E => Self::PageFault,
-            0x10 => Self::X87FPUError,
-            0x11 => Self::AlignmentCheck,
-            0x12 => Self::MachineCheck,
-            0x13 => Self::SIMDFloatingPointException,
-
-            // Kernel defined IDT entries
-            0x20 => Self::SoftRebootTimer,
-
-            // Everything else is reserved and must not be used
-            _ => Self::Reserved,
-        }
-    }
-}
-
-impl From<InterruptId> for usize {
-    fn from(val: InterruptId) -> Self {
-        val as u8 as usize
-    }
-}
@@ -17,6 +17,7 @@ pub mod mm;
 pub mod interrupts;
 pub mod apic;
 pub mod time;
+pub mod console;
 pub mod pci;
 pub mod acpi;
 pub mod net;
@@ -8,8 +8,18 @@ extern "sysv64" fn entry(shared: page_table::PhysAddr) -> ! {
     // Initialize core locals
     kernel::core_locals::init(shared);
 
-    // Disable interrupts just to match the `enable_interrupts()` call later
-    unsafe { kernel::core!().disable_interrupts(); }
+    // Load the page attribute table, so all memory types can be mapped in
+    unsafe { cpu::set_pat(page_table::PAT); }
+
+    // Enable the supervisor protections supported by the core. The kernel
+    // doesn't map in any user pages, so nothing needs to get around SMAP
+    let features = cpu::Features::get_cached();
+    unsafe {
+        cpu::enable_protections(features.smep, features.smap, features.umip);
+    }
+
+    // Keep the interrupts disabled until the core is initialized
+    let no_interrupts = kernel::core!().interrupts_disabled();
 
     // Initialize the interrupts
     kernel::interrupts::init();
@@ -22,25 +32,35 @@ extern "sysv64" fn entry(shared: page_table::PhysAddr) -> ! {
         // Calibrate the TSC
         unsafe { kernel::time::calibrate(); }
 
-        // Initialize PCI devices and drivers
-        unsafe { kernel::pci::init(); }
+        // Enforce W^X on the kernel before the other cores start using its
+        // page table
+        kernel::mm::harden_self();
 
-        // Initialize NUMA information and bring up all APICs on the system
+        // Initialize NUMA information and bring up all APICs on the system.
+        // This has to happen before PCI init, as the ACPI tables tell us where
+        // the PCIe configuration space lives
         unsafe { kernel::acpi::init().expect("Couldn't parse ACPI tables"); }
 
-        // Enable the APIC timer. This timer is used to check the serial port
-        // periodically to see if the user wants to issue a soft reboot
-        unsafe {
-            kernel::core!().apic().lock().as_mut().unwrap()
-                .enable_reboot_timer();
-        }
+        // Initialize PCI devices and drivers
+        unsafe { kernel::pci::init(); }
+    }
+
+    // Enable the APIC timer. This timer is used to check the serial port
+    // periodically to see if the user wants to issue a soft reboot, and to
+    // service the timers of this core
+    unsafe {
+        kernel::core!().apic().lock().as_mut().unwrap()
+            .enable_reboot_timer();
     }
 
     // The core is ready, enable interrupts!
-    unsafe { kernel::core!().enable_interrupts(); }
+    drop(no_interrupts);
 
     // Check in that this core has booted and is ready!
     kernel::apic::check_in();
 
+    // Nothing is in progress on this core before it goes idle
+    kernel::core!().assert_quiescent();
+
     cpu::halt();
 }
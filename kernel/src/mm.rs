@@ -1,19 +1,21 @@
 //! Kernel memory allocation routines and structures
 
 use alloc::vec::Vec;
-use core::mem::size_of;
+use core::mem::{size_of, align_of};
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::alloc::{GlobalAlloc, Layout};
 use core::marker::PhantomData;
 
 use oncelock::OnceLock;
 use page_table::{
-    PhysMem, PhysAddr, VirtAddr, MapRequest, Permissions, PageType};
+    PhysMem, PhysAddr, VirtAddr, MapRequest, Permissions, PageType, Window};
 use shared_data::{
     KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE, KERNEL_VMEM_BASE};
-use rangeset::RangeSet;
+use rangeset::{RangeSet, Range, AllocPolicy};
+use rangeset::shards::{self, Shard};
 
 use crate::apic::{ApicDomains, MemoryDomains, MAX_APIC_ID};
+use crate::core_locals::{CoreLocals, InterruptLock};
 
 /// Mappings of APIC IDs to their NUMA node memory ranges
 ///
@@ -23,6 +25,18 @@ use crate::apic::{ApicDomains, MemoryDomains, MAX_APIC_ID};
 /// associated memory range, the entry will be `None`.
 static APIC_TO_MEM_RANGE: OnceLock<&[Option<RangeSet>]> = OnceLock::new();
 
+/// Free physical memory shards of all NUMA nodes
+///
+/// Once NUMA is registered, the memory of each node is moved out of the global
+/// free memory (`shared.free_memory()`) into the node's shard, so cores on
+/// different nodes allocate under different locks. The global free memory
+/// keeps the memory which doesn't belong to any node and serves as the
+/// fallback when a shard runs dry. See `rangeset::shards` for the lock order
+static NUMA_SHARDS: OnceLock<&[Shard<InterruptLock>]> = OnceLock::new();
+
+/// Index into `NUMA_SHARDS` of the NUMA node of each APIC ID
+static APIC_TO_SHARD: OnceLock<&[Option<usize>]> = OnceLock::new();
+
 /// Get the preferred memory range for the currently running APIC.
 /// Returns none if there's no valid APIC ID or we have no knowledge of NUMA.
 pub fn mem_range<'a>() -> Option<&'a RangeSet> {
@@ -43,25 +57,196 @@ pub unsafe fn register_numa(ad: ApicDomains, mut md: MemoryDomains) {
     let max_apic_id = *MAX_APIC_ID.get();
     assert!(max_apic_id != 0, "Registering NUMA before parsing ACPI");
 
+    // Firmware can report domain ranges which aren't usable memory. Only keep
+    // the parts of the domains which are free memory, so the allocator never
+    // hands out reserved memory through a domain. Memory allocated before this
+    // point (e.g. the kernel image) isn't considered to be part of any domain
+    {
+        let phys_mem = core!().shared.free_memory().lock();
+        let phys_mem = phys_mem.as_ref().unwrap();
+
+        for ranges in md.values_mut() {
+            *ranges = ranges.intersect(phys_mem)
+                .expect("Failed to intersect the memory domain");
+        }
+    }
+
+    // Domains should never overlap, but firmware can get this wrong. Memory
+    // reported by several domains is only kept by the first one of them
+    let mut claimed = RangeSet::new();
+    for (domain, ranges) in md.iter_mut() {
+        let overlap = ranges.intersect(&claimed)
+            .expect("Failed to intersect the memory domain");
+        if !overlap.is_empty() {
+            println!("Warning: NUMA domain {domain} overlaps other domains, \
+                ignoring the overlap");
+            for range in overlap.iter() {
+                ranges.remove(range).unwrap();
+            }
+        }
+        claimed.merge_from(ranges).expect("Failed to merge the memory domain");
+    }
+
     // Allocate the database
     let mut mappings = (0..=max_apic_id)
         .map(|_| None)
         .collect::<Vec<Option<RangeSet>>>();
 
-    // Go through each APIC to domain mapping and store it in the database
+    // Go through each APIC to domain mapping and store it in the database.
+    // Multiple APICs can share a domain, so its ranges are cloned
     ad.iter().for_each(|(&apic, domain)| {
-        mappings[apic as usize] = md.remove(domain)
+        mappings[apic as usize] = md.get(domain).cloned()
     });
 
-    // Store the apic mapping database as global!
+    // Map the APICs of each domain to its shard. The shards are allocated
+    // before the free memory is locked, as allocating takes the lock as well
+    let mut shards = Vec::with_capacity(md.len());
+    let mut apic_to_shard = (0..=max_apic_id)
+        .map(|_| None)
+        .collect::<Vec<Option<usize>>>();
+    for (idx, domain) in md.keys().enumerate() {
+        ad.iter().filter(|&(_, apic_domain)| apic_domain == domain)
+            .for_each(|(&apic, _)| apic_to_shard[apic as usize] = Some(idx));
+    }
+
+    // Move the free memory of each domain into its shard
+    {
+        let mut phys_mem = core!().shared.free_memory().lock();
+        let phys_mem = phys_mem.as_mut().unwrap();
+
+        // The cores which haven't been started yet allocate their core locals
+        // from the global free memory, as the shards can't be locked before a
+        // core has its core locals. Keep room for all of them out of the
+        // shards
+        let reserve_size = (size_of::<CoreLocals>() as u64)
+            .checked_mul(max_apic_id as u64 + 1)
+            .expect("Overflow when reserving memory for the core locals");
+        let reserve = phys_mem.allocate_region(
+            reserve_size, align_of::<CoreLocals>() as u64)
+            .expect("Invalid core locals reservation")
+            .expect("Not enough memory to reserve for the core locals");
+
+        // Nothing can be allocated on the heap here, as we hold the lock
+        for (_, domain) in core::mem::take(&mut md) {
+            shards.push(Shard::carve(domain, phys_mem)
+                .expect("Failed to move memory into a NUMA shard"));
+        }
+
+        phys_mem.insert(reserve)
+            .expect("Failed to return the core locals reservation");
+    }
+
+    // Store the shards and the apic mapping databases as globals!
+    NUMA_SHARDS.set(shards.leak());
+    APIC_TO_SHARD.set(apic_to_shard.leak());
     APIC_TO_MEM_RANGE.set(mappings.leak());
 }
 
+/// Get the index into `NUMA_SHARDS` of the shard of the currently running
+/// APIC. Returns none if there's no valid APIC ID or we have no knowledge of
+/// NUMA.
+fn local_shard() -> Option<usize> {
+    let apic_to_shard = APIC_TO_SHARD.try_get()?;
+    *apic_to_shard.get(core!().apic_id()? as usize)?
+}
+
+/// Returns the number of bytes of free physical memory, counting both the
+/// global free memory and the memory of all of the NUMA nodes.
+///
+/// The free memory locks are only tried, so this can be used in interrupts.
+/// Returns `None` if any of them is held
+pub fn free_bytes() -> Option<u64> {
+    let global = core!().shared.free_memory_try()?
+        .as_ref().map_or(Some(0), |free| free.len())?;
+
+    let shards = NUMA_SHARDS.try_get().map_or(&[][..], |shards| shards);
+    shards.iter().try_fold(global, |acc, shard| {
+        acc.checked_add(shard.free_bytes_try()?)
+    })
+}
+
+/// Allocate `size` bytes of physical memory aligned to `align`, returning the
+/// physical address of the allocation.
+///
+/// The memory of the current core's NUMA node is tried first, followed by the
+/// global free memory and the memory of the other nodes. Within each of them,
+/// the memory is picked by `policy`.
+fn allocate_phys(size: u64, align: u64, policy: AllocPolicy) -> Option<u64> {
+    // Allocate from the global free memory, preferring our node's memory in
+    // case NUMA isn't registered yet
+    let global = || {
+        let mut phys_mem = core!().shared.free_memory().lock();
+        phys_mem.as_mut()?
+            .allocate_with(size, align, mem_range(), policy).ok()?
+    };
+
+    let shards = NUMA_SHARDS.try_get().map_or(&[][..], |shards| shards);
+    shards::allocate(shards, local_shard(), size, align, policy, global)
+}
+
+/// Return the physical memory `range` to the free memory of the NUMA node it
+/// belongs to, or to the global free memory if it doesn't belong to a node
+fn free_phys_range(range: Range) {
+    let shards = NUMA_SHARDS.try_get().map_or(&[][..], |shards| shards);
+    shards::free(shards, range, |range| {
+        let mut phys_mem = core!().shared.free_memory().lock();
+        phys_mem.as_mut()
+            .expect("Freeing physical memory before it was initialized")
+            .insert(range)
+    }).expect("Failed to free physical memory");
+}
+
+/// The kernel physical window, set up by the bootloader
+pub const KERNEL_PHYS_WINDOW: Window = Window {
+    base: KERNEL_PHYS_WINDOW_BASE,
+    size: KERNEL_PHYS_WINDOW_SIZE,
+};
+
+/// Conversion of physical addresses into the kernel physical window
+pub trait PhysWindow: Sized {
+    /// Get the virtual address of `self` in the physical window, or `None`
+    /// if it's outside of the window
+    fn try_to_virt(self) -> Option<VirtAddr>;
+
+    /// Get the virtual address of `self` in the physical window, panicking
+    /// if it's outside of the window
+    #[track_caller]
+    fn to_virt(self) -> VirtAddr {
+        self.try_to_virt().expect("Physical address outside physical window")
+    }
+}
+
+impl PhysWindow for PhysAddr {
+    fn try_to_virt(self) -> Option<VirtAddr> {
+        KERNEL_PHYS_WINDOW.to_virt(self)
+    }
+}
+
+/// Conversion of virtual addresses in the kernel physical window back to
+/// physical addresses. This is the inverse of [`PhysWindow`]
+pub trait VirtWindow: Sized {
+    /// Get the physical address `self` maps to in the physical window, or
+    /// `None` if it's outside of the window
+    fn try_to_phys(self) -> Option<PhysAddr>;
+
+    /// Get the physical address `self` maps to in the physical window,
+    /// panicking if it's outside of the window
+    #[track_caller]
+    fn to_phys(self) -> PhysAddr {
+        self.try_to_phys().expect("Virtual address outside physical window")
+    }
+}
+
+impl VirtWindow for VirtAddr {
+    fn try_to_phys(self) -> Option<PhysAddr> {
+        KERNEL_PHYS_WINDOW.to_phys(self)
+    }
+}
+
 /// Offset a physical address into our physical window
 #[track_caller]
 pub fn phys_ptr(addr: PhysAddr) -> VirtAddr {
-    VirtAddr(addr.0.checked_add(KERNEL_PHYS_WINDOW_BASE)
-        .expect("Overflow when offsetting into physical window"))
+    addr.to_virt()
 }
 
 /// Find a free region of virtual memory that can hold `size` bytes and return
@@ -93,26 +278,125 @@ pub fn receive_vaddr_4k(size: u64) -> VirtAddr {
     VirtAddr(ret)
 }
 
+/// Map in `size` bytes of memory mapped I/O starting at `paddr` as uncacheable
+/// memory and return the virtual address it has been mapped at
+pub fn map_mmio(paddr: PhysAddr, size: u64) -> VirtAddr {
+    // Make sure the region is page aligned
+    assert!(paddr.is_aligned_to_page(PageType::Page4K),
+        "MMIO region not page aligned");
+
+    // Get a virtual address capable of holding this region
+    let vaddr = receive_vaddr_4k(size);
+
+    // Acquire access to physical memory and the page tables
+    let mut pmem = PhysicalMemory;
+    let mut table = core!().shared.kernel_pt().lock();
+    let table = table.as_mut().unwrap();
+
+    // Map in the MMIO into virtual memory
+    unsafe {
+        table.map_range(&mut pmem, vaddr, paddr, size, PageType::Page4K,
+                        Permissions::uncached(true, false, false))
+            .expect("Failed to map in MMIO to virtual memory");
+    }
+
+    vaddr
+}
+
+/// Map in a new `size` byte stack and return its top, which should be put into
+/// RSP.
+///
+/// The stack is preceded by an unmapped guard region, so overflowing it faults
+/// instead of silently corrupting other memory.
+pub fn map_kernel_stack(size: u64) -> VirtAddr {
+    // Get a virtual address for the stack. The guard region of the previous
+    // virtual allocation sits right below it
+    let vaddr = receive_vaddr_4k(size);
+
+    // Create the mapping request
+    let request = MapRequest::new(
+        vaddr, PageType::Page4K, size,
+        Permissions::new(true, false, false)).unwrap();
+
+    // Acquire access to physical and virtual memory and map it in
+    let mut pmem = PhysicalMemory;
+    let mut table = core!().shared.kernel_pt().lock();
+    let table = table.as_mut().unwrap();
+    table.map(&mut pmem, request).expect("Failed to map in a kernel stack");
+
+    // Stacks grow down, so return the end of the mapping
+    vaddr.checked_add(size).expect("Overflow when mapping in a kernel stack")
+}
+
+/// Enforce W^X on the pages of the kernel page table, so no page is both
+/// writable and executable.
+///
+/// Writable and executable pages at or above the trampoline, which hold the
+/// trampoline and the kernel image, keep being executable and lose their write
+/// access instead. All of the other pages, such as the physical window, stay
+/// writable and lose their execute access. This means that the physical window
+/// remains a writable alias of the kernel code.
+///
+/// Only the TLB of the current core is flushed, so this should be called
+/// before other cores start using the kernel page table
+pub fn harden_self() {
+    // Acquire access to physical memory and the page tables
+    let mut pmem = PhysicalMemory;
+    let mut table = core!().shared.kernel_pt().lock();
+    let table = table.as_mut().unwrap();
+
+    // Find all of the pages which are both writable and executable. Only the
+    // permissions of the pages change, so the tables can be walked meanwhile
+    let pages = table.iter_mappings(&mut pmem)
+        .map(|(vaddr, page_type, raw)| {
+            (vaddr, Permissions::from_bits(raw, page_type))
+        })
+        .filter(|(_, perms)| perms.write && perms.execute);
+
+    // Strip either the write or the execute access from each of them
+    for (vaddr, mut perms) in pages {
+        if vaddr.0 >= shared_data::TRAMPOLINE_ADDR {
+            perms.write = false;
+        } else {
+            perms.execute = false;
+        }
+
+        unsafe {
+            table.protect(&mut PhysicalMemory, vaddr, perms)
+                .expect("Failed to change the permissions of a page");
+        }
+    }
+
+    // Get rid of the stale permissions
+    unsafe { cpu::flush_tlb(); }
+}
+
 /// Get mutable access to a slice of physical memory
+///
+/// Panics if the slice is empty or doesn't fit into the physical window. See
+/// [`try_slice_phys_mut`] for a non-panicking version
 #[inline]
+#[track_caller]
 pub fn slice_phys_mut<'a>(paddr: PhysAddr, size: u64) -> &'a mut [u8] {
-    // Make sure the address doesn't overflow
-    let end = size.checked_sub(1).and_then(|x| {
-        x.checked_add(paddr.0)
-    }).expect("Integer overflow");
+    try_slice_phys_mut(paddr, size)
+        .expect("Physical slice outside physical window")
+}
 
-    // Make sure we fit in our physical window
-    assert!(end < KERNEL_PHYS_WINDOW_SIZE,
-        "Physical address outside physical window");
+/// Get mutable access to a slice of physical memory, or `None` if the slice
+/// is empty, its end overflows or it doesn't fit into the physical window
+#[inline]
+pub fn try_slice_phys_mut<'a>(paddr: PhysAddr, size: u64)
+        -> Option<&'a mut [u8]> {
+    // Make sure the slice fits in our physical window
+    let vaddr = KERNEL_PHYS_WINDOW.range_to_virt(paddr, size)?;
 
     // Return out the slice
     unsafe {
-        core::slice::from_raw_parts_mut(
-            (KERNEL_PHYS_WINDOW_BASE + paddr.0) as *mut u8, size as usize)
+        Some(core::slice::from_raw_parts_mut(
+            vaddr.0 as *mut u8, size as usize))
     }
 }
 
-
 /// Wrapper around a rangeset that implemente the `PhysMem` trait
 ///
 /// The bootloader should have created a physical window in our memory map for
@@ -127,14 +411,25 @@ impl PhysMem for PhysicalMemory {
 
     unsafe fn translate_mut(&mut self, paddr: PhysAddr, size: usize)
             -> Option<*mut u8> {
-        // Compute the ending physical address and make sure we don't overflow
-        let end = (size as u64).checked_sub(1)?.checked_add(paddr.0)?;
+        // Make sure the memory fits inside our physical window and convert
+        // the physical address into a linear address
+        let vaddr = KERNEL_PHYS_WINDOW.range_to_virt(paddr, size as u64)?;
+        Some(vaddr.0 as *mut u8)
+    }
 
-        // Make sure this physical address fits inside our physical window
-        if end >= KERNEL_PHYS_WINDOW_SIZE { return None; }
+    /// The physical window is mapped as write-back, so it can't be used for
+    /// device memory. Instead, every call maps `paddr` in as uncacheable
+    /// memory with `map_mmio()` again, so this should only be called once per
+    /// device region
+    unsafe fn translate_volatile(&mut self, paddr: PhysAddr, size: usize)
+            -> Option<*mut u8> {
+        if size == 0 { return None; }
 
-        // Convert the physical address into a linear address
-        Some((paddr.0 + KERNEL_PHYS_WINDOW_BASE) as *mut u8)
+        // Map in the pages holding the region
+        let offset = paddr.0 & 0xFFF;
+        let size = (size as u64).checked_add(offset)?;
+        let vaddr = map_mmio(PhysAddr(paddr.0 - offset), size);
+        Some((vaddr.0 + offset) as *mut u8)
     }
 
     fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
@@ -144,67 +439,61 @@ impl PhysMem for PhysicalMemory {
         if layout.size() == page_size && layout.align() == page_size {
             unsafe {
                 let ptr = core!().free_list(layout).lock().pop();
-                Some(PhysAddr(ptr as u64 - KERNEL_PHYS_WINDOW_BASE))
+                Some(VirtAddr(ptr as u64).to_phys())
             }
         } else {
-            // Get access to physical memory
-            let mut phys_mem = core!().shared.free_memory().lock();
-            let phys_mem = phys_mem.as_mut()?;
-
             // Allocate directly from physical memory
             let size = layout.size() as u64;
             let align = layout.align() as u64;
-            let allocation = phys_mem
-                .allocate_prefer(size, align, mem_range())
-                .ok().flatten()?;
-            Some(PhysAddr(allocation))
+            allocate_phys(size, align, AllocPolicy::BestFit).map(PhysAddr)
         }
     }
-}
 
-/// Freed allocation metadata
-pub struct FreeListNode {
-    /// Virtual address of the next node in the freelist.
-    ///
-    /// If it's `0`, it indicates that this is the last node in the freelist.
-    next: VirtAddr,
+    fn alloc_phys_contiguous(&mut self, pages: usize) -> Option<PhysAddr> {
+        // Allocate directly from physical memory, as pages from the free lists
+        // don't have to be contiguous with each other
+        let page_size = PageType::Page4K as u64;
+        let size = (pages as u64).checked_mul(page_size)?;
+        allocate_phys(size, page_size, AllocPolicy::BestFit).map(PhysAddr)
+    }
 
-    /// Number of free slots in the `free_addrs` array.
-    ///
-    /// This value tracks how many addresses are currently available for reuse
-    /// within this node. Each slot corresponds to a previously freed memory
-    /// block.
-    ///
-    /// This is basically the value that would be returned by `free_addrs.len()`
-    /// if it wasn't dynamic.
-    free_slots: usize,
+    fn free_phys(&mut self, paddr: PhysAddr, layout: Layout) {
+        // Zero sized allocations don't hold any memory
+        if layout.size() == 0 { return; }
 
-    /// Virtual addresses of freed memory blocks.
-    ///
-    /// The array size is dynamic and can hold a variable number of free
-    /// addresses, depending on the size of the allocation managed by the
-    /// freelist. `free_slots` is the number of elements in this array.
-    free_addrs: [*mut u8; 0],
+        // 4-KiB pages came from our free lists, so put them back there
+        let page_size = PageType::Page4K as usize;
+        if layout.size() == page_size && layout.align() == page_size {
+            unsafe {
+                let vaddr = paddr.to_virt().0 as *mut u8;
+                core!().free_list(layout).lock().push(vaddr);
+            }
+        } else {
+            // Return the memory directly to physical memory
+            let end = paddr.0 + (layout.size() as u64 - 1);
+            free_phys_range(Range::new(paddr.0, end).unwrap());
+        }
+    }
 }
 
-// This specific freelist implementation was originally designed by Brandon
-/// A freelist allocator that manages fixed-size memory blocks.
+/// A freelist allocator that manages fixed-size memory blocks, backing them
+/// with new memory whenever it runs out of free ones
 pub struct FreeList {
-    /// Pointer to the first entry in the freelist
-    head: VirtAddr,
-
-    /// Size of allocations (in bytes) for this freelist
-    size: usize,
+    /// The free blocks
+    list: freelist::FreeList,
 }
 
 impl FreeList {
     /// Create a new, empty freelist containings addresses to `size` allocations
     pub fn new(size: usize) -> Self {
-        assert!(size.count_ones() == 1,
-            "Freelist size not power of two");
-        assert!(size >= size_of::<usize>(),
-            "Freelist size must be at least pointer width");
-        Self { head: VirtAddr(0), size }
+        Self { list: freelist::FreeList::new(size) }
+    }
+
+    /// Create a new, empty freelist containing addresses to `size`
+    /// allocations, which fills freed blocks with `pattern`. See
+    /// `freelist::FreeList::new_scrubbed()` for the cost of scrubbing
+    pub fn new_scrubbed(size: usize, pattern: u8) -> Self {
+        Self { list: freelist::FreeList::new_scrubbed(size, pattern) }
     }
 
     /// If the blocks backed by this freelist fit into a 4-KiB page,
@@ -217,25 +506,34 @@ impl FreeList {
     #[inline]
     fn allocate_page_for_blocks(&mut self) {
         let page_size = PageType::Page4K as u64;
+        let size = self.list.size();
         // Make sure we're not allocating for a block that can't be backed by it
-        assert!(self.size <= page_size as usize,
+        assert!(size <= page_size as usize,
             "Can't allocate page for a freelist whose blocks don't fit in");
 
-        // Allocate the page from physical memory
-        let allocation = {
-            let mut phys_mem = core!().shared.free_memory().lock();
-            let phys_mem = phys_mem.as_mut().unwrap();
+        // Allocate the page from physical memory, preferring this core's NUMA
+        // node. If the node is exhausted, fall back to any other memory before
+        // giving up. Any page will do, so take the first one which fits
+        let local = mem_range();
+        let allocation = allocate_phys(page_size, page_size,
+                                       AllocPolicy::FirstFit)
+            .expect("Out of physical memory");
+
+        // Keep track of refills which couldn't be satisfied by the local node
+        if let Some(local) = local {
+            let page = Range::new(allocation, allocation + page_size - 1)
+                .unwrap();
+            if !local.entries().iter().any(|x| x.contains(&page)) {
+                core!().record_remote_refill();
+            }
+        }
 
-            phys_mem.allocate_prefer(page_size, page_size, mem_range())
-                .ok().flatten()
-                .expect("Failed to allocate physical memory") as u64
-        };
         // Split up this allocation into blocks backed by this freelist
         // and make them available
-        for offset in (0..page_size).step_by(self.size) {
+        for offset in (0..page_size).step_by(size) {
             let vaddr = slice_phys_mut(
-                PhysAddr(allocation + offset), self.size as u64).as_mut_ptr();
-            unsafe { self.push(vaddr); }
+                PhysAddr(allocation + offset), size as u64).as_mut_ptr();
+            unsafe { self.list.push(vaddr); }
         }
     }
 
@@ -243,12 +541,14 @@ impl FreeList {
     /// and return the mapping
     #[inline]
     fn allocate_virt_block(&mut self) -> VirtAddr {
+        let size = self.list.size() as u64;
+
         // Get a virtual address for this allocation
-        let vaddr = receive_vaddr_4k(self.size as u64);
+        let vaddr = receive_vaddr_4k(size);
 
         // Create the allocation request
         let request = MapRequest::new(
-            vaddr, PageType::Page4K, self.size as u64,
+            vaddr, PageType::Page4K, size,
             Permissions::new(true, false, false)).unwrap();
 
         // Acquire access to physical and virtual memory and map it in
@@ -264,10 +564,10 @@ impl FreeList {
     /// Get an address from this freelist
     pub unsafe fn pop(&mut self) -> *mut u8 {
         // If this freelist is empty, allocate memory to back up the allocations
-        if self.head.0 == 0 {
+        if self.list.is_empty() {
             // If the blocks backed by this freelist are smaller than a page,
             // just point the blocks to our physical memory window.
-            if self.size <= PageType::Page4K as usize {
+            if self.list.size() <= PageType::Page4K as usize {
                 self.allocate_page_for_blocks();
             // Blocks backed by this freelist don't fit into a page. Just
             // allocate new virtual memory for the block and return the pointer
@@ -277,100 +577,38 @@ impl FreeList {
         }
 
         // At this point the freelist can allocate at least one block
-
-        // For allocations that can't hold our stack-based freelist metadata,
-        // use a linked list.
-        if self.size <= size_of::<FreeListNode>() {
-            // Save the current head and set it to the next node
-            let allocation = self.head.0 as *mut FreeListNode;
-            self.head = unsafe { (*allocation).next };
-            return allocation as *mut u8
-        }
-
-        // Use the free list stack
-        let list = unsafe { &mut *(self.head.0 as *mut FreeListNode) };
-
-        // Calculate the available slots considering metadata overhead
-        let available_slots = (self.size / size_of::<usize>()) -
-            (size_of::<FreeListNode>() / size_of::<usize>());
-
-        // If there's a free entry in the stack, return it
-        if list.free_slots < available_slots {
-            // Just grab the free entry
-            let allocation = unsafe {
-                *list.free_addrs.as_mut_ptr()
-                    .add(list.free_slots)
-            };
-
-            // Update the number of free slots
-            list.free_slots += 1;
-
-            return allocation;
-        }
-
-        // If no free slots are available, use the head of the list as the
-        // allocation
-        let allocation = self.head;
-
-        // Update the head to point to the next entry
-        self.head = list.next;
-
-        allocation.0 as *mut u8
+        unsafe { self.list.pop() }.unwrap()
     }
 
     /// Put an allocation back onto the free list
     pub unsafe fn push(&mut self, vaddr: *mut u8) {
-        // For allocations that can't hold our stack-based freelist metadata,
-        // use a linked list.
-        if self.size <= size_of::<FreeListNode>() {
-            // Write the old head into the newly freed address
-            let vaddr = vaddr as *mut FreeListNode;
-            unsafe { (*vaddr).next = self.head; }
-
-            // Update the head
-            self.head = VirtAddr(vaddr as u64);
-            return;
-        }
-
-        // Check if there is room for this allocation in the free stack,
-        // or if we need to create a new stack
-        let check = self.head.0 == 0 ||
-            unsafe { (*(self.head.0 as *const FreeListNode)).free_slots == 0 };
-        if check {
-            // No free slots, create a new stack out of the freed vaddr
-            let list = unsafe { &mut *(vaddr as *mut FreeListNode) };
-
-            // Calculate the available slots considering metadata overhead
-            let available_slots = (self.size / size_of::<usize>()) -
-                (size_of::<FreeListNode>() / size_of::<usize>());
-
-            // Set the number of free slots to the maximum size, as all
-            // entries are free in the stack
-            list.free_slots = available_slots;
-
-            // Update the next to point to the old head
-            list.next = self.head;
-
-            // Establish this as the new free list head
-            self.head = VirtAddr(vaddr as *mut FreeListNode as u64);
-            return;
-        }
+        unsafe { self.list.push(vaddr); }
+    }
+}
 
-        // There's room in the current stack, just throw us in there
-        let list = unsafe { &mut *(self.head.0 as *mut FreeListNode) };
+/// Allocate whole pages of physically contiguous memory holding at least `size`
+/// bytes. Returns the virtual and physical addresses of the allocation and its
+/// size in bytes
+fn alloc_contig(size: usize) -> (VirtAddr, PhysAddr, usize) {
+    let alloc_size = PageType::Page4K.span(size);
+    let pages = alloc_size / PageType::Page4K as usize;
 
-        // Decrement the number of free slots
-        list.free_slots -= 1;
+    // Allocate straight from physical memory. Allocations from our free
+    // lists larger than a page are only virtually contiguous
+    let paddr = PhysicalMemory.alloc_phys_contiguous(pages)
+        .expect("PhysContig allocation failed");
 
-        // Store our newly freed virtual address into this slot
-        unsafe {
-            *list.free_addrs.as_mut_ptr()
-                .add(list.free_slots) = vaddr;
-        }
-    }
+    // Get the address of the allocation in our physical window
+    (phys_ptr(paddr), paddr, alloc_size)
 }
 
 /// Physically contiguous page-aligned allocation
+///
+/// The allocation backing the `T` is always aligned to a 4-KiB page and spans
+/// a whole number of pages. The backing memory is contiguous in both virtual
+/// and physical memory, so any byte of the `T` is at the same offset from
+/// `phys_addr()` as it is from the start of the `T`. This makes it suitable for
+/// structures which are accessed by devices through DMA.
 pub struct ContigPageAligned<T> {
     /// Virtual address of the allocation
     vaddr: VirtAddr,
@@ -388,33 +626,18 @@ pub struct ContigPageAligned<T> {
 impl<T> ContigPageAligned<T> {
     /// Create a new physically contiguous page-aligned allocation
     pub fn new(val: T) -> Self {
-        let page_size = PageType::Page4K as usize;
         let size = size_of::<T>();
         assert!(size > 0, "Cannot use ZST for PhysContig");
+        let (vaddr, paddr, alloc_size) = alloc_contig(size);
 
-        // Round up to the nearest multiple of 4096
-        let pages = (size + page_size - 1) / page_size;
-        let alloc_size = pages * page_size;
+        // Initialize the memory
+        unsafe { core::ptr::write(vaddr.0 as *mut T, val); }
 
-        unsafe {
-            // Make the allocation
-            let alloc = GLOBAL_ALLOCATOR.alloc(
-                Layout::from_size_align(alloc_size, page_size).unwrap());
-
-            assert!(!alloc.is_null(), "PhysContig allocation failed");
-
-            // Compute the physical address
-            let paddr = PhysAddr(alloc as u64 - KERNEL_PHYS_WINDOW_BASE);
-
-            // Initialize the memory
-            core::ptr::write(alloc as *mut T, val);
-
-            Self {
-                vaddr: VirtAddr(alloc as u64),
-                paddr,
-                size: alloc_size,
-                _phantom: PhantomData,
-            }
+        Self {
+            vaddr,
+            paddr,
+            size: alloc_size,
+            _phantom: PhantomData,
         }
     }
 
@@ -422,14 +645,32 @@ impl<T> ContigPageAligned<T> {
     pub fn phys_addr(&self) -> PhysAddr {
         self.paddr
     }
+
+    /// Get the physical address of the byte at `offset` into the `T`
+    pub fn phys_addr_of(&self, offset: usize) -> PhysAddr {
+        debug_assert!(offset < size_of::<T>(),
+            "Offset out of bounds of PhysContig");
+        PhysAddr(self.paddr.0 + offset as u64)
+    }
+}
+
+impl<T, const N: usize> ContigPageAligned<[T; N]> {
+    /// Get the elements of the array as a slice
+    pub fn as_slice(&self) -> &[T] {
+        &**self
+    }
+
+    /// Get the elements of the array as a mutable slice
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut **self
+    }
 }
 
 impl<T> Drop for ContigPageAligned<T> {
     fn drop(&mut self) {
-        unsafe {
-            GLOBAL_ALLOCATOR.dealloc(self.vaddr.0 as *mut u8,
-                Layout::from_size_align(self.size, 4096).unwrap());
-        }
+        unsafe { core::ptr::drop_in_place(self.vaddr.0 as *mut T); }
+        PhysicalMemory.free_phys(self.paddr,
+            Layout::from_size_align(self.size, 4096).unwrap());
     }
 }
 
@@ -451,6 +692,68 @@ impl<T> core::ops::DerefMut for ContigPageAligned<T> {
     }
 }
 
+/// Physically contiguous page-aligned byte buffer, whose length is only known
+/// at runtime.
+///
+/// This is the same as a `ContigPageAligned<[u8; N]>`, except for the length
+/// being chosen when the buffer is created. The buffer starts out zeroed
+pub struct ContigBuffer {
+    /// Virtual address of the allocation
+    vaddr: VirtAddr,
+
+    /// Physical address of the allocation
+    paddr: PhysAddr,
+
+    /// Length of the buffer in bytes
+    len: usize,
+
+    /// Allocation size in bytes
+    size: usize,
+}
+
+impl ContigBuffer {
+    /// Create a new zeroed buffer of `len` bytes
+    pub fn new_zeroed(len: usize) -> Self {
+        assert!(len > 0, "Cannot create an empty ContigBuffer");
+        let (vaddr, paddr, size) = alloc_contig(len);
+
+        // Zero out the buffer
+        unsafe { core::ptr::write_bytes(vaddr.0 as *mut u8, 0, len); }
+
+        Self { vaddr, paddr, len, size }
+    }
+
+    /// Get the physical address of this buffer
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.paddr
+    }
+}
+
+impl Drop for ContigBuffer {
+    fn drop(&mut self) {
+        PhysicalMemory.free_phys(self.paddr,
+            Layout::from_size_align(self.size, 4096).unwrap());
+    }
+}
+
+impl core::ops::Deref for ContigBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe {
+            core::slice::from_raw_parts(self.vaddr.0 as *const u8, self.len)
+        }
+    }
+}
+
+impl core::ops::DerefMut for ContigBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.vaddr.0 as *mut u8, self.len)
+        }
+    }
+}
+
 /// Handler for allocation errors, likely OOMs;
 /// simply panic, notifying that we can't satisfy the allocation
 #[alloc_error_handler]
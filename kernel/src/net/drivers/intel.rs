@@ -2,19 +2,22 @@
 
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use alloc::collections::VecDeque;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use spinlock::SpinLock;
 use const_assert::const_assert;
-use page_table::{
-    PhysAddr, VirtAddr, PageType, PAGE_WRITE, PAGE_NXE, PAGE_PRESENT,
-    PAGE_CACHE_DISABLE};
+use net_proto::rx_ring::{RxDescriptor, RxError, RxRing};
+use page_table::{PhysAddr, PageType, PhysMem};
 
 use crate::pci::{DeviceConfig, Device, BarBits, BarType};
 use crate::mm;
-use crate::net::{NetDriver, NetDevice, Mac};
+use crate::net::{NetDriver, NetDevice, Mac, PacketStats, DriverCaps};
 use crate::net::packet::{Packet, PacketLease};
+use crate::net::protocols::{eth::{self, EthType}, ip::TransportProtocol};
 use crate::core_locals::InterruptLock;
+use crate::interrupts::{InterruptArgs, InterruptId};
 
 /// The Intel NICs map in 128KiB of memory
 const MMIO_SIZE: usize = 128 * 1024;
@@ -33,12 +36,66 @@ const TX_DESCS_N: usize = 256;
 const_assert!(TX_DESCS_N <= 256);
 const_assert!(TX_DESCS_N % 8 == 0);
 
+/// Size of the RX buffers the NIC is programmed with. All packets which may be
+/// handed to the NIC for receiving have (at least) this capacity
+const RX_BUF_SIZE: usize = Packet::DEFAULT_CAPACITY;
+
+/// The buffer size bits of RCTL for `RX_BUF_SIZE`
+const RCTL_BUF_SIZE: u32 = rctl_buf_size(RX_BUF_SIZE);
+
+/// Get the buffer size bits of the RCTL register (BSIZE and BSEX) for RX
+/// buffers of `size` bytes.
+///
+/// The NIC writes up to this many bytes into each RX buffer, so the packets
+/// given to it must have at least this capacity. Only the sizes below are
+/// supported by the hardware
+const fn rctl_buf_size(size: usize) -> u32 {
+    const BSEX: u32 = 1 << 25;
+    match size {
+        256   => 3 << 16,
+        512   => 2 << 16,
+        1024  => 1 << 16,
+        2048  => 0,
+        4096  => (3 << 16) | BSEX,
+        8192  => (2 << 16) | BSEX,
+        16384 => (1 << 16) | BSEX,
+        _ => panic!("RX buffer size not supported by the NIC"),
+    }
+}
+const_assert!(rctl_buf_size(2048)  == 0);
+const_assert!(rctl_buf_size(256)   == 3 << 16);
+const_assert!(rctl_buf_size(4096)  == (3 << 16) | (1 << 25));
+const_assert!(rctl_buf_size(16384) == (1 << 16) | (1 << 25));
+
+/// Receiver timer interrupt bit in the interrupt registers. Fires when packets
+/// have been written into the RX ring
+const INT_RXT0: u32 = 1 << 7;
+
+/// Link up bit in the device status register
+const STATUS_LU: u32 = 1 << 1;
+
+/// Shift of the link speed field in the device status register
+const STATUS_SPEED_SHIFT: u32 = 6;
+
+/// NICs whose receive interrupts are routed to an MSI vector
+static INTERRUPT_NICS: SpinLock<Vec<(InterruptId, Arc<IntelNic>)>,
+    InterruptLock> = SpinLock::new_no_preempt(Vec::new());
+
 /// NIC register offsets
 #[derive(Clone, Copy)]
 struct NicRegisters {
     /// Device control
     ctrl: usize,
 
+    /// Device status
+    status: usize,
+
+    /// Interrupt cause read
+    icr: usize,
+
+    /// Interrupt mask set/read
+    ims: usize,
+
     /// Interrupt mask clear
     imc: usize,
 
@@ -90,39 +147,29 @@ struct NicRegisters {
 impl Default for NicRegisters {
     fn default() -> Self {
         Self {
-            ctrl:  0x0000,
-            imc:   0x00D8,
-            rctl:  0x0100,
-            ral:   0x5400,
-            rah:   0x5404,
-            rdbal: 0x2800,
-            rdbah: 0x2804,
-            rdlen: 0x2808,
-            rdh:   0x2810,
-            rdt:   0x2818,
-            tctl:  0x0400,
-            tdbal: 0x3800,
-            tdbah: 0x3804,
-            tdlen: 0x3808,
-            tdh:   0x3810,
-            tdt:   0x3818,
+            ctrl:   0x0000,
+            status: 0x0008,
+            icr:    0x00C0,
+            ims:    0x00D0,
+            imc:    0x00D8,
+            rctl:   0x0100,
+            ral:    0x5400,
+            rah:    0x5404,
+            rdbal:  0x2800,
+            rdbah:  0x2804,
+            rdlen:  0x2808,
+            rdh:    0x2810,
+            rdt:    0x2818,
+            tctl:   0x0400,
+            tdbal:  0x3800,
+            tdbah:  0x3804,
+            tdlen:  0x3808,
+            tdh:    0x3810,
+            tdt:    0x3818,
         }
     }
 }
 
-/// Intel NIC receive descriptor
-#[allow(missing_docs)]
-#[derive(Clone, Copy, Debug, Default)]
-#[repr(C)]
-struct RxDescriptor {
-    addr:     PhysAddr,
-    len:      u16,
-    checksum: u16,
-    status:   u8,
-    errors:   u8,
-    special:  u16,
-}
-
 /// Intel NIC legacy transmit descriptor
 #[allow(missing_docs)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -137,6 +184,124 @@ struct LegacyTxDescriptor {
     special: u16,
 }
 
+/// Intel NIC TCP/IP context transmit descriptor.
+///
+/// It doesn't send anything on its own. Instead, it loads the checksum offsets
+/// into the NIC, which uses them for all the data descriptors with checksum
+/// insertion that follow it in the ring, until another context descriptor is
+/// queued. The context is therefore only queued when the offsets change, and
+/// always right before the data descriptor that needs it
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+struct ContextTxDescriptor {
+    ipcss:  u8,
+    ipcso:  u8,
+    ipcse:  u16,
+    tucss:  u8,
+    tucso:  u8,
+    tucse:  u16,
+    cmd:    u32,
+    status: u8,
+    hdrlen: u8,
+    mss:    u16,
+}
+
+/// Intel NIC TCP/IP data transmit descriptor, which sends a packet using the
+/// checksum offsets of the last context descriptor
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct DataTxDescriptor {
+    addr:    PhysAddr,
+    cmd:     u32,
+    status:  u8,
+    popts:   u8,
+    special: u16,
+}
+
+// All transmit descriptors share the ring, so they must be of the same size
+const_assert!(size_of::<ContextTxDescriptor>() == 16);
+const_assert!(size_of::<DataTxDescriptor>() == 16);
+const_assert!(size_of::<LegacyTxDescriptor>() == 16);
+
+/// Extended descriptor bit of the context and data descriptor commands
+const TX_CMD_DEXT: u32 = 1 << 29;
+
+/// Report status bit of the context and data descriptor commands
+const TX_CMD_RS: u32 = 1 << 27;
+
+/// Descriptor type of data descriptors
+const TX_DTYP_DATA: u32 = 1 << 20;
+
+/// Context command bit selecting the TCP (over the UDP) checksum
+const TX_TUCMD_TCP: u32 = 1 << 24;
+
+/// Context command bit marking the packet as IPv4
+const TX_TUCMD_IP: u32 = 1 << 25;
+
+/// Data command bits to insert the FCS and to mark the end of packet
+const TX_DCMD_IFCS_EOP: u32 = (1 << 25) | (1 << 24);
+
+/// Data descriptor option to insert the IP header checksum
+const TX_POPTS_IXSM: u8 = 1 << 0;
+
+/// Data descriptor option to insert the TCP or UDP checksum
+const TX_POPTS_TXSM: u8 = 1 << 1;
+
+/// Get the context descriptor and the data descriptor options which make the
+/// NIC insert the checksums the packet builders left to it, or `None` if
+/// there are none
+fn checksum_context(packet: &Packet) -> Option<(ContextTxDescriptor, u8)> {
+    let raw = packet.raw();
+    let ethertype = u16::from_be_bytes(raw.get(12..14)?.try_into().ok()?);
+
+    let mut ctx = ContextTxDescriptor {
+        cmd: TX_CMD_DEXT | TX_CMD_RS,
+        ..Default::default()
+    };
+    let mut popts = 0;
+
+    // Get the offset of the transport header and its protocol. IPv4 headers
+    // have a checksum of their own
+    let ip = eth::HEADER_LEN;
+    let (l4, protocol) = match EthType::from_raw(ethertype)? {
+        EthType::Ipv4 => {
+            let header_len = (*raw.get(ip)? & 0xF) as usize * 4;
+            ctx.ipcss = ip as u8;
+            ctx.ipcso = (ip + 10) as u8;
+            ctx.ipcse = (ip + header_len - 1) as u16;
+            ctx.cmd |= TX_TUCMD_IP;
+            popts |= TX_POPTS_IXSM;
+            (ip + header_len, *raw.get(ip + 9)?)
+        },
+        EthType::Ipv6 => (ip + 40, *raw.get(ip + 6)?),
+        EthType::Arp => return None,
+    };
+
+    // Get the offset of the checksum in the transport header
+    let csum = match protocol {
+        x if x == TransportProtocol::Tcp as u8 => Some(16),
+        x if x == TransportProtocol::Udp as u8 => Some(6),
+        _ => None,
+    };
+
+    // The builders seed the transport checksum with the pseudo-header sum,
+    // which is never 0. A zero means that no checksum is wanted, as is the case
+    // with UDP over IPv4
+    if let Some(csum) = csum
+            && raw.get(l4 + csum..l4 + csum + 2)? != [0, 0] {
+        ctx.tucss = l4 as u8;
+        ctx.tucso = (l4 + csum) as u8;
+        if protocol == TransportProtocol::Tcp as u8 {
+            ctx.cmd |= TX_TUCMD_TCP;
+        }
+        popts |= TX_POPTS_TXSM;
+    }
+
+    (popts != 0).then_some((ctx, popts))
+}
+
 /// Transmit state of a NIC
 struct TxState {
     /// Virtually mapped TX descriptors
@@ -150,6 +315,9 @@ struct TxState {
 
     /// Current index of the transmit descriptors that has been sent
     tail: usize,
+
+    /// The checksum context which was queued last
+    context: Option<ContextTxDescriptor>,
 }
 
 /// Receive state of a NIC
@@ -184,6 +352,25 @@ pub struct IntelNic {
 
     /// A free list of packets, used to avoid packet relocation
     packets: SpinLock<Vec<Packet>, InterruptLock>,
+
+    /// Number of packets handed out by `allocate_packet()` and the receive
+    /// interrupt handler
+    packets_allocated: AtomicUsize,
+
+    /// Number of packets given back by `release_packet()`
+    packets_released: AtomicUsize,
+
+    /// Number of released packets which were freed, as the free list was full
+    packets_dropped: AtomicUsize,
+
+    /// Number of received frames dropped because of errors reported by the NIC
+    rx_errors: AtomicU64,
+
+    /// Packets moved out of the RX ring by the receive interrupt handler
+    rx_queue: SpinLock<VecDeque<Packet>, InterruptLock>,
+
+    /// Whether the receive interrupts have been enabled for this NIC
+    rx_interrupts: AtomicBool,
 }
 
 impl IntelNic {
@@ -200,42 +387,13 @@ impl IntelNic {
             "Intel NIC MMIO not page aligned");
 
         // Map in the MMIO region into our page tables
-        let mmio = {
-            // Get a virtual address capable of holding this region
-            let vaddr = mm::receive_vaddr_4k(MMIO_SIZE as u64);
-
-            // Acquire acces to physical memory and the page tables
-            let mut pmem = mm::PhysicalMemory;
-            let mut table = core!().shared.kernel_pt().lock();
-            let table = table.as_mut().unwrap();
-
-            // Calculate the end address of the MMIO
-            let end_addr = phys_addr.0.checked_add(MMIO_SIZE as u64)
-                .expect("Overflow when mapping in Intel NIC MMIO");
-
-            // Map in the MMIO into virtual memory
-            let page_size = PageType::Page4K as usize;
-            for paddr in (phys_addr.0..end_addr).step_by(page_size) {
-                // Compute the offset into MMIO space
-                let offset = paddr - phys_addr.0;
-
-                // Map it in
-                unsafe {
-                    table.map_raw(&mut pmem, VirtAddr(vaddr.0 + offset),
-                                  PageType::Page4K,
-                                  paddr | PAGE_NXE | PAGE_WRITE |
-                                  PAGE_CACHE_DISABLE | PAGE_PRESENT)
-                        .expect("Failed to map in Intel NIC MMIO to \
-                            virtual memory")
-                }
-            }
+        let mmio = unsafe {
+            let ptr = mm::PhysicalMemory.translate_volatile(
+                phys_addr, MMIO_SIZE).expect("Failed to map in NIC MMIO");
 
             // Return the MMIO slice
-            unsafe {
-                core::slice::from_raw_parts_mut(
-                    vaddr.0 as *mut u32,
-                    (MMIO_SIZE / size_of::<u32>()) as usize)
-            }
+            core::slice::from_raw_parts_mut(
+                ptr as *mut u32, MMIO_SIZE / size_of::<u32>())
         };
 
         // Create the RX descriptor table
@@ -246,10 +404,10 @@ impl IntelNic {
         let mut rx_bufs: Vec<Packet> = Vec::with_capacity(RX_DESCS_N);
         for i in 0..rx_bufs.capacity() {
             // Allocate a new packet buffer
-            let rx_buf = Packet::new();
+            let rx_buf = Packet::new_with_capacity(RX_BUF_SIZE);
 
             // Store the address of the packet buffer in the descriptor table
-            rx_descs[i].addr = rx_buf.phys_addr();
+            rx_descs[i].addr = rx_buf.phys_addr().0;
 
             // Save a ref to the packet buffer
             rx_bufs.push(rx_buf);
@@ -264,7 +422,7 @@ impl IntelNic {
             mmio,
             mac: Default::default(),
             regs: Default::default(),
-            rx_state: SpinLock::new(RxState {
+            rx_state: SpinLock::new_no_preempt(RxState {
                 descs:   rx_descs,
                 packets: rx_bufs,
                 head:    0,
@@ -274,8 +432,17 @@ impl IntelNic {
                 packets: (0..TX_DESCS_N).map(|_| None).collect(),
                 head:    0,
                 tail:    0,
+                context: None,
             }),
-            packets: SpinLock::new(Vec::with_capacity(TX_DESCS_N + RX_DESCS_N)),
+            packets: SpinLock::new_no_preempt(
+                Vec::with_capacity(TX_DESCS_N + RX_DESCS_N)),
+            packets_allocated: AtomicUsize::new(0),
+            packets_released: AtomicUsize::new(0),
+            packets_dropped: AtomicUsize::new(0),
+            rx_errors: AtomicU64::new(0),
+            rx_queue: SpinLock::new_no_preempt(
+                VecDeque::with_capacity(RX_DESCS_N)),
+            rx_interrupts: AtomicBool::new(false),
         };
 
         // Reset the NIC and initialize it for receive and transmit
@@ -308,8 +475,8 @@ impl IntelNic {
             self.write(self.regs.rdt, rx_state.descs.len() as u32 - 1);
 
             // Enable receive, accept broadcast packets and set the receive
-            // buffer size to 4 KiB
-            let bits = (1 << 1) | (1 << 15) | (3 << 16) | (1 << 25);
+            // buffer size to the capacity of the RX packets
+            let bits = (1 << 1) | (1 << 15) | RCTL_BUF_SIZE;
             self.write(self.regs.rctl, bits);
         }
     }
@@ -352,6 +519,102 @@ impl IntelNic {
         Mac(mac)
     }
 
+    /// Attempt to route the RX interrupts of this NIC to a newly allocated
+    /// vector on the current core through MSI or MSI-X.
+    ///
+    /// Returns whether the interrupts have been enabled. If they haven't, the
+    /// NIC simply keeps on being polled in `recv()`.
+    fn enable_rx_interrupts(nic: &Arc<Self>, cfg: &DeviceConfig) -> bool {
+        // Allocate a vector for the interrupts
+        let id = {
+            let mut interrupts = core!().interrupts().lock();
+            let interrupts = interrupts.as_mut().unwrap();
+            match interrupts.allocate(rx_interrupt, true) {
+                Some(id) => id,
+                None => return false,
+            }
+        };
+
+        // Register the NIC such that the handler can find it
+        INTERRUPT_NICS.lock().push((id, nic.clone()));
+
+        // Route the interrupts to this core
+        let apic_id = core!().apic_id().unwrap();
+        if unsafe { cfg.enable_msi(apic_id, id.into()) }.is_none() {
+            // Neither MSI nor MSI-X can reach this core, free the vector
+            INTERRUPT_NICS.lock().retain(|(x, _)| *x != id);
+            core!().interrupts().lock().as_mut().unwrap().unregister(id);
+            return false;
+        }
+
+        // Unmask the receive interrupts
+        nic.rx_interrupts.store(true, Ordering::SeqCst);
+        unsafe { nic.write(nic.regs.ims, INT_RXT0); }
+
+        true
+    }
+
+    /// Handle an interrupt of this NIC, moving all received packets from the
+    /// RX ring into the receive queue.
+    ///
+    /// This runs in the interrupt context and as such must not allocate or
+    /// free memory. Packets that can't be moved to the queue without doing so
+    /// are left in the ring for `recv()` to pick up.
+    fn handle_interrupt(&self) {
+        // Read the interrupt causes, acknowledging them
+        let _causes = unsafe { self.read(self.regs.icr) };
+
+        // Move the packets over to the queue while there's space for them.
+        // Frames with errors have been dropped already, so just move on
+        let mut rx_queue = self.rx_queue.lock();
+        while rx_queue.len() < rx_queue.capacity() {
+            match self.pop_rx(|| self.pop_free_packet()) {
+                Ok(Some(packet)) => rx_queue.push_back(packet),
+                Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+    }
+
+    /// Take a packet out of the free list, counting it as allocated
+    fn pop_free_packet(&self) -> Option<Packet> {
+        let packet = self.packets.lock().pop()?;
+        self.record_allocation();
+        Some(packet)
+    }
+
+    /// Count a packet as allocated. Packets in flight may sit in the rings,
+    /// the receive queue, socket queues or IP reassembly, so there's no fixed
+    /// bound to check here. The count is reported through `stats()` instead
+    fn record_allocation(&self) {
+        self.packets_allocated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take the next received packet out of the RX ring, handing the packet
+    /// returned by `replacement` to the NIC in its place.
+    ///
+    /// If there's no packet on the line or `replacement` returns `None`, the
+    /// ring is left untouched. Frames received with errors are counted and
+    /// dropped, handing their buffers straight back to the NIC, and the errors
+    /// are returned.
+    fn pop_rx(&self, replacement: impl FnOnce() -> Option<Packet>)
+            -> Result<Option<Packet>, RxError> {
+        // Get unique access to the RX
+        let mut rx_state = self.rx_state.lock();
+        let RxState { descs, packets, head } = &mut *rx_state;
+        let mut ring = RxRing { descs: &mut descs[..], buffers: packets, head };
+
+        // Set the tail, letting the NIC know the buffers are available again
+        let popped = ring.pop(replacement, |tail| unsafe {
+            self.write(self.regs.rdt, tail as u32);
+        });
+
+        if popped.is_err() {
+            self.rx_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        popped
+    }
+
     /// Mask off all of the interrupts
     fn disable_interrupts(&self) {
         unsafe { self.write(self.regs.imc, !0) }
@@ -413,47 +676,44 @@ impl NetDriver for IntelNic {
         self.mac.clone()
     }
 
-    fn recv<'a: 'b, 'b>(&'a self) -> Option<PacketLease<'b>> {
-        // Get unique access to the RX
-        let mut rx_state = self.rx_state.lock();
-        let head = rx_state.head;
-        let desc = &rx_state.descs[head];
-
-        unsafe {
-            // Check if there's packet on the line and bail out if not
-            if (core::ptr::read_volatile(&desc.status) & 1) == 0 {
-                return None;
-            }
-
-            // Make sure there's no errors
-            assert!(core::ptr::read_volatile(&desc.errors) == 0,
-                "RX descriptor error detected");
-
-            // Get the length of the packet
-            let len = core::ptr::read_volatile(&desc.len) as usize;
-
-            // Allocate a new packet for this descriptor
-            let mut new_packet = self.allocate_packet();
-            let phys_addr = new_packet.phys_addr();
+    fn link_up(&self) -> bool {
+        unsafe { self.read(self.regs.status) & STATUS_LU != 0 }
+    }
 
-            // Swap in the new packet with the old one
-            core::mem::swap(&mut new_packet, &mut rx_state.packets[head]);
+    fn link_speed_mbps(&self) -> Option<u32> {
+        if !self.link_up() { return None; }
 
-            // Put this descriptor back for use by the NIC
-            core::ptr::write_volatile(
-                &mut rx_state.descs[head],
-                RxDescriptor { addr: phys_addr, ..Default::default() });
+        let status = unsafe { self.read(self.regs.status) };
+        match (status >> STATUS_SPEED_SHIFT) & 0b11 {
+            0b00 => Some(10),
+            0b01 => Some(100),
+            _    => Some(1000),
+        }
+    }
 
-            // Set the tail, letting the NIC know this buffer is available again
-            self.write(self.regs.rdt, head as u32);
+    fn recv<'a: 'b, 'b>(&'a self) -> Option<PacketLease<'b>> {
+        // Take a packet out of the receive queue if the interrupts have put
+        // one in there already
+        let queued = if self.rx_interrupts.load(Ordering::SeqCst) {
+            self.rx_queue.lock().pop_front()
+        } else {
+            None
+        };
 
-            // Increment the head
-            rx_state.head = (head + 1) % rx_state.descs.len();
+        // Otherwise poll the RX ring. This is also done with the interrupts
+        // enabled, as they might be disabled on this core right now. Frames
+        // with errors are skipped
+        let packet = match queued {
+            Some(packet) => packet,
+            None => loop {
+                match self.pop_rx(|| Some(self.allocate_packet())) {
+                    Ok(packet) => break packet?,
+                    Err(_) => continue,
+                }
+            },
+        };
 
-            // Set the length of the packet and return a lease to it
-            new_packet.set_len(len);
-            Some(PacketLease::new(self, new_packet))
-        }
+        Some(PacketLease::new(self, packet))
     }
 
     fn send(&self, mut packet: Packet, flush: bool) {
@@ -465,17 +725,25 @@ impl NetDriver for IntelNic {
 
         // Pad packet if smaller than minimum size
         if packet.len() < PACKET_MIN_SIZE {
-            let len = packet.len();
-            let needed = PACKET_MIN_SIZE - len;
-
-            let cursor = packet.cursor();
-            let (_, cursor) = cursor.split_at_current();
-            let (buf, _) = cursor.split_at(needed);
-            buf.fill(0);
+            let needed = PACKET_MIN_SIZE - packet.len();
+            packet.cursor().fill(0, needed)
+                .expect("Failed to pad the packet to the minimum size");
         }
 
+        // Get the checksums the NIC has to insert, and whether a new context
+        // descriptor has to be queued for them
+        let offload = if packet.tx_checksum_offload() {
+            checksum_context(&packet)
+        } else {
+            None
+        };
+        let context = offload.map(|(ctx, _)| ctx)
+            .filter(|ctx| tx_state.context != Some(*ctx));
+        let needed = 1 + context.is_some() as usize;
+
         // Wait until there's space in the TX ring
-        while tx_state.tail - tx_state.head >= tx_state.descs.len() - 1 {
+        while tx_state.tail - tx_state.head + needed
+                > tx_state.descs.len() - 1 {
             // No room in the queue, update the head for each packet which was
             // sent by the NIC previously
             for end in (tx_state.head..tx_state.tail).rev() {
@@ -492,15 +760,49 @@ impl NetDriver for IntelNic {
             }
         }
 
+        // Queue the new checksum context right before the packet using it
+        if let Some(ctx) = context {
+            let idx = tx_state.tail % tx_state.descs.len();
+            let desc = &mut tx_state.descs[idx] as *mut LegacyTxDescriptor;
+            unsafe { core::ptr::write(desc as *mut ContextTxDescriptor, ctx); }
+
+            // The context doesn't hold a packet. Free whatever the descriptor
+            // held before
+            if let Some(old) = tx_state.packets[idx].take() {
+                self.release_packet(old);
+            }
+
+            tx_state.context = Some(ctx);
+            tx_state.tail = tx_state.tail.wrapping_add(1);
+        }
+
         // Fill in the TX descriptor
         let idx = tx_state.tail % tx_state.descs.len();
-        tx_state.descs[idx] = LegacyTxDescriptor {
-            // Report status, insert FCS, end of packet
-            cmd: (1 << 3) | (1 << 1) | (1 << 0),
-            addr: packet.phys_addr(),
-            len: packet.len() as u16,
-            ..Default::default()
-        };
+        match offload {
+            Some((_, popts)) => {
+                let desc = &mut tx_state.descs[idx] as *mut LegacyTxDescriptor;
+                let data = DataTxDescriptor {
+                    // Report status, insert FCS, end of packet
+                    cmd: TX_CMD_DEXT | TX_CMD_RS | TX_DCMD_IFCS_EOP
+                        | TX_DTYP_DATA | packet.len() as u32,
+                    addr: packet.phys_addr(),
+                    popts,
+                    ..Default::default()
+                };
+                unsafe {
+                    core::ptr::write(desc as *mut DataTxDescriptor, data);
+                }
+            },
+            None => {
+                tx_state.descs[idx] = LegacyTxDescriptor {
+                    // Report status, insert FCS, end of packet
+                    cmd: (1 << 3) | (1 << 1) | (1 << 0),
+                    addr: packet.phys_addr(),
+                    len: packet.len() as u16,
+                    ..Default::default()
+                };
+            },
+        }
 
         // Swap the new packet into the buffer list
         let mut old_packet = Some(packet);
@@ -515,7 +817,7 @@ impl NetDriver for IntelNic {
         tx_state.tail = tx_state.tail.wrapping_add(1);
 
         // Flush if we should
-        if flush || (tx_state.tail-tx_state.head) == (tx_state.descs.len()-1) {
+        if flush || (tx_state.tail-tx_state.head) >= (tx_state.descs.len()-1) {
             unsafe {
                 self.write(
                     self.regs.tdt,
@@ -524,19 +826,59 @@ impl NetDriver for IntelNic {
         }
     }
 
+    fn caps(&self) -> DriverCaps {
+        DriverCaps { tx_checksum: true }
+    }
+
     fn allocate_packet(&self) -> Packet {
-        self.packets.lock().pop().unwrap_or_else(|| Packet::new())
+        let packet = self.packets.lock().pop()
+            .unwrap_or_else(|| Packet::new_with_capacity(RX_BUF_SIZE));
+        self.record_allocation();
+        packet
     }
 
     fn release_packet(&self, mut packet: Packet) {
+        self.packets_released.fetch_add(1, Ordering::Relaxed);
+
+        // The free packets are handed to the NIC for receiving, so they must
+        // be large enough for its RX buffers
         let mut packets = self.packets.lock();
-        if packets.len() < packets.capacity() {
+        if packets.len() < packets.capacity() &&
+                packet.capacity() >= RX_BUF_SIZE {
             packet.clear();
             packets.push(packet)
+        } else {
+            self.packets_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn rx_errors(&self) -> u64 {
+        self.rx_errors.load(Ordering::Relaxed)
+    }
+
+    fn packet_stats(&self) -> PacketStats {
+        let allocated = self.packets_allocated.load(Ordering::Relaxed);
+        let released  = self.packets_released.load(Ordering::Relaxed);
+
+        PacketStats {
+            allocated,
+            released,
+            dropped_on_release: self.packets_dropped.load(Ordering::Relaxed),
+            in_flight: allocated.saturating_sub(released),
         }
     }
 }
 
+/// Receive interrupt handler for all interrupt driven Intel NICs
+unsafe fn rx_interrupt(args: InterruptArgs) -> bool {
+    // Handle the interrupt for all NICs using this vector
+    INTERRUPT_NICS.lock().iter()
+        .filter(|(id, _)| *id == args.id)
+        .for_each(|(_, nic)| nic.handle_interrupt());
+
+    true
+}
+
 impl Device for IntelNic {
     fn purge(&self) {
         unsafe { self.reset() }
@@ -554,6 +896,11 @@ fn probe(cfg: &DeviceConfig) -> Option<Arc<dyn Device>> {
         // Create the driver
         let driver = Arc::new(IntelNic::new(*cfg));
 
+        // Attempt to make the NIC interrupt driven, polling it otherwise
+        if !IntelNic::enable_rx_interrupts(&driver, cfg) {
+            println!("No MSI support for device: {}, polling", cfg.did_vid());
+        }
+
         // Register it as a net device
         NetDevice::register(driver.clone());
 
@@ -5,16 +5,20 @@ use alloc::boxed::Box;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::collections::{BTreeMap, VecDeque};
-use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
 use core::net::{IpAddr, Ipv4Addr};
 
+use net_proto::route::{Ipv4Config, NextHop};
 use oncelock::OnceLock;
 use spinlock::SpinLock;
 
 use crate::core_locals::InterruptLock;
-use crate::net::protocols::dhcp;
+use crate::net::protocols::{dhcp, eth, tcp};
+use crate::net::protocols::ip::Reassembly;
 use crate::net::packet::{Packet, PacketLease};
 
+pub use net_proto::eth::Mac;
+
 /// All net devices registered during the PCI probing process. When the
 /// probing process ends, these will be locked into `NET_DEVICES`, which
 /// can be accessed without locks during runtime.
@@ -28,18 +32,6 @@ static NET_DEVICES: OnceLock<Box<[Arc<NetDevice>]>> = OnceLock::new();
 /// `split_at_mut()` methods
 pub type Payload<'a> = &'a mut [u8];
 
-/// The MAC address of a NIC
-#[repr(transparent)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
-pub struct Mac(pub [u8; 6]);
-
-impl Mac {
-    /// MAC address used for broadcasts
-    pub const BROADCAST: Mac = Mac([0xFF; 6]);
-
-    pub const ZERO: Mac = Mac([0; 6]);
-}
-
 /// A network port
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
@@ -92,14 +84,42 @@ pub struct NetAddress {
     pub dst_port: Port,
 }
 
+/// Errors that can occur while resolving a `NetAddress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveError {
+    /// The device has no IPv4 configuration, so it has no IP address
+    NotConfigured,
+
+    /// The destination isn't on the subnet and there's no gateway to reach it
+    NoRoute(Ipv4Addr),
+
+    /// The MAC address of the next hop couldn't be resolved via ARP
+    ArpFailed(Ipv4Addr),
+}
+
 impl NetAddress {
-    /// Attempt to resolve the provided arguments as a network address
+    /// Attempt to resolve the provided arguments as a network address.
+    ///
+    /// The source IP is the device's configured address. Destinations outside
+    /// of its subnet are reached through the gateway, so the destination MAC
+    /// is the gateway's
     pub fn resolve(dev: &NetDevice, src_port: Port, dst_port: Port,
-                   dst_ip: Ipv4Addr) -> Option<Self> {
-        Some(Self {
+                   dst_ip: Ipv4Addr) -> Result<Self, ResolveError> {
+        let config = dev.ipv4_config().ok_or(ResolveError::NotConfigured)?;
+
+        // Broadcasts don't need to be resolved, everything else is sent to
+        // the next hop
+        let dst_mac = match config.route(dst_ip) {
+            Some(NextHop::Broadcast) => Mac::BROADCAST,
+            Some(NextHop::Host(hop)) =>
+                dev.arp(hop).ok_or(ResolveError::ArpFailed(hop))?,
+            None => return Err(ResolveError::NoRoute(dst_ip)),
+        };
+
+        Ok(Self {
             src_mac: dev.mac(),
-            dst_mac: dev.arp(dst_ip)?,
-            src_ip:  IpAddr::V4(dev.dhcp_lease.lock().as_ref()?.client_ip),
+            dst_mac,
+            src_ip:  IpAddr::V4(config.addr),
             dst_ip:  IpAddr::V4(dst_ip),
             src_port,
             dst_port,
@@ -134,13 +154,39 @@ pub struct NetDevice {
     /// The DHCP lease for this device
     pub(in crate::net) dhcp_lease: SpinLock<Option<dhcp::Lease>, InterruptLock>,
 
+    /// The IPv4 configuration of this device, used as the source of all
+    /// traffic originating from it
+    ipv4: SpinLock<Option<Ipv4Config>, InterruptLock>,
+
     /// Packet queues for bound UDP ports
     pub(in crate::net) udp_binds:
         SpinLock<BTreeMap<Port, VecDeque<Packet>>, InterruptLock>,
 
-    // /// Active TCP connections
-    // pub(in crate::net) tcp_connections:
-    //     SpinLock<BTreeMap<Port, Arc<tcp::Connection>>, InterruptLock>,
+    /// IPv4 datagrams which are being reassembled from their fragments
+    pub(in crate::net) ipv4_fragments:
+        SpinLock<Vec<Reassembly>, InterruptLock>,
+
+    /// Outstanding ARP requests of this device, along with the MAC addresses
+    /// from their replies once they arrive. Entries only live for as long as
+    /// someone is resolving the IP
+    pub(in crate::net) arp_replies:
+        SpinLock<BTreeMap<Ipv4Addr, Option<Mac>>, InterruptLock>,
+
+    /// Number of packets received by the network stack
+    rx_packets: AtomicU64,
+
+    /// Number of bytes received by the network stack
+    rx_bytes: AtomicU64,
+
+    /// Number of packets handed to the driver for sending
+    tx_packets: AtomicU64,
+
+    /// Number of bytes handed to the driver for sending
+    tx_bytes: AtomicU64,
+
+    /// Active TCP connections
+    pub(in crate::net) tcp_connections:
+        SpinLock<BTreeMap<Port, Arc<tcp::Connection>>, InterruptLock>,
 }
 
 impl NetDevice {
@@ -174,6 +220,12 @@ impl NetDevice {
         ret
     }
 
+    /// Get all of the network devices on the system. This is empty until the
+    /// PCI probing process ends
+    pub fn all() -> &'static [Arc<Self>] {
+        NET_DEVICES.try_get().map_or(&[], |devs| devs)
+    }
+
     /// Register a device during the PCI probing process as a network device
     pub fn register(driver: Arc<dyn NetDriver>) {
         /// The next available unique identifier
@@ -191,9 +243,16 @@ impl NetDevice {
         // Create a new `Arc<NetDevice>`
         let nd = Arc::new(Self {
             dhcp_lease: SpinLock::new(None),
+            ipv4: SpinLock::new(None),
             mac: driver.mac(),
             udp_binds: SpinLock::new(BTreeMap::new()),
-            //tcp_connections: SpinLock::new(BTreeMap::new()),
+            ipv4_fragments: SpinLock::new(Vec::new()),
+            arp_replies: SpinLock::new(BTreeMap::new()),
+            rx_packets: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            tx_packets: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            tcp_connections: SpinLock::new(BTreeMap::new()),
             driver,
             id,
         });
@@ -214,15 +273,10 @@ impl NetDevice {
 
         // Attempt to get a DHCP lease for all devices
         for dev in devs {
-            // Get the lease
-            let lease = dhcp::get_lease(dev.clone());
-
-            // Assign the lease
-            let mut dev_lease = dev.dhcp_lease.lock();
-            *dev_lease = lease;
-
-            // If we actually got a lease, save this device
-            if dev_lease.is_some() {
+            // Get and assign the lease. If we actually got one, save this
+            // device
+            if let Some(lease) = dhcp::get_lease(dev.clone()) {
+                dev.set_lease(lease);
                 leased_devs.push(dev.clone());
             }
         }
@@ -231,13 +285,43 @@ impl NetDevice {
         NET_DEVICES.set(leased_devs.into_boxed_slice());
     }
 
+    /// Apply a DHCP `lease` to this device, replacing the previous one and
+    /// configuring the device with it
+    pub fn set_lease(&self, lease: dhcp::Lease) {
+        self.configure(&lease);
+        *self.dhcp_lease.lock() = Some(lease);
+    }
+
+    /// Configure the IPv4 address, subnet and gateway of this device from
+    /// `lease`
+    pub fn configure(&self, lease: &dhcp::Lease) {
+        let config = Ipv4Config::from_ack(lease.client_ip, &lease.ack);
+        *self.ipv4.lock() = Some(config);
+    }
+
+    /// Get the IPv4 configuration of this device, if it's been configured
+    pub fn ipv4_config(&self) -> Option<Ipv4Config> {
+        *self.ipv4.lock()
+    }
+
+    /// Get the configured IPv4 address of this device
+    pub fn local_ipv4(&self) -> Option<Ipv4Addr> {
+        self.ipv4_config().map(|config| config.addr)
+    }
+
     /// Discard a packet from somewhere in the network stack and attempt to
     /// handle it somewhere else in the network stack
     pub fn discard(&self, packet: PacketLease) {
         let mut packet = Some(packet);
+
+        // Handle reassembled datagrams once all of their fragments arrive
+        if let Some(datagram) = self.discard_ipv4_fragment(&mut packet) {
+            self.discard(PacketLease::new(&*self.driver, datagram));
+        }
+
         self.discard_arp(&mut packet);
         self.discard_udp(&mut packet);
-        //self.discard_tcp(&mut packet);
+        self.discard_tcp(&mut packet);
     }
 
     /// Get the device's unique identifier
@@ -247,20 +331,95 @@ impl NetDevice {
 
     /// Receive a raw packet from the network
     pub fn recv(&self) -> Option<PacketLease> {
-        self.driver.recv()
+        let mut packet = self.driver.recv()?;
+        packet.set_checksum_offloaded(self.driver.checksum_offload());
+
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
+        Some(packet)
+    }
+
+    /// Receive a single packet from the network and dispatch it to whichever
+    /// part of the network stack handles it
+    ///
+    /// Returns whether a packet has been received and processed
+    pub fn poll(&self) -> bool {
+        match self.recv() {
+            Some(packet) => {
+                self.discard(packet);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Keep polling the network until `func` returns a value or the TSC
+    /// reaches `deadline`, whichever comes first
+    ///
+    /// `func` is called before each poll, so values that can be produced from
+    /// already dispatched packets are returned without touching the network
+    pub fn poll_until<T, F>(&self, deadline: u64, mut func: F) -> Option<T>
+    where
+        F: FnMut() -> Option<T>,
+    {
+        loop {
+            // Check whether the value is already available
+            if let Some(val) = func() {
+                return Some(val);
+            }
+
+            // Return nothing on timeout
+            if cpu::rdtsc() >= deadline { return None; }
+
+            // Dispatch the next packet from the network, if any
+            self.poll();
+        }
     }
 
     /// Send a raw packet over the network
     ///
     /// The `packet` must not include the FCS as that will be computed by the
-    /// driver.
+    /// driver. IPv4 packets which don't fit into the MTU are fragmented, other
+    /// oversized packets are dropped.
     pub fn send(&self, packet: Packet, flush: bool) {
+        if packet.len() > eth::HEADER_LEN + self.mtu() {
+            self.send_fragmented(packet, flush);
+        } else {
+            self.transmit(packet, flush);
+        }
+    }
+
+    /// Hand a raw `packet` which fits into the MTU to the driver for sending,
+    /// counting it in the statistics of this device
+    pub(in crate::net) fn transmit(&self, packet: Packet, flush: bool) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
         self.driver.send(packet, flush);
     }
 
+    /// Whether the link of this device is up
+    pub fn link_up(&self) -> bool {
+        self.driver.link_up()
+    }
+
+    /// Get the speed of the link of this device in Mbit/s, if it's known
+    pub fn link_speed_mbps(&self) -> Option<u32> {
+        self.driver.link_speed_mbps()
+    }
+
+    /// Get the MTU of this device
+    pub fn mtu(&self) -> usize {
+        self.driver.mtu()
+    }
+
     /// Allocate a new packet for use
+    ///
+    /// If the NIC supports it, the checksums of the packet are left to the NIC
+    /// by the packet builders
     pub fn allocate_packet(&self) -> Packet {
-        self.driver.allocate_packet()
+        let mut packet = self.driver.allocate_packet();
+        packet.set_tx_checksum_offload(self.driver.caps().tx_checksum);
+        packet
     }
 
     /// Get this device's MAC address
@@ -268,11 +427,71 @@ impl NetDevice {
         self.mac
     }
 
+    /// Get the packet pool statistics of this device's driver
+    pub fn packet_stats(&self) -> PacketStats {
+        self.driver.packet_stats()
+    }
+
+    /// Get the traffic statistics of this device
+    pub fn stats(&self) -> NetStats {
+        NetStats {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_bytes:   self.rx_bytes.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_bytes:   self.tx_bytes.load(Ordering::Relaxed),
+            rx_errors:  self.driver.rx_errors(),
+        }
+    }
+
     pub fn driver(&self) -> Arc<dyn NetDriver> {
         self.driver.clone()
     }
 }
 
+/// Traffic statistics of a network device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetStats {
+    /// Number of packets received
+    pub rx_packets: u64,
+
+    /// Number of bytes received, excluding the FCS
+    pub rx_bytes: u64,
+
+    /// Number of packets sent
+    pub tx_packets: u64,
+
+    /// Number of bytes sent, excluding the padding and the FCS
+    pub tx_bytes: u64,
+
+    /// Number of received frames dropped because of errors reported by the
+    /// NIC
+    pub rx_errors: u64,
+}
+
+/// Statistics of a driver's packet pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketStats {
+    /// Number of packets handed out by the pool
+    pub allocated: usize,
+
+    /// Number of packets given back to the pool
+    pub released: usize,
+
+    /// Number of released packets which were freed, as the pool was full
+    pub dropped_on_release: usize,
+
+    /// Number of packets handed out and not yet released
+    pub in_flight: usize,
+}
+
+/// Optional features of a NIC which the network stack can make use of
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DriverCaps {
+    /// The NIC inserts the IPv4 header checksum and the TCP and UDP checksums
+    /// into sent packets, so they don't have to be computed in software
+    pub tx_checksum: bool,
+}
+
 /// The driver trait that allows access to NIC RX and TX
 pub trait NetDriver: Send + Sync {
     /// Forcibly reset the NIC
@@ -281,6 +500,36 @@ pub trait NetDriver: Send + Sync {
     /// Get the MAC address of the NIC
     fn mac(&self) -> Mac;
 
+    /// Whether the link of the NIC is up. Drivers which can't tell report the
+    /// link as up
+    fn link_up(&self) -> bool {
+        true
+    }
+
+    /// Get the speed of the link in Mbit/s, if it's up and known
+    fn link_speed_mbps(&self) -> Option<u32> {
+        None
+    }
+
+    /// Get the largest IP packet that can be sent in a single frame
+    fn mtu(&self) -> usize {
+        // The standard Ethernet MTU by default
+        1500
+    }
+
+    /// Whether the NIC verifies the checksums of received packets itself. Such
+    /// NICs may deliver the checksums zeroed, so the network stack doesn't
+    /// verify them
+    fn checksum_offload(&self) -> bool {
+        false
+    }
+
+    /// Get the optional features supported by the NIC
+    fn caps(&self) -> DriverCaps {
+        // No optional features by default
+        DriverCaps::default()
+    }
+
     /// Send a raw frame over the network. This `packet` does not include the
     /// FCS; the driver must compute and insert it.
     fn send(&self, packet: Packet, flush: bool);
@@ -309,4 +558,17 @@ pub trait NetDriver: Send + Sync {
     fn release_packet(&self, _packet: Packet) {
         // Drop/free the packet by default
     }
+
+    /// Get the number of received frames which were dropped because the NIC
+    /// reported errors for them
+    fn rx_errors(&self) -> u64 {
+        // No errors are reported by default
+        0
+    }
+
+    /// Get the statistics of the packet pool of this NIC
+    fn packet_stats(&self) -> PacketStats {
+        // No pool, no statistics by default
+        PacketStats::default()
+    }
 }
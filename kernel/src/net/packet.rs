@@ -1,76 +1,64 @@
 //! Packet interface
 
-use cursor::Cursor;
-
-use crate::net::{Mac, NetDriver};
-use crate::mm::ContigPageAligned;
-
-/// Errors that can occur while parsing network packet headers
-#[derive(Debug)]
-pub enum ParseError {
-    /// Indicates the packet is too short to contain the required data for the
-    /// given field.
-    TruncatedPacket,
-
-    /// The MAC address bytes could not be properly converted into a 6-byte
-    /// array
-    InvalidMacAddress,
-
-    /// Attempted to parse a big-endian `u16` but got an error
-    InvalidWord,
-
-    /// Attempted to parse a big-endian `u32` but got an error
-    InvalidDword,
-
-    /// The Ethernet frame indicates a version we do not support
-    UnsupportedVersion,
-
-    /// The IP header is either missing or too short to be valid
-    InvalidIpHeader,
-
-    /// The IP header included options which we do not support
-    IpOptionsUnsupported,
+use alloc::boxed::Box;
 
-    /// Attempted to parse an IP packet but got invalid protocol
-    InvalidIpProtocol,
+use cursor::Cursor;
 
-    /// Fragmentation is not supported and the packet is either fragmented or
-    /// has disallowed flags set
-    FragmentationUnsupported,
+use crate::net::NetDriver;
+use crate::mm::ContigBuffer;
 
-    /// The total packet length is invalid (too short or longer than
-    /// available data)
-    InvalidLength,
-}
+pub use net_proto::ParseError;
 
 /// Allocated packet that can be put into and taken from DMA buffers.
 ///
-/// The inner backing buffer is guaranteed to be page-sized and page-aligned.
+/// The inner backing buffer is guaranteed to be physically contiguous and
+/// page-aligned.
 pub struct Packet {
     /// The raw backing memory for the packet
-    raw: ContigPageAligned<[u8; 4096]>,
+    raw: ContigBuffer,
 
     /// Size of the inner backing memory
     length: usize,
+
+    /// Whether the checksums of this packet were verified (or zeroed) by the
+    /// NIC, so the network stack shouldn't verify them again
+    checksum_offloaded: bool,
+
+    /// Whether the NIC inserts the checksums of this packet when it's sent, so
+    /// the packet builders should leave them to it
+    tx_checksum_offload: bool,
 }
 
 impl Packet {
-    /// Maximum allowed packet length in bytes for a standard Ethernet frame,
-    /// excluding FCS.
-    const MAX_PACKET_LEN: usize = 1514;
+    /// Capacity in bytes of the packets allocated by `new()`. Packets longer
+    /// than the MTU of the device are fragmented when they are sent.
+    pub const DEFAULT_CAPACITY: usize = 4096;
 
-    /// Allocate a new packet buffer
+    /// Allocate a new packet buffer of `DEFAULT_CAPACITY` bytes
     pub fn new() -> Self {
+        Self::new_with_capacity(Self::DEFAULT_CAPACITY)
+    }
+
+    /// Allocate a new packet buffer which can hold packets of up to `bytes`
+    /// bytes.
+    ///
+    /// Packets handed to a NIC for receiving must be at least as large as the
+    /// receive buffers the NIC has been programmed with, e.g. through the
+    /// buffer size bits of the Intel RCTL register, as the NIC writes that
+    /// many bytes into them
+    pub fn new_with_capacity(bytes: usize) -> Self {
         Self {
-            raw: ContigPageAligned::new([0u8; 4096]),
+            raw: ContigBuffer::new_zeroed(bytes),
             length: 0,
+            checksum_offloaded: false,
+            tx_checksum_offload: false,
         }
     }
 
-    /// Helper function to parse a MAC address from a packet
-    pub(super) fn parse_mac(bytes: Option<&[u8]>) -> Result<Mac, ParseError> {
-        let slice = bytes.ok_or(ParseError::TruncatedPacket)?;
-        slice.try_into().map(Mac).map_err(|_| ParseError::InvalidMacAddress)
+    /// Get the maximum length in bytes of a packet that can be built in the
+    /// backing buffer
+    pub fn capacity(&self) -> usize {
+        self.raw.len()
     }
 
     /// Helper function to parse a `u16` from a packet
@@ -89,27 +77,6 @@ impl Packet {
             .map_err(|_| ParseError::InvalidDword)
     }
 
-    /// Compute a ones-complement checksum over the provided byte slice.
-    pub fn checksum(bytes: &[u8]) -> u16 {
-        let mut checksum: u32 = 0;
-
-        // Process all 2-byte chunks
-        for chunk in bytes.chunks_exact(2) {
-            let word = u16::from_be_bytes([chunk[0], chunk[1]]);
-            checksum = checksum.wrapping_add(word as u32);
-        }
-
-        // Handle final byte (low byte) if length is odd
-        if let Some(&last_byte) = bytes.chunks_exact(2).remainder().first() {
-            checksum = checksum.wrapping_add((last_byte as u32) << 8);
-        }
-
-        // Fold carries
-        let checksum = (checksum & 0xFFFF).wrapping_add(checksum >> 16);
-        let checksum = (checksum & 0xFFFF).wrapping_add(checksum >> 16);
-        checksum as u16
-    }
-
     /// Get the physical address of the packet
     pub fn phys_addr(&self) -> page_table::PhysAddr {
         self.raw.phys_addr()
@@ -120,6 +87,27 @@ impl Packet {
         &self.raw[..self.length]
     }
 
+    /// Copy the whole frame, headers included, into a heap allocation of just
+    /// its size and free the packet.
+    ///
+    /// Packets which came from a NIC should be copied out with
+    /// `PacketLease::detach_payload()` instead, so the packet is released back
+    /// to the NIC rather than freed
+    pub fn into_owned_frame(self) -> Box<[u8]> {
+        Box::from(self.raw())
+    }
+
+    /// Get mutable access to the whole backing buffer, regardless of the
+    /// length of the packet
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.raw
+    }
+
+    /// Print a hexdump of the packet contents
+    pub fn dump(&self) {
+        crate::print::hexdump(self.raw(), 0);
+    }
+
     /// Get the length of the packet
     pub fn len(&self) -> usize {
         self.length
@@ -133,6 +121,32 @@ impl Packet {
     /// Sets the len of the packet to `0`
     pub fn clear(&mut self) {
         self.set_len(0);
+        self.checksum_offloaded = false;
+        self.tx_checksum_offload = false;
+    }
+
+    /// Returns whether the checksums of this packet were handled by the NIC
+    pub fn checksum_offloaded(&self) -> bool {
+        self.checksum_offloaded
+    }
+
+    /// Set whether the checksums of this packet were handled by the NIC
+    pub fn set_checksum_offloaded(&mut self, offloaded: bool) {
+        self.checksum_offloaded = offloaded;
+    }
+
+    /// Returns whether the NIC inserts the checksums of this packet when it's
+    /// sent
+    pub fn tx_checksum_offload(&self) -> bool {
+        self.tx_checksum_offload
+    }
+
+    /// Set whether the NIC inserts the checksums of this packet when it's sent.
+    ///
+    /// This must only be set for packets sent through a driver with the
+    /// `DriverCaps::tx_checksum` capability
+    pub fn set_tx_checksum_offload(&mut self, offload: bool) {
+        self.tx_checksum_offload = offload;
     }
 
     /// Provides a cursor to modify the packet's buffer, ensuring length is
@@ -142,6 +156,22 @@ impl Packet {
     }
 }
 
+impl AsMut<[u8]> for Packet {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.buffer_mut()
+    }
+}
+
+impl net_proto::rx_ring::RxBuffer for Packet {
+    fn phys_addr(&self) -> u64 {
+        Packet::phys_addr(self).0
+    }
+
+    fn set_len(&mut self, len: usize) {
+        Packet::set_len(self, len);
+    }
+}
+
 /// A cursor that ensures the `Packet`'s length is updated on writes or splits
 pub struct PacketCursor<'a> {
     /// Inner cursor over the packet's buffer
@@ -149,6 +179,9 @@ pub struct PacketCursor<'a> {
 
     /// Reference to the packet's length to update on changes
     packet_len: &'a mut usize,
+
+    /// Whether the NIC inserts the checksums of the packet when it's sent
+    tx_checksum_offload: bool,
 }
 
 impl<'a> PacketCursor<'a> {
@@ -156,8 +189,8 @@ impl<'a> PacketCursor<'a> {
     pub fn new(packet: &'a mut Packet) -> Self {
         // Create the cursor
         let cur_pos = packet.len();
-        let buffer = packet.raw.as_mut();
-        let mut inner = Cursor::new_with_limit(buffer, Packet::MAX_PACKET_LEN);
+        let capacity = packet.capacity();
+        let mut inner = Cursor::new_with_limit(&mut packet.raw, capacity);
 
         // Set the initial position to the current length
         inner.set_position(cur_pos);
@@ -165,9 +198,16 @@ impl<'a> PacketCursor<'a> {
         Self {
             inner,
             packet_len: &mut packet.length,
+            tx_checksum_offload: packet.tx_checksum_offload,
         }
     }
 
+    /// Returns whether the NIC inserts the checksums of the packet when it's
+    /// sent, in which case builders must not compute them
+    pub fn tx_checksum_offload(&self) -> bool {
+        self.tx_checksum_offload
+    }
+
     /// Adjusts the length of the packet to the overall position of the cursor
     fn update_len(&mut self) {
         *self.packet_len = self.inner.overall_position();
@@ -182,6 +222,7 @@ impl<'a> PacketCursor<'a> {
         let right = Self {
             inner: right,
             packet_len: self.packet_len,
+            tx_checksum_offload: self.tx_checksum_offload,
         };
 
         (left, right)
@@ -196,6 +237,7 @@ impl<'a> PacketCursor<'a> {
         let mut right = Self {
             inner: right,
             packet_len: self.packet_len,
+            tx_checksum_offload: self.tx_checksum_offload,
         };
         right.update_len();
 
@@ -209,6 +251,31 @@ impl<'a> PacketCursor<'a> {
             .expect("Attempted to split PacketCursor with overflow")
     }
 
+    /// Returns the overall packet length up to which the cursor can be written
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// Returns the number of bytes which can still be written to the packet.
+    /// Writes past this fail and return `None`
+    pub fn remaining(&self) -> usize {
+        self.inner.remaining()
+    }
+
+    /// Returns a checkpoint of the cursor position, which can be restored
+    /// with `rewind_to()`
+    pub fn checkpoint(&self) -> usize {
+        self.inner.checkpoint()
+    }
+
+    /// Rewinds the cursor back to a `checkpoint`, as defined by the
+    /// `Cursor::rewind_to()` spec, and updates the packet length
+    pub fn rewind_to(&mut self, checkpoint: usize) -> Option<()> {
+        self.inner.rewind_to(checkpoint)?;
+        self.update_len();
+        Some(())
+    }
+
     /// Gets a reference to the initialized part of underlying buffer
     pub fn get(&self) -> &[u8] {
         self.inner.get()
@@ -230,6 +297,17 @@ impl<'a> PacketCursor<'a> {
         Some((old, new))
     }
 
+    /// Writes `count` copies of `value` into the cursor and updates the packet
+    /// length
+    ///
+    /// Read the documentation for `Cursor::fill()` for more
+    pub fn fill(&mut self, value: u8, count: usize) -> Option<(usize, usize)> {
+        let (old, new) = self.inner.fill(value, count)?;
+        self.update_len();
+
+        Some((old, new))
+    }
+
     /// Writes a `u8` into the cursor using `Cursor::write()`
     pub fn write_u8(&mut self, val: u8) -> Option<(usize, usize)> {
         self.write(val.to_be_bytes().as_ref())
@@ -281,6 +359,21 @@ impl<'a> PacketLease<'a> {
         lease.packet.take()
             .expect("Packet already taken out from lease")
     }
+
+    /// Copy the bytes `select` picks out of the packet, e.g. the payload of a
+    /// parsed protocol, into a heap allocation and release the packet back to
+    /// the NIC. Returns `None` if `select` does
+    ///
+    /// Borrowing the packet in a callback avoids this copy and should be
+    /// preferred when the data doesn't have to outlive the callback. Copying
+    /// is for data that has to be kept around, as the NIC only has so many
+    /// packets to lease out
+    pub fn detach_payload<F>(self, select: F) -> Option<Box<[u8]>>
+    where
+        F: FnOnce(&Packet) -> Option<&[u8]>,
+    {
+        select(&self).map(Box::from)
+    }
 }
 
 impl<'a> Drop for PacketLease<'a> {
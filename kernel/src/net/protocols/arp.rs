@@ -2,24 +2,16 @@
 
 use core::net::Ipv4Addr;
 
-use crate::net::protocols::eth;
+use crate::net::protocols::eth::{self, EthType};
 use crate::net::{Mac, NetDevice};
 use crate::net::packet::{Packet, PacketLease, ParseError};
 
-// TODO: put these ETH_* constants somewhere..
-
 /// Number of retries for ARP resolution
 const N_RETRIES: usize = 1_000;
 
 /// Time in microseconds to wait before timing out on an ARP reply
 const TIMEOUT: u64 = 100_000;
 
-/// Ethernet type for ARP
-const ETH_TYPE_ARP: u16 = 0x0806;
-
-/// Ethernet type for IPv4
-const ETH_TYPE_IPV4: u16 = 0x0800;
-
 /// Hardware type for Ethernet
 const HW_TYPE_ETH: u16 = 1;
 
@@ -34,35 +26,32 @@ impl NetDevice {
     /// Resolve the MAC address for `ip` using this device
     pub fn arp(&self, ip: Ipv4Addr) -> Option<Mac> {
         // Get this device's IP
-        let this_ip  = self.dhcp_lease.lock().as_ref().unwrap().client_ip;
+        let this_ip  = self.local_ipv4()?;
         let this_mac = self.mac();
 
-        'send_arp: for _retry in 0..N_RETRIES {
+        let mut mac = None;
+        for _retry in 0..N_RETRIES {
             // Allocate and send a new ARP packet
             let mut packet = self.allocate_packet();
-            self.build_arp_packet(&mut packet, Opcode::Request,
-                                  this_mac, this_ip, Mac::ZERO, ip)?;
+            if self.build_arp_packet(&mut packet, Opcode::Request,
+                    this_mac, this_ip, Mac::ZERO, ip).is_none() {
+                break;
+            }
             self.send(packet, true);
 
+            // Let the central poller dispatch packets until the reply shows
+            // up. The request is registered as outstanding on every poll, as
+            // a concurrent resolution of the same IP removes it when it's done
             let timeout = crate::time::future(TIMEOUT);
-            loop {
-                // If we timed out, retry
-                if cpu::rdtsc() >= timeout { continue 'send_arp; }
-
-                if let Some(packet) = self.recv() {
-                    if let Ok(arp) = packet.parse_arp() {
-                        if arp.is_valid_reply(ip, this_ip, self.mac()) {
-                            return Some(arp.sender_mac);
-                        }
-                    }
-
-                    // We couldn't handle the packet, discard it
-                    self.discard(packet);
-                }
-            }
+            mac = self.poll_until(timeout, || {
+                *self.arp_replies.lock().entry(ip).or_default()
+            });
+            if mac.is_some() { break; }
         }
-        // No response
-        None
+
+        // The request isn't outstanding anymore, so stop recording replies
+        self.arp_replies.lock().remove(&ip);
+        mac
     }
 
     /// Discard an ARP packet and attempt to handle it somewhere else in the
@@ -85,19 +74,26 @@ impl NetDevice {
             },
         };
 
-        // If we don't have a DHCP lease, there's nothing we can do
-        let lease = self.dhcp_lease.lock();
-        let lease = match lease.as_ref() {
-            Some(lease) => lease,
+        // If we don't have an IP address, there's nothing we can do
+        let this_ip = match self.local_ipv4() {
+            Some(ip) => ip,
             None => return,
         };
 
-        let this_ip = lease.client_ip;
+        // If this was a reply to us, hand it to whoever is resolving the IP.
+        // Replies nobody is waiting for are dropped
+        if arp.is_valid_reply(arp.sender_ip, this_ip, self.mac()) {
+            if let Some(reply) =
+                    self.arp_replies.lock().get_mut(&arp.sender_ip) {
+                *reply = Some(arp.sender_mac);
+            }
+            return;
+        }
 
         // If this was a request to us, reply to it
         if matches!(arp.opcode, x if x == Opcode::Request as u16)
             && arp.hw_type == HW_TYPE_ETH
-            && arp.proto_type == ETH_TYPE_IPV4
+            && arp.proto_type == EthType::Ipv4 as u16
             && arp.hw_size == 6
             && arp.proto_size == 4
             && arp.target_ip == this_ip
@@ -124,7 +120,7 @@ impl NetDevice {
             .take_cursor();
 
         cursor.write_u16(HW_TYPE_ETH)?;
-        cursor.write_u16(ETH_TYPE_ARP)?;
+        cursor.write_u16(EthType::Arp as u16)?;
         cursor.write_u8(6)?;
         cursor.write_u8(4)?;
 
@@ -162,7 +158,7 @@ impl<'a> Parsed<'a> {
     fn is_valid_reply(&self, sender_ip: Ipv4Addr, target_ip: Ipv4Addr,
                       target_mac: Mac) -> bool {
         self.hw_type == HW_TYPE_ETH
-            && self.proto_type == ETH_TYPE_IPV4
+            && self.proto_type == EthType::Ipv4 as u16
             && self.hw_size == 6
             && self.proto_size == 4
             && self.opcode == Opcode::Reply as u16
@@ -177,7 +173,7 @@ impl Packet {
     pub fn parse_arp(&self) -> Result<Parsed, ParseError> {
         let eth = self.parse_eth()?; // Assume this already returns Result<eth::Parsed, ParseError>
 
-        if eth.eth_type != ETH_TYPE_ARP {
+        if eth.ethertype() != Some(EthType::Arp) {
             return Err(ParseError::InvalidLength); // Or consider a new ParseError::NotArp
         }
 
@@ -7,6 +7,8 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::net::{Ipv4Addr, IpAddr};
 
+use net_proto::dhcp::{Ack, Deadlines, DhcpOption, DhcpOptionId, MessageType};
+
 use crate::net::{NetDevice, Port, NetAddress, Mac};
 use crate::net::protocols::udp;
 use crate::net::packet::Packet;
@@ -15,6 +17,10 @@ use crate::net::packet::Packet;
 /// If a response doesn't come within this timeout, the process will be aborted
 const TIMEOUT: u64 = 5_000_000;
 
+/// Time in microseconds to wait for the link to come up before giving up on a
+/// lease. Auto-negotiation takes a few seconds on real hardware
+const LINK_TIMEOUT: u64 = 5_000_000;
+
 /// DHCP port of the client
 const CLIENT_PORT: Port = Port(68);
 
@@ -103,173 +109,6 @@ impl DhcpOptionsBuilder {
     }
 }
 
-/// DHCP options
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[allow(missing_docs)]
-enum DhcpOption<'a> {
-    Pad,
-    SubnetMask(Ipv4Addr),
-    BroadcastIp(Ipv4Addr),
-    RequestedIp(Ipv4Addr),
-    LeaseTime(u32),
-    MessageType(MessageType),
-    ServerIp(Ipv4Addr),
-    ParameterRequestList(&'a [u8]),
-    RenewalTime(u32),
-    Unknown(u8, &'a [u8]),
-    End,
-}
-
-/// Mapping of DHCP options to their IDs
-#[derive(Debug, PartialEq, Eq)]
-#[repr(u8)]
-#[allow(missing_docs)]
-enum DhcpOptionId {
-    Pad                  = 0,
-    SubnetMask           = 1,
-    BroadcastIp          = 28,
-    RequestedIp          = 50,
-    LeaseTime            = 51,
-    MessageType          = 53,
-    ServerIp             = 54,
-    ParameterRequestList = 55,
-    RenewalTime          = 58,
-    End                  = 255,
-}
-
-impl TryFrom<u8> for DhcpOptionId {
-    type Error = ();
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0   => Ok(Self::Pad),
-            1   => Ok(Self::SubnetMask),
-            28  => Ok(Self::BroadcastIp),
-            50  => Ok(Self::RequestedIp),
-            51  => Ok(Self::LeaseTime),
-            53  => Ok(Self::MessageType),
-            54  => Ok(Self::ServerIp),
-            55  => Ok(Self::ParameterRequestList),
-            58  => Ok(Self::RenewalTime),
-            255 => Ok(Self::End),
-            _   => Err(()),
-        }
-    }
-}
-
-impl<'a> DhcpOption<'a> {
-    /// Parse a DHCP option from a raw message, updating the message pointer to
-    /// reflect the number of parsed bytes
-    fn parse(ptr: &mut &'a [u8]) -> Option<Self> {
-        let code = *ptr.first()?;
-
-        // Handle single-byte options first
-        if let Ok(DhcpOptionId::Pad) = DhcpOptionId::try_from(code) {
-            *ptr = &ptr[1..];
-            return Some(Self::Pad);
-        }
-
-        if let Ok(DhcpOptionId::End) = DhcpOptionId::try_from(code) {
-            *ptr = &ptr[1..];
-            return Some(Self::End);
-        }
-
-        // Handle variable-length options
-        let len = *ptr.get(1)? as usize;
-        let payload = ptr.get(2..2 + len)?;
-
-        let option = match DhcpOptionId::try_from(code) {
-            Ok(id) => match id {
-                DhcpOptionId::SubnetMask => {
-                    let bytes: [u8; 4] = payload.try_into().ok()?;
-                    Self::SubnetMask(Ipv4Addr::from(u32::from_be_bytes(bytes)))
-                }
-                DhcpOptionId::BroadcastIp => {
-                    let bytes: [u8; 4] = payload.try_into().ok()?;
-                    Self::BroadcastIp(Ipv4Addr::from(u32::from_be_bytes(bytes)))
-                }
-                DhcpOptionId::RequestedIp => {
-                    let bytes: [u8; 4] = payload.try_into().ok()?;
-                    Self::RequestedIp(Ipv4Addr::from(u32::from_be_bytes(bytes)))
-                }
-                DhcpOptionId::LeaseTime => {
-                    let bytes: [u8; 4] = payload.try_into().ok()?;
-                    Self::LeaseTime(u32::from_be_bytes(bytes))
-                }
-                DhcpOptionId::MessageType => {
-                    let byte: u8 = payload.get(0).copied()?;
-                    Self::MessageType(MessageType::try_from(byte).ok()?)
-                }
-                DhcpOptionId::ServerIp => {
-                    let bytes: [u8; 4] = payload.try_into().ok()?;
-                    Self::ServerIp(Ipv4Addr::from(u32::from_be_bytes(bytes)))
-                }
-                DhcpOptionId::ParameterRequestList => {
-                    Self::ParameterRequestList(payload)
-                }
-                DhcpOptionId::RenewalTime => {
-                    let bytes: [u8; 4] = payload.try_into().ok()?;
-                    Self::RenewalTime(u32::from_be_bytes(bytes))
-                }
-                _ => unreachable!(), // Handled single-byte variants already
-            },
-            Err(_) => Self::Unknown(code, payload),
-        };
-
-        *ptr = &ptr[2 + len..];
-        Some(option)
-    }
-
-    /// Serialize a DHCP option by appending it to `buffer`
-    fn serialize(&self, buffer: &mut Vec<u8>) {
-        match self {
-            Self::Pad => buffer.push(DhcpOptionId::Pad as u8),
-            Self::End => buffer.push(DhcpOptionId::End as u8),
-            Self::SubnetMask(addr) =>
-                Self::push_ip_option(buffer, DhcpOptionId::SubnetMask, addr),
-            Self::BroadcastIp(addr) =>
-                Self::push_ip_option(buffer, DhcpOptionId::BroadcastIp, addr),
-            Self::RequestedIp(addr) =>
-                Self::push_ip_option(buffer, DhcpOptionId::RequestedIp, addr),
-            Self::ServerIp(addr) =>
-                Self::push_ip_option(buffer, DhcpOptionId::ServerIp, addr),
-            Self::LeaseTime(time) =>
-                Self::push_u32_option(buffer, DhcpOptionId::LeaseTime, *time),
-            Self::RenewalTime(time) =>
-                Self::push_u32_option(buffer, DhcpOptionId::RenewalTime, *time),
-            Self::MessageType(typ) => {
-                buffer.push(DhcpOptionId::MessageType as u8);
-                buffer.push(1);
-                buffer.push(*typ as u8);
-            }
-            Self::ParameterRequestList(data) => {
-                buffer.push(DhcpOptionId::ParameterRequestList as u8);
-                buffer.push(data.len() as u8);
-                buffer.extend_from_slice(data);
-            }
-            Self::Unknown(code, data) => {
-                buffer.push(*code);
-                buffer.push(data.len() as u8);
-                buffer.extend_from_slice(data);
-            }
-        }
-    }
-
-    // Helper functions for common patterns
-
-    fn push_ip_option(buffer: &mut Vec<u8>, code: DhcpOptionId, ip: &Ipv4Addr) {
-        buffer.push(code as u8);
-        buffer.push(4);
-        buffer.extend_from_slice(&ip.octets());
-    }
-
-    fn push_u32_option(buffer: &mut Vec<u8>, code: DhcpOptionId, value: u32) {
-        buffer.push(code as u8);
-        buffer.push(4);
-        buffer.extend_from_slice(&value.to_be_bytes());
-    }
-}
-
 /// DHCP op code / message type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -310,64 +149,119 @@ impl Default for HardwareType {
     }
 }
 
-/// DHCP client-server message type
+/// A DHCP lease
 ///
-/// [Source](https://datatracker.ietf.org/doc/html/rfc2131#section-3.1)
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-#[allow(missing_docs)]
-enum MessageType {
-    Discover = 1,
-    Offer    = 2,
-    Request  = 3,
-    Ack      = 5,
-    Unsupported,
+/// The lease times follow RFC 2131: at T1 (`renew_at`, half of the lease time
+/// by default) the client should renew the lease by unicasting a REQUEST to the
+/// server which granted it. If the server doesn't answer until T2
+/// (`rebind_at`, 87.5% of the lease time), the REQUEST is broadcast instead, so
+/// any server may extend the lease. Once the lease expires, the address must
+/// no longer be used.
+///
+/// The times are stored as absolute TSC deadlines. Leases without a lease time
+/// never expire.
+#[derive(Debug, Clone, Copy)]
+pub struct Lease {
+    pub client_ip:    Ipv4Addr,
+    pub server_ip:    Ipv4Addr,
+
+    /// The parameters of the lease granted by the server
+    pub ack:          Ack,
+
+    /// TSC deadlines of T1, T2 and the expiry of the lease
+    pub deadlines:    Deadlines,
 }
 
-impl From<u8> for MessageType {
-    fn from(val: u8) -> Self {
-        match val {
-            1 => Self::Discover,
-            2 => Self::Offer,
-            3 => Self::Request,
-            5 => Self::Ack,
-            _ => Self::Unsupported,
-        }
+impl Lease {
+    /// Returns whether T1 has passed and the lease should be renewed
+    pub fn needs_renewal(&self) -> bool {
+        self.deadlines.needs_renewal(cpu::rdtsc())
+    }
+
+    /// Returns whether T2 has passed and the renewal should be broadcast
+    pub fn needs_rebinding(&self) -> bool {
+        self.deadlines.needs_rebinding(cpu::rdtsc())
+    }
+
+    /// Returns whether the lease has expired
+    pub fn expired(&self) -> bool {
+        self.deadlines.expired(cpu::rdtsc())
     }
 }
 
-/// A DHCP lease
-#[derive(Debug, Clone, Copy)]
-pub struct Lease {
-    pub client_ip:    Ipv4Addr,
-    pub server_ip:    Ipv4Addr,
-    pub broadcast_ip: Option<Ipv4Addr>,
-    pub subnet_mask:  Option<Ipv4Addr>,
+/// Serialize a DHCP message of `msg_type` with the `extra_opts`, requesting
+/// all of the parameters stored in a `Lease`
+fn request_options(msg_type: MessageType, extra_opts: &[DhcpOption])
+        -> Box<[u8]> {
+    let mut opts = DhcpOptionsBuilder::new();
+    opts
+        .add(DhcpOption::MessageType(msg_type))
+        .add(DhcpOption::ParameterRequestList(&[
+            DhcpOptionId::MessageType as u8,
+            DhcpOptionId::ServerIp    as u8,
+            DhcpOptionId::BroadcastIp as u8,
+            DhcpOptionId::SubnetMask  as u8,
+            DhcpOptionId::Router      as u8,
+            DhcpOptionId::DnsServer   as u8,
+            DhcpOptionId::LeaseTime   as u8,
+            DhcpOptionId::RenewalTime as u8,
+        ]));
+
+    for opt in extra_opts.iter() {
+        opts.add(opt.clone());
+    }
+
+    opts.end()
+}
+
+/// Wait for a DHCPACK for the transaction `xid` and return the lease it grants
+/// to `client_ip` from `server_ip`
+fn recv_ack(bind: &udp::UdpBind, xid: u32, mac: Mac, client_ip: Ipv4Addr,
+            server_ip: Ipv4Addr) -> Option<Lease> {
+    bind.recv_timeout(TIMEOUT, |_, udp| {
+        // Accept packets destined for us
+        let dst_mac = udp.ip.eth().dst_mac;
+        if dst_mac != mac && dst_mac != Mac::BROADCAST { return None; }
+
+        // Attempt to parse the packet as a DHCP reply
+        let (_header, options) = udp.parse_dhcp_reply(xid)?;
+        lease_from_ack(&options, client_ip, server_ip)
+    })
+}
+
+/// Create the lease granted to `client_ip` from `server_ip` by the `options`
+/// of a DHCP reply. Returns `None` if the reply isn't an ACK
+fn lease_from_ack(options: &[DhcpOption], client_ip: Ipv4Addr,
+                  server_ip: Ipv4Addr) -> Option<Lease> {
+    let ack = Ack::from_options(options)?;
+    Some(Lease {
+        client_ip,
+        server_ip,
+        deadlines: Deadlines::new(&ack, cpu::rdtsc(), crate::time::tsc_mhz()),
+        ack,
+    })
 }
 
 /// Attempt to get a DHCP lease for `dev`
 pub fn get_lease(dev: Arc<NetDevice>) -> Option<Lease> {
+    // Wait for the link to come up, as the NIC may still be negotiating it.
+    // Don't wait for replies that can't come if it doesn't
+    let link_deadline = crate::time::future(LINK_TIMEOUT);
+    while !dev.link_up() {
+        if cpu::rdtsc() >= link_deadline { return None; }
+        core::hint::spin_loop();
+    }
+
     let xid = cpu::rdtsc() as u32;
     let mac = dev.mac();
     let bind = NetDevice::bind_udp_port(dev.clone(), CLIENT_PORT)?;
 
     let send_dhcp_request = |msg_type: MessageType, extra_opts: &[DhcpOption]| {
-        let mut opts = DhcpOptionsBuilder::new();
-        opts
-            .add(DhcpOption::MessageType(msg_type))
-            .add(DhcpOption::ParameterRequestList(&[
-                DhcpOptionId::MessageType as u8,
-                DhcpOptionId::ServerIp    as u8,
-                DhcpOptionId::BroadcastIp as u8,
-                DhcpOptionId::SubnetMask  as u8,
-            ]));
-
-        for opt in extra_opts.into_iter() {
-            opts.add(opt.clone());
-        }
-
         let mut packet = dev.allocate_packet();
-        packet.create_dhcp_request(xid, mac, &opts.end());
+        packet.create_dhcp_request(
+            xid, &broadcast_address(mac, Ipv4Addr::from_bits(0)),
+            Ipv4Addr::from_bits(0),
+            &request_options(msg_type, extra_opts));
         dev.send(packet, true);
     };
 
@@ -412,56 +306,63 @@ pub fn get_lease(dev: Arc<NetDevice>) -> Option<Lease> {
         DhcpOption::ServerIp(server_ip),
     ]);
 
-    // Attempt to get the broadcast IP and the subnet mask
-    let mut broadcast_ip: Option<Ipv4Addr> = None;
-    let mut subnet_mask:  Option<Ipv4Addr> = None;
-    bind.recv_timeout(TIMEOUT, |_, udp| {
-        // Accept packets destined for us
-        let dst_mac = udp.ip.eth().dst_mac;
-        if dst_mac != mac && dst_mac != Mac::BROADCAST { return None; }
-
-        // Attempt to parse the packet as a DHCP reply
-        let (_header, options) = udp.parse_dhcp_reply(xid)?;
-
-        // We're looking for an ACK now
-        options.iter()
-            .find(|&x| *x == DhcpOption::MessageType(MessageType::Ack))?;
+    // Wait for the ACK and return the lease
+    let lease = recv_ack(&bind, xid, mac, offered_ip, server_ip)?;
+    println!("Got DHCP lease for {mac:X?}! {lease:#X?}");
+    Some(lease)
+}
 
-        // Save the broadcast IP
-        broadcast_ip = options.iter().find_map(|x| {
-            if let DhcpOption::BroadcastIp(ip) = x {
-                Some(*ip)
-            } else { None }
-        });
+/// Attempt to renew the `lease` of `dev`, returning the extended lease.
+///
+/// Before T2, the REQUEST is unicast to the server which granted the lease.
+/// Afterwards, it's broadcast. The new lease isn't applied to the device,
+/// that's up to the caller (see `NetDevice::set_lease()`)
+pub fn renew(dev: Arc<NetDevice>, lease: Lease) -> Option<Lease> {
+    let xid = cpu::rdtsc() as u32;
+    let mac = dev.mac();
+    let bind = NetDevice::bind_udp_port(dev.clone(), CLIENT_PORT)?;
 
-        // Save the subnet_mask
-        subnet_mask = options.iter().find_map(|x| {
-            if let DhcpOption::SubnetMask(ip) = x {
-                Some(*ip)
-            } else { None }
-        });
+    // Get the address to send the REQUEST to
+    let addr = if lease.needs_rebinding() {
+        broadcast_address(mac, lease.client_ip)
+    } else {
+        NetAddress::resolve(&dev, CLIENT_PORT, SERVER_PORT, lease.server_ip)
+            .ok()?
+    };
 
-        Some(())
-    })?;
+    // Renewals carry our IP in the header instead of the requested IP and
+    // the server identifier options
+    let mut packet = dev.allocate_packet();
+    packet.create_dhcp_request(xid, &addr, lease.client_ip,
+        &request_options(MessageType::Request, &[]));
+    dev.send(packet, true);
 
-    // Return the lease
-    let lease = Lease {
-        client_ip: offered_ip,
-        server_ip,
-        broadcast_ip,
-        subnet_mask,
-    };
-    println!("Got DHCP lease for {mac:X?}! {lease:#X?}");
+    // Wait for the ACK and return the extended lease
+    let lease = recv_ack(&bind, xid, mac, lease.client_ip, lease.server_ip)?;
+    println!("Renewed DHCP lease for {mac:X?}! {lease:#X?}");
     Some(lease)
 }
 
+/// Returns the address of a broadcast from `mac` to the DHCP servers. `src_ip`
+/// is our IP if we're bound to one already, or zero
+fn broadcast_address(mac: Mac, src_ip: Ipv4Addr) -> NetAddress {
+    NetAddress {
+        src_mac: mac,
+        dst_mac: Mac::BROADCAST,
+        src_ip: IpAddr::V4(src_ip),
+        dst_ip: IpAddr::V4(Ipv4Addr::from_bits(!0)),
+        src_port: CLIENT_PORT,
+        dst_port: SERVER_PORT,
+    }
+}
+
 impl<'a> udp::Parsed<'a> {
     /// Attempt to parse a DHCPREPLY packet
     fn parse_dhcp_reply(&self, xid: u32)
             -> Option<(Header, Vec<DhcpOption<'a>>)> {
 
         // Get the header and the options
-        let (header_bytes, mut raw_options) = self.payload
+        let (header_bytes, raw_options) = self.payload
             .split_at_checked(core::mem::size_of::<Header>())?;
 
         // Cast the header bytes as the header
@@ -484,35 +385,17 @@ impl<'a> udp::Parsed<'a> {
         }
 
         // Parse DHCP options
-        let mut options = Vec::new();
-        while !raw_options.is_empty() {
-            if let Some(option) = DhcpOption::parse(&mut raw_options) {
-                options.push(option);
-            } else {
-                break;
-            }
-        }
-
-        Some((*header, options))
+        Some((*header, net_proto::dhcp::parse_options(raw_options)))
     }
 }
 
 impl Packet {
-    /// Creates a finalized DHCPREQUEST out of this packet, using `mac` as the
-    /// source MAC address
-    fn create_dhcp_request(&mut self, xid: u32, mac: Mac, options: &[u8]) {
-        // Initialize the address
-        let addr = NetAddress {
-            src_mac: mac,
-            dst_mac: Mac::BROADCAST,
-            src_ip: IpAddr::V4(Ipv4Addr::from_bits(0)),
-            dst_ip: IpAddr::V4(Ipv4Addr::from_bits(!0)),
-            src_port: CLIENT_PORT,
-            dst_port: SERVER_PORT,
-        };
-
+    /// Creates a finalized DHCPREQUEST out of this packet, sent to `addr`.
+    /// `ciaddr` is our IP, if we have one already
+    fn create_dhcp_request(&mut self, xid: u32, addr: &NetAddress,
+                           ciaddr: Ipv4Addr, options: &[u8]) {
         // Create a UDP builder
-        let mut builder = self.create_udp(&addr);
+        let mut builder = self.create_udp(addr);
 
         {
             // Write in the empty DHCP header
@@ -529,8 +412,9 @@ impl Packet {
             header.hlen  = header.htype.hlen();
             header.xid   = xid.to_be();
 
-            // Set our MAC
-            header.chaddr[..6].copy_from_slice(&mac.0);
+            // Set our IP and MAC
+            header.ciaddr = ciaddr.to_bits().to_be();
+            header.chaddr[..6].copy_from_slice(&addr.src_mac.0);
 
             // Set the DHCP cookie
             header.cookie = COOKIE.to_be();
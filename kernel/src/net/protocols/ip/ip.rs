@@ -62,6 +62,15 @@ impl<'a> Parsed<'a> {
             Parsed::V6(p) => p.payload,
         }
     }
+
+    /// Returns whether the header checksum matches the header. IPv6 headers
+    /// have no checksum, so they always pass
+    pub fn verify_checksum(&self) -> bool {
+        match self {
+            Parsed::V4(p) => p.verify_checksum(),
+            Parsed::V6(_) => true,
+        }
+    }
 }
 
 /// Unified representation of the IP builders
@@ -79,6 +88,24 @@ impl<'a> Builder<'a> {
         }
     }
 
+    /// Set the time to live (the hop limit in IPv6) of the packet. Defaults
+    /// to 64
+    pub fn set_ttl(&mut self, ttl: u8) {
+        match self {
+            Builder::V4(b) => b.set_ttl(ttl),
+            Builder::V6(b) => b.set_ttl(ttl),
+        }
+    }
+
+    /// Set the 6-bit differentiated services code point of the packet.
+    /// Defaults to 0
+    pub fn set_dscp(&mut self, dscp: u8) {
+        match self {
+            Builder::V4(b) => b.set_dscp(dscp),
+            Builder::V6(b) => b.set_dscp(dscp),
+        }
+    }
+
     /// Take out the cursor out of the builder
     pub fn take_cursor(&mut self) -> Option<PacketCursor<'a>> {
         match self {
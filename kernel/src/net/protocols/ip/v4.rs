@@ -1,13 +1,32 @@
 //! L2: IPv4 implementation
 
 use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicU16, Ordering};
 
-use crate::net::protocols::eth;
-use crate::net::packet::{Packet, ParseError, PacketCursor};
+use net_proto::ipv4::{self, HEADER_LEN};
+
+use crate::net::NetDevice;
+use crate::net::protocols::eth::{self, EthType};
+use crate::net::packet::{Packet, ParseError, PacketCursor, PacketLease};
 use crate::net::protocols::ip::TransportProtocol;
 
-/// Ethernet type for IPv4
-const ETH_TYPE_IPV4: u16 = 0x0800;
+/// Offset of the IPv4 payload in the packet
+const PAYLOAD_OFFSET: usize = eth::HEADER_LEN + HEADER_LEN;
+
+/// Maximum size of the payload of a reassembled datagram.
+///
+/// Datagrams are reassembled in a single packet, so the payload is limited by
+/// the packet buffer size minus the Ethernet and IPv4 headers, which comes out
+/// to 4062 bytes. Fragments reaching beyond this size are dropped.
+pub const MAX_REASSEMBLED_LEN: usize =
+    Packet::DEFAULT_CAPACITY - PAYLOAD_OFFSET;
+
+/// Maximum number of datagrams reassembled at once by a device. Fragments of
+/// new datagrams are dropped while all of them are in use
+const MAX_REASSEMBLIES: usize = 8;
+
+/// Time in microseconds after which an incomplete datagram is dropped
+const REASSEMBLY_TIMEOUT: u64 = 5_000_000;
 
 /// A parsed IPv4 header and payload
 #[derive(Debug)]
@@ -24,67 +43,182 @@ pub struct ParsedV4<'a> {
     /// IP payload protocol
     pub protocol: u8,
 
+    /// Identification of the datagram this packet belongs to
+    pub id: u16,
+
+    /// Whether more fragments of the datagram follow this one
+    pub more_fragments: bool,
+
+    /// Offset of the payload of this fragment in the datagram in bytes
+    pub frag_offset: usize,
+
     /// Raw byte payload of the IP packet
     pub payload: &'a [u8],
 }
 
+impl<'a> ParsedV4<'a> {
+    /// Whether this packet is a fragment of a larger datagram
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments || self.frag_offset != 0
+    }
+
+    /// Returns whether the header checksum matches the header
+    pub fn verify_checksum(&self) -> bool {
+        // The ones-complement sum of a header including its checksum is all
+        // ones
+        net_proto::checksum(&self.eth.payload[..HEADER_LEN]) == 0xFFFF
+    }
+}
+
 impl Packet {
     /// Parse the IP header
+    ///
+    /// Fragments are rejected, as their payload can be parsed only once the
+    /// datagram has been reassembled
     pub fn parse_ipv4(&self) -> Result<ParsedV4, ParseError> {
+        let ip = self.parse_ipv4_fragment()?;
+        if ip.is_fragment() {
+            return Err(ParseError::Fragmented);
+        }
+
+        Ok(ip)
+    }
+
+    /// Parse the IP header, accepting fragments of larger datagrams
+    ///
+    /// The header checksum is verified unless the NIC handled it already or is
+    /// going to insert it when the packet is sent
+    pub fn parse_ipv4_fragment(&self) -> Result<ParsedV4, ParseError> {
         // Parse the Ethernet header
         let eth = self.parse_eth()?;
 
         // Handle the ethernet type
-        if eth.eth_type != ETH_TYPE_IPV4 {
-            return Err(ParseError::UnsupportedVersion);
-        }
-
-        // Get the header. This will always be at least 20 bytes without options
-        let header = eth.payload.get(..20).ok_or(ParseError::InvalidIpHeader)?;
-
-        // Verify the version and the header length
-        if (header[0] >> 4)  != 4 {
+        if eth.ethertype() != Some(EthType::Ipv4) {
             return Err(ParseError::UnsupportedVersion);
         }
-        if (header[0] & 0xF) != 5 {
-            return Err(ParseError::IpOptionsUnsupported);
-        }
 
-        // Get the total length of the hader and the payload
-        let total_length = Self::parse_u16(Some(&header[2..4]))? as usize;
+        // Parse the header, verifying its checksum unless the NIC handled it
+        // already or is going to insert it
+        let verify = !self.checksum_offloaded() && !self.tx_checksum_offload();
+        let ip = ipv4::parse(eth.payload, verify)?;
 
-        // Get the flags and make sure the reserved bit and fragments are clear
-        // as we do not support fragmentation yet
-        let flags = header[6] >> 5 & 0x7;
-        if (flags & 0b101) != 0 {
-            return Err(ParseError::FragmentationUnsupported);
-        }
+        Ok(ParsedV4 {
+            src_ip: ip.src_ip,
+            dst_ip: ip.dst_ip,
+            protocol: ip.protocol,
+            id: ip.id,
+            more_fragments: ip.more_fragments,
+            frag_offset: ip.frag_offset,
+            payload: ip.payload,
+            eth,
+        })
+    }
+}
 
-        // Make sure there's actually no fragmentation
-        let frag_offset = Self::parse_u16(Some(&header[6..8]))?;
-        if (frag_offset & 0x1FFF) != 0 {
-            return Err(ParseError::FragmentationUnsupported)
+/// A datagram which is being reassembled from its fragments in a packet
+pub(in crate::net) type Reassembly = ipv4::Reassembly<Packet>;
+
+impl NetDevice {
+    /// Send an IPv4 `packet` that doesn't fit into the MTU of this device as
+    /// multiple fragments
+    ///
+    /// Packets which can't be fragmented are dropped
+    pub(in crate::net) fn send_fragmented(&self, mut packet: Packet,
+                                          flush: bool) {
+        /// The identification of the next fragmented datagram
+        static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+        // The transport checksum has to span all of the fragments, so it can't
+        // be left to the NIC
+        if packet.tx_checksum_offload() {
+            let len = packet.len();
+            let frame = &mut packet.buffer_mut()[..len];
+            if let Some(datagram) = frame.get_mut(eth::HEADER_LEN..) {
+                ipv4::finish_transport_checksum(datagram);
+            }
         }
 
-        // Get the protocol
-        let protocol = header[9];
-
-        // Get the source and destination IPs
-        let src_ip = Self::parse_u32(Some(&header[12..16]))?.into();
-        let dst_ip = Self::parse_u32(Some(&header[16..20]))?.into();
+        let Ok(ip) = packet.parse_ipv4() else {
+            self.driver().release_packet(packet);
+            return;
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let header = &ip.eth.payload[..HEADER_LEN];
+        ipv4::fragment(header, ip.payload, self.mtu(), id, |hdr, data, last| {
+            // Write out the fragment and send it. Its header checksum has
+            // been computed already, so it's not left to the NIC
+            let mut frag = self.allocate_packet();
+            frag.set_tx_checksum_offload(false);
+            let mut cursor = frag.cursor();
+            cursor.write(&packet.raw()[..eth::HEADER_LEN]).unwrap();
+            cursor.write(hdr).unwrap();
+            cursor.write(data).unwrap();
+            self.transmit(frag, flush && last);
+        });
+
+        self.driver().release_packet(packet);
+    }
 
-        // Validate the total length
-        if total_length < header.len() || total_length > eth.payload.len() {
-            return Err(ParseError::InvalidLength);
+    /// Discard an IPv4 fragment, putting it into the reassembly of its
+    /// datagram.
+    ///
+    /// If this function handles the packet, it will be taken out of the option.
+    /// Once all fragments of a datagram have been received, the packet with the
+    /// reassembled datagram is returned.
+    pub fn discard_ipv4_fragment(&self, packet: &mut Option<PacketLease>)
+            -> Option<Packet> {
+        let pk = packet.as_ref()?;
+
+        // Only handle fragments
+        let ip = match pk.parse_ipv4_fragment() {
+            Ok(ip) if ip.is_fragment() => ip,
+            _ => return None,
+        };
+        let key = (ip.src_ip, ip.dst_ip, ip.id, ip.protocol);
+        let now = cpu::rdtsc();
+
+        let mut reassemblies = self.ipv4_fragments.lock();
+
+        // Drop the reassemblies which have timed out
+        let mut ii = 0;
+        while ii < reassemblies.len() {
+            if reassemblies[ii].deadline() <= now {
+                let timed_out = reassemblies.swap_remove(ii);
+                self.driver().release_packet(timed_out.into_buffer());
+            } else {
+                ii += 1;
+            }
         }
 
-        Ok(ParsedV4 {
-            src_ip,
-            dst_ip,
-            protocol,
-            payload: &eth.payload[20..total_length],
-            eth,
-        })
+        // Find the reassembly of this datagram or start a new one if there's
+        // space for it
+        let idx = match reassemblies.iter().position(|r| r.key() == key) {
+            Some(idx) => Some(idx),
+            None if reassemblies.len() < MAX_REASSEMBLIES => {
+                let headers = &pk.raw()[..PAYLOAD_OFFSET];
+                let deadline = crate::time::future(REASSEMBLY_TIMEOUT);
+                reassemblies.push(Reassembly::new(key, self.allocate_packet(),
+                                                  headers, deadline));
+                Some(reassemblies.len() - 1)
+            },
+            None => None,
+        };
+
+        // Put the fragment into the reassembly. Invalid fragments are dropped
+        let reassembled = idx.and_then(|idx| {
+            reassemblies[idx]
+                .insert(ip.frag_offset, ip.more_fragments, ip.payload)?;
+            reassemblies[idx].is_complete().then(|| {
+                let (mut packet, len) = reassemblies.swap_remove(idx).finish();
+                packet.set_len(len);
+                packet
+            })
+        });
+
+        // The fragment has been handled and its packet can be released
+        packet.take();
+        reassembled
     }
 }
 
@@ -93,7 +227,7 @@ impl<'a> eth::Builder<'a> {
     pub fn ipv4(mut self, src: &'a Ipv4Addr, dst: &'a Ipv4Addr)
             -> Option<BuilderV4<'a>> {
         // Write in the type
-        self.cursor.write_u16(ETH_TYPE_IPV4);
+        self.cursor.write_u16(EthType::Ipv4 as u16);
 
         // Split the cursor and save the Ethernet header
         let (_, cursor) = self.cursor.split_at_current();
@@ -101,20 +235,13 @@ impl<'a> eth::Builder<'a> {
     }
 }
 
-/// Indexes to fields which must be filled in when the packet is finalized
-struct ToFill {
-    len:  usize,
-    prot: usize,
-    crc:  usize,
-}
-
 /// Builder for IPv4 headers
 pub struct BuilderV4<'a> {
     hdr:     &'a mut [u8],
     src:     &'a Ipv4Addr,
     dst:     &'a Ipv4Addr,
-    to_fill: ToFill,
     cursor:  Option<PacketCursor<'a>>,
+    offload: bool,
 }
 
 impl<'a> BuilderV4<'a> {
@@ -124,39 +251,16 @@ impl<'a> BuilderV4<'a> {
         src: &'a Ipv4Addr,
         dst: &'a Ipv4Addr
     ) -> Option<Self> {
-        // Ip version 4 and 20 byte header length
-        cursor.write_u8((4 << 4 | 5) as u8)?;
-
-        // No DSCP or ECN
-        cursor.write_u8(0)?;
-
-        // Zero out the length for now
-        let (len, _) = cursor.write_u16(0)?;
-
-        // Identification, flags and fragment offset are all zero
-        cursor.write_u32(0)?;
-
-        // 64 TTL
-        cursor.write_u8(64)?;
-
-        // Zero out the protocol. We'll fill this in when the protocol is chosen
-        let (prot, _) = cursor.write_u8(0)?;
-
-        // Zero out the checksum. Likewise will be filled in later
-        let (crc, _) = cursor.write_u16(0)?;
-
-        // Source and destination IPs
-        cursor.write_u32(src.to_bits())?;
-        cursor.write_u32(dst.to_bits())?;
-
-        // Save off the indexes of the fields which we'll edit later
-        let to_fill = ToFill { len, prot, crc };
+        // Write the header. The length, protocol and checksum are filled in
+        // once they are known
+        cursor.write(&ipv4::header(src, dst))?;
 
         // Split off the header
+        let offload = cursor.tx_checksum_offload();
         let (hdr, cursor) = cursor.split_at_current();
         let cursor = Some(cursor);
 
-        Some(Self { hdr, to_fill, cursor, src, dst })
+        Some(Self { hdr, cursor, src, dst, offload })
     }
 
     /// Gets the source IP address this builder was called with
@@ -169,31 +273,20 @@ impl<'a> BuilderV4<'a> {
         self.dst
     }
 
-
-    /// Sets the size of this IP header + `len` as the total packet size
-    fn write_len(&mut self, len: u16) {
-        // Calculate the total size
-        let size = ((self.hdr.len() as u16).checked_add(len))
-            .expect("totale packet size len overflow");
-
-        // Write it down
-        let idx = self.to_fill.len;
-        self.hdr[idx..idx + 2].copy_from_slice(&size.to_be_bytes());
+    /// Set the transport protocol of the payload
+    pub fn set_protocol(&mut self, prot: TransportProtocol) {
+        ipv4::set_protocol(self.hdr, prot as u8);
     }
 
-    /// Calculates and sets the CRC field of this IP header
-    fn write_crc(&mut self) {
-        // Calculate the checksum
-        let checksum = !Packet::checksum(&self.hdr);
-
-        // Write it down
-        let idx = self.to_fill.crc;
-        self.hdr[idx..idx + 2].copy_from_slice(&checksum.to_ne_bytes());
+    /// Set the time to live of the packet. Defaults to 64
+    pub fn set_ttl(&mut self, ttl: u8) {
+        ipv4::set_ttl(self.hdr, ttl);
     }
 
-    /// Set the transport protocol of the payload
-    pub fn set_protocol(&mut self, prot: TransportProtocol) {
-        self.hdr[self.to_fill.prot] = prot as u8;
+    /// Set the 6-bit differentiated services code point of the packet, leaving
+    /// the ECN bits clear. Defaults to 0
+    pub fn set_dscp(&mut self, dscp: u8) {
+        ipv4::set_dscp(self.hdr, dscp);
     }
 
     /// Take out the cursor out of the builder
@@ -203,9 +296,10 @@ impl<'a> BuilderV4<'a> {
 
     /// Finalize the IP header, writing in the `payload_len` and calculating the
     /// crc (if applicable). This `payload_len` does not include the IP header
-    /// size, only the tranport layer size
+    /// size, only the tranport layer size.
+    ///
+    /// If the NIC inserts the checksum, the field is left zeroed for it
     pub fn finalize(&mut self, payload_len: u16) {
-        self.write_len(payload_len);
-        self.write_crc();
+        ipv4::finalize(self.hdr, payload_len, !self.offload);
     }
 }
@@ -2,13 +2,10 @@
 
 use core::net::Ipv6Addr;
 
-use crate::net::protocols::eth;
+use crate::net::protocols::eth::{self, EthType};
 use crate::net::packet::{Packet, ParseError, PacketCursor};
 use crate::net::protocols::ip::TransportProtocol;
 
-/// Ethernet type for IPv6
-const ETH_TYPE_IPV6: u16 = 0x86DD;
-
 /// A parsed IPv6 header and payload
 #[derive(Debug)]
 pub struct ParsedV6<'a> {
@@ -34,7 +31,7 @@ impl Packet {
         let eth = self.parse_eth()?;
 
         // Handle the ethernet type
-        if eth.eth_type != ETH_TYPE_IPV6 {
+        if eth.ethertype() != Some(EthType::Ipv6) {
             return Err(ParseError::UnsupportedVersion);
         }
 
@@ -74,7 +71,7 @@ impl<'a> eth::Builder<'a> {
     pub fn ipv6(mut self, src: &'a Ipv6Addr, dst: &'a Ipv6Addr)
         -> Option<BuilderV6<'a>> {
         // Write in the type
-        self.cursor.write_u16(ETH_TYPE_IPV6);
+        self.cursor.write_u16(EthType::Ipv6 as u16);
 
         // Split the cursor and save the ethernet header
         let (_, cursor) = self.cursor.split_at_current();
@@ -153,6 +150,20 @@ impl<'a> BuilderV6<'a> {
         self.hdr[self.to_fill.prot] = prot as u8;
     }
 
+    /// Set the hop limit of the packet. Defaults to 64
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.hdr[7] = ttl;
+    }
+
+    /// Set the 6-bit differentiated services code point of the packet, leaving
+    /// the ECN bits clear. Defaults to 0
+    pub fn set_dscp(&mut self, dscp: u8) {
+        // The traffic class is split between the version and the flow label
+        let class = (dscp & 0x3F) << 2;
+        self.hdr[0] = (6 << 4) | (class >> 4);
+        self.hdr[1] = (class << 4) | (self.hdr[1] & 0xF);
+    }
+
     /// Take out the cursor out of the builder
     pub fn take_cursor(&mut self) -> Option<PacketCursor<'a>> {
         self.cursor.take()
@@ -2,7 +2,7 @@
 //! interface
 
 pub mod dhcp;
-//pub mod tcp;
+pub mod tcp;
 pub mod udp;
 pub mod ip;
 pub mod eth;
@@ -1,17 +1,17 @@
 //! L3: TCP implementation
 
-use alloc::collections::VecDeque;
 use alloc::sync::Arc;
-use core::net::Ipv4Addr;
+use core::net::{IpAddr, Ipv4Addr};
 
 use spinlock::SpinLock;
+use net_proto::tcp::Receiver;
 
-use crate::net::packet::{Packet, PacketCursor, ParseError};
+use crate::net::packet::{Packet, PacketCursor, PacketLease, ParseError};
 use crate::net::protocols::{ip, eth};
 use crate::net::{NetDevice, NetAddress, Port};
 use crate::core_locals::InterruptLock;
 
-/// Maximum number of bytes ot use for TCP windows
+/// Default number of bytes to use for TCP receive windows
 const WINDOW_SIZE: usize = u16::MAX as usize;
 
 /// Time in microseconds to wait before timing out on ACK responses
@@ -26,6 +26,12 @@ const IP_PROT_TCP: u8 = 0x6;
 /// Maximum MSS the TCP stack will use
 const MAX_MSS: usize = 1420;
 
+/// MSS assumed for the remote end until it advertises its own
+const DEFAULT_MSS: u16 = 536;
+
+/// Kind of the MSS TCP option
+const OPT_MSS: u8 = 2;
+
 // TODO: enum these
 
 /// TCP synchronize flag (indicates a request to sync sequence numbers)
@@ -34,6 +40,9 @@ const TCP_SYN: u8 = 1 << 1;
 /// TCP reset flag (resets a TCP connection)
 const TCP_RST: u8 = 1 << 2;
 
+/// TCP push flag (asks the receiver to hand the data to the application)
+const TCP_PSH: u8 = 1 << 3;
+
 /// TCP acknowledge
 const TCP_ACK: u8 = 1 << 4;
 
@@ -61,6 +70,9 @@ pub struct Parsed<'a> {
     /// TCP flags
     pub flags: u8,
 
+    /// Raw TCP options
+    pub options: &'a [u8],
+
     /// Raw byte payload
     pub payload: &'a [u8],
 }
@@ -94,20 +106,18 @@ impl Connection {
         let mut timeout = !0;
 
         // Pointer to the data that we have yet to send
-        let mut to_send = &buf[..];
+        let mut to_send = buf;
+
+        // The device the ACKs are received on
+        let dev = self.0.with(|con| con.dev.clone());
 
-        // Parse responses until we recieve a packet not destined to us
         loop {
-            {
-                // Get access to the connection
-                let mut con = self.0.lock();
-                if con.state != TcpState::Established { return None; }
+            let done = self.0.with(|con| {
+                if con.state != TcpState::Established { return Some(false); }
 
                 // If we didn't get an ACK before timing out, we either have no
-                // window left, or we have sent everything and we're waiting for
-                // the final ack
-                //
-                // Calculate how much was sent but unacknowledged
+                // window left, or we have sent everything and we're waiting
+                // for the final ack. Resend everything that's unacknowledged
                 if cpu::rdtsc() >= timeout {
                     // Compute how much we have sent so far
                     let sent = buf.len() - to_send.len();
@@ -115,43 +125,30 @@ impl Connection {
                     // Compute the number of unacked bytes
                     let unacked = con.seq.wrapping_sub(con.remote_ack) as usize;
 
-                    // Rewind the pointer
+                    // Rewind the pointer and the seq
                     to_send = &buf[sent - unacked..];
-
-                    // Reset the seq
                     con.seq = con.remote_ack;
                 }
 
-                // If everything is sent and acked, return
-                if to_send.len == 0 && con.remote_ack = con.seq {
-                    return Some(());
-                }
-
-                // Check if we got an ACK and handle it
-                if let Some(pkt) = dev.as_ref().unwrap().recv() {
-                    if let Some(tcp) = pkt.parse_tcp() {
-                        if tcp.dst_port != con.port {
-                            // Packet not for us
-                            core::mem::drop(con);
-                            dev.as_ref().unwrap().discard(pkt);
-                            continue;
-                        }
-
-                        // Handle the packet that is confirmed destined to us
-                        con.handle_packet(&tcp);
-                    } else {
-                        // Not TCP; discard it
-                        core::mem::drop(con);
-                        dev.as_ref().unwrap().discard(pkt);
-                    }
-                }
+                // If everything is sent and acked, we're done
+                (to_send.is_empty() && con.remote_ack == con.seq)
+                    .then_some(true)
+            });
+            match done {
+                Some(true)  => return Some(()),
+                Some(false) => return None,
+                None        => {},
             }
 
+            // Dispatch the next packet. ACKs for this connection are handled
+            // by `discard_tcp()`, which needs the connection to be unlocked
+            dev.poll();
+
             // Get mut access to the connection
             let mut con = self.0.lock();
 
             // Cap the MSS
-            let mss = core:;cmp::min(con.remote_mss as usize, MAX_MSS);
+            let mss = core::cmp::min(con.remote_mss as usize, MAX_MSS);
 
             // Compute the number of unacknowledged bytes
             let unacked = con.seq.wrapping_sub(con.remote_ack) as usize;
@@ -163,56 +160,72 @@ impl Connection {
                 (con.remote_window as usize).saturating_sub(unacked));
 
             // Everything sent; wait for the final ack
-            if remain == 0 { continue; }
+            if remaining == 0 { continue; }
 
             // Send the MSS-sized chunks of the buffer
             let mut iter = to_send[..remaining].chunks(mss);
             while let Some(chunk) = iter.next() {
+                let last = iter.len() == 0;
+
                 // Create the packet
-                let mut packet = dev.as_ref().unwrap().allocate_packet();
+                let mut packet = dev.allocate_packet();
                 {
-                    let mut cursor = packet.create_tcp(
+                    let mut tcp = packet.create_tcp(
                         &con.server,
-                        TCP_ACK | if iter.len() == 0 { TCP_PSH } else { 0 },
+                        TCP_ACK | if last { TCP_PSH } else { 0 },
                         con.seq,
-                        con.ack,
-                        (WINDOW_SIZE - con.window.len()) as u16);
-                    cursor.write(chunk);
+                        con.rx.ack(),
+                        con.rx.free_window());
+                    tcp.write(chunk)?;
                 }
 
                 // Update our seq and send the packet
                 con.seq = con.seq.wrapping_add(chunk.len() as u32);
-                dev.as_ref().unwrap().send(packet, iter.len() == 0);
+                dev.send(packet, last);
             }
 
             // Advance the pointer reflecting what we sent
-            to_send = &to_send[remain..];
+            to_send = &to_send[remaining..];
 
             // Set a timeout for a window update
-            timout = crate::time::future(1_000);
+            timeout = crate::time::future(1_000);
         }
     }
 
-    /// Receives data from the TCP connection
-    pub fn recv(&self, buf: &mut[u8]) -> Option<usize> {
-        // Get a pointer to the buffer
-        let mut to_recv = &mut buf[..];
-
-        // Get mutable access to the TCP connection
-        let mut con = self.0.lock();
-
-        // If the connection isn't established, nothing to do
-        if con.state != TcpState::Established { return None; }
+    /// Set the size of the receive window advertised to the remote end.
+    ///
+    /// Without window scaling, at most `u16::MAX` bytes are advertised. Bytes
+    /// already in a window that's being shrunk are kept.
+    pub fn set_window(&self, size: usize) {
+        self.0.lock().rx.set_window_size(size);
+    }
 
-        // TODO
+    /// Receives data from the TCP connection into `buf`, returning the number
+    /// of bytes received
+    ///
+    /// Returns `None` if the connection isn't established
+    pub fn recv(&self, buf: &mut [u8]) -> Option<usize> {
+        // Dispatch a packet from the network if there's nothing to receive
+        let (dev, empty) = self.0.with(|con| {
+            (con.dev.clone(), con.rx.is_empty())
+        });
+        if empty { dev.poll(); }
+
+        self.0.with(|con| {
+            // If the connection isn't established, nothing to do
+            if con.state != TcpState::Established { return None; }
+
+            // Move as much of the window as fits into the buffer
+            Some(con.rx.read(buf))
+        })
     }
 }
 
 /// The actual internal state of a TCP connection
 #[allow(dead_code)]
 pub struct Internal {
-    /// TCP receive window
-    window: VecDeque<u8>,
+    /// The receive window and the segments waiting to be put into it
+    rx: Receiver,
 
     /// The network device this connection is bound on
     dev: Arc<NetDevice>,
@@ -228,6 +241,15 @@ pub struct Internal {
 
     /// The connection sequence identifier
     seq: u32,
+
+    /// The last sequence number acknowledged by the remote end
+    remote_ack: u32,
+
+    /// Size of the receive window advertised by the remote end
+    remote_window: u16,
+
+    /// MSS advertised by the remote end
+    remote_mss: u16,
 }
 
 impl Internal {
@@ -258,59 +280,67 @@ impl Internal {
         // Make sure the remote end is not acknowledging bytes we never sent
         if tcp.ack.wrapping_sub(self.remote_ack) > unacked { return None; }
 
-        // TODO: handle out of order packets
-        // For now, we'll just drop them
-        if self.state == TcpState::Established && tcp.seq != seq.ack {
-            return None;
-        }
+        // Update the server state to the most recent packet information
+        self.remote_ack = tcp.ack;
+        self.remote_window = tcp.window;
 
         // Track whether we need to send an ACK
         let mut should_ack = false;
 
-        // Check if the packet contains any data and if it does, copy it to our
-        // window
-        if self.state == TcpState::Established && tcp.payload.len() > 0 {
-            // Drop packets that exceed our window; the remote side should never
-            // send more than that.
-            if tcp.payload.len() > WINDOW_SIZE - self.window.len() {
-                return None;
-            }
-
-            // Save the data into oru window
-            self.window.extend(tcp.payload);
-
-            // Update the ack to indicate we read the bytes
-            self.ack = self.ack.wrapping_add(tcp.payload.len() as u32);
-            should_ack = true;
+        // Put any data the packet contains into our window. Data which isn't
+        // the next expected byte is acknowledged as well
+        if self.state == TcpState::Established {
+            should_ack = self.rx.receive(tcp.seq, tcp.payload).needs_ack();
         }
 
         // If we're waiting for a SYN-ACK, check if this is it
-        if (self.state == Tcp::SynSent || self.state == TcpState::Established)
+        if (self.state == TcpState::Syn || self.state == TcpState::Established)
                 && tcp.flags & TCP_SYN != 0 {
             // If we just acked a SYN, update the state
             self.state = TcpState::Established;
-            self.ack = tcp.seq.wrapping_add(1);
+            self.rx.set_ack(tcp.seq.wrapping_add(1));
+            self.remote_mss = parse_mss(tcp.options).unwrap_or(DEFAULT_MSS);
             should_ack = true;
         }
 
         // Send an ACK if needed
         if should_ack {
             let mut packet = self.dev.allocate_packet();
-            {
-                packet.create_tcp(
-                    &self.server, TCP_ACK, self.seq, self.ack,
-                    (WINDOW_SIZE as usize - self.window.len()) as u16);
-            }
+            packet.create_tcp(
+                &self.server, TCP_ACK, self.seq, self.rx.ack(),
+                self.rx.free_window());
             self.dev.send(packet, true);
         }
 
-        // Update the server state to the most recent packet information
-        self.remote_ack = tcp.ack;
-        self.remote_window = tcp.window;
         Some(())
     }
 }
 
+/// Get the MSS out of the raw TCP `options`, if it's advertised in them
+fn parse_mss(options: &[u8]) -> Option<u16> {
+    let mut opts = options;
+    while let Some(&kind) = opts.first() {
+        match kind {
+            // End of the option list
+            0 => break,
+
+            // No-op padding
+            1 => opts = &opts[1..],
+
+            // Every other option has its length after the kind
+            _ => {
+                let len = *opts.get(1)? as usize;
+                let opt = opts.get(..len).filter(|_| len >= 2)?;
+                if kind == OPT_MSS {
+                    return Packet::parse_u16(opt.get(2..4)).ok();
+                }
+                opts = &opts[len..];
+            },
+        }
+    }
+    None
+}
+
 impl NetDevice {
     /// Discard a TCP packet and attempt to handle it somewhere else in the
     /// network stac.
@@ -319,7 +349,7 @@ impl NetDevice {
     pub fn discard_tcp(&self, packet: &mut Option<PacketLease>) {
         let pk = match packet.take() {
             None => return,
-            Some(pk) = pk,
+            Some(pk) => pk,
         };
 
         // Parse the packet as TCP
@@ -327,31 +357,46 @@ impl NetDevice {
             Ok(tcp) => tcp,
             _ => {
                 // Couldn't parse as TCP. put the packet back and return
-                *packet = Some(pkt);
+                *packet = Some(pk);
                 return;
             }
         };
 
-        // Get access to TCP connections
-        let mut cons = self.tcp_connections.lock();
+        // Get the connection for this port, releasing the connections lock
+        // before the connection itself is locked
+        let con = self.tcp_connections.with(|cons| {
+            cons.get(&tcp.dst_port).cloned()
+        });
 
-        // If we have a connection for this port, attempt to handle the packet
-        if let Some(con) = cons.get_mut(&tcp.dst_port) {
-            let con = con.clone();
-            core::mem::drop(cons);
-            con.lock().handle_packet(&tcp);
-            return;
+        // If we have a connection for this port, attempt to handle the packet.
+        // Otherwise it's dropped
+        if let Some(con) = con {
+            con.0.with(|con| con.handle_packet(&tcp));
         }
     }
 
     /// Create a connection to the remote server specified by the IP and port
     pub fn tcp_connect(dev: Arc<NetDevice>, dst_ip: Ipv4Addr, dst_port: Port)
             -> Option<Arc<Connection>> {
+        Self::tcp_connect_window(dev, dst_ip, dst_port, WINDOW_SIZE)
+    }
+
+    /// Create a connection to the remote server specified by the IP and port
+    /// with a receive window of `window_size` bytes
+    pub fn tcp_connect_window(
+        dev: Arc<NetDevice>,
+        dst_ip: Ipv4Addr,
+        dst_port: Port,
+        window_size: usize,
+    ) -> Option<Arc<Connection>> {
+        // Don't wait for replies that can't come
+        if !dev.link_up() { return None; }
+
         // Bind/rebind a TCP connection on the first free port
         'rebind: for _ in 0..N_RETRIES {
             // Acquire a possibly unbound port and resolve the server address
             let port = Port::next_free();
-            let server = NetAddress::resolve(&dev, port, dst_port, dst_ip)?;
+            let server = NetAddress::resolve(&dev, port, dst_port, dst_ip).ok()?;
 
             // Attempt to create and register a connection with this port
             let con = {
@@ -362,12 +407,15 @@ impl NetDevice {
                 // Port not reserved yet. Create a TCP connection
                 let seq = cpu::rdtsc() as u32;
                 let con = SpinLock::new(Internal {
-                    window: VecDeque::with_capacity(WINDOW_SIZE),
+                    rx:     Receiver::new(window_size),
                     dev:    dev.clone(),
                     state:  TcpState::Closed,
                     server,
                     port,
                     seq,
+                    remote_ack: seq,
+                    remote_window: 0,
+                    remote_mss: DEFAULT_MSS,
                 });
                 let con = Arc::new(Connection(con));
 
@@ -378,7 +426,8 @@ impl NetDevice {
 
             // Send a SYN packet
             {
-                let opts = [2, 4, (MAX_MSS >> 8) as u8, (MAX_MSS & 0xFF) as u8];
+                let mss = (MAX_MSS as u16).to_be_bytes();
+                let opts = [OPT_MSS, 4, mss[0], mss[1]];
                 let mut con = con.0.lock();
                 let mut packet = dev.allocate_packet();
                 {
@@ -387,7 +436,7 @@ impl NetDevice {
                         TCP_SYN,
                         con.seq,
                         0,
-                        (WINDOW_SIZE - con.window.len()) as u16,
+                        con.rx.free_window(),
                         &opts);
                 }
                 // Send the packet, update the seq number and the TCP state
@@ -410,40 +459,22 @@ impl NetDevice {
                     }
                 }
 
-                // Process incoming packets
-                if let Some(pkt) = dev.recv() {
-                    // Parse the packet as TCP
-                    let tcp = match pkt.parse_tcp() {
-                        Ok(tcp) => tcp,
-                        Err(_) => {
-                            dev.discard(pkt);
-                            continue;
-                        }
-                    };
-
-                    // If we have a TCP packet for a differnet port, discard it
-                    if tcp.dst_port != port {
-                        dev.discard(pkt);
-                        continue;
-                    }
-
-                    // Packet for us; handle it
-                    let mut con = con.0.lock();
-                    con.handle_packet(&tcp);
-                }
+                // Dispatch incoming packets. The SYN-ACK is handled by
+                // `discard_tcp()`
+                dev.poll();
             }
 
             return Some(con);
         }
 
         // Could not get a connection
-        // Resolve the
         None
     }
 }
 
 impl<'a> ip::Builder<'a> {
     /// Creates a new TCP builder out of this IP builder
+    #[allow(clippy::too_many_arguments)]
     pub fn tcp(
         mut self,
         src: &'a Port,
@@ -476,10 +507,12 @@ pub struct Builder<'a> {
     pub(super) hdr:     &'a mut [u8],
     pub(super) payload: PacketCursor<'a>,
     to_fill: ToFill,
+    offload: bool,
 }
 
 impl<'a> Builder<'a> {
     /// Creates a new TCP builder
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         mut ip: ip::Builder<'a>,
         mut cursor: PacketCursor<'a>,
@@ -503,10 +536,11 @@ impl<'a> Builder<'a> {
         cursor.write_u8(flags)?;
         cursor.write_u16(window)?;
         let (crc, _) = cursor.write_u16(0)?;
-        cursor.write_u8(0)?; // Urgent pointer
+        cursor.write_u16(0)?; // Urgent pointer
         cursor.write(opts)?;
 
         // Split the header and the payload
+        let offload = cursor.tx_checksum_offload();
         let (hdr, payload) = cursor.split_at_current();
 
         // Set the data offset
@@ -517,7 +551,7 @@ impl<'a> Builder<'a> {
         // Save off the indexes of fields which we'll edit later
         let to_fill = ToFill { crc };
 
-        Some(Self { ip, hdr, payload, to_fill })
+        Some(Self { ip, hdr, payload, to_fill, offload })
     }
 
     /// Create a new TCP builder from the provided packet
@@ -535,62 +569,57 @@ impl<'a> Builder<'a> {
             .tcp(&addr.src_port, &addr.dst_port, flags, seq, ack, window, opts)
     }
 
+    /// Writes to the TCP payload if possible, as defined by the
+    /// `Cursor::write()` spec
+    pub fn write(&mut self, buf: &[u8]) -> Option<(usize, usize)> {
+        self.payload.write(buf)
+    }
+
+    /// Set the time to live (the hop limit in IPv6) of the IP header. Defaults
+    /// to 64
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ip.set_ttl(ttl);
+    }
+
+    /// Set the differentiated services code point of the IP header. Defaults
+    /// to 0
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.ip.set_dscp(dscp);
+    }
+
     /// Calculates and writes the CRC
+    ///
+    /// If the NIC inserts the checksum, only the pseudo-header sum is written,
+    /// which the NIC adds the header and the payload to
     fn write_crc(&mut self) {
-        // Determine IP version and gather information for pseudo-header
-        let (src_ip, dst_ip, len, is_ipv4) = match &self.ip {
-            ip::Builder::V4(ipv4) => {
-                let len = (self.hdr.len() + self.payload.get().len()) as u32;
-                (
-                    &ipv4.src().octets()[..],
-                    &ipv4.dst().octets()[..],
-                    len,
-                    true,
-                )
-            }
-            ip::Builder::V6(ipv6) => {
-                let len = (self.hdr.len() + self.payload.get().len()) as u32;
-                (
-                    &ipv6.src().octets()[..],
-                    &ipv6.dst().octets()[..],
-                    len,
-                    false,
-                )
-            }
+        // Get the IP addresses for the pseudo-header
+        let (src_ip, dst_ip) = match &self.ip {
+            ip::Builder::V4(ipv4) =>
+                (IpAddr::V4(*ipv4.src()), IpAddr::V4(*ipv4.dst())),
+            ip::Builder::V6(ipv6) =>
+                (IpAddr::V6(*ipv6.src()), IpAddr::V6(*ipv6.dst())),
         };
+        let len = (self.hdr.len() + self.payload.get().len()) as u32;
 
-        // Build the appropriate pseudo-header based on IP version
-        let mut ph = [0u8; 40];
-        let pseudo_header = if is_ipv4 {
-            // IPv4 pseudo-header (12 bytes)
-            ph[0..4].copy_from_slice(&src_ip);
-            ph[4..8].copy_from_slice(&dst_ip);
-            ph[9] = IP_PROT_TCP;
-            ph[10..12].copy_from_slice(&(len as u16).to_be_bytes());
-            &ph[0..12]
-        } else {
-            // IPv6 pseudo-header (40 bytes)
-            ph[0..16].copy_from_slice(&src_ip);
-            ph[16..32].copy_from_slice(&dst_ip);
-            ph[32..36].copy_from_slice(&len.to_be_bytes());
-            ph[39] = IP_PROT_TCP;
-            &ph[0..40]
-        };
+        // Sum up the pseudo-header
+        let pseudo_header = net_proto::pseudo_header_checksum(
+            &src_ip, &dst_ip, IP_PROT_TCP, len);
 
-        // Calculate checksum accumulator
-        let mut acc: u32 = 0;
-        acc = acc.wrapping_add(Packet::checksum(pseudo_header) as u32);
-        acc = acc.wrapping_add(Packet::checksum(&self.hdr) as u32);
-        acc = acc.wrapping_add(Packet::checksum(self.payload.get()) as u32);
+        // Leave the rest of the sum to the NIC if it inserts the checksum
+        let idx = self.to_fill.crc;
+        if self.offload {
+            let seed = pseudo_header.to_be_bytes();
+            self.hdr[idx..idx + 2].copy_from_slice(&seed);
+            return;
+        }
 
-        // Fold and complement to get final checksum
-        let mut final_sum = (acc & 0xFFFF) + (acc >> 16);
-        final_sum = (final_sum & 0xFFFF) + (final_sum >> 16);
-        let checksum = !(final_sum as u16);
-        let checksum = if checksum == 0 { 0xFFFF } else { checksum };
+        // Add up the header and the payload
+        let mut acc = pseudo_header as u32;
+        acc = acc.wrapping_add(net_proto::checksum(self.hdr) as u32);
+        acc = acc.wrapping_add(net_proto::checksum(self.payload.get()) as u32);
+        let checksum = net_proto::finalize_checksum(acc);
 
         // Write checksum to TCP header
-        let idx = self.to_fill.crc;
         self.hdr[idx..idx + 2].copy_from_slice(&checksum.to_be_bytes());
     }
 
@@ -648,6 +677,7 @@ impl Packet {
             ack: Packet::parse_u32(header.get(8..12))?,
             window: Packet::parse_u16(header.get(14..16))?,
             flags: header.get(13).copied().ok_or(ParseError::TruncatedPacket)?,
+            options: &header[min_hdr..],
             payload,
             ip,
         })
@@ -664,7 +694,7 @@ impl Packet {
         ack: u32,
         window: u16,
     ) -> Builder<'b> {
-        self.create_tcp(addr, flags, seq, ack, window, &[])
+        self.create_tcp_options(addr, flags, seq, ack, window, &[])
     }
 
     /// Create a TCP builder out of this packet, setting `options` as TCP opts
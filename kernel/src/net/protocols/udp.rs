@@ -2,6 +2,7 @@
 
 use alloc::sync::Arc;
 use alloc::collections::VecDeque;
+use core::net::IpAddr;
 
 use crate::net::packet::{Packet, PacketCursor, PacketLease, ParseError};
 use crate::net::{NetDevice, Port, NetAddress};
@@ -10,6 +11,10 @@ use crate::net::protocols::{ip, eth};
 /// UDP protocol for the IP header
 const IP_PROT_UDP: u8 = 0x11;
 
+/// Maximum number of packets queued on a bound port before new packets
+/// destined to it are dropped
+const QUEUE_LEN: usize = 64;
+
 /// A parsed UDP header and payload
 #[derive(Debug)]
 pub struct Parsed<'a> {
@@ -51,7 +56,13 @@ impl UdpBind {
     where
         F: FnMut(&Packet, Parsed) -> Option<T>,
     {
-        self.device().recv_udp(self.port, &mut func)
+        // Return a queued packet if there is one. Otherwise dispatch a packet
+        // from the network and check the queue again
+        let dev = self.device();
+        dev.recv_udp(self.port, &mut func).or_else(|| {
+            dev.poll();
+            dev.recv_udp(self.port, &mut func)
+        })
     }
 
     /// Attempts to receive a UDP packet on the bound port for `timeout` μs
@@ -59,15 +70,9 @@ impl UdpBind {
     where
         F: FnMut(&Packet, Parsed) -> Option<T>,
     {
-        let timeout = crate::time::future(timeout);
-        loop {
-            // Return nothing on timeout
-            if cpu::rdtsc() >= timeout { return None; }
-
-            if let Some(val) = self.device().recv_udp(self.port, &mut func) {
-                return Some(val)
-            }
-        }
+        let dev = self.device();
+        dev.poll_until(crate::time::future(timeout),
+            || dev.recv_udp(self.port, &mut func))
     }
 }
 
@@ -91,17 +96,17 @@ impl NetDevice {
 
     /// Create a UDP bind to `port`
     pub fn bind_udp_port(dev: Arc<Self>, port: Port) -> Option<UdpBind> {
-        // Acquire unique access to the binds
-        let mut udp_binds = dev.udp_binds.lock();
-
-        // If this port is already bound, bail out
-        if udp_binds.contains_key(&port) {
-            return None;
-        }
-
-        udp_binds.insert(port, VecDeque::new());
+        // Reserve the port, releasing the binds before the bind is created
+        let bound = dev.udp_binds.with(|udp_binds| {
+            // If this port is already bound, bail out
+            if udp_binds.contains_key(&port) {
+                return false;
+            }
 
-        core::mem::drop(udp_binds);
+            udp_binds.insert(port, VecDeque::with_capacity(QUEUE_LEN));
+            true
+        });
+        if !bound { return None; }
 
         Some(UdpBind { dev, port, })
     }
@@ -115,40 +120,22 @@ impl NetDevice {
     }
 
 
-    /// Receive a UDP packet destined to `port`
+    /// Receive a UDP packet destined to `port` from the queue of the bind.
+    ///
+    /// This doesn't touch the network; packets are put into the queue when
+    /// they are dispatched by `NetDevice::poll()`
     fn recv_udp<T, F>(&self, port: Port, func: &mut F) -> Option<T>
     where
         F: FnMut(&Packet, Parsed) -> Option<T>
     {
-        // If there's a packet in the queue, return it
-        {
-            let mut binds = self.udp_binds.lock();
-
-            let ent = binds.get_mut(&port)?;
-            if !ent.is_empty() {
-                let packet = ent.pop_front().unwrap();
-                let ret = func(&packet, packet.parse_udp().unwrap());
-                self.driver().release_packet(packet);
-                return ret
-            }
-        }
-
-        // No packet in the queue. Attempt to recv a new raw packet
-        let packet = self.recv()?;
-
-        // Attempt to parse the packet as UDP
-        if let Ok(udp) = packet.parse_udp() {
-            // If it was destined to our port, return it
-            if udp.dst_port == port {
-                func(&*packet, udp)
-            } else {
-                self.discard(packet);
-                None
-            }
-        } else {
-            self.discard(packet);
-            None
-        }
+        // Take the packet out of the queue, so the binds aren't locked while
+        // `func` runs
+        let packet = self.udp_binds.lock().get_mut(&port)?.pop_front()?;
+
+        // Queued packets have already been parsed successfully
+        let ret = func(&packet, packet.parse_udp().unwrap());
+        self.driver().release_packet(packet);
+        ret
     }
 
     /// Discard a UDP packet and attempt to handle it somewhere else in the
@@ -162,8 +149,8 @@ impl NetDevice {
         };
 
         // Parse the packet as UDP
-        let udp = match pk.parse_udp() {
-            Ok(udp) => udp,
+        let port = match pk.parse_udp() {
+            Ok(udp) => udp.dst_port,
             _ => {
                 // Couldn't parse it as UDP. Put the packet back and return
                 *packet = Some(pk);
@@ -171,18 +158,18 @@ impl NetDevice {
             },
         };
 
-        // Check if we are bound on this packet's port
+        // Check if we are bound on this packet's port. If we're not, the
+        // packet is dropped
         let mut binds = self.udp_binds.lock();
-        let bind = match binds.get_mut(&udp.dst_port) {
+        let bind = match binds.get_mut(&port) {
             Some(b) => b,
             None    => return,
         };
 
         // We are bound on the packet's port, put it into queue if there's
         // space, otherwise drop it
-        if bind.len() < bind.capacity() {
-            let packet = packet.take().unwrap();
-            bind.push_back(PacketLease::take(packet));
+        if bind.len() < QUEUE_LEN {
+            bind.push_back(PacketLease::take(pk));
         } else {
             // Drop
         }
@@ -256,6 +243,7 @@ pub struct Builder<'a> {
     pub(super) hdr:     &'a mut [u8],
     pub(super) payload: PacketCursor<'a>,
     to_fill: ToFill,
+    offload: bool,
 }
 
 impl<'a> Builder<'a> {
@@ -278,12 +266,13 @@ impl<'a> Builder<'a> {
         let (crc, _) = cursor.write_u16(0)?;
 
         // Split the header and the payload
+        let offload = cursor.tx_checksum_offload();
         let (hdr, payload) = cursor.split_at_current();
 
         // Write down the fields that will have to be filled in later
         let to_fill = ToFill { len, crc };
 
-        Some(Self { ip, hdr, payload, to_fill })
+        Some(Self { ip, hdr, payload, to_fill, offload })
     }
 
     /// Creates a new UDP builder from this `cursor`
@@ -296,10 +285,25 @@ impl<'a> Builder<'a> {
 
     /// Writes to the UDP payload if possible, as defined by the
     /// `Cursor::write()` spec
+    ///
+    /// The payload may exceed the MTU of the device, in which case the packet
+    /// is fragmented when it's sent with `NetDevice::send()`
     pub fn write(&mut self, buf: &[u8]) -> Option<(usize, usize)> {
         self.payload.write(buf)
     }
 
+    /// Set the time to live (the hop limit in IPv6) of the IP header. Defaults
+    /// to 64
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ip.set_ttl(ttl);
+    }
+
+    /// Set the differentiated services code point of the IP header. Defaults
+    /// to 0
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.ip.set_dscp(dscp);
+    }
+
     /// Writes down the size of the header and the payload into the header, and
     /// returns the size
     fn write_len(&mut self) -> u16 {
@@ -315,6 +319,10 @@ impl<'a> Builder<'a> {
 
     /// Calculates and writes the CRC if the IP layer uses IPv6, otherwise keeps
     /// the CRC as 0, because IPv4 doesn't require it.
+    ///
+    /// If the NIC inserts the checksum, only the pseudo-header sum is written,
+    /// which the NIC adds the header and the payload to. IPv6 packets are never
+    /// fragmented, so the checksum never has to span multiple frames
     fn write_crc(&mut self) {
         // IPv4 doesn't require a checksum
         let ip = match &self.ip {
@@ -325,27 +333,26 @@ impl<'a> Builder<'a> {
         // UDP length (header + payload)
         let udp_len = (self.hdr.len() + self.payload.get().len()) as u32;
 
-        // Pseudo-header: source IP, dest IP, length, next header
-        let mut pseudo_header = [0u8; 40];
-        pseudo_header[00..16].copy_from_slice(&ip.src().octets());
-        pseudo_header[16..32].copy_from_slice(&ip.dst().octets());
-        pseudo_header[32..36].copy_from_slice(&udp_len.to_be_bytes());
-        pseudo_header[39] = IP_PROT_UDP;
-
-        // Start with an empty checksum accumulator
-        let mut acc: u32 = 0;
-        acc = acc.wrapping_add(Packet::checksum(&pseudo_header) as u32);
-        acc = acc.wrapping_add(Packet::checksum(self.hdr) as u32);
-        acc = acc.wrapping_add(Packet::checksum(self.payload.get()) as u32);
-
-        // Final fold and complement
-        let mut final_sum = (acc & 0xFFFF) + (acc >> 16);
-        final_sum = (final_sum & 0xFFFF) + (final_sum >> 16);
-        let checksum = !(final_sum as u16);
-        let checksum = if checksum == 0 { 0xFFFF } else { checksum };
+        // Sum up the pseudo-header
+        let pseudo_header = net_proto::pseudo_header_checksum(
+            &IpAddr::V6(*ip.src()), &IpAddr::V6(*ip.dst()),
+            IP_PROT_UDP, udp_len);
 
-        // Write the checksum into the header
+        // Leave the rest of the sum to the NIC if it inserts the checksum
         let idx = self.to_fill.crc;
+        if self.offload {
+            let seed = pseudo_header.to_be_bytes();
+            self.hdr[idx..idx + 2].copy_from_slice(&seed);
+            return;
+        }
+
+        // Add up the header and the payload
+        let mut acc = pseudo_header as u32;
+        acc = acc.wrapping_add(net_proto::checksum(self.hdr) as u32);
+        acc = acc.wrapping_add(net_proto::checksum(self.payload.get()) as u32);
+        let checksum = net_proto::finalize_checksum(acc);
+
+        // Write the checksum into the header
         self.hdr[idx..idx + 2].copy_from_slice(&checksum.to_be_bytes());
     }
 
@@ -3,7 +3,8 @@
 use core::sync::atomic::{AtomicPtr, AtomicBool, Ordering};
 use core::panic::PanicInfo;
 
-use crate::apic::{ApicState, core_state, MAX_APIC_ID, LocalApic};
+use crate::apic::{
+    ApicState, core_state, total_cores, MAX_APIC_ID, LocalApic};
 
 /// Tracks whether we're currently in the process of a panic on the BSP
 static BSP_IN_PANIC: AtomicBool = AtomicBool::new(false);
@@ -20,10 +21,6 @@ pub fn bsp_in_panic() -> bool {
     BSP_IN_PANIC.load(Ordering::SeqCst)
 }
 
-/// The NMI ICR
-const NMI: u32 = (1 << 14) | (4 << 8);
-// TODO: encode the ICRs somehow. I don't like this^ nor 0x4500 for INIT
-
 /// This is the panic routine used by rust within our kernel
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
@@ -49,7 +46,7 @@ pub fn panic(info: &PanicInfo) -> ! {
                 let apic = apic.as_mut().unwrap();
 
                 // Send out the NMI
-                apic.ipi(0, NMI);
+                apic.send_nmi(0);
             }
         }
 
@@ -65,6 +62,9 @@ pub fn panic(info: &PanicInfo) -> ! {
     let our_info: *const PanicInfo = info;
     let other_info: *const PanicInfo = PANIC_PENDING.load(Ordering::SeqCst);
 
+    // Replay the recent messages, as they might not have made it out
+    crate::print::dump_log();
+
     // Print information about the panic
     for &(bsp_msg, info) in &[
         ("non-BSP", other_info),
@@ -96,12 +96,13 @@ pub fn panic(info: &PanicInfo) -> ! {
         apic
     };
 
-    // Wait for a soft reboot request to be issued
+    // Wait for a soft reboot request to be issued. The serial lock might be
+    // held by the print we interrupted, so it's shattered rather than taken
     {
-        let mut serial = core!().shared.serial.lock();
+        let serial = unsafe { &mut *core!().shared.serial.shatter() };
         let serial = serial.as_mut().unwrap();
         while !core!().shared.rebooting.load(Ordering::SeqCst) {
-            if serial.read_byte() == Some(b'S') {
+            if serial.read_byte().is_some_and(crate::console::is_reboot) {
                 core!().shared.rebooting.store(true, Ordering::SeqCst);
             }
         }
@@ -121,7 +122,10 @@ unsafe fn disable_cores(apic: &mut LocalApic) {
         // Only shut down the other APICs if they were initialized
         if !MAX_APIC_ID.initialized() { return; }
 
-        for id in 0..*MAX_APIC_ID.get() {
+        // Number of cores which have been parked, including us
+        let mut parked = 1;
+
+        for id in 0..=*MAX_APIC_ID.get() {
             // Don't NMI the BSP
             if id == bsp_id { continue; }
 
@@ -130,13 +134,30 @@ unsafe fn disable_cores(apic: &mut LocalApic) {
             if state == ApicState::Online {
                 // Send the NMI and wait for the core to halt
                 while core_state(id) != ApicState::Halted {
-                    unsafe { apic.ipi(id, NMI); }
+                    unsafe { apic.send_nmi(id); }
                     crate::time::sleep(1_000);
                     core::hint::spin_loop();
                 }
+                parked += 1;
+            }
+        }
+
+        // If all cores on the system have been online, wait for all of them
+        // to park at the barrier. Cores which never came online can't arrive
+        // at it, so there's nothing to wait for otherwise. Cores only park
+        // when we're rebooting, on a plain panic they halt right away
+        let rebooting = core!().shared.is_rebooting();
+        if rebooting && Some(parked) == total_cores() {
+            core!().shared.rendezvous(parked as u64);
+        }
+
+        // Release the barrier, so it can be used again after the reboot
+        core!().shared.release_aps();
 
-                // INIT the core
-                unsafe { apic.ipi(id, 0x4500); }
+        // INIT the halted cores
+        for id in 0..=*MAX_APIC_ID.get() {
+            if id != bsp_id && core_state(id) == ApicState::Halted {
+                unsafe { apic.send_init(id); }
                 crate::time::sleep(1_000);
             }
         }
@@ -152,24 +173,19 @@ pub unsafe fn soft_reboot(apic: &mut LocalApic) -> ! {
     // Disable other cores
     unsafe { disable_cores(apic); }
 
+    // Cores which arrived at the barrier after it has been released are still
+    // counted in it. All of them are INITed by now, so reset the barrier before
+    // the APs are brought up and released again after the reboot
+    core!().shared.reset_barrier();
+
     // Reset all PCI devices
     unsafe { crate::pci::reset_devices(); }
 
     // Reset the APIC
     unsafe { apic.reset(); }
 
-    // Get the trampoline pointer
-    let tramp = unsafe { shared_data::get_trampoline() };
-
-    // Bootloader keeps its own pointer to the shared struct, we don't need to
-    // set it
-    let shared = page_table::PhysAddr(0);
-
-    // Get the bootloader state
-    let bstate = core!().shared.bootloader().get();
-
     // Jump to the bootloader
     unsafe {
-        tramp(bstate.entry, bstate.stack, bstate.page_table.clone(), shared)
+        shared_data::return_to_bootloader(core!().shared.bootloader().get())
     };
 }
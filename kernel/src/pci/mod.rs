@@ -1,7 +1,9 @@
 //! Drivers and other routines related to PCI-based devices
 
 mod pci;
+mod msi;
 mod drivers;
 
 pub use pci::*;
+pub use msi::*;
 pub use drivers::*;
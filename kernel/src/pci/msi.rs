@@ -0,0 +1,171 @@
+//! Message signaled interrupts (MSI and MSI-X)
+//!
+//! Interrupts are delivered as fixed, edge triggered interrupts straight to a
+//! local APIC. Functions which implement neither of the capabilities could only
+//! signal legacy INTx interrupts, which require an IO APIC that the kernel
+//! doesn't drive. Drivers for these functions have to fall back to polling.
+
+use page_table::{PhysAddr, PageType};
+
+use crate::mm;
+use crate::pci::{DeviceConfig, BarBits, BarType, read_config, write_config};
+
+/// Capability ID of MSI
+const CAP_MSI: u8 = 0x05;
+
+/// Capability ID of MSI-X
+const CAP_MSIX: u8 = 0x11;
+
+/// Base of the message address which targets a local APIC
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+/// Offset of the command register in the configuration space
+const COMMAND: u16 = 0x04;
+
+/// Flag in the command register disabling legacy INTx interrupts
+const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+
+/// Flag in the MSI message control showing 64-bit message address support
+const MSI_64BIT: u32 = 1 << 7;
+
+/// Flag in the MSI message control enabling MSI
+const MSI_ENABLE: u32 = 1 << 0;
+
+/// Bits in the MSI message control selecting the number of enabled messages
+const MSI_MULTIPLE_MESSAGE: u32 = 0b111 << 4;
+
+/// Flag in the MSI-X message control masking all of the vectors
+const MSIX_FUNCTION_MASK: u32 = 1 << 14;
+
+/// Flag in the MSI-X message control enabling MSI-X
+const MSIX_ENABLE: u32 = 1 << 15;
+
+/// Size of a single MSI-X table entry
+const MSIX_ENTRY_SIZE: u64 = 16;
+
+/// Flag in the MSI-X vector control masking the vector
+const MSIX_VECTOR_MASK: u32 = 1 << 0;
+
+/// The message signaled interrupt mechanism enabled for a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiMode {
+    /// Plain MSI
+    Msi,
+
+    /// MSI-X, with only the first entry in the table unmasked
+    MsiX,
+}
+
+impl DeviceConfig {
+    /// Route the interrupts of this function to `vector` on the local APIC
+    /// with `apic_id`, preferring MSI-X over MSI.
+    ///
+    /// Returns the mechanism which has been enabled, or `None` if the function
+    /// implements neither of them or the APIC ID doesn't fit into the message,
+    /// in which case the device must be polled.
+    pub unsafe fn enable_msi(&self, apic_id: u32, vector: u8)
+            -> Option<MsiMode> {
+        // The message address can only hold 8-bit destination IDs, so x2APIC
+        // IDs above 255 can't be targeted
+        if apic_id > 0xFF { return None; }
+
+        // Compute the message delivering `vector` to the APIC
+        let addr = MSI_ADDRESS_BASE | (apic_id << 12);
+        let data = vector as u32;
+
+        // Program whichever capability is present, bailing out if neither is
+        let mode = if let Some(cap) = self.read_cap(CAP_MSIX) {
+            unsafe { self.enable_msix_cap(cap, addr, data); }
+            MsiMode::MsiX
+        } else {
+            let cap = self.read_cap(CAP_MSI)?;
+            unsafe { self.enable_msi_cap(cap, addr, data); }
+            MsiMode::Msi
+        };
+
+        // Disable the legacy interrupts. Only write the command half of the
+        // register, so we don't clear any of the status bits
+        unsafe {
+            let command = read_config(self.addr, COMMAND) & 0xFFFF;
+            write_config(self.addr, COMMAND, command | COMMAND_INTX_DISABLE);
+        }
+
+        Some(mode)
+    }
+
+    /// Program and enable the MSI capability at `cap` with a single message
+    unsafe fn enable_msi_cap(&self, cap: u16, addr: u32, data: u32) {
+        unsafe {
+            // Get the message control
+            let reg = read_config(self.addr, cap);
+            let control = reg >> 16;
+
+            // Write the message address. The data register comes after the
+            // high part of the address if the function supports 64-bit ones
+            write_config(self.addr, cap + 4, addr);
+            let data_offset = if (control & MSI_64BIT) != 0 {
+                write_config(self.addr, cap + 8, 0);
+                cap + 12
+            } else {
+                cap + 8
+            };
+
+            // Write the message data
+            write_config(self.addr, data_offset, data);
+
+            // Enable MSI with a single message
+            let control = (control & !MSI_MULTIPLE_MESSAGE) | MSI_ENABLE;
+            write_config(self.addr, cap, (reg & 0xFFFF) | (control << 16));
+        }
+    }
+
+    /// Program and enable the MSI-X capability at `cap`, unmasking only the
+    /// first entry in the table
+    unsafe fn enable_msix_cap(&self, cap: u16, addr: u32, data: u32) {
+        // Get the message control and the number of entries in the table
+        let reg = unsafe { read_config(self.addr, cap) };
+        let control = reg >> 16;
+        let entries = ((control & 0x7FF) + 1) as u64;
+
+        // Get the BAR holding the table and the offset into it
+        let table = unsafe { read_config(self.addr, cap + 4) };
+        let bir = (table & 0b111) as usize;
+        let offset = (table & !0b111) as u64;
+
+        // Get the physical address of the table
+        let bars = self.bars();
+        assert!(bir < bars.len() && BarType::from_bar(bars[bir]) ==
+            BarType::Memory, "MSI-X table not in a memory BAR");
+        let bar_hi = bars.get(bir + 1).copied().unwrap_or(0);
+        let table_addr = BarBits::u64(bars[bir], bar_hi).checked_add(offset)
+            .expect("Overflow when computing MSI-X table address");
+
+        // Map in the pages holding the table
+        let page_size = PageType::Page4K as u64;
+        let page_addr = table_addr & !(page_size - 1);
+        let size = (table_addr - page_addr + entries * MSIX_ENTRY_SIZE)
+            .next_multiple_of(page_size);
+        let vaddr = mm::map_mmio(PhysAddr(page_addr), size);
+        let table = (vaddr.0 + (table_addr - page_addr)) as *mut u32;
+
+        unsafe {
+            // Mask the whole function while we're programming the table
+            let control = control | MSIX_ENABLE | MSIX_FUNCTION_MASK;
+            write_config(self.addr, cap, (reg & 0xFFFF) | (control << 16));
+
+            // Program the entries, masking all but the first one
+            for entry in 0..entries as usize {
+                let entry = table.add(entry * MSIX_ENTRY_SIZE as usize / 4);
+                let mask = if entry == table { 0 } else { MSIX_VECTOR_MASK };
+                core::ptr::write_volatile(entry.add(0), addr);
+                core::ptr::write_volatile(entry.add(1), 0);
+                core::ptr::write_volatile(entry.add(2), data);
+                core::ptr::write_volatile(entry.add(3), mask);
+            }
+
+            // Unmask the function
+            let control = control & !MSIX_FUNCTION_MASK;
+            write_config(self.addr, cap, (reg & 0xFFFF) | (control << 16));
+        }
+    }
+}
@@ -1,14 +1,22 @@
 //! Routines for the handling of PCI devices
-
-// PCIe might be implemented later if needed
+//!
+//! The configuration space is accessed through the memory mapped PCIe ECAM if
+//! the ACPI MCFG table describes it, and through the legacy port I/O mechanism
+//! otherwise. Only ECAM can reach the extended configuration space.
 
 use alloc::vec::Vec;
 use alloc::sync::Arc;
-use core::mem::size_of;
+use core::mem::{size_of, offset_of, MaybeUninit};
 use core::fmt::Debug;
 
+use oncelock::OnceLock;
 use spinlock::SpinLock;
+use const_assert::const_assert;
+use page_table::{PhysAddr, VirtAddr};
+pub use pci_bar::{BarBits, BarType};
 
+use crate::mm;
+use crate::acpi::McfgEntry;
 use crate::core_locals::InterruptLock;
 
 /// I/O port for the configuration space address
@@ -17,10 +25,94 @@ const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
 /// I/O port for the configuration space data
 const PCI_CONFIG_DATA: u16 = 0xCFC;
 
+/// Size of the legacy configuration space of a function. Anything above this
+/// is the extended configuration space
+const LEGACY_CONFIG_SIZE: u16 = 0x100;
+
+/// Size of the whole configuration space of a function
+const CONFIG_SIZE: u16 = 0x1000;
+
+/// Flag in the header type showing that the device implements multiple
+/// functions
+const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+
+/// Offset of the register holding the primary, secondary and subordinate bus
+/// numbers in the configuration space of a PCI-to-PCI bridge
+const BRIDGE_BUS_NUMBERS: u16 = 0x18;
+
+/// Flag in the status register showing that the function implements the
+/// capabilities list
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
 /// List of devices handled by a driver
 static DEVICES: SpinLock<Vec<Arc<dyn crate::pci::Device>>, InterruptLock> =
     SpinLock::new(Vec::new());
 
+/// Configurations of all devices discovered during enumeration
+static PCI_DEVICES: OnceLock<&[DeviceConfig]> = OnceLock::new();
+
+/// Memory mapped configuration space regions described by the MCFG. If these
+/// haven't been registered, the legacy port I/O mechanism is used instead
+static ECAM_REGIONS: OnceLock<&[EcamRegion]> = OnceLock::new();
+
+/// Address of a single PCI function
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[allow(missing_docs)]
+pub struct PciAddress {
+    pub segment:  u16,
+    pub bus:      u8,
+    pub device:   u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Compute the legacy port I/O selection address for the register at
+    /// `offset` of this function
+    fn legacy_select(&self, offset: u16) -> u32 {
+        // Compute the address for this Bus:Device.Function
+        let pci_addr = ((self.bus as u32) << 8) | ((self.device as u32) << 3) |
+            self.function as u32;
+
+        // Compute the PCI selection address for this BDF and register
+        (1 << 31) | (pci_addr << 8) | (offset as u32 & 0xFC)
+    }
+}
+
+/// A memory mapped configuration space region of a PCIe segment group
+struct EcamRegion {
+    /// PCI segment group number
+    segment: u16,
+
+    /// First bus decoded by this region
+    start_bus: u8,
+
+    /// Last bus decoded by this region
+    end_bus: u8,
+
+    /// Virtual address of the configuration space of `start_bus`
+    base: VirtAddr,
+}
+
+impl EcamRegion {
+    /// Get a pointer to the register at `offset` of the function at `addr`,
+    /// if this region decodes it
+    fn register(&self, addr: PciAddress, offset: u16) -> Option<*mut u32> {
+        // Make sure this region decodes the function
+        if addr.segment != self.segment ||
+                !(self.start_bus..=self.end_bus).contains(&addr.bus) {
+            return None;
+        }
+
+        // Compute the offset of the register within this region
+        let offset = (((addr.bus - self.start_bus) as u64) << 20) |
+            ((addr.device as u64) << 15) |
+            ((addr.function as u64) << 12) |
+            (offset & 0xFFC) as u64;
+
+        Some((self.base.0 + offset) as *mut u32)
+    }
+}
+
 /// PCI header common for any other PCI header
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
@@ -62,9 +154,94 @@ pub struct DeviceConfig {
     pub interrupt_pin:         u8,
     pub min_grant:             u8,
     pub max_latency:           u8,
+
+    /// Address of this function. This is not a part of the configuration space
+    pub addr:                  PciAddress,
 }
 
+// Make sure the address comes right after the configuration registers
+const_assert!(offset_of!(DeviceConfig, addr) == 0x40);
+
 impl DeviceConfig {
+    /// Read the configuration of the function at `addr`
+    unsafe fn read(addr: PciAddress) -> Self {
+        let mut cfg = MaybeUninit::<Self>::uninit();
+
+        // Read the configuration registers
+        let regs = unsafe {
+            read_pci_registers::<[u8; offset_of!(DeviceConfig, addr)]>(addr)
+        };
+
+        // Fill in the registers and the address
+        unsafe {
+            (cfg.as_mut_ptr() as *mut [u8; offset_of!(DeviceConfig, addr)])
+                .write(regs);
+            (&raw mut (*cfg.as_mut_ptr()).addr).write(addr);
+            cfg.assume_init()
+        }
+    }
+
+    /// Walk the capabilities list of this function and return the
+    /// configuration space offset of the first capability with `cap_id`
+    pub fn read_cap(&self, cap_id: u8) -> Option<u16> {
+        // Make sure there's a capabilities list at all
+        if self.header.status & STATUS_CAPABILITIES == 0 { return None; }
+
+        // Get the first capability. The bottom two bits are reserved
+        let mut ptr = (self.capabilities & !0b11) as u16;
+
+        // Bound the walk by the number of capabilities that can fit into the
+        // legacy configuration space, in case the list loops
+        for _ in 0..(LEGACY_CONFIG_SIZE - 0x40) / 4 {
+            // Reached the end of the list
+            if ptr < 0x40 { return None; }
+
+            // Read the capability ID and the pointer to the next one
+            let reg = unsafe { read_config(self.addr, ptr) };
+            if (reg & 0xFF) as u8 == cap_id { return Some(ptr); }
+            ptr = ((reg >> 8) & 0xFC) as u16;
+        }
+
+        None
+    }
+
+    /// Walk the extended capabilities list of this function and return the
+    /// configuration space offset of the first capability with `cap_id`.
+    ///
+    /// This is only possible if the function is reachable through ECAM
+    pub fn read_ext_cap(&self, cap_id: u16) -> Option<u16> {
+        // The extended capabilities list always starts at the legacy boundary
+        let mut ptr = LEGACY_CONFIG_SIZE;
+
+        // Bound the walk by the number of capabilities that can fit into the
+        // extended configuration space, in case the list loops
+        for _ in 0..(CONFIG_SIZE - LEGACY_CONFIG_SIZE) / 4 {
+            // Reached the end of the list
+            if ptr < LEGACY_CONFIG_SIZE { return None; }
+
+            // Read the capability ID and the pointer to the next one. A read
+            // of all ones or zeroes means there's no extended list
+            let reg = unsafe { read_config(self.addr, ptr) };
+            if reg == 0 || reg == !0 { return None; }
+            if (reg & 0xFFFF) as u16 == cap_id { return Some(ptr); }
+            ptr = ((reg >> 20) & 0xFFC) as u16;
+        }
+
+        None
+    }
+
+    /// Returns the raw values of the six BARs of this function
+    pub fn bars(&self) -> [u32; 6] {
+        [self.bar0, self.bar1, self.bar2, self.bar3, self.bar4, self.bar5]
+    }
+
+    /// Returns the base I/O port of BAR `n`, or `None` if there's no such BAR
+    /// or it's not an I/O space BAR
+    pub fn io_bar(&self, n: usize) -> Option<u16> {
+        let bar = *self.bars().get(n)?;
+        (BarType::from_bar(bar) == BarType::Io).then(|| BarBits::io_port(bar))
+    }
+
     /// Returns the string representation of the header and subsystem vendor and
     /// device IDs
     pub fn did_vid(&self) -> alloc::string::String {
@@ -76,53 +253,218 @@ impl DeviceConfig {
     }
 }
 
-/// The bitness of a BAR
-#[derive(Debug, PartialEq)]
-#[allow(missing_docs)]
-pub enum BarBits {
-    Bit32,
-    Bit64,
+/// Register the memory mapped configuration space regions described by the
+/// ACPI MCFG. From this call on, configuration space accesses to the functions
+/// within these regions will go through ECAM.
+pub unsafe fn register_ecam(entries: &[McfgEntry]) {
+    let regions = entries.iter().map(|entry| {
+        // Compute the physical address and the size of the region. The base
+        // address always corresponds to bus 0, so offset it to the first bus
+        let bus_size = 1u64 << 20;
+        let paddr = (entry.start_bus as u64).checked_mul(bus_size)
+            .and_then(|x| x.checked_add(entry.base.0))
+            .expect("Overflow when computing ECAM region base");
+        let size = (entry.end_bus as u64 - entry.start_bus as u64 + 1) *
+            bus_size;
+
+        // Map the region in
+        EcamRegion {
+            segment:   entry.segment,
+            start_bus: entry.start_bus,
+            end_bus:   entry.end_bus,
+            base:      mm::map_mmio(PhysAddr(paddr), size),
+        }
+    }).collect::<Vec<EcamRegion>>();
+
+    ECAM_REGIONS.set(regions.leak());
 }
 
-impl BarBits {
-    /// Return the bitness of `bar`
-    pub fn from_bar(bar: u32) -> Self {
-        match (bar >> 1) & 0b11 {
-            0b10 => Self::Bit64,
-            _    => Self::Bit32,
-        }
+/// Get a pointer to the memory mapped register at `offset` of the function at
+/// `addr`, if it's reachable through ECAM
+fn ecam_register(addr: PciAddress, offset: u16) -> Option<*mut u32> {
+    ECAM_REGIONS.try_get()?.iter()
+        .find_map(|region| region.register(addr, offset))
+}
+
+/// Read the `u32` configuration register at `offset` of the function at
+/// `addr`.
+///
+/// The extended configuration space (`offset >= 0x100`) and segments other
+/// than 0 are only reachable through ECAM. Reads which can't be serviced return
+/// all ones, just like a read from a function which doesn't exist.
+pub unsafe fn read_config(addr: PciAddress, offset: u16) -> u32 {
+    // Use ECAM if possible
+    if let Some(reg) = ecam_register(addr, offset) {
+        return unsafe { core::ptr::read_volatile(reg) };
     }
 
-    /// Based on the bitness of `bar0`, returns a whole `u64` value from
-    /// either `bar0` (if 32-bit), or in `bar0 << 32 | bar1` (if 64-bit)
-    /// with the BAR type bits masked off from both
-    pub fn u64(bar0: u32, bar1: u32) -> u64 {
-        // Mask off the bits from both
-        let lower  = (bar0 & !0b1111) as u64;
-        let higher = (bar1 & !0b1111) as u64;
+    // Make sure the legacy mechanism can reach the register
+    if addr.segment != 0 || offset >= LEGACY_CONFIG_SIZE { return !0; }
 
-        // Return the u64 based on bitness
-        match Self::from_bar(bar0) {
-            Self::Bit32 => lower,
-            Self::Bit64 => (higher << 32) | lower,
-        }
+    // Set the window to the selected register and read the value
+    unsafe {
+        cpu::out32(PCI_CONFIG_ADDRESS, addr.legacy_select(offset));
+        cpu::in32(PCI_CONFIG_DATA)
     }
 }
 
-/// The memory type of a BAR
-#[derive(Debug, PartialEq)]
-#[allow(missing_docs)]
-pub enum BarType {
-    IO,
-    Memory,
+/// Write `val` to the `u32` configuration register at `offset` of the function
+/// at `addr`.
+///
+/// Writes which can't be serviced (see `read_config()`) are dropped.
+pub unsafe fn write_config(addr: PciAddress, offset: u16, val: u32) {
+    // Use ECAM if possible
+    if let Some(reg) = ecam_register(addr, offset) {
+        unsafe { core::ptr::write_volatile(reg, val); }
+        return;
+    }
+
+    // Make sure the legacy mechanism can reach the register
+    if addr.segment != 0 || offset >= LEGACY_CONFIG_SIZE { return; }
+
+    // Set the window to the selected register and write the value
+    unsafe {
+        cpu::out32(PCI_CONFIG_ADDRESS, addr.legacy_select(offset));
+        cpu::out32(PCI_CONFIG_DATA, val);
+    }
+}
+
+/// Get the configurations of all PCI devices discovered on the system.
+///
+/// Returns an empty slice if the devices haven't been enumerated yet.
+pub fn devices() -> &'static [DeviceConfig] {
+    PCI_DEVICES.try_get().copied().unwrap_or(&[])
 }
 
-impl BarType {
-    /// Return the type of `bar`
-    pub fn from_bar(bar: u32) -> Self {
-        match bar & 0b1 {
-            0 => Self::Memory,
-            _ => Self::IO,
+/// Recursively enumerates the PCI hierarchy, descending through PCI-to-PCI
+/// bridges
+struct Enumerator {
+    /// PCI segment group being enumerated
+    segment: u16,
+
+    /// First bus decoded by the segment, which is where its host bridge lives
+    start_bus: u8,
+
+    /// Buses which have been scanned already. Used to guard against malformed
+    /// bridges sending us into a loop
+    visited: [bool; 256],
+
+    /// Configurations of all devices found so far
+    devices: Vec<DeviceConfig>,
+}
+
+impl Enumerator {
+    /// Create a new enumerator for the `segment` whose buses start at
+    /// `start_bus`
+    fn new(segment: u16, start_bus: u8) -> Self {
+        Self {
+            segment,
+            start_bus,
+            visited: [false; 256],
+            devices: Vec::new(),
+        }
+    }
+
+    /// Enumerate all buses of the segment, starting at the host bridges
+    unsafe fn enumerate(mut self) -> Vec<DeviceConfig> {
+        let host = PciAddress {
+            segment: self.segment,
+            bus:     self.start_bus,
+            ..Default::default()
+        };
+
+        // Make sure there's a host bridge at all
+        if unsafe { read_config(host, 0) } == u32::MAX {
+            return self.devices;
+        }
+
+        // If the host bridge is a multifunction device, each function is a
+        // separate host bridge responsible for the bus with its number, offset
+        // by the first bus of the segment
+        let header = unsafe { read_pci_registers::<Header>(host) };
+        if (header.header_type & HEADER_MULTIFUNCTION) == 0 {
+            unsafe { self.scan_bus(self.start_bus); }
+        } else {
+            for function in 0..8 {
+                let addr = PciAddress { function, ..host };
+                if unsafe { read_config(addr, 0) } == u32::MAX { continue; }
+
+                let Some(bus) = self.start_bus.checked_add(function) else {
+                    break;
+                };
+                unsafe { self.scan_bus(bus); }
+            }
+        }
+
+        self.devices
+    }
+
+    /// Scan all devices on the `bus`
+    unsafe fn scan_bus(&mut self, bus: u8) {
+        // Don't scan the same bus twice
+        if core::mem::replace(&mut self.visited[bus as usize], true) {
+            return;
+        }
+
+        for device in 0..32 {
+            unsafe { self.scan_device(bus, device); }
+        }
+    }
+
+    /// Scan all functions of the `device` on the `bus`
+    unsafe fn scan_device(&mut self, bus: u8, device: u8) {
+        let addr = PciAddress {
+            segment: self.segment,
+            bus,
+            device,
+            function: 0,
+        };
+
+        // If no device is registered, go next
+        if unsafe { read_config(addr, 0) } == u32::MAX { return; }
+
+        // Only multifunction devices implement functions other than 0
+        let header = unsafe { read_pci_registers::<Header>(addr) };
+        let functions = if (header.header_type & HEADER_MULTIFUNCTION) != 0 {
+            8
+        } else {
+            1
+        };
+
+        for function in 0..functions {
+            unsafe { self.scan_function(PciAddress { function, ..addr }); }
+        }
+    }
+
+    /// Scan the function at `addr`, descending into the secondary bus if it's
+    /// a PCI-to-PCI bridge
+    unsafe fn scan_function(&mut self, addr: PciAddress) {
+        // If no function is registered, go next
+        if unsafe { read_config(addr, 0) } == u32::MAX { return; }
+
+        let header = unsafe { read_pci_registers::<Header>(addr) };
+        match header.header_type & !HEADER_MULTIFUNCTION {
+            // Regular device, save its configuration
+            0 => {
+                self.devices.push(unsafe { DeviceConfig::read(addr) });
+            },
+
+            // PCI-to-PCI bridge, descend into the buses behind it
+            1 if (header.class, header.subclass) == (0x06, 0x04) => {
+                // Read the bus numbers
+                let buses = unsafe { read_config(addr, BRIDGE_BUS_NUMBERS) };
+                let secondary   = (buses >>  8) as u8;
+                let subordinate = (buses >> 16) as u8;
+
+                // Only descend downwards, a bridge pointing back up the
+                // hierarchy is malformed
+                if secondary > addr.bus && secondary <= subordinate {
+                    unsafe { self.scan_bus(secondary); }
+                }
+            },
+
+            // CardBus bridges and such aren't supported
+            _ => {},
         }
     }
 }
@@ -137,45 +479,35 @@ pub unsafe fn init() {
     // Get the drivers registered in the kernel
     let drivers = crate::pci::get_pci_drivers();
 
-    // For each bus ID
-    (0..256).flat_map(|bus| {
-        // For each device ID
-        (0..32).flat_map(move |device| {
-            // For each function ID
-            (0..8).map(move |function| (bus, device, function))
+    // Get all segments on the system along with their first bus. Without
+    // ECAM, only segment 0 is reachable, starting at bus 0
+    let mut segments: Vec<(u16, u8)> = ECAM_REGIONS.try_get()
+        .map(|regions| {
+            regions.iter().map(|x| (x.segment, x.start_bus)).collect()
         })
-    }).for_each(|(bus, device, function)| {
-        // Compute the address for this Bus:Device.Function
-        let pci_addr = (bus << 8) | (device << 3) | function;
-
-        // Compute the PCI selection address for this BDF
-        let select_addr = (1 << 31) | (pci_addr << 8);
+        .unwrap_or_else(|| alloc::vec![(0, 0)]);
 
-        // Read the device and vendor IDs
-        unsafe { cpu::out32(PCI_CONFIG_ADDRESS, select_addr); }
-        let did_vid = unsafe { cpu::in32(PCI_CONFIG_DATA) };
-
-        // If no device is registered, go next
-        if did_vid == u32::MAX { return; }
+    // A segment may be described by multiple regions, keep the lowest bus
+    segments.sort_unstable();
+    segments.dedup_by_key(|(segment, _)| *segment);
 
-        let header = unsafe { read_pci_registers::<Header>(select_addr) };
-
-        // Skip non-device entries (bridges etc.)
-        if (header.header_type & 0x7F) != 0 { return; }
-
-        // Read the device config
-        let dev_cfg = unsafe {
-            read_pci_registers::<DeviceConfig>(select_addr)
-        };
+    // Enumerate the devices in all segments
+    let found = segments.into_iter()
+        .flat_map(|(segment, start_bus)| unsafe {
+            Enumerator::new(segment, start_bus).enumerate()
+        })
+        .collect::<Vec<DeviceConfig>>();
+    PCI_DEVICES.set(found.leak());
 
+    for dev_cfg in devices() {
         // If we have a driver registered for this device, save the device
         for probe in drivers {
-            if let Some(device) = probe(&dev_cfg) {
+            if let Some(device) = probe(dev_cfg) {
                 println!("Got PCI driver for device: {} ", dev_cfg.did_vid());
                 DEVICES.lock().push(device);
             }
         }
-    });
+    }
 
     // This is a post-probe hook. If things get more complicated, it might be
     // better to actually create a hook-register, but this works for now
@@ -196,24 +528,20 @@ pub unsafe fn reset_devices() {
         .for_each(|dev| dev.purge());
 }
 
-/// Read a struct `T` given the PCI `select_addr`
+/// Read a struct `T` from the configuration space of the function at `addr`
 ///
 /// It is up to the caller to ensure the type `T` has the correct size and
 /// alignment (multiple of u32 and alignment at most u32).
-unsafe fn read_pci_registers<T>(select_addr: u32)
+unsafe fn read_pci_registers<T>(addr: PciAddress)
         -> T where [u32; size_of::<T>() / size_of::<u32>()]: {
     // Create array to hold the register data
     let mut data = [0u32; size_of::<T>() / size_of::<u32>()];
 
     for (idx, register) in data.iter_mut().enumerate() {
-        // Set the window to the selected register
-        unsafe {
-            cpu::out32(PCI_CONFIG_ADDRESS,
-                select_addr | (idx * size_of::<u32>()) as u32);
-        }
-
         // Read the value
-        *register = unsafe { cpu::in32(PCI_CONFIG_DATA) };
+        *register = unsafe {
+            read_config(addr, (idx * size_of::<u32>()) as u16)
+        };
     }
 
     // Transmute the array into the desired type
@@ -1,26 +1,142 @@
 //! `print!()` macros
 
 use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// Size of the in-memory log in bytes
+const LOG_SIZE: usize = 16 * 1024;
+
+/// In-memory ring buffer holding the last `LOG_SIZE` bytes of everything
+/// printed, so that the messages can be replayed by `dump_log()` when serial
+/// output was lost (a panic mid-print, no serial attached, ...).
+///
+/// Once full, the oldest bytes are overwritten by the newest ones. Writes only
+/// reserve space with an atomic add and never take a lock, so logging is safe
+/// from NMI and panic contexts. Concurrent writers don't block each other, but
+/// a racing `dump_log()` may see partially written messages.
+static LOG: [AtomicU8; LOG_SIZE] = [const { AtomicU8::new(0) }; LOG_SIZE];
+
+/// Total number of bytes ever written to `LOG`
+static LOG_HEAD: AtomicUsize = AtomicUsize::new(0);
+
+/// Append `bytes` to the in-memory log
+fn log(bytes: &[u8]) {
+    // Reserve the space for the bytes
+    let start = LOG_HEAD.fetch_add(bytes.len(), Ordering::SeqCst);
+
+    // Only the tail of messages longer than the log fits into it
+    let skip = bytes.len().saturating_sub(LOG_SIZE);
+    for (ii, &byte) in bytes.iter().enumerate().skip(skip) {
+        LOG[(start + ii) % LOG_SIZE].store(byte, Ordering::Relaxed);
+    }
+}
+
+/// Replay the contents of the in-memory log to the serial port, bypassing the
+/// serial lock
+pub fn dump_log() {
+    // Get the range of bytes still held in the log
+    let end = LOG_HEAD.load(Ordering::SeqCst);
+    let start = end.saturating_sub(LOG_SIZE);
+
+    let serial = unsafe { &mut *crate::core!().shared.serial.shatter() };
+    let Some(serial) = serial else { return; };
+
+    // Write out the bytes in chunks, so we don't have to write them one by one.
+    // This bypasses the printing routines, so the dump itself isn't logged
+    serial.write(b"\n---- Recent log ----\n");
+    let mut chunk = [0u8; 256];
+    for base in (start..end).step_by(chunk.len()) {
+        let len = chunk.len().min(end - base);
+        for (ii, byte) in chunk[..len].iter_mut().enumerate() {
+            *byte = LOG[(base + ii) % LOG_SIZE].load(Ordering::Relaxed);
+        }
+        serial.write(&chunk[..len]);
+    }
+    serial.write(b"\n---- End of log ----\n");
+}
+
+/// Print a hexdump of `bytes` in the classic 16 bytes per line offset, hex and
+/// ASCII format. Offsets start at `base_addr`.
+///
+/// The print lock is held for the whole dump, so it's not interleaved with
+/// prints from other cores
+pub fn hexdump(bytes: &[u8], base_addr: u64) {
+    let _lock = crate::core!().shared.print_lock.lock();
+
+    for (ii, line) in bytes.chunks(16).enumerate() {
+        // Print the offset of the line
+        let _ = write!(Serial, "{:016X} ", base_addr + ii as u64 * 16);
+
+        // Print the bytes in hex, replacing missing bytes of the last line
+        // with padding and collecting their printable representations
+        let mut ascii = [b'.'; 16];
+        for (jj, printable) in ascii.iter_mut().enumerate() {
+            if jj == 8 { let _ = Serial.write_str(" "); }
+
+            match line.get(jj) {
+                Some(&byte) => {
+                    let _ = write!(Serial, " {byte:02X}");
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        *printable = byte;
+                    }
+                },
+                None => { let _ = Serial.write_str("   "); },
+            }
+        }
+
+        // Print the ASCII representation
+        let ascii = core::str::from_utf8(&ascii[..line.len()]).unwrap();
+        let _ = writeln!(Serial, "  |{ascii}|");
+    }
+}
 
 /// Dummy struct that implements `Write` such that `print!()` can be used on it
 pub struct Serial;
 
 impl Write for Serial {
     fn write_str(&mut self, string: &str) -> core::fmt::Result {
-        let mut serial = crate::core!().shared.serial.lock();
-        if let Some(serial) = &mut *serial {
-            serial.write(string.as_bytes());
-        }
+        log(string.as_bytes());
+
+        // The print macros hold the print lock already
+        crate::core!().shared.serial_write_raw(string.as_bytes());
         Ok(())
     }
 }
 
+/// Returns whether the emergency print path may bypass the locks. This is only
+/// the case while the BSP is panicking or while handling an exception (NMIs
+/// included), as those are the only contexts which may have interrupted a core
+/// in the middle of a print.
+pub fn in_emergency_context() -> bool {
+    crate::panic::bsp_in_panic() || crate::core!().in_exception()
+}
+
 /// Dummy struct that implements `Write` such that `print_shatter!()` can be
-/// used on it, printing to the serial ports while bypassing the serial lock.
+/// used on it, printing to the serial ports while bypassing the print lock and
+/// the serial lock.
+///
+/// This is the emergency path for the panic and NMI handlers. If a core
+/// panicked while holding either of the locks, taking them again would
+/// deadlock, so they're shattered instead. The price for that is that the
+/// output may interleave with, or cut into, a message which was being printed
+/// when the core got interrupted, and that the serial driver may be used while
+/// it's in an inconsistent state.
+///
+/// Outside of an emergency context (see `in_emergency_context()`), the locks
+/// are taken as usual, so a stray `print_shatter!()` can't corrupt the output.
 pub struct SerialShatter;
 
 impl Write for SerialShatter {
     fn write_str(&mut self, string: &str) -> core::fmt::Result {
+        log(string.as_bytes());
+
+        // Only bypass the locks when they might be held by an interrupted
+        // print on this core
+        if !in_emergency_context() {
+            crate::core!().shared.serial_write(string.as_bytes());
+            return Ok(());
+        }
+
         unsafe {
             let serial = crate::core!().shared.serial.shatter();
             if let Some(serial) = &mut *serial {
@@ -41,6 +157,9 @@ impl Write for SerialShatter {
 }
 
 /// Serial `print_shatter!()` support for the bootloader
+///
+/// Bypasses the print and serial locks in panic and NMI contexts. See
+/// `SerialShatter` for the hazards
 #[macro_export] macro_rules! print_shatter {
     ($($arg:tt)*) => {
         let _ = <$crate::print::SerialShatter as core::fmt::Write>::write_fmt(
@@ -65,6 +184,9 @@ impl Write for SerialShatter {
 }
 
 /// Serial `println_shatter!()` support for the bootloader
+///
+/// Bypasses the print and serial locks in panic and NMI contexts. See
+/// `SerialShatter` for the hazards
 #[macro_export] macro_rules! println_shatter {
     () => {
         let _ = <$crate::print::SerialShatter as core::fmt::Write>::write_str(
@@ -2,6 +2,14 @@
 
 use core::sync::atomic::{Ordering, AtomicU64};
 
+use tsc::TimerId;
+
+/// The timers pending on a single core.
+///
+/// Each core has its own wheel, serviced from its APIC timer interrupt, so
+/// scheduling and firing a timer never synchronizes with other cores
+pub use tsc::TimerWheel;
+
 /// The TSC tick rate in MHz
 ///
 /// This starts at a relatively sane default but will be set correctly by
@@ -20,14 +28,13 @@ pub fn tsc_mhz() -> u64 {
 /// Returns the TSC value upon a future time in microseconds
 #[inline]
 pub fn future(ms: u64) -> u64 {
-    cpu::rdtsc() + (ms * tsc_mhz())
+    tsc::deadline(cpu::rdtsc(), ms, tsc_mhz())
 }
 
 /// Busy sleep for a given number of microseconds
 #[inline]
 pub fn sleep(ms: u64) {
-    let wait = future(ms);
-    while cpu::rdtsc() < wait { core::hint::spin_loop(); }
+    tsc::spin_until(future(ms), cpu::rdtsc);
 }
 
 /// Using the PIT, determine the frequency of rdtsc. Round this frequency to
@@ -79,3 +86,95 @@ pub unsafe fn calibrate() {
     // Store the TSC rate
     RDTSC_MHZ.store(rounded_rate, Ordering::Relaxed);
 }
+
+/// Measures the time elapsed since it was started
+///
+/// A stopwatch created with `named()` prints its label and the elapsed time
+/// once it's dropped, timing the scope it lives in.
+pub struct Stopwatch {
+    /// The stopwatch counting the TSC ticks
+    watch: tsc::Stopwatch,
+
+    /// Label printed along with the elapsed time on drop
+    name: Option<&'static str>,
+}
+
+impl Stopwatch {
+    /// Start a new stopwatch
+    pub fn start() -> Self {
+        Self { watch: tsc::Stopwatch::new(cpu::rdtsc()), name: None }
+    }
+
+    /// Start a new stopwatch which prints `name` and the elapsed time when
+    /// it's dropped
+    pub fn named(name: &'static str) -> Self {
+        Self { watch: tsc::Stopwatch::new(cpu::rdtsc()), name: Some(name) }
+    }
+
+    /// Get the number of TSC ticks elapsed since the stopwatch was started
+    pub fn elapsed_ticks(&self) -> u64 {
+        self.watch.elapsed_ticks(cpu::rdtsc())
+    }
+
+    /// Get the number of microseconds elapsed since the stopwatch was started
+    pub fn elapsed_us(&self) -> u64 {
+        self.watch.elapsed_us(cpu::rdtsc(), tsc_mhz())
+    }
+}
+
+impl Drop for Stopwatch {
+    fn drop(&mut self) {
+        if let Some(name) = self.name {
+            crate::println!("{name}: {}us", self.elapsed_us());
+        }
+    }
+}
+
+/// Handle to a timer scheduled by `after()`, which can be used to cancel it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use = "dropping the handle doesn't cancel the timer"]
+pub struct TimerHandle {
+    /// ID of the core the timer was scheduled on
+    core: u32,
+
+    /// ID of the timer in the wheel of the core
+    id: TimerId,
+}
+
+impl TimerHandle {
+    /// Cancel the timer. Returns whether it was still pending, which is
+    /// `false` if it has already fired.
+    ///
+    /// Timers can only be cancelled from the core they were scheduled on, so
+    /// this returns `false` on any other core
+    pub fn cancel(self) -> bool {
+        if core!().id != self.core { return false; }
+        core!().timers().lock().cancel(self.id)
+    }
+}
+
+/// Schedule `callback` to run on this core once `us` microseconds have
+/// passed. Returns `None` if too many timers are pending on this core.
+///
+/// The callback runs in interrupt context of the APIC timer, so it has to be
+/// short and must not use blocking locks, allocate memory, or wait for other
+/// interrupts. The accuracy of the deadline is limited by the period of the
+/// APIC timer
+pub fn after(us: u64, callback: fn()) -> Option<TimerHandle> {
+    let ticks = tsc::us_to_ticks(us, tsc_mhz());
+    let id = core!().timers().lock()
+        .schedule(cpu::rdtsc(), ticks, callback).ok()?;
+    Some(TimerHandle { core: core!().id, id })
+}
+
+/// Run the callbacks of all of the timers on this core whose deadline has
+/// passed. Called from the APIC timer interrupt
+pub fn service_timers() {
+    let now = cpu::rdtsc();
+
+    // Pop the timers one at a time, as the callbacks may schedule new timers
+    let timers = core!().timers();
+    while let Some(callback) = timers.with(|x| x.pop_expired(now)) {
+        callback();
+    }
+}
@@ -0,0 +1,54 @@
+//! Validation of ACPI system description tables, operating on the plain bytes
+//! of the tables
+
+#![no_std]
+
+pub mod madt;
+
+#[cfg(test)]
+mod tests;
+
+/// Size of the header present in all SDTs
+pub const HEADER_LEN: usize = 36;
+
+/// Offset of the length of the table in the header
+const LENGTH: usize = 4;
+
+/// Reasons for a table to fail validation
+#[derive(Debug, PartialEq, Eq)]
+pub enum Invalid {
+    /// The table has an unexpected signature
+    Signature,
+
+    /// The length in the header is shorter than the header or longer than the
+    /// bytes given
+    Length,
+
+    /// The bytes of the table don't add up to zero
+    Checksum,
+}
+
+/// Whether all of the `bytes` of a table, including the checksum field, add up
+/// to zero
+pub fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |x, &byte| x.wrapping_add(byte)) == 0
+}
+
+/// Make sure that `bytes` start with a table with `signature` whose checksum
+/// is valid, returning the bytes of the table, including the header, as long
+/// as its header says
+pub fn validate<'a>(bytes: &'a [u8], signature: &[u8; 4])
+        -> Result<&'a [u8], Invalid> {
+    if bytes.len() < HEADER_LEN { return Err(Invalid::Length); }
+    if &bytes[..4] != signature { return Err(Invalid::Signature); }
+
+    // Make sure the table spans its header and fits into the bytes
+    let len = u32::from_le_bytes(
+        bytes[LENGTH..LENGTH + 4].try_into().unwrap()) as usize;
+    let table = bytes.get(..len)
+        .filter(|_| len >= HEADER_LEN)
+        .ok_or(Invalid::Length)?;
+
+    if !checksum_valid(table) { return Err(Invalid::Checksum); }
+    Ok(table)
+}
@@ -0,0 +1,132 @@
+//! Walking the entries of the multiple APIC description table (MADT)
+
+use crate::{HEADER_LEN, Invalid};
+
+/// Offset of the first entry of the MADT, past the local APIC address and the
+/// flags following the header
+pub const ENTRIES: usize = HEADER_LEN + 8;
+
+/// Flag of a local APIC entry showing that the processor is enabled
+const ENABLED: u32 = 1 << 0;
+
+/// Flag of a local APIC entry showing that the processor can be enabled at
+/// runtime
+const ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// An entry of the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// Type of the entry
+    pub typ: u8,
+
+    /// Bytes of the entry, including the type and the length
+    pub bytes: &'a [u8],
+}
+
+impl Entry<'_> {
+    /// Read the `u32` at `offset` into the entry
+    fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+}
+
+/// Iterator over the entries of the MADT, see `entries()`
+pub struct Entries<'a> {
+    /// Bytes of the entries which haven't been walked yet
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Invalid>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let &[typ, len, ..] = self.bytes else {
+            // A lone byte can't hold an entry
+            if self.bytes.is_empty() { return None; }
+            self.bytes = &[];
+            return Some(Err(Invalid::Length));
+        };
+
+        // Make sure the entry spans its type and length and fits in the table.
+        // The walk stops at the first broken entry
+        let len = len as usize;
+        if len < 2 || len > self.bytes.len() {
+            self.bytes = &[];
+            return Some(Err(Invalid::Length));
+        }
+
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(Ok(Entry { typ, bytes }))
+    }
+}
+
+/// Walk the entries of the MADT `table`, given its bytes including the header
+pub fn entries(table: &[u8]) -> Entries<'_> {
+    Entries { bytes: table.get(ENTRIES..).unwrap_or(&[]) }
+}
+
+/// A processor local APIC (or x2APIC) entry of the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicEntry {
+    /// ACPI processor UID of the processor
+    pub processor_uid: u32,
+
+    /// APIC ID of the processor
+    pub apic_id: u32,
+
+    /// The processor is enabled
+    pub enabled: bool,
+
+    /// The processor is disabled, but can be enabled at runtime. Processors
+    /// which are neither enabled nor online capable are permanently disabled
+    pub online_capable: bool,
+}
+
+impl LocalApicEntry {
+    /// Create a new entry from the processor UID, APIC ID and the MADT flags
+    pub fn new(processor_uid: u32, apic_id: u32, flags: u32) -> Self {
+        let enabled = (flags & ENABLED) != 0;
+        Self {
+            processor_uid,
+            apic_id,
+            enabled,
+            online_capable: !enabled && (flags & ONLINE_CAPABLE) != 0,
+        }
+    }
+
+    /// Decode a local APIC (type 0) or local x2APIC (type 9) `entry`. Returns
+    /// `None` for entries of other types
+    pub fn from_entry(entry: &Entry) -> Result<Option<Self>, Invalid> {
+        match (entry.typ, entry.bytes.len()) {
+            // Local APIC, with an 8-bit UID and ID
+            (0, 8) => Ok(Some(Self::new(
+                entry.bytes[2] as u32,
+                entry.bytes[3] as u32,
+                entry.read_u32(4)))),
+
+            // Local x2APIC, with the UID following the ID and the flags
+            (9, 16) => Ok(Some(Self::new(
+                entry.read_u32(12),
+                entry.read_u32(4),
+                entry.read_u32(8)))),
+
+            (0 | 9, _) => Err(Invalid::Length),
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether the processor is enabled, or can be enabled
+    pub fn usable(&self) -> bool {
+        self.enabled || self.online_capable
+    }
+}
+
+/// Walk the local APIC and local x2APIC entries of the MADT `table`, given its
+/// bytes including the header
+pub fn local_apics(table: &[u8])
+        -> impl Iterator<Item = Result<LocalApicEntry, Invalid>> + '_ {
+    entries(table).filter_map(|entry| {
+        entry.and_then(|entry| LocalApicEntry::from_entry(&entry)).transpose()
+    })
+}
@@ -0,0 +1,139 @@
+extern crate std;
+
+use super::*;
+
+use std::vec::Vec;
+
+/// Build an MCFG with `extra` bytes of entries, with a checksum making its
+/// bytes add up to zero
+fn table(extra: usize) -> Vec<u8> {
+    let mut bytes = std::vec![0; HEADER_LEN + extra];
+    bytes[..4].copy_from_slice(b"MCFG");
+    let len = bytes.len() as u32;
+    bytes[4..8].copy_from_slice(&len.to_le_bytes());
+    bytes[10..16].copy_from_slice(b"ELISE ");
+    bytes[HEADER_LEN..].iter_mut().enumerate()
+        .for_each(|(ii, byte)| *byte = ii as u8);
+
+    let sum = bytes.iter().fold(0u8, |x, &byte| x.wrapping_add(byte));
+    bytes[9] = sum.wrapping_neg();
+    bytes
+}
+
+#[test]
+fn validate_valid_table() {
+    let bytes = table(16);
+    assert!(checksum_valid(&bytes));
+    assert_eq!(validate(&bytes, b"MCFG"), Ok(&bytes[..]));
+    assert_eq!(validate(&bytes, b"APIC"), Err(Invalid::Signature));
+}
+
+#[test]
+fn validate_corrupted_table() {
+    // Corrupt a single byte of the header and of the entries
+    for offset in [24, HEADER_LEN + 3] {
+        let mut bytes = table(16);
+        bytes[offset] ^= 1;
+        assert!(!checksum_valid(&bytes));
+        assert_eq!(validate(&bytes, b"MCFG"), Err(Invalid::Checksum));
+    }
+}
+
+#[test]
+fn validate_table_length() {
+    // Only the bytes the header says belong to the table are returned
+    let mut bytes = table(8);
+    bytes.extend_from_slice(&[0xAA; 8]);
+    assert_eq!(validate(&bytes, b"MCFG"), Ok(&bytes[..HEADER_LEN + 8]));
+
+    // The table reaches past the end of the bytes
+    let bytes = table(8);
+    assert_eq!(validate(&bytes[..HEADER_LEN + 4], b"MCFG"),
+               Err(Invalid::Length));
+    assert_eq!(validate(&bytes[..HEADER_LEN - 1], b"MCFG"),
+               Err(Invalid::Length));
+
+    // The length in the header is shorter than the header
+    let mut bytes = table(0);
+    bytes[4..8].copy_from_slice(&8u32.to_le_bytes());
+    bytes[9] = bytes[9].wrapping_add(HEADER_LEN as u8 - 8);
+    assert_eq!(validate(&bytes, b"MCFG"), Err(Invalid::Length));
+}
+
+/// Build the bytes of a MADT with `entries` following its header, the local
+/// APIC address and the flags
+fn madt(entries: &[&[u8]]) -> Vec<u8> {
+    let mut bytes = std::vec![0; madt::ENTRIES];
+    bytes[..4].copy_from_slice(b"APIC");
+    entries.iter().for_each(|entry| bytes.extend_from_slice(entry));
+    let len = bytes.len() as u32;
+    bytes[4..8].copy_from_slice(&len.to_le_bytes());
+    bytes
+}
+
+#[test]
+fn madt_local_apics() {
+    use madt::LocalApicEntry;
+
+    let bytes = madt(&[
+        // Local APIC of UID 0 and ID 0, enabled
+        &[0, 8, 0, 0, 1, 0, 0, 0],
+
+        // I/O APIC, which is skipped
+        &[1, 12, 4, 0, 0, 0, 0xC0, 0xFE, 0, 0, 0, 0],
+
+        // Local APIC of UID 1 and ID 2, disabled
+        &[0, 8, 1, 2, 0, 0, 0, 0],
+
+        // Local x2APIC of ID 0x100 and UID 3, online capable
+        &[9, 16, 0, 0, 0, 1, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0],
+    ]);
+
+    let apics = madt::local_apics(&bytes).collect::<Result<Vec<_>, _>>();
+    assert_eq!(apics, Ok(std::vec![
+        LocalApicEntry {
+            processor_uid: 0, apic_id: 0,
+            enabled: true, online_capable: false,
+        },
+        LocalApicEntry {
+            processor_uid: 1, apic_id: 2,
+            enabled: false, online_capable: false,
+        },
+        LocalApicEntry {
+            processor_uid: 3, apic_id: 0x100,
+            enabled: false, online_capable: true,
+        },
+    ]));
+
+    // Only the disabled processor can't be brought up
+    let apics = apics.unwrap();
+    assert_eq!(apics.iter().filter(|apic| apic.usable()).count(), 2);
+    assert!(!apics[1].usable());
+
+    // Without the x2APIC, one of the two local APICs is usable
+    let bytes = madt(&[&[0, 8, 0, 0, 1, 0, 0, 0], &[0, 8, 1, 2, 0, 0, 0, 0]]);
+    let usable = madt::local_apics(&bytes)
+        .filter(|apic| apic.as_ref().unwrap().usable())
+        .count();
+    assert_eq!(usable, 1);
+}
+
+#[test]
+fn madt_broken_entries() {
+    // A local APIC entry of the wrong length
+    let bytes = madt(&[&[0, 6, 0, 0, 1, 0]]);
+    assert_eq!(madt::local_apics(&bytes).next(), Some(Err(Invalid::Length)));
+
+    // An entry too short for its type and length, which stops the walk
+    let bytes = madt(&[&[4, 1], &[0, 8, 0, 0, 1, 0, 0, 0]]);
+    let mut entries = madt::entries(&bytes);
+    assert_eq!(entries.next(), Some(Err(Invalid::Length)));
+    assert_eq!(entries.next(), None);
+
+    // An entry reaching past the end of the table
+    let bytes = madt(&[&[0, 8, 0, 0, 1, 0]]);
+    assert_eq!(madt::entries(&bytes).next(), Some(Err(Invalid::Length)));
+
+    // A table without any entries
+    assert_eq!(madt::entries(&madt(&[])).next(), None);
+}
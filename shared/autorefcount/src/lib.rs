@@ -20,6 +20,26 @@ impl AutoRefCount {
         self.0.load(Ordering::SeqCst)
     }
 
+    /// Panic with `msg` if the count isn't zero.
+    ///
+    /// This is meant to be called at points where no guards should be alive,
+    /// to catch guards which have been leaked (e.g. by `core::mem::forget()`)
+    /// and wedged the count
+    #[track_caller]
+    pub fn assert_zero(&self, msg: &str) {
+        let count = self.count();
+        assert!(count == 0, "{msg} (count is {count})");
+    }
+
+    /// Forcibly reset the count to zero.
+    ///
+    /// This is only valid when no guards are alive, e.g. on a reboot path
+    /// which abandons the scopes that held them. Any guard which is still
+    /// alive will panic on an underflow when it's dropped
+    pub fn reset(&self) {
+        self.0.store(0, Ordering::SeqCst);
+    }
+
     /// Increment the reference count and return the guard which will decrement
     /// the count automatically when it goes out of scope
     pub fn increment(&self) -> AutoRefCountGuard {
@@ -48,3 +48,32 @@ fn underflow_panic_on_increment_check() {
     let rc = AutoRefCount::new(usize::MAX);
     let _guard = rc.increment();
 }
+
+#[test]
+fn assert_zero_on_balanced_scopes() {
+    let rc = AutoRefCount::new(0);
+    {
+        let _guard = rc.increment();
+    }
+    rc.assert_zero("Guard leaked");
+}
+
+#[test]
+#[should_panic(expected = "Guard leaked")]
+fn assert_zero_on_leaked_guard() {
+    let rc = AutoRefCount::new(0);
+    core::mem::forget(rc.increment());
+    rc.assert_zero("Guard leaked");
+}
+
+#[test]
+fn reset_clears_leaked_guards() {
+    let rc = AutoRefCount::new(0);
+    core::mem::forget(rc.increment());
+    core::mem::forget(rc.increment());
+    assert_eq!(rc.count(), 2);
+
+    rc.reset();
+    assert_eq!(rc.count(), 0);
+    rc.assert_zero("Count not reset");
+}
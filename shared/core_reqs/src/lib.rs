@@ -1,29 +1,73 @@
 //! Requirements for The Rust Core Library™.
+//!
+//! The functions are only exported under their C names outside of tests, as
+//! the test harness links against the host's libc, which provides them already.
 
 #![no_std]
 #![allow(missing_docs)]
 
+#[cfg(test)]
+mod tests;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
 use core::arch::asm;
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize)
         -> *mut u8 {
     unsafe { memmove(dest, src, n) }
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub unsafe extern "C" fn memmove(dest: *mut u8, src: *const u8, n: usize)
         -> *mut u8 {
+    const WORD: usize = core::mem::size_of::<usize>();
+
+    // Words can only be copied if both pointers can be aligned at once
+    let words = (dest as usize % WORD) == (src as usize % WORD);
+
     // If the `src` is placed before `dest`, copy the memory backwards.
     // Thus the memory won't overwrite itself as it copies bytes.
     if src < dest as *const u8 {
         let mut i = n;
+
+        // Copy the bytes until the end is aligned, then copy whole words
+        if words {
+            while i != 0 && !(dest as usize + i).is_multiple_of(WORD) {
+                i -= 1;
+                unsafe { *dest.add(i) = *src.add(i); }
+            }
+            while i >= WORD {
+                i -= WORD;
+                unsafe {
+                    let word = (src.add(i) as *const usize).read();
+                    (dest.add(i) as *mut usize).write(word);
+                }
+            }
+        }
+
         while i != 0 {
             i -= 1;
             unsafe { *dest.offset(i as isize) = *src.offset(i as isize); }
         }
     } else {
         let mut i = 0;
+
+        // Copy the bytes until the start is aligned, then copy whole words
+        if words {
+            while i < n && !(dest as usize + i).is_multiple_of(WORD) {
+                unsafe { *dest.add(i) = *src.add(i); }
+                i += 1;
+            }
+            while n - i >= WORD {
+                unsafe {
+                    let word = (src.add(i) as *const usize).read();
+                    (dest.add(i) as *mut usize).write(word);
+                }
+                i += WORD;
+            }
+        }
+
         while i < n {
             unsafe { *dest.offset(i as isize) = *src.offset(i as isize); }
             i += 1;
@@ -32,7 +76,7 @@ pub unsafe extern "C" fn memmove(dest: *mut u8, src: *const u8, n: usize)
     dest
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub unsafe extern "C" fn memcmp(s1: *mut u8, s2: *const u8, n: usize) -> i32 {
     let mut i = 0;
     while i < n {
@@ -46,7 +90,7 @@ pub unsafe extern "C" fn memcmp(s1: *mut u8, s2: *const u8, n: usize) -> i32 {
     0
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 #[cfg(target_arch = "x86_64")]
 pub unsafe extern "C" fn memset(s: *const u8, c: i32, n: usize) -> *const u8 {
     if n == 0 { return s; }
@@ -60,7 +104,7 @@ pub unsafe extern "C" fn memset(s: *const u8, c: i32, n: usize) -> *const u8 {
     s
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 #[cfg(target_arch = "x86")]
 pub unsafe extern "C" fn memset(s: *const u8, c: i32, n: usize) -> *const u8 {
     if n == 0 { return s; }
@@ -74,7 +118,21 @@ pub unsafe extern "C" fn memset(s: *const u8, c: i32, n: usize) -> *const u8 {
     s
 }
 
-#[unsafe(no_mangle)]
+/// Portable `memset` for the targets without a `rep stosb`.
+///
+/// The bytes are written with volatile writes, as the compiler would otherwise
+/// recognize the loop and turn it into a call to `memset` itself
+#[cfg_attr(not(test), unsafe(no_mangle))]
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+pub unsafe extern "C" fn memset(s: *const u8, c: i32, n: usize) -> *const u8 {
+    let ptr = s as *mut u8;
+    for i in 0..n {
+        unsafe { ptr.add(i).write_volatile(c as u8); }
+    }
+    s
+}
+
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub unsafe extern "C" fn strlen(s: *const u8) -> usize {
     let mut i = 0;
     while unsafe { *s.offset(i as isize) } != b'\0' {
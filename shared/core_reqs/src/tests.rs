@@ -0,0 +1,89 @@
+extern crate std;
+
+use super::*;
+
+use std::vec::Vec;
+
+/// Size of the buffers the copies are done within
+const SIZE: usize = 96;
+
+/// Returns a buffer of `SIZE` bytes, each different from its neighbours
+fn pattern() -> Vec<u8> {
+    (0..SIZE).map(|x| x as u8).collect()
+}
+
+#[test]
+fn memmove_overlapping() {
+    // Cover every alignment of both pointers, in both directions, with
+    // lengths below, at and above a whole number of words
+    for src in 0..16 {
+        for dst in 0..16 {
+            for n in 0..=SIZE - 16 {
+                let mut expected = pattern();
+                expected.copy_within(src..src + n, dst);
+
+                let mut buf = pattern();
+                let ptr = buf.as_mut_ptr();
+                let ret = unsafe { memmove(ptr.add(dst), ptr.add(src), n) };
+
+                assert_eq!(ret, unsafe { ptr.add(dst) });
+                assert_eq!(buf, expected, "src {src}, dst {dst}, n {n}");
+            }
+        }
+    }
+}
+
+#[test]
+fn memcpy_disjoint() {
+    let src = pattern();
+    for src_off in 0..16 {
+        for dst_off in 0..16 {
+            for n in 0..=SIZE - 16 {
+                let mut expected = std::vec![0xAA; SIZE];
+                expected[dst_off..dst_off + n]
+                    .copy_from_slice(&src[src_off..src_off + n]);
+
+                let mut dst = std::vec![0xAA; SIZE];
+                unsafe {
+                    memcpy(dst.as_mut_ptr().add(dst_off),
+                        src.as_ptr().add(src_off), n);
+                }
+
+                assert_eq!(dst, expected,
+                    "src {src_off}, dst {dst_off}, n {n}");
+            }
+        }
+    }
+}
+
+/// Compare `a` and `b` with `memcmp`
+fn compare(a: &[u8], b: &[u8]) -> i32 {
+    assert_eq!(a.len(), b.len());
+    unsafe { memcmp(a.as_ptr() as *mut u8, b.as_ptr(), a.len()) }
+}
+
+#[test]
+fn memcmp_sign() {
+    // The sign follows the first mismatching byte, compared as unsigned
+    assert!(compare(&[1, 2, 3], &[1, 2, 4]) < 0);
+    assert!(compare(&[1, 2, 4], &[1, 2, 3]) > 0);
+    assert!(compare(&[0x00, 0xFF], &[0xFF, 0x00]) < 0);
+    assert!(compare(&[0xFF, 0x00], &[0x00, 0xFF]) > 0);
+
+    // Equal and empty ranges
+    assert_eq!(compare(&[1, 2, 3], &[1, 2, 3]), 0);
+    assert_eq!(compare(&[], &[]), 0);
+}
+
+#[test]
+fn memcmp_matches_slice_ordering() {
+    const BYTES: [u8; 6] = [0x00, 0x01, 0x7F, 0x80, 0xFE, 0xFF];
+    for a in BYTES {
+        for b in BYTES {
+            let lhs = [0x55, a, 0xAA];
+            let rhs = [0x55, b, 0xAA];
+            assert_eq!(compare(&lhs, &rhs).cmp(&0), lhs.cmp(&rhs),
+                "{a:#X} vs {b:#X}");
+        }
+    }
+}
@@ -1,8 +1,117 @@
-use crate::cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{cpuid, cpuid_subleaves};
+
+/// Cached boolean features packed by `Features::pack()`. Bit 63 is set once
+/// the cache is valid
+static CACHED_FLAGS: AtomicU64 = AtomicU64::new(0);
+
+/// Cached maximum basic (low 32 bits) and extended (high 32 bits) cpuid leaves
+static CACHED_MAX_CPUID: AtomicU64 = AtomicU64::new(0);
+
+/// Bit of `CACHED_FLAGS` signifying the cache is valid
+const CACHE_VALID: u64 = 1 << 63;
+
+/// Maximum number of caches reported in a `CacheTopology`
+const MAX_CACHES: usize = 8;
+
+/// Type of a cache as reported by the deterministic cache parameters leaves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// Parameters of a single cache, as reported by cpuid leaf 4 on Intel or leaf
+/// 0x8000001D on AMD
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheInfo {
+    /// Level of the cache, starting at 1
+    pub level: u8,
+
+    /// Type of the cache
+    pub kind: CacheType,
+
+    /// Size of a cache line in bytes
+    pub line_size: u32,
+
+    /// Number of ways of associativity
+    pub ways: u32,
+
+    /// Number of sets
+    pub sets: u32,
+
+    /// Total size of the cache in bytes
+    pub total_bytes: u64,
+}
+
+impl CacheInfo {
+    /// Decode the `eax`, `ebx` and `ecx` registers of a deterministic cache
+    /// parameters subleaf. Returns `None` if the subleaf reports an invalid
+    /// (null) cache type, which terminates the list of caches
+    pub fn decode(eax: u32, ebx: u32, ecx: u32) -> Option<Self> {
+        let kind = match eax & 0x1F {
+            1 => CacheType::Data,
+            2 => CacheType::Instruction,
+            3 => CacheType::Unified,
+            _ => return None,
+        };
+
+        // All of the fields are encoded as their value minus one
+        let line_size  = (ebx & 0xFFF) + 1;
+        let partitions = ((ebx >> 12) & 0x3FF) + 1;
+        let ways       = ((ebx >> 22) & 0x3FF) + 1;
+        let sets       = ecx.wrapping_add(1);
+
+        Some(Self {
+            level: ((eax >> 5) & 7) as u8,
+            kind,
+            line_size,
+            ways,
+            sets,
+            total_bytes: line_size as u64 * partitions as u64 *
+                ways as u64 * sets as u64,
+        })
+    }
+}
+
+/// Caches of this core, in the order reported by the CPU
+#[derive(Debug, Clone, Copy)]
+pub struct CacheTopology {
+    /// Reported caches, followed by `None`s
+    caches: [Option<CacheInfo>; MAX_CACHES],
+}
+
+impl CacheTopology {
+    /// Decode the caches reported by the deterministic cache parameters
+    /// `subleaves`, up to the first one reporting an invalid cache type
+    pub(crate) fn from_subleaves<I>(subleaves: I) -> Self
+            where I: Iterator<Item = (u32, u32, u32, u32)> {
+        let mut topology = Self { caches: [None; MAX_CACHES] };
+        let caches = subleaves
+            .map_while(|(eax, ebx, ecx, _)| CacheInfo::decode(eax, ebx, ecx));
+        topology.caches.iter_mut().zip(caches)
+            .for_each(|(slot, cache)| *slot = Some(cache));
+        topology
+    }
+
+    /// Returns the reported caches
+    pub fn caches(&self) -> impl Iterator<Item = &CacheInfo> {
+        self.caches.iter().flatten()
+    }
+
+    /// Returns the data (or unified) cache at `level`, if there is one
+    pub fn data_cache(&self, level: u8) -> Option<&CacheInfo> {
+        self.caches().find(|cache| {
+            cache.level == level && cache.kind != CacheType::Instruction
+        })
+    }
+}
 
 /// Structure representing the various CPU features which are supported on this
 /// system. These can be detected with the `get_cpu_features` function
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, Copy)]
 pub struct Features {
     pub max_cpuid: u32,
     pub max_extended_cpuid: u32,
@@ -39,62 +148,182 @@ pub struct Features {
     pub rdtscp: bool,
     pub bits64: bool,
 
+    pub avx2: bool,
     pub avx512f: bool,
+
+    pub la57: bool,
+
+    pub smep: bool,
+    pub smap: bool,
+    pub umip: bool,
 }
 
-impl Features {
-    /// Returns the set of CPU features
-    pub fn get() -> Self {
-        let mut features: Self = Default::default();
-
-        unsafe {
-            features.max_cpuid          = cpuid(0, 0).0;
-            features.max_extended_cpuid = cpuid(0x80000000, 0).0;
-
-            if features.max_cpuid >= 1 {
-                let cpuid_1   = cpuid(1, 0);
-                features.fpu  = ((cpuid_1.3 >>  0) & 1) == 1;
-                features.vme  = ((cpuid_1.3 >>  1) & 1) == 1;
-                features.de   = ((cpuid_1.3 >>  2) & 1) == 1;
-                features.pse  = ((cpuid_1.3 >>  3) & 1) == 1;
-                features.tsc  = ((cpuid_1.3 >>  4) & 1) == 1;
-                features.apic = ((cpuid_1.3 >>  9) & 1) == 1;
-                features.mmx  = ((cpuid_1.3 >> 23) & 1) == 1;
-                features.fxsr = ((cpuid_1.3 >> 24) & 1) == 1;
-                features.sse  = ((cpuid_1.3 >> 25) & 1) == 1;
-                features.sse2 = ((cpuid_1.3 >> 26) & 1) == 1;
-                features.htt  = ((cpuid_1.3 >> 28) & 1) == 1;
-
-                features.sse3    = ((cpuid_1.2 >>  0) & 1) == 1;
-                features.vmx     = ((cpuid_1.2 >>  5) & 1) == 1;
-                features.ssse3   = ((cpuid_1.2 >>  9) & 1) == 1;
-                features.sse4_1  = ((cpuid_1.2 >> 19) & 1) == 1;
-                features.sse4_2  = ((cpuid_1.2 >> 20) & 1) == 1;
-                features.x2apic  = ((cpuid_1.2 >> 21) & 1) == 1;
-                features.aesni   = ((cpuid_1.2 >> 25) & 1) == 1;
-                features.xsave   = ((cpuid_1.2 >> 26) & 1) == 1;
-                features.avx     = ((cpuid_1.2 >> 28) & 1) == 1;
+/// Implement packing of the boolean `fields` of `Features` into a `u64`
+macro_rules! packed_flags {
+    ($($field:ident),* $(,)?) => {
+        impl Features {
+            /// Pack the boolean features into the low bits of a `u64`
+            fn pack(&self) -> u64 {
+                let mut bit = 0..;
+                let mut flags = 0u64;
+                $( flags |= (self.$field as u64) << bit.next().unwrap(); )*
+                flags
             }
 
-            // Detect AVX-512 support
-            if features.max_cpuid >= 7 {
-                let cpuid_7 = cpuid(7, 0);
-                features.avx512f = ((cpuid_7.1 >> 16) & 1) == 1;
+            /// Unpack the boolean features from `flags` created by `pack()`
+            fn unpack(&mut self, flags: u64) {
+                let mut bit = 0..;
+                $( self.$field = ((flags >> bit.next().unwrap()) & 1) == 1; )*
             }
+        }
+    };
+}
+
+packed_flags!(
+    fpu, vme, de, pse, tsc, mmx, fxsr, sse, sse2, htt, sse3, ssse3, sse4_1,
+    sse4_2, x2apic, aesni, xsave, avx, apic, vmx, lahf, lzcnt, prefetchw,
+    syscall, xd, gbyte_pages, rdtscp, bits64, avx512f, avx2, la57, smep,
+    smap, umip,
+);
 
-            if features.max_extended_cpuid >= 0x80000001 {
-                let cpuid_e1 = cpuid(0x80000001, 0);
+impl Features {
+    /// Returns the set of CPU features, probing them on the first call and
+    /// returning a cached copy afterwards.
+    ///
+    /// `cpuid` results are invariant for the duration of a boot, so this is
+    /// safe to use instead of `get()`. The cache is per-image, not per-core;
+    /// all cores are assumed to report the same features, which holds for the
+    /// APIC/x2APIC bits on supported configurations.
+    pub fn get_cached() -> Self {
+        // Return the cached features if they're valid
+        let flags = CACHED_FLAGS.load(Ordering::Acquire);
+        if (flags & CACHE_VALID) != 0 {
+            let max_cpuid = CACHED_MAX_CPUID.load(Ordering::Relaxed);
+
+            let mut features = Self {
+                max_cpuid:          max_cpuid as u32,
+                max_extended_cpuid: (max_cpuid >> 32) as u32,
+                ..Default::default()
+            };
+            features.unpack(flags);
+            return features;
+        }
 
-                features.lahf      = ((cpuid_e1.2 >> 0) & 1) == 1;
-                features.lzcnt     = ((cpuid_e1.2 >> 5) & 1) == 1;
-                features.prefetchw = ((cpuid_e1.2 >> 8) & 1) == 1;
+        // Probe the features and cache them. Racing cores store the same
+        // values, so there's no need to synchronize them
+        let features = Self::get();
+        CACHED_MAX_CPUID.store(
+            (features.max_extended_cpuid as u64) << 32
+                | features.max_cpuid as u64,
+            Ordering::Relaxed);
+        CACHED_FLAGS.store(features.pack() | CACHE_VALID, Ordering::Release);
 
-                features.syscall     = ((cpuid_e1.3 >> 11) & 1) == 1;
-                features.xd          = ((cpuid_e1.3 >> 20) & 1) == 1;
-                features.gbyte_pages = ((cpuid_e1.3 >> 26) & 1) == 1;
-                features.rdtscp      = ((cpuid_e1.3 >> 27) & 1) == 1;
-                features.bits64      = ((cpuid_e1.3 >> 29) & 1) == 1;
-            }
+        features
+    }
+
+    /// Returns the cache topology of this core, read from the deterministic
+    /// cache parameters leaf (4 on Intel, 0x8000001D on AMD). The topology is
+    /// empty if the leaf isn't supported.
+    pub fn cache_info(&self) -> CacheTopology {
+        // Pick the leaf based on the vendor; "Genu" is Intel, "Auth" is AMD
+        let vendor = unsafe { cpuid(0, 0).1 };
+        let leaf = match vendor {
+            0x756E6547 if self.max_cpuid >= 4 => 4,
+            0x68747541 if self.max_extended_cpuid >= 0x8000001D => 0x8000001D,
+            _ => return CacheTopology::from_subleaves(core::iter::empty()),
+        };
+
+        CacheTopology::from_subleaves(cpuid_subleaves(leaf))
+    }
+
+    /// Returns the size in bytes of an XSAVE area large enough for all of the
+    /// state components supported by this core, or `None` if XSAVE isn't
+    /// supported.
+    ///
+    /// The AVX bits only report what the CPU supports. Before AVX state can be
+    /// used (and saved), CR4.OSXSAVE has to be set and the AVX components
+    /// enabled in XCR0 with `xsetbv`
+    pub fn xsave_area_size(&self) -> Option<usize> {
+        self.xsave_area_size_with(|eax, ecx| unsafe { cpuid(eax, ecx) })
+    }
+
+    /// Same as [`Features::xsave_area_size`], except the cpuid is performed by
+    /// `backend`, which gets passed eax and ecx
+    pub fn xsave_area_size_with<F>(&self, mut backend: F) -> Option<usize>
+            where F: FnMut(u32, u32) -> (u32, u32, u32, u32) {
+        if !self.xsave || self.max_cpuid < 0xD { return None; }
+        Some(backend(0xD, 0).2 as usize)
+    }
+
+    /// Returns the line size of the L1 data cache in bytes, if it's reported
+    pub fn cache_line_size(&self) -> Option<u32> {
+        self.cache_info().data_cache(1).map(|cache| cache.line_size)
+    }
+
+    /// Probes and returns the set of CPU features, bypassing the cache
+    pub fn get() -> Self {
+        Self::get_with(|eax, ecx| unsafe { cpuid(eax, ecx) })
+    }
+
+    /// Same as [`Features::get`], except the cpuid is performed by `backend`,
+    /// which gets passed eax and ecx
+    pub fn get_with<F>(mut backend: F) -> Self
+            where F: FnMut(u32, u32) -> (u32, u32, u32, u32) {
+        let mut features = Self {
+            max_cpuid:          backend(0, 0).0,
+            max_extended_cpuid: backend(0x80000000, 0).0,
+            ..Default::default()
+        };
+
+        if features.max_cpuid >= 1 {
+            let cpuid_1   = backend(1, 0);
+            features.fpu  = (cpuid_1.3 & 1) == 1;
+            features.vme  = ((cpuid_1.3 >>  1) & 1) == 1;
+            features.de   = ((cpuid_1.3 >>  2) & 1) == 1;
+            features.pse  = ((cpuid_1.3 >>  3) & 1) == 1;
+            features.tsc  = ((cpuid_1.3 >>  4) & 1) == 1;
+            features.apic = ((cpuid_1.3 >>  9) & 1) == 1;
+            features.mmx  = ((cpuid_1.3 >> 23) & 1) == 1;
+            features.fxsr = ((cpuid_1.3 >> 24) & 1) == 1;
+            features.sse  = ((cpuid_1.3 >> 25) & 1) == 1;
+            features.sse2 = ((cpuid_1.3 >> 26) & 1) == 1;
+            features.htt  = ((cpuid_1.3 >> 28) & 1) == 1;
+
+            features.sse3    = (cpuid_1.2 & 1) == 1;
+            features.vmx     = ((cpuid_1.2 >>  5) & 1) == 1;
+            features.ssse3   = ((cpuid_1.2 >>  9) & 1) == 1;
+            features.sse4_1  = ((cpuid_1.2 >> 19) & 1) == 1;
+            features.sse4_2  = ((cpuid_1.2 >> 20) & 1) == 1;
+            features.x2apic  = ((cpuid_1.2 >> 21) & 1) == 1;
+            features.aesni   = ((cpuid_1.2 >> 25) & 1) == 1;
+            features.xsave   = ((cpuid_1.2 >> 26) & 1) == 1;
+            features.avx     = ((cpuid_1.2 >> 28) & 1) == 1;
+        }
+
+        // Detect AVX2, AVX-512, 5-level paging and the supervisor
+        // protections
+        if features.max_cpuid >= 7 {
+            let cpuid_7 = backend(7, 0);
+            features.smep    = ((cpuid_7.1 >>  7) & 1) == 1;
+            features.avx2    = ((cpuid_7.1 >>  5) & 1) == 1;
+            features.avx512f = ((cpuid_7.1 >> 16) & 1) == 1;
+            features.smap    = ((cpuid_7.1 >> 20) & 1) == 1;
+            features.umip    = ((cpuid_7.2 >>  2) & 1) == 1;
+            features.la57    = ((cpuid_7.2 >> 16) & 1) == 1;
+        }
+
+        if features.max_extended_cpuid >= 0x80000001 {
+            let cpuid_e1 = backend(0x80000001, 0);
+
+            features.lahf      = (cpuid_e1.2 & 1) == 1;
+            features.lzcnt     = ((cpuid_e1.2 >> 5) & 1) == 1;
+            features.prefetchw = ((cpuid_e1.2 >> 8) & 1) == 1;
+
+            features.syscall     = ((cpuid_e1.3 >> 11) & 1) == 1;
+            features.xd          = ((cpuid_e1.3 >> 20) & 1) == 1;
+            features.gbyte_pages = ((cpuid_e1.3 >> 26) & 1) == 1;
+            features.rdtscp      = ((cpuid_e1.3 >> 27) & 1) == 1;
+            features.bits64      = ((cpuid_e1.3 >> 29) & 1) == 1;
         }
 
         features
@@ -2,11 +2,23 @@
 
 #![no_std]
 
+#[cfg(test)]
+mod tests;
+
 mod features;
 pub use features::*;
 
 use core::arch::asm;
 
+/// Trigger a breakpoint exception.
+///
+/// This is a trap, so once the exception is handled, execution continues
+/// right after the `int3`
+#[inline(always)]
+pub fn int3() {
+    unsafe { asm!("int3"); }
+}
+
 /// Halts the core in a loop forever
 #[inline]
 pub fn halt() -> ! {
@@ -17,18 +29,34 @@ pub fn halt() -> ! {
 }
 
 /// Disables the interrupts on this core
+///
+/// # Safety
+///
+/// The caller must make sure nothing relies on interrupts being delivered to
+/// this core while they're disabled
 #[inline]
 pub unsafe fn disable_interrupts() {
     unsafe { asm!("cli"); }
 }
 
-/// Disables the interrupts on this core
+/// Enables the interrupts on this core
+///
+/// # Safety
+///
+/// The IDT of this core must be set up to handle any interrupt that can be
+/// delivered, and the caller must not hold state that an interrupt handler
+/// could touch
 #[inline]
 pub unsafe fn enable_interrupts() {
     unsafe { asm!("sti"); }
 }
 
 /// Read a byte from I/O port `addr`
+///
+/// # Safety
+///
+/// Accessing I/O ports has side effects on the devices behind them, so the
+/// caller must own the device at `addr` and know the access is valid for it
 #[inline]
 pub unsafe fn in8(addr: u16) -> u8 {
     let mut byte: u8;
@@ -37,12 +65,46 @@ pub unsafe fn in8(addr: u16) -> u8 {
 }
 
 /// Write a `byte` to I/O port `addr`
+///
+/// # Safety
+///
+/// Accessing I/O ports has side effects on the devices behind them, so the
+/// caller must own the device at `addr` and know the access is valid for it
 #[inline]
 pub unsafe fn out8(addr: u16, byte: u8) {
     unsafe { asm!("out dx, al", in("dx") addr, in("al") byte) };
 }
 
+/// Read a word from I/O port `addr`
+///
+/// # Safety
+///
+/// Accessing I/O ports has side effects on the devices behind them, so the
+/// caller must own the device at `addr` and know the access is valid for it
+#[inline]
+pub unsafe fn in16(addr: u16) -> u16 {
+    let mut word: u16;
+    unsafe { asm!("in ax, dx", in("dx") addr, out("ax") word) };
+    word
+}
+
+/// Write a `word` to I/O port `addr`
+///
+/// # Safety
+///
+/// Accessing I/O ports has side effects on the devices behind them, so the
+/// caller must own the device at `addr` and know the access is valid for it
+#[inline]
+pub unsafe fn out16(addr: u16, word: u16) {
+    unsafe { asm!("out dx, ax", in("dx") addr, in("ax") word) };
+}
+
 /// Read bytes from I/O port `addr`
+///
+/// # Safety
+///
+/// Accessing I/O ports has side effects on the devices behind them, so the
+/// caller must own the device at `addr` and know the access is valid for it
 #[inline]
 pub unsafe fn in32(addr: u16) -> u32 {
     let mut bytes: u32;
@@ -51,12 +113,21 @@ pub unsafe fn in32(addr: u16) -> u32 {
 }
 
 /// Write `bytes` to I/O port `addr`
+///
+/// # Safety
+///
+/// Accessing I/O ports has side effects on the devices behind them, so the
+/// caller must own the device at `addr` and know the access is valid for it
 #[inline]
 pub unsafe fn out32(addr: u16, bytes: u32) {
     unsafe { asm!("out dx, eax", in("dx") addr, in("eax") bytes) };
 }
 
 /// Read a value from the Model-Specific Register `msr`
+///
+/// # Safety
+///
+/// `msr` must be supported by the core, otherwise this raises a #GP
 #[inline]
 pub unsafe fn rdmsr(msr: u32) -> u64 {
     let high: u32;
@@ -66,6 +137,12 @@ pub unsafe fn rdmsr(msr: u32) -> u64 {
 }
 
 /// Write a 64-bit `val` to the Model-Specific Register `msr`
+///
+/// # Safety
+///
+/// `msr` must be supported by the core and `val` must be valid for it.
+/// Writing MSRs can change how the core operates, e.g. the paging or the
+/// segment bases, so the caller must make sure nothing relies on the old value
 #[inline]
 pub unsafe fn wrmsr(msr: u32, val: u64) {
     let high = (val >> 32) as u32;
@@ -73,11 +150,93 @@ pub unsafe fn wrmsr(msr: u32, val: u64) {
     unsafe { asm!("wrmsr", in("ecx") msr, in("edx") high, in("eax") low) };
 }
 
+/// Model-Specific Registers used by the kernel and the bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Msr {
+    /// `IA32_APIC_BASE`: local APIC base address and enable bits
+    ApicBase,
+
+    /// `IA32_TSC_DEADLINE`: TSC value at which the APIC timer fires in
+    /// TSC-deadline mode
+    TscDeadline,
+
+    /// `IA32_PAT`: page attribute table
+    Pat,
+
+    /// `IA32_EFER`: extended feature enables (long mode, NXE, ...)
+    Efer,
+
+    /// `IA32_FS_BASE`: base address of the FS segment
+    FsBase,
+
+    /// `IA32_GS_BASE`: base address of the GS segment
+    GsBase,
+
+    /// `IA32_KERNEL_GS_BASE`: GS base swapped in by `swapgs`
+    KernelGsBase,
+
+    /// Any other MSR by its number, e.g. the computed x2APIC registers
+    Raw(u32),
+}
+
+impl Msr {
+    /// Get the MSR number of this register
+    #[inline]
+    pub const fn number(self) -> u32 {
+        match self {
+            Msr::ApicBase     => 0x1B,
+            Msr::TscDeadline  => 0x6E0,
+            Msr::Pat          => 0x277,
+            Msr::Efer         => 0xC000_0080,
+            Msr::FsBase       => 0xC000_0100,
+            Msr::GsBase       => 0xC000_0101,
+            Msr::KernelGsBase => 0xC000_0102,
+            Msr::Raw(msr)     => msr,
+        }
+    }
+
+    /// Read the value of this MSR
+    ///
+    /// # Safety
+    ///
+    /// See [`rdmsr`]
+    #[inline]
+    pub unsafe fn read(self) -> u64 {
+        unsafe { rdmsr(self.number()) }
+    }
+
+    /// Write a 64-bit `val` to this MSR
+    ///
+    /// # Safety
+    ///
+    /// See [`wrmsr`]
+    #[inline]
+    pub unsafe fn write(self, val: u64) {
+        unsafe { wrmsr(self.number(), val) };
+    }
+}
+
+/// Load the page attribute table into the `IA32_PAT` MSR
+///
+/// # Safety
+///
+/// Changing the PAT changes the memory types of every mapping using it, so
+/// the caller must make sure the mappings still get the memory types they
+/// rely on, and flush the TLB and the caches as needed
+#[inline]
+pub unsafe fn set_pat(pat: u64) {
+    unsafe { Msr::Pat.write(pat) };
+}
+
 /// Set the GS base
+///
+/// # Safety
+///
+/// The kernel accesses its core locals through GS, so `base` must point to
+/// whatever GS based accesses expect to find there
 #[inline]
 pub unsafe fn set_gs_base(base: u64) {
-    const IA32_GS_BASE: u32 = 0xC0000101;
-    unsafe { wrmsr(IA32_GS_BASE, base) };
+    unsafe { Msr::GsBase.write(base) };
 }
 
 /// Calls RDTSC
@@ -86,6 +245,23 @@ pub fn rdtsc() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() as u64 }
 }
 
+/// Busy wait for `ticks` TSC cycles.
+///
+/// This takes raw cycles, not microseconds, so it can be used before the TSC
+/// frequency is known. The wall time it takes depends on the TSC frequency
+#[inline]
+pub fn tsc_delay(ticks: u64) {
+    let start = rdtsc();
+    while rdtsc().wrapping_sub(start) < ticks { core::hint::spin_loop(); }
+}
+
+/// Delay for roughly a microsecond by writing to the unused POST code port
+/// `0x80`, the classic delay between accesses to slow legacy devices
+#[inline]
+pub fn io_delay() {
+    unsafe { out8(0x80, 0); }
+}
+
 /// Canonicalizes the `addr`, making sure the highest `high_bits` are the same.
 #[inline]
 pub const fn canonicalize_address(high_bits: usize, addr: u64) -> u64 {
@@ -101,8 +277,82 @@ pub fn read_cr2() -> u64 {
     cr2
 }
 
+/// CR4 bit enabling user-mode instruction prevention
+pub const CR4_UMIP: u64 = 1 << 11;
+
+/// CR4 bit enabling 5-level paging
+pub const CR4_LA57: u64 = 1 << 12;
+
+/// CR4 bit enabling supervisor mode execution prevention
+pub const CR4_SMEP: u64 = 1 << 20;
+
+/// CR4 bit enabling supervisor mode access prevention
+pub const CR4_SMAP: u64 = 1 << 21;
+
+/// Read `cr4`
+#[inline]
+pub fn read_cr4() -> u64 {
+    let mut cr4: u64;
+    unsafe { asm!("mov {}, cr4", out(reg) cr4); }
+    cr4
+}
+
+/// Write `val` to `cr4`
+///
+/// # Safety
+///
+/// `cr4` controls the paging and the protections of this core, so `val` must
+/// be valid and only enable features the core supports
+#[inline]
+pub unsafe fn write_cr4(val: u64) {
+    unsafe { asm!("mov cr4, {}", in(reg) val); }
+}
+
+/// Enable the supervisor protections selected by the arguments on this core,
+/// leaving the others as they are:
+///
+/// * `smep` prevents the kernel from executing user pages
+/// * `smap` prevents the kernel from accessing user pages
+/// * `umip` prevents user mode from executing `sgdt`, `sidt`, `sldt`, `smsw`
+///   and `str`
+///
+/// The protections must be supported by the core, see `Features`. Once SMAP is
+/// enabled, any legitimate access to user memory has to be wrapped in
+/// `stac`/`clac`, which the kernel doesn't do yet
+///
+/// # Safety
+///
+/// The selected protections must be supported by the core, and nothing may
+/// rely on the access they take away
+pub unsafe fn enable_protections(smep: bool, smap: bool, umip: bool) {
+    let bits = protection_bits(smep, smap, umip);
+    unsafe { write_cr4(read_cr4() | bits); }
+}
+
+/// Returns the CR4 bits enabling the protections selected by the arguments,
+/// see [`enable_protections`]
+const fn protection_bits(smep: bool, smap: bool, umip: bool) -> u64 {
+    (if smep { CR4_SMEP } else { 0 })
+        | (if smap { CR4_SMAP } else { 0 })
+        | (if umip { CR4_UMIP } else { 0 })
+}
+
+/// Flush all of the non-global TLB entries of this core by reloading `cr3`
+///
+/// # Safety
+///
+/// The current page table must map the code and the stack this is running on
+#[inline]
+pub unsafe fn flush_tlb() {
+    unsafe { asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _); }
+}
+
 /// Performs cpuid passing in eax and ecx as parameters. Returns a tuple
 /// containing the resulting (eax, ebx, ecx, edx)
+///
+/// # Safety
+///
+/// The core must support cpuid, which every x86_64 core does
 #[inline]
 pub unsafe fn cpuid(eax: u32, ecx: u32) -> (u32, u32, u32, u32) {
     let mut oeax: u32;
@@ -125,3 +375,34 @@ pub unsafe fn cpuid(eax: u32, ecx: u32) -> (u32, u32, u32, u32) {
 
     (oeax, oebx, oecx, oedx)
 }
+
+/// Returns the highest basic cpuid leaf supported by the CPU
+#[inline]
+pub fn max_leaf() -> u32 {
+    unsafe { cpuid(0, 0).0 }
+}
+
+/// Returns the highest extended cpuid leaf supported by the CPU
+#[inline]
+pub fn max_ext_leaf() -> u32 {
+    unsafe { cpuid(0x80000000, 0).0 }
+}
+
+/// Returns an iterator performing cpuid on `leaf` with incrementing subleaves
+/// (ecx = 0, 1, 2, ...), yielding the resulting (eax, ebx, ecx, edx).
+///
+/// The terminator differs between leaves, so it's up to the caller to stop
+/// the iteration. `leaf` should be supported by the CPU, see [`max_leaf`] and
+/// [`max_ext_leaf`]
+pub fn cpuid_subleaves(leaf: u32)
+        -> impl Iterator<Item = (u32, u32, u32, u32)> {
+    cpuid_subleaves_with(leaf, |eax, ecx| unsafe { cpuid(eax, ecx) })
+}
+
+/// Same as [`cpuid_subleaves`], except the cpuid is performed by `backend`,
+/// which gets passed eax and ecx
+pub fn cpuid_subleaves_with<F>(leaf: u32, mut backend: F)
+        -> impl Iterator<Item = (u32, u32, u32, u32)>
+        where F: FnMut(u32, u32) -> (u32, u32, u32, u32) {
+    (0..=u32::MAX).map(move |subleaf| backend(leaf, subleaf))
+}
@@ -0,0 +1,198 @@
+use super::*;
+
+/// Deterministic cache parameters subleaves (eax, ebx, ecx) of a core with a
+/// 32 KiB L1D and L1I, a 256 KiB L2 and an 8 MiB L3, all with 64-byte lines,
+/// terminated by a null cache type
+const CACHE_SUBLEAVES: [(u32, u32, u32); 5] = [
+    (0x121, 0x01C0_003F, 63),
+    (0x122, 0x01C0_003F, 63),
+    (0x143, 0x00C0_003F, 1023),
+    (0x163, 0x03C0_003F, 8191),
+    (0x000, 0x0000_0000, 0),
+];
+
+/// Decode `CACHE_SUBLEAVES` into a topology
+fn topology() -> CacheTopology {
+    CacheTopology::from_subleaves(CACHE_SUBLEAVES.iter()
+        .map(|&(eax, ebx, ecx)| (eax, ebx, ecx, 0)))
+}
+
+#[test]
+fn cache_decode() {
+    let (eax, ebx, ecx) = CACHE_SUBLEAVES[0];
+    assert_eq!(CacheInfo::decode(eax, ebx, ecx), Some(CacheInfo {
+        level:       1,
+        kind:        CacheType::Data,
+        line_size:   64,
+        ways:        8,
+        sets:        64,
+        total_bytes: 32 * 1024,
+    }));
+
+    let (eax, ebx, ecx) = CACHE_SUBLEAVES[3];
+    assert_eq!(CacheInfo::decode(eax, ebx, ecx), Some(CacheInfo {
+        level:       3,
+        kind:        CacheType::Unified,
+        line_size:   64,
+        ways:        16,
+        sets:        8192,
+        total_bytes: 8 * 1024 * 1024,
+    }));
+}
+
+#[test]
+fn cache_decode_partitions() {
+    // Two physical line partitions double the size of the cache
+    let cache = CacheInfo::decode(0x121, 0x01C0_103F, 63).unwrap();
+    assert_eq!(cache.total_bytes, 64 * 1024);
+}
+
+#[test]
+fn cache_decode_null() {
+    assert_eq!(CacheInfo::decode(0, 0x01C0_003F, 63), None);
+
+    // Types 4 to 31 are reserved
+    assert_eq!(CacheInfo::decode(0x124, 0x01C0_003F, 63), None);
+}
+
+#[test]
+fn cache_topology() {
+    let topology = topology();
+    assert_eq!(topology.caches().count(), 4);
+
+    // The instruction cache is skipped when looking for data caches
+    let l1d = topology.data_cache(1).unwrap();
+    assert_eq!(l1d.kind, CacheType::Data);
+    assert_eq!(l1d.line_size, 64);
+
+    assert_eq!(topology.data_cache(2).unwrap().total_bytes, 256 * 1024);
+    assert_eq!(topology.data_cache(3).unwrap().kind, CacheType::Unified);
+    assert_eq!(topology.data_cache(4), None);
+}
+
+#[test]
+fn subleaves_increment() {
+    // Echo the leaf and the subleaf back
+    let mut subleaves = cpuid_subleaves_with(0xD, |eax, ecx| {
+        (eax, ecx, !ecx, eax ^ ecx)
+    });
+
+    assert_eq!(subleaves.next(), Some((0xD, 0, !0, 0xD)));
+    assert_eq!(subleaves.next(), Some((0xD, 1, !1, 0xC)));
+    assert_eq!(subleaves.next(), Some((0xD, 2, !2, 0xF)));
+}
+
+#[test]
+fn subleaves_lazy() {
+    // Only the subleaves which are consumed are queried
+    let mut calls = 0;
+    let found = cpuid_subleaves_with(4, |_, ecx| {
+        calls += 1;
+        (if ecx == 3 { 0 } else { 1 }, 0, 0, 0)
+    }).position(|(eax, ..)| eax == 0);
+
+    assert_eq!(found, Some(3));
+    assert_eq!(calls, 4);
+}
+
+#[test]
+fn max_leaves() {
+    // Every x86_64 CPU supports the feature leaves
+    assert!(max_leaf() >= 1);
+    assert!(max_ext_leaf() >= 0x8000_0001);
+}
+
+/// Registers returned by cpuid, (eax, ebx, ecx, edx)
+type Regs = (u32, u32, u32, u32);
+
+/// Returns a cpuid backend answering with the `leaves`, given as
+/// (leaf, subleaf, result). Unlisted leaves return all zeroes
+fn leaves(leaves: &[(u32, u32, Regs)]) -> impl FnMut(u32, u32) -> Regs + '_ {
+    |eax, ecx| leaves.iter()
+        .find(|&&(leaf, subleaf, _)| (leaf, subleaf) == (eax, ecx))
+        .map_or((0, 0, 0, 0), |&(_, _, regs)| regs)
+}
+
+#[test]
+fn vector_extensions() {
+    let backend = leaves(&[
+        (0, 0, (0xD, 0, 0, 0)),
+        (1, 0, (0, 0, 1 << 28, 0)),
+        (7, 0, (0, (1 << 5) | (1 << 16), 0, 0)),
+    ]);
+    let features = Features::get_with(backend);
+    assert!(features.avx);
+    assert!(features.avx2);
+    assert!(features.avx512f);
+
+    // AVX2 without AVX-512
+    let backend = leaves(&[
+        (0, 0, (7, 0, 0, 0)),
+        (1, 0, (0, 0, 1 << 28, 0)),
+        (7, 0, (0, 1 << 5, 0, 0)),
+    ]);
+    let features = Features::get_with(backend);
+    assert!(features.avx && features.avx2 && !features.avx512f);
+}
+
+#[test]
+fn vector_extensions_unsupported_leaf() {
+    // Leaf 7 must not be read if the CPU doesn't report it
+    let backend = leaves(&[
+        (0, 0, (1, 0, 0, 0)),
+        (1, 0, (0, 0, 1 << 28, 0)),
+        (7, 0, (0, !0, !0, !0)),
+    ]);
+    let features = Features::get_with(backend);
+    assert!(features.avx);
+    assert!(!features.avx2 && !features.avx512f);
+}
+
+#[test]
+fn xsave_area_size() {
+    let cpuid = [
+        (0,   0, (0xD, 0, 0, 0)),
+        (1,   0, (0, 0, 1 << 26, 0)),
+        (0xD, 0, (0, 0x340, 0x988, 0)),
+    ];
+    let features = Features::get_with(leaves(&cpuid));
+    assert!(features.xsave);
+    assert_eq!(features.xsave_area_size_with(leaves(&cpuid)), Some(0x988));
+
+    // No XSAVE, no area
+    let features = Features { xsave: false, ..features };
+    assert_eq!(features.xsave_area_size_with(leaves(&cpuid)), None);
+
+    // Leaf 0xD isn't supported
+    let features = Features { xsave: true, max_cpuid: 7, ..features };
+    assert_eq!(features.xsave_area_size_with(leaves(&cpuid)), None);
+}
+
+#[test]
+fn supervisor_protections() {
+    // Each of the protections on its own, so the bits can't be mixed up
+    let cases = [
+        ((1 << 7, 0), (true, false, false)),
+        ((1 << 20, 0), (false, true, false)),
+        ((0, 1 << 2), (false, false, true)),
+        (((1 << 7) | (1 << 20), 1 << 2), (true, true, true)),
+        ((!((1 << 7) | (1 << 20)), !(1 << 2)), (false, false, false)),
+    ];
+
+    for ((ebx, ecx), expected) in cases {
+        let cpuid = [(0, 0, (7, 0, 0, 0)), (7, 0, (0, ebx, ecx, 0))];
+        let features = Features::get_with(leaves(&cpuid));
+        assert_eq!((features.smep, features.smap, features.umip), expected,
+            "ebx {ebx:#x}, ecx {ecx:#x}");
+    }
+}
+
+#[test]
+fn supervisor_protections_bits() {
+    assert_eq!(protection_bits(false, false, false), 0);
+    assert_eq!(protection_bits(true, false, false), CR4_SMEP);
+    assert_eq!(protection_bits(false, true, false), CR4_SMAP);
+    assert_eq!(protection_bits(false, false, true), CR4_UMIP);
+    assert_eq!(protection_bits(true, true, true),
+        (1 << 20) | (1 << 21) | (1 << 11));
+}
@@ -97,10 +97,10 @@ impl<'a, T: Copy> Cursor<'a, T> {
             Some(self.total_pos)
 
         // If we're setting the position further than it is, make sure we're
-        // not going over the limit
+        // not going over the limit or the buffer
         } else if pos > self.pos {
             let total = self.total_pos + (pos - self.pos);
-            if total > self.limit {
+            if total > self.limit || pos > self.inner.len() {
                 return None;
             }
 
@@ -117,6 +117,20 @@ impl<'a, T: Copy> Cursor<'a, T> {
         }
     }
 
+    /// Gets the overall position up to which the cursor can be written, which
+    /// is bound by both the limit and the length of the underlying buffer
+    pub const fn capacity(&self) -> usize {
+        // The overall position at which the current buffer starts
+        let start = self.total_pos - self.pos;
+        let end = start.saturating_add(self.inner.len());
+        if end < self.limit { end } else { self.limit }
+    }
+
+    /// Gets the number of elements which can still be written to the cursor
+    pub const fn remaining(&self) -> usize {
+        self.capacity().saturating_sub(self.total_pos)
+    }
+
     /// Gets the current size limit for the underlying buffer
     pub const fn limit(&self) -> usize {
         self.limit
@@ -139,6 +153,30 @@ impl<'a, T: Copy> Cursor<'a, T> {
         }
     }
 
+    /// Returns a checkpoint of the current position over all splits, which can
+    /// be restored with `rewind_to()`
+    pub const fn checkpoint(&self) -> usize {
+        self.total_pos
+    }
+
+    /// Rewinds the cursor back to a `checkpoint` returned by `checkpoint()`,
+    /// discarding everything written after it.
+    ///
+    /// Returns `None` and leaves the cursor untouched if the checkpoint is not
+    /// within the current buffer, i.e. it lies before the split that created
+    /// this cursor or past the current position.
+    pub const fn rewind_to(&mut self, checkpoint: usize) -> Option<()> {
+        // The overall position at which the current buffer starts
+        let start = self.total_pos - self.pos;
+        if checkpoint < start || checkpoint > self.total_pos {
+            return None;
+        }
+
+        self.pos = checkpoint - start;
+        self.total_pos = checkpoint;
+        Some(())
+    }
+
     /// Append the contents `buf` to the end of the underlying buffer
     ///
     /// On success, returns the position before the write and after the write
@@ -154,7 +192,26 @@ impl<'a, T: Copy> Cursor<'a, T> {
         Some((cur_pos, new_pos))
     }
 
-    /// Splits the cursor at the current position
+    /// Append `count` copies of `value` to the end of the underlying buffer
+    ///
+    /// On success, returns the position before the write and after the write
+    pub fn fill(&mut self, value: T, count: usize) -> Option<(usize, usize)> {
+        // Set the new position
+        let cur_pos = self.pos;
+        let new_pos = cur_pos.checked_add(count)?;
+        self.try_set_position(new_pos)?;
+
+        // Fill in the value
+        self.inner[cur_pos..new_pos].fill(value);
+
+        Some((cur_pos, new_pos))
+    }
+
+    /// Splits the cursor at the current position.
+    ///
+    /// The returned slice holds everything written into the current buffer and
+    /// the new cursor starts at the same overall position, so splitting this
+    /// way never marks unwritten data as initialized.
     pub fn split_at_current(self) -> (&'a mut [T], Self) {
         let pos = self.pos;
         self.split_at(pos)
@@ -240,3 +240,122 @@ fn test_split_reuse_then_backtrack_position() {
     cursor_b.write(&[7, 8]).unwrap();
     assert_eq!(&data[4..6], &[7, 8]);
 }
+
+#[test]
+fn rewind_to_checkpoint() {
+    let mut data = [0u8; 8];
+    let mut cursor = Cursor::new(&mut data);
+
+    cursor.write(&[1, 2]).unwrap();
+    let checkpoint = cursor.checkpoint();
+    cursor.write(&[3, 4, 5]).unwrap();
+
+    // Abort the partial write and write something else instead
+    assert_eq!(cursor.rewind_to(checkpoint), Some(()));
+    assert_eq!(cursor.pos, 2);
+    assert_eq!(cursor.total_pos, 2);
+    cursor.write(&[6]).unwrap();
+    assert_eq!(cursor.get(), &[1, 2, 6]);
+}
+
+#[test]
+fn rewind_to_checkpoint_after_split() {
+    let mut data = [0u8; 8];
+    let mut cursor = Cursor::new(&mut data);
+
+    cursor.write(&[1, 2]).unwrap();
+    let (_, mut cursor) = cursor.split_at_current();
+    cursor.write(&[3]).unwrap();
+    let checkpoint = cursor.checkpoint();
+    cursor.write(&[4, 5]).unwrap();
+
+    // The checkpoint is within the buffer of the split cursor
+    assert_eq!(cursor.rewind_to(checkpoint), Some(()));
+    assert_eq!(cursor.pos, 1);
+    assert_eq!(cursor.total_pos, 3);
+
+    // Rewinding to the exact split boundary is allowed
+    assert_eq!(cursor.rewind_to(2), Some(()));
+    assert_eq!(cursor.pos, 0);
+    assert_eq!(cursor.total_pos, 2);
+}
+
+#[test]
+fn rewind_to_checkpoint_across_split() {
+    let mut data = [0u8; 8];
+    let mut cursor = Cursor::new(&mut data);
+
+    cursor.write(&[1]).unwrap();
+    let checkpoint = cursor.checkpoint();
+    cursor.write(&[2, 3]).unwrap();
+    let (_, mut cursor) = cursor.split_at_current();
+    cursor.write(&[4]).unwrap();
+
+    // The checkpoint lies in the split off part of the buffer
+    assert_eq!(cursor.rewind_to(checkpoint), None);
+    assert_eq!(cursor.pos, 1);
+    assert_eq!(cursor.total_pos, 4);
+
+    // Checkpoints past the current position are rejected as well
+    assert_eq!(cursor.rewind_to(5), None);
+}
+
+#[test]
+fn capacity_and_remaining() {
+    let mut data = [0u8; 8];
+    let mut cursor = Cursor::new_with_limit(&mut data, 6);
+
+    // The limit is lower than the buffer length
+    assert_eq!(cursor.capacity(), 6);
+    cursor.write(&[1, 2]).unwrap();
+    assert_eq!(cursor.remaining(), 4);
+
+    // Splitting keeps the overall capacity
+    let (_, mut right) = cursor.split_at_current();
+    assert_eq!(right.capacity(), 6);
+    assert_eq!(right.remaining(), 4);
+    right.write(&[3, 4, 5, 6]).unwrap();
+    assert_eq!(right.remaining(), 0);
+}
+
+#[test]
+fn write_fails_if_exceeds_buffer() {
+    let mut data = [0u8; 4];
+    let mut cursor = Cursor::new_with_limit(&mut data, 16);
+
+    // The buffer is shorter than the limit
+    assert_eq!(cursor.capacity(), 4);
+    cursor.write(&[1, 2, 3]).unwrap();
+
+    // Writing past the buffer fails instead of panicking, leaving the cursor
+    // untouched
+    assert!(cursor.write(&[4, 5]).is_none());
+    assert_eq!(cursor.overall_position(), 3);
+    assert_eq!(cursor.remaining(), 1);
+    cursor.write(&[4]).unwrap();
+    assert_eq!(cursor.get(), &[1, 2, 3, 4]);
+}
+
+#[test]
+fn fill_within_limit() {
+    let mut data = [0u8; 8];
+    let mut cursor = Cursor::new_with_limit(&mut data, 6);
+
+    cursor.write(&[1, 2]).unwrap();
+    assert_eq!(cursor.fill(7, 3), Some((2, 5)));
+    assert_eq!(cursor.pos, 5);
+    assert_eq!(cursor.total_pos, 5);
+    assert_eq!(&data[..6], &[1, 2, 7, 7, 7, 0]);
+}
+
+#[test]
+fn fill_past_limit() {
+    let mut data = [0u8; 8];
+    let mut cursor = Cursor::new_with_limit(&mut data, 4);
+
+    cursor.write(&[1, 2]).unwrap();
+    assert!(cursor.fill(7, 3).is_none());
+    assert_eq!(cursor.pos, 2);
+    assert_eq!(cursor.total_pos, 2);
+    assert_eq!(&data[..4], &[1, 2, 0, 0]);
+}
@@ -1,28 +1,63 @@
-//! Parser for basic x86_64 statically linked little endian ELF files.
+//! Parser for basic statically linked ELF files. By default, only x86_64
+//! little endian executables are accepted.
 
 #![no_std]
 
+#[cfg(test)]
+mod tests;
+
 use page_table::VirtAddr;
 
-/// Read bytes and little-endian interpret them as a given type
-macro_rules! get_bytes {
-    ($bytes:expr, $offset:expr, $type:ty) => {{
+/// Read bytes and interpret them as a given type with the `$endian`
+/// byte order, returning `$err` if the bytes are out of bounds
+macro_rules! get_bytes_or {
+    ($bytes:expr, $offset:expr, $type:ty, $endian:expr, $err:expr) => {{
         use core::mem::size_of;
         let range = ($offset as usize)..(($offset as usize)
-            .checked_add(size_of::<$type>()).ok_or(Error::ParseFailure)?);
-        <$type>::from_le_bytes($bytes.get(range).ok_or(Error::ParseFailure)?
-            .try_into().ok().ok_or(Error::ParseFailure)?)
+            .checked_add(size_of::<$type>()).ok_or($err)?);
+        let raw = $bytes.get(range).ok_or($err)?
+            .try_into().ok().ok_or($err)?;
+        match $endian {
+            Endian::Little => <$type>::from_le_bytes(raw),
+            Endian::Big    => <$type>::from_be_bytes(raw),
+        }
     }}
 }
 
-/// Read bytes and little-endian interpret them as a given type
-macro_rules! get_bytes_no_err {
-    ($bytes:expr, $offset:expr, $type:ty) => {{
-        use core::mem::size_of;
-        let range = ($offset as usize)..(($offset as usize)
-            .checked_add(size_of::<$type>())?);
-        <$type>::from_le_bytes($bytes.get(range)?.try_into().ok()?)
-    }}
+/// Read bytes and interpret them as a given type with the `$endian`
+/// byte order
+macro_rules! get_bytes {
+    ($bytes:expr, $offset:expr, $type:ty, $endian:expr) => {
+        get_bytes_or!($bytes, $offset, $type, $endian, Error::ParseFailure)
+    }
+}
+
+/// Read a native word (`u32` or `u64` depending on `$bitness`) and widen it
+/// to a `u64`, returning `$err` if the bytes are out of bounds
+macro_rules! get_word_or {
+    ($bytes:expr, $offset:expr, $bitness:expr, $endian:expr, $err:expr) => {
+        match $bitness {
+            Bitness::Bits32 =>
+                get_bytes_or!($bytes, $offset, u32, $endian, $err) as u64,
+            Bitness::Bits64 =>
+                get_bytes_or!($bytes, $offset, u64, $endian, $err),
+        }
+    }
+}
+
+/// Update a CRC32 (IEEE 802.3) `crc` with `bytes`.
+///
+/// This is computed bit by bit instead of using a lookup table to keep the
+/// parser small.
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
 }
 
 /// Virtual size type for better readability
@@ -40,16 +75,16 @@ pub enum Error {
     /// The ELF file had the wrong magic bytes
     WrongMagic([u8; 4]),
 
-    /// The file was not 64-bit
+    /// The file didn't have the expected bitness
     WrongBitness,
 
-    /// The file was not little endian
+    /// The file didn't have the expected byte order
     WrongEndian,
 
     /// The ELF version was incorrect
     WrongVersion(u8),
 
-    /// The ELF type was not EXECUTABLE
+    /// The ELF type was not one of the expected types
     WrongType(u16),
 
     /// A different machine type was expected
@@ -63,6 +98,71 @@ pub enum Error {
 
     /// The closure that was called on each segment failed
     SegmentsClosureFailed,
+
+    /// The CRC32 of the loadable segments didn't match the expected one. The
+    /// computed CRC32 is returned
+    ChecksumMismatch(u32),
+
+    /// Two loadable segments would be mapped over the same page
+    OverlappingSegments,
+}
+
+/// ELF type of relocatable object files
+pub const ET_REL: u16 = 1;
+
+/// ELF type of executable files
+pub const ET_EXEC: u16 = 2;
+
+/// ELF type of shared object files
+pub const ET_DYN: u16 = 3;
+
+/// ELF machine type of Intel 80386
+pub const EM_386: u16 = 0x03;
+
+/// ELF machine type of AMD x86-64
+pub const EM_X86_64: u16 = 0x3E;
+
+/// Bitness (ELF class) of an ELF file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bitness {
+    Bits32,
+    Bits64,
+}
+
+/// Byte order of an ELF file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// The kind of ELF file `Elf::parse_with()` accepts
+#[derive(Debug, Clone, Copy)]
+pub struct ExpectedElf {
+    /// Expected bitness of the file
+    pub bitness: Bitness,
+
+    /// Expected byte order of the file
+    pub endian: Endian,
+
+    /// Expected machine type
+    pub machine: u16,
+
+    /// ELF types which are accepted
+    pub allowed_types: &'static [u16],
+}
+
+impl ExpectedElf {
+    /// Expect a 64-bit little endian x86_64 executable. This is what
+    /// `Elf::parse()` accepts
+    pub const fn amd64_exe() -> Self {
+        Self {
+            bitness:       Bitness::Bits64,
+            endian:        Endian::Little,
+            machine:       EM_X86_64,
+            allowed_types: &[ET_EXEC],
+        }
+    }
 }
 
 /// Permission bits for memory segments
@@ -112,7 +212,47 @@ pub struct Segment<'a> {
     pub permissions: Permissions,
 }
 
-/// An iterator of `Segment`
+impl<'a> Segment<'a> {
+    /// Size of the file-backed part of the segment (the raw `p_filesz`)
+    pub fn file_size(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    /// Virtual addresses of the zero-filled tail of the segment (the BSS),
+    /// which follows the file-backed part. Empty if the whole segment is
+    /// file-backed
+    pub fn bss_range(&self) -> core::ops::Range<u64> {
+        let start = self.vaddr.0 + self.offset;
+        (start + self.file_size())..(start + self.vsize)
+    }
+
+    /// Size of the mapping needed for the segment, counted from `vaddr`. This
+    /// includes the `offset` of the segment data within its first page
+    pub fn map_len(&self) -> u64 {
+        self.offset + self.vsize
+    }
+
+    /// Page aligned virtual addresses the mapping of the segment covers
+    fn page_range(&self) -> Result<core::ops::Range<u64>, Error> {
+        let end = self.vaddr.0.checked_add(self.map_len())
+            .and_then(|end| end.checked_add(0xFFF))
+            .ok_or(Error::ParseFailure)? & !0xFFF;
+        Ok(self.vaddr.0..end)
+    }
+
+    /// Returns the byte of the segment at `mem_offset` bytes into its mapping
+    /// (see [`Segment::map_len`]). Bytes before the segment data and in the
+    /// BSS are 0
+    pub fn byte_at(&self, mem_offset: u64) -> u8 {
+        mem_offset.checked_sub(self.offset)
+            .and_then(|off| usize::try_from(off).ok())
+            .and_then(|off| self.bytes.get(off))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// An iterator of `Segment`. Iteration ends after the first error
 #[derive(Debug, Clone)]
 pub struct ElfSegments<'a> {
     /// Reference to the parsed ELF file
@@ -122,65 +262,79 @@ pub struct ElfSegments<'a> {
     index: usize,
 }
 
-impl<'a> core::iter::Iterator for ElfSegments<'a> {
-    type Item = Result<Segment<'a>, Error>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let bytes = self.elf.bytes;
-
-        // Stop iterating if we've gone through all program headers
-        if self.index >= self.elf.ph_num { return None; }
-
-        // Get the offset to the beginning of this program header.
-        // This calculation won't overflow; it's been checked during parsing
-        let offset = self.elf.ph_offset +
-            self.index * self.elf.ph_entry_size as usize;
-
-        // Increment index for the next call to next
-        self.index += 1;
+impl<'a> ElfSegments<'a> {
+    /// Parse the program header at `offset`, returning `None` if its segment
+    /// is not loadable.
+    ///
+    /// Running out of bytes in the middle of the header is reported as
+    /// `Error::NotEnoughBytes`
+    fn parse_header(&self, offset: usize)
+            -> Result<Option<Segment<'a>>, Error> {
+        let bytes   = self.elf.bytes;
+        let bitness = self.elf.bitness;
+        let endian  = self.elf.endian;
+
+        // Read the bytes of the header, which must all be in the file
+        macro_rules! read {
+            ($offset:expr, $type:ty) => {
+                get_bytes_or!(bytes, offset.checked_add($offset)
+                    .ok_or(Error::NotEnoughBytes)?,
+                    $type, endian, Error::NotEnoughBytes)
+            }
+        }
+        macro_rules! read_word {
+            ($offset:expr) => {
+                get_word_or!(bytes, offset.checked_add($offset)
+                    .ok_or(Error::NotEnoughBytes)?,
+                    bitness, endian, Error::NotEnoughBytes)
+            }
+        }
 
         // Skip segments that are not loadable
-        if get_bytes_no_err!(bytes, offset + 0x00, u32) != 0x00000001 {
-            return self.next();
+        if read!(0x00, u32) != 0x00000001 {
+            return Ok(None);
         }
 
+        // Get the offsets of the fields, which are laid out differently for
+        // 32-bit files, as the flags come after the sizes there
+        let (flags_off, word, align_off) = match bitness {
+            Bitness::Bits32 => (0x18, 0x04, 0x1C),
+            Bitness::Bits64 => (0x04, 0x08, 0x30),
+        };
+
         // Get the segment memory permissions
-        let perms = Permissions::from_flags(
-            get_bytes_no_err!(bytes, offset + 0x04, u32));
+        let perms = Permissions::from_flags(read!(flags_off, u32));
 
         // Get the offset of the segment in the file image
-        let raw_offset = get_bytes_no_err!(bytes, offset + 0x08, u64) as usize;
+        let raw_offset = read_word!(word) as usize;
 
         // Get the virtual address of the segment in memory
-        let vaddr = VirtAddr(get_bytes_no_err!(bytes, offset + 0x10, u64));
+        let vaddr = VirtAddr(read_word!(word * 2));
 
         // Get the size of the segment in file (may be 0)
-        let raw_size = get_bytes_no_err!(bytes, offset + 0x20, u64) as usize;
+        let raw_size = read_word!(word * 4) as usize;
 
         // Get the size of the segment in memory
-        let vsize = get_bytes_no_err!(bytes, offset + 0x28, u64);
+        let vsize = read_word!(word * 5);
 
         // The segment size in the file should never be larger than the
         // virtual size
-        if raw_size as u64 > vsize { return Some(Err(Error::RawSizeTooLarge)); }
-        if raw_offset.checked_add(raw_size)? >
-                bytes.len() {
-            return Some(Err(Error::NotEnoughBytes))
-        }
+        if raw_size as u64 > vsize { return Err(Error::RawSizeTooLarge); }
 
         // Get the required alignment mask for this segment
-        let align_mask = get_bytes_no_err!(bytes, offset + 0x30, u64) - 1;
-        if align_mask != 0xFFF { return Some(Err(Error::WrongAlignment)); }
+        let align_mask = read_word!(align_off).wrapping_sub(1);
+        if align_mask != 0xFFF { return Err(Error::WrongAlignment); }
 
         // Get the aligned virtual address and the offset for this segment
         let aligned_vaddr = vaddr.0 & (!align_mask);
         let virtual_offset = vaddr.0 - aligned_vaddr;
 
         // Extract raw segment data
-        let segment_bytes =
-            bytes.get(raw_offset..raw_offset.checked_add(raw_size)?)?;
+        let segment_bytes = raw_offset.checked_add(raw_size)
+            .and_then(|end| bytes.get(raw_offset..end))
+            .ok_or(Error::NotEnoughBytes)?;
 
-        Some(Ok(Segment {
+        Ok(Some(Segment {
             vaddr: VirtAddr(aligned_vaddr),
             offset: virtual_offset,
             vsize,
@@ -190,6 +344,37 @@ impl<'a> core::iter::Iterator for ElfSegments<'a> {
     }
 }
 
+impl<'a> core::iter::Iterator for ElfSegments<'a> {
+    type Item = Result<Segment<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Stop iterating once we've gone through all program headers
+        while self.index < self.elf.ph_num {
+            // Get the offset to the beginning of this program header.
+            // This calculation won't overflow; it's been checked during
+            // parsing
+            let offset = self.elf.ph_offset +
+                self.index * self.elf.ph_entry_size as usize;
+
+            // Increment index for the next call to next
+            self.index += 1;
+
+            match self.parse_header(offset) {
+                Ok(Some(segment)) => return Some(Ok(segment)),
+                Ok(None)          => continue,
+                Err(err) => {
+                    // Don't yield anything after an error
+                    self.index = self.elf.ph_num;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl core::iter::FusedIterator for ElfSegments<'_> {}
+
 /// A validated ELF file
 #[derive(Debug, Clone)]
 pub struct Elf<'a> {
@@ -205,59 +390,91 @@ pub struct Elf<'a> {
     /// Number of program header entries
     ph_num: usize,
 
+    /// Bitness of the file
+    bitness: Bitness,
+
+    /// Byte order of the file
+    endian: Endian,
+
     /// Address of the entry point
     pub entry: VirtAddr,
 }
 
 impl<'a> Elf<'a> {
-    /// Parse an ELF file and return its parsed representation
+    /// Parse an x86_64 little endian executable ELF file and return its parsed
+    /// representation
     pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        Self::parse_with(bytes, ExpectedElf::amd64_exe())
+    }
+
+    /// Parse an ELF file of the `expected` kind and return its parsed
+    /// representation
+    pub fn parse_with(bytes: &'a [u8], expected: ExpectedElf)
+            -> Result<Self, Error> {
         let bytes: &[u8] = bytes.as_ref();
 
         // Check for the ELF header
         if bytes.get(..0x04) != Some(b"\x7FELF") {
-            return Err(Error::WrongMagic(bytes[0x00..0x04]
+            return Err(Error::WrongMagic(bytes.get(0x00..0x04)
+                    .ok_or(Error::NotEnoughBytes)?
                     .try_into().unwrap()));
         }
 
-        // Make sure we have a 64-bit file
-        if get_bytes!(bytes, 0x04, u8) != 2 {
+        // Make sure we have a file of the expected bitness
+        let bitness = match get_bytes!(bytes, 0x04, u8, Endian::Little) {
+            1 => Bitness::Bits32,
+            2 => Bitness::Bits64,
+            _ => return Err(Error::WrongBitness),
+        };
+        if bitness != expected.bitness {
             return Err(Error::WrongBitness);
         }
 
-        // Make sure we have a little endian file
-        if get_bytes!(bytes, 0x05, u8) != 1 {
+        // Make sure we have a file of the expected byte order
+        let endian = match get_bytes!(bytes, 0x05, u8, Endian::Little) {
+            1 => Endian::Little,
+            2 => Endian::Big,
+            _ => return Err(Error::WrongEndian),
+        };
+        if endian != expected.endian {
             return Err(Error::WrongEndian);
         }
 
         // Make sure we have the expected version
-        if get_bytes!(bytes, 0x06, u8) != 1 {
+        if get_bytes!(bytes, 0x06, u8, endian) != 1 {
             return Err(Error::WrongVersion(bytes[0x06]))
         }
 
-        // Make sure we have an executable
-        if get_bytes!(bytes, 0x10, u16) != 2 {
-            return Err(Error::WrongType(u16::from_le_bytes(
-                        bytes[0x10..0x12].try_into().unwrap())))
+        // Make sure we have one of the expected types
+        let typ = get_bytes!(bytes, 0x10, u16, endian);
+        if !expected.allowed_types.contains(&typ) {
+            return Err(Error::WrongType(typ))
         }
 
-        // Make sure we have an amd64 file
-        if get_bytes!(bytes, 0x12, u16) != 0x3E {
-            return Err(Error::WrongMachine(u16::from_le_bytes(
-                        bytes[0x12..0x14].try_into().unwrap())))
+        // Make sure we have a file for the expected machine
+        let machine = get_bytes!(bytes, 0x12, u16, endian);
+        if machine != expected.machine {
+            return Err(Error::WrongMachine(machine))
         }
 
-        // Get the entry point
-        let entry = VirtAddr(get_bytes!(bytes, 0x18, u64));
-
-        // Get the offset to the start of the program header table
-        let ph_offset = get_bytes!(bytes, 0x20, u64) as usize;
-
-        // Get the size of program header table entries
-        let ph_entry_size = get_bytes!(bytes, 0x36, u16);
-
-        // Get the number of program header table entries
-        let ph_num = get_bytes!(bytes, 0x38, u16) as usize;
+        // Get the entry point, the offset to the start of the program header
+        // table, the size of its entries and the number of them. Addresses
+        // and offsets are only 32-bit wide in 32-bit files
+        let (entry, ph_offset, ph_entry_size, ph_num) = match bitness {
+            Bitness::Bits32 => (
+                get_bytes!(bytes, 0x18, u32, endian) as u64,
+                get_bytes!(bytes, 0x1C, u32, endian) as usize,
+                get_bytes!(bytes, 0x2A, u16, endian),
+                get_bytes!(bytes, 0x2C, u16, endian) as usize,
+            ),
+            Bitness::Bits64 => (
+                get_bytes!(bytes, 0x18, u64, endian),
+                get_bytes!(bytes, 0x20, u64, endian) as usize,
+                get_bytes!(bytes, 0x36, u16, endian),
+                get_bytes!(bytes, 0x38, u16, endian) as usize,
+            ),
+        };
+        let entry = VirtAddr(entry);
 
         // Make sure that all the entries are in bounds of the bytes
         let table_size = ph_offset.checked_add(
@@ -269,12 +486,59 @@ impl<'a> Elf<'a> {
             return Err(Error::NotEnoughBytes);
         }
 
+        let elf = Self {
+            bytes, entry, ph_offset, ph_entry_size, ph_num, bitness, endian
+        };
+
+        // Make sure the segments can all be mapped in
+        elf.validate_segments()?;
+
         // Return the parsed ELF
-        Ok(Self { bytes, entry, ph_offset, ph_entry_size, ph_num })
+        Ok(elf)
+    }
+
+    /// Make sure that all loadable segments parse and that no two of them
+    /// would be mapped over the same page.
+    ///
+    /// The parser doesn't allocate, so instead of sorting the segments by
+    /// their address, every pair of segments is compared
+    pub fn validate_segments(&self) -> Result<(), Error> {
+        let segments = ElfSegments { elf: self, index: 0 };
+
+        for (idx, segment) in segments.clone().enumerate() {
+            let range = segment?.page_range()?;
+
+            // Compare against all the segments after this one
+            for other in segments.clone().skip(idx + 1) {
+                let other = other?.page_range()?;
+                if range.start < other.end && other.start < range.end {
+                    return Err(Error::OverlappingSegments);
+                }
+            }
+        }
+        Ok(())
     }
 
     /// Returns an iterator over loadable segments in the ELF file
     pub fn segments(&'a self) -> ElfSegments<'a> {
         ElfSegments { elf: self, index: 0 }
     }
+
+    /// Compute the CRC32 over the raw bytes of all loadable segments, in the
+    /// order they appear in the program header table
+    pub fn crc32(&'a self) -> Result<u32, Error> {
+        let crc = self.segments().try_fold(!0, |crc, segment| {
+            Ok(crc32_update(crc, segment?.bytes))
+        })?;
+        Ok(!crc)
+    }
+
+    /// Verify that the CRC32 of the loadable segments is `expected`
+    pub fn verify_crc32(&'a self, expected: u32) -> Result<(), Error> {
+        let crc = self.crc32()?;
+        if crc != expected {
+            return Err(Error::ChecksumMismatch(crc));
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,284 @@
+extern crate std;
+
+use std::vec;
+use std::vec::Vec;
+
+use super::*;
+
+/// Program header type of loadable segments
+const PT_LOAD: u32 = 1;
+
+/// Program header type of auxiliary information
+const PT_NOTE: u32 = 4;
+
+/// A program header of a test ELF file
+struct Phdr {
+    typ:    u32,
+    vaddr:  u64,
+    filesz: u64,
+    memsz:  u64,
+}
+
+impl Phdr {
+    /// A loadable segment with `filesz` bytes in the file
+    fn load(vaddr: u64, filesz: u64, memsz: u64) -> Self {
+        Self { typ: PT_LOAD, vaddr, filesz, memsz }
+    }
+}
+
+/// Write `bytes` into `elf` at `offset`
+fn put(elf: &mut [u8], offset: usize, bytes: &[u8]) {
+    elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// Encode a native word of a file of `bitness`
+fn word(bitness: Bitness, val: u64) -> Vec<u8> {
+    match bitness {
+        Bitness::Bits32 => (val as u32).to_le_bytes().to_vec(),
+        Bitness::Bits64 => val.to_le_bytes().to_vec(),
+    }
+}
+
+/// Build a little endian executable for `machine` with the program headers
+/// `phdrs`. The data of the segments comes right after the ELF header, and the
+/// program header table is at the end of the file, so truncating the file
+/// only cuts into the table. Segment `n` is filled with the byte `n + 1`
+fn build(bitness: Bitness, machine: u16, phdrs: &[Phdr]) -> Vec<u8> {
+    // Sizes of the ELF header and of the program headers
+    let (ehsize, phsize) = match bitness {
+        Bitness::Bits32 => (52, 32),
+        Bitness::Bits64 => (64, 56),
+    };
+
+    // Write the segment data
+    let mut elf = vec![0u8; ehsize];
+    let mut offsets = Vec::new();
+    for (ii, phdr) in phdrs.iter().enumerate() {
+        offsets.push(elf.len() as u64);
+        elf.extend(core::iter::repeat_n(ii as u8 + 1, phdr.filesz as usize));
+    }
+    let phoff = elf.len();
+    elf.resize(phoff + phsize * phdrs.len(), 0);
+
+    // Write the ELF header
+    put(&mut elf, 0x00, b"\x7FELF");
+    elf[0x04] = if bitness == Bitness::Bits32 { 1 } else { 2 };
+    elf[0x05] = 1;
+    elf[0x06] = 1;
+    put(&mut elf, 0x10, &ET_EXEC.to_le_bytes());
+    put(&mut elf, 0x12, &machine.to_le_bytes());
+    put(&mut elf, 0x18, &word(bitness, 0x1000));
+    let (phoff_off, phentsize_off) = match bitness {
+        Bitness::Bits32 => (0x1C, 0x2A),
+        Bitness::Bits64 => (0x20, 0x36),
+    };
+    put(&mut elf, phoff_off, &word(bitness, phoff as u64));
+    put(&mut elf, phentsize_off, &(phsize as u16).to_le_bytes());
+    put(&mut elf, phentsize_off + 2, &(phdrs.len() as u16).to_le_bytes());
+
+    // Write the program headers, which are readable and executable
+    let (flags_off, word_len, align_off) = match bitness {
+        Bitness::Bits32 => (0x18, 0x04, 0x1C),
+        Bitness::Bits64 => (0x04, 0x08, 0x30),
+    };
+    for (ii, phdr) in phdrs.iter().enumerate() {
+        let hdr = &mut elf[phoff + ii * phsize..][..phsize];
+        put(hdr, 0x00, &phdr.typ.to_le_bytes());
+        put(hdr, flags_off, &5u32.to_le_bytes());
+        put(hdr, word_len, &word(bitness, offsets[ii]));
+        put(hdr, word_len * 2, &word(bitness, phdr.vaddr));
+        put(hdr, word_len * 4, &word(bitness, phdr.filesz));
+        put(hdr, word_len * 5, &word(bitness, phdr.memsz));
+        put(hdr, align_off, &word(bitness, 0x1000));
+    }
+    elf
+}
+
+/// Build a 64-bit x86_64 executable with the program headers `phdrs`
+fn build64(phdrs: &[Phdr]) -> Vec<u8> {
+    build(Bitness::Bits64, EM_X86_64, phdrs)
+}
+
+#[test]
+fn segments_complete_table() {
+    let bytes = build64(&[
+        Phdr::load(0x1000, 0x10, 0x10),
+        Phdr { typ: PT_NOTE, vaddr: 0, filesz: 4, memsz: 4 },
+        Phdr::load(0x3000, 0x20, 0x20),
+    ]);
+    let elf = Elf::parse(&bytes).unwrap();
+
+    // Every loadable segment is yielded, in order, and nothing else
+    let vaddrs: Vec<u64> = elf.segments()
+        .map(|segment| segment.unwrap().vaddr.0)
+        .collect();
+    assert_eq!(vaddrs, [0x1000, 0x3000]);
+
+    let first = elf.segments().next().unwrap().unwrap();
+    assert!(first.bytes.iter().all(|&byte| byte == 1));
+    let last = elf.segments().nth(1).unwrap().unwrap();
+    assert!(last.bytes.iter().all(|&byte| byte == 3));
+}
+
+#[test]
+fn segments_truncated_table() {
+    let bytes = build64(&[
+        Phdr::load(0x1000, 0x10, 0x10),
+        Phdr::load(0x2000, 0x10, 0x10),
+        Phdr::load(0x3000, 0x10, 0x10),
+    ]);
+
+    // Cut the file in the middle of the second program header
+    let cut = bytes.len() - 56 - 56 / 2;
+    assert!(matches!(Elf::parse(&bytes[..cut]), Err(Error::NotEnoughBytes)));
+
+    // The iterator reports the truncated header instead of silently ending
+    let mut elf = Elf::parse(&bytes).unwrap();
+    elf.bytes = &bytes[..cut];
+    let mut segments = elf.segments();
+    assert_eq!(segments.next().unwrap().unwrap().vaddr.0, 0x1000);
+    assert!(matches!(segments.next(), Some(Err(Error::NotEnoughBytes))));
+
+    // Nothing is yielded after the error, not even the third header
+    assert!(segments.next().is_none());
+    assert!(segments.next().is_none());
+}
+
+#[test]
+fn segment_bss() {
+    // The segment starts 0x234 bytes into its page, and only its first 0x100
+    // bytes are backed by the file
+    let bytes = build64(&[Phdr::load(0x1234, 0x100, 0x300)]);
+    let elf = Elf::parse(&bytes).unwrap();
+    let segment = elf.segments().next().unwrap().unwrap();
+
+    assert_eq!(segment.vaddr.0, 0x1000);
+    assert_eq!(segment.offset, 0x234);
+    assert_eq!(segment.file_size(), 0x100);
+    assert_eq!(segment.vsize, 0x300);
+    assert_eq!(segment.bss_range(), 0x1334..0x1534);
+    assert_eq!(segment.map_len(), 0x534);
+
+    // The file-backed part holds the data, everything around it is zeroed
+    assert_eq!(segment.byte_at(0x233), 0);
+    assert_eq!(segment.byte_at(0x234), 1);
+    assert_eq!(segment.byte_at(0x333), 1);
+    assert_eq!(segment.byte_at(0x334), 0);
+    assert_eq!(segment.byte_at(0x533), 0);
+}
+
+#[test]
+fn segment_without_bss() {
+    let bytes = build64(&[Phdr::load(0x1000, 0x100, 0x100)]);
+    let elf = Elf::parse(&bytes).unwrap();
+    let segment = elf.segments().next().unwrap().unwrap();
+    assert!(segment.bss_range().is_empty());
+}
+
+#[test]
+fn segment_filesz_above_memsz() {
+    let bytes = build64(&[Phdr::load(0x1000, 0x200, 0x100)]);
+    assert!(matches!(Elf::parse(&bytes), Err(Error::RawSizeTooLarge)));
+}
+
+/// Expect a 32-bit little endian i386 executable
+const I386_EXE: ExpectedElf = ExpectedElf {
+    bitness:       Bitness::Bits32,
+    endian:        Endian::Little,
+    machine:       EM_386,
+    allowed_types: &[ET_EXEC],
+};
+
+#[test]
+fn parse_32bit_expected() {
+    let bytes = build(Bitness::Bits32, EM_386, &[
+        Phdr::load(0x1000, 0x10, 0x20),
+        Phdr::load(0x2000, 0x10, 0x10),
+    ]);
+    let elf = Elf::parse_with(&bytes, I386_EXE).unwrap();
+    assert_eq!(elf.entry.0, 0x1000);
+
+    // The fields of the 32-bit program headers are laid out differently
+    let segments: Vec<_> = elf.segments().map(Result::unwrap).collect();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0].vaddr.0, 0x1000);
+    assert_eq!(segments[0].file_size(), 0x10);
+    assert_eq!(segments[0].vsize, 0x20);
+    assert!(segments[0].permissions.read && segments[0].permissions.execute);
+    assert!(!segments[0].permissions.write);
+    assert_eq!(segments[1].vaddr.0, 0x2000);
+    assert!(segments[1].bytes.iter().all(|&byte| byte == 2));
+}
+
+#[test]
+fn parse_32bit_unexpected() {
+    let bytes = build(Bitness::Bits32, EM_386, &[Phdr::load(0x1000, 0, 0)]);
+    assert!(matches!(Elf::parse(&bytes), Err(Error::WrongBitness)));
+}
+
+#[test]
+fn parse_64bit_under_32bit_expectation() {
+    let bytes = build64(&[Phdr::load(0x1000, 0, 0)]);
+    assert!(matches!(Elf::parse_with(&bytes, I386_EXE),
+                     Err(Error::WrongBitness)));
+}
+
+#[test]
+fn parse_32bit_wrong_machine() {
+    let bytes = build(Bitness::Bits32, EM_X86_64, &[Phdr::load(0x1000, 0, 0)]);
+    assert!(matches!(Elf::parse_with(&bytes, I386_EXE),
+                     Err(Error::WrongMachine(EM_X86_64))));
+}
+
+#[test]
+fn parse_wrong_type() {
+    let mut bytes = build64(&[Phdr::load(0x1000, 0, 0)]);
+    put(&mut bytes, 0x10, &ET_DYN.to_le_bytes());
+    assert!(matches!(Elf::parse(&bytes), Err(Error::WrongType(ET_DYN))));
+
+    // Types can be allowed by the caller
+    let expected = ExpectedElf {
+        allowed_types: &[ET_EXEC, ET_DYN],
+        ..ExpectedElf::amd64_exe()
+    };
+    assert!(Elf::parse_with(&bytes, expected).is_ok());
+}
+
+#[test]
+fn overlapping_segments() {
+    // The second segment starts in the last page of the first one
+    let bytes = build64(&[
+        Phdr::load(0x1000, 0x10, 0x1800),
+        Phdr::load(0x2800, 0x10, 0x10),
+    ]);
+    assert!(matches!(Elf::parse(&bytes), Err(Error::OverlappingSegments)));
+
+    // The order of the headers doesn't matter
+    let bytes = build64(&[
+        Phdr::load(0x2800, 0x10, 0x10),
+        Phdr::load(0x1000, 0x10, 0x1800),
+    ]);
+    assert!(matches!(Elf::parse(&bytes), Err(Error::OverlappingSegments)));
+}
+
+#[test]
+fn overlapping_segments_in_between() {
+    // The last segment overlaps the first one, with an unrelated one between
+    let bytes = build64(&[
+        Phdr::load(0x1000, 0x10, 0x3000),
+        Phdr::load(0x8000, 0x10, 0x10),
+        Phdr::load(0x3000, 0x10, 0x10),
+    ]);
+    assert!(matches!(Elf::parse(&bytes), Err(Error::OverlappingSegments)));
+}
+
+#[test]
+fn adjacent_segments() {
+    // Segments ending and starting in different pages don't overlap, even
+    // when the first one ends right where the next page begins
+    let bytes = build64(&[
+        Phdr::load(0x1000, 0x10, 0x1000),
+        Phdr::load(0x2000, 0x10, 0x10),
+    ]);
+    assert!(Elf::parse(&bytes).is_ok());
+}
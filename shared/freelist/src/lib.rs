@@ -0,0 +1,208 @@
+//! A stack of free fixed-size memory blocks, which keeps its metadata in the
+//! free blocks themselves
+//!
+//! Getting memory to back the blocks is up to the user of the list, which
+//! pushes the blocks onto it once they're available
+
+#![no_std]
+
+#[cfg(test)]
+mod tests;
+
+use core::mem::size_of;
+
+/// Freed allocation metadata
+pub struct FreeListNode {
+    /// Address of the next node in the freelist.
+    ///
+    /// If it's `0`, it indicates that this is the last node in the freelist.
+    next: usize,
+
+    /// Number of free slots in the `free_addrs` array.
+    ///
+    /// This value tracks how many addresses are currently available for reuse
+    /// within this node. Each slot corresponds to a previously freed memory
+    /// block.
+    ///
+    /// This is basically the value that would be returned by `free_addrs.len()`
+    /// if it wasn't dynamic.
+    free_slots: usize,
+
+    /// Addresses of freed memory blocks.
+    ///
+    /// The array size is dynamic and can hold a variable number of free
+    /// addresses, depending on the size of the allocation managed by the
+    /// freelist. `free_slots` is the number of elements in this array.
+    free_addrs: [*mut u8; 0],
+}
+
+/// Fill a freed `block` with `pattern`, wiping the stale data out of it
+pub fn scrub(block: &mut [u8], pattern: u8) {
+    block.fill(pattern);
+}
+
+// This specific freelist implementation was originally designed by Brandon
+/// A freelist allocator that manages fixed-size memory blocks.
+pub struct FreeList {
+    /// Address of the first entry in the freelist, `0` if it's empty
+    head: usize,
+
+    /// Size of allocations (in bytes) for this freelist
+    size: usize,
+
+    /// Byte pattern freed blocks are filled with before they're put back onto
+    /// the freelist, if any
+    scrub: Option<u8>,
+}
+
+impl FreeList {
+    /// Create a new, empty freelist containings addresses to `size` allocations
+    pub fn new(size: usize) -> Self {
+        assert!(size.count_ones() == 1,
+            "Freelist size not power of two");
+        assert!(size >= size_of::<usize>(),
+            "Freelist size must be at least pointer width");
+        Self { head: 0, size, scrub: None }
+    }
+
+    /// Create a new, empty freelist containing addresses to `size`
+    /// allocations, which fills freed blocks with `pattern`.
+    ///
+    /// This wipes stale data out of freed memory and makes use-after-free bugs
+    /// visible. Every block is filled as a whole when it's pushed, so freeing
+    /// costs a write of the whole block, which adds up for large blocks.
+    /// Blocks which held the freelist metadata while they were free are
+    /// filled again when they're popped, so every popped block holds nothing
+    /// but the pattern.
+    pub fn new_scrubbed(size: usize, pattern: u8) -> Self {
+        Self { scrub: Some(pattern), ..Self::new(size) }
+    }
+
+    /// Get the size of the blocks of this freelist
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether there are no free blocks in this freelist
+    pub fn is_empty(&self) -> bool {
+        self.head == 0
+    }
+
+    /// Number of free addresses a single node can hold in its `free_addrs`
+    fn available_slots(&self) -> usize {
+        (self.size / size_of::<usize>()) -
+            (size_of::<FreeListNode>() / size_of::<usize>())
+    }
+
+    /// Get a block from this freelist, or `None` if it's empty
+    ///
+    /// # Safety
+    ///
+    /// All of the blocks pushed onto the freelist must still be valid
+    pub unsafe fn pop(&mut self) -> Option<*mut u8> {
+        if self.is_empty() { return None; }
+
+        // For allocations that can't hold our stack-based freelist metadata,
+        // use a linked list.
+        if self.size <= size_of::<FreeListNode>() {
+            // Save the current head and set it to the next node
+            let allocation = self.head as *mut FreeListNode;
+            self.head = unsafe { (*allocation).next };
+            return Some(unsafe { self.wipe_metadata(allocation as *mut u8) });
+        }
+
+        // Use the free list stack
+        let list = unsafe { &mut *(self.head as *mut FreeListNode) };
+
+        // If there's a free entry in the stack, return it
+        if list.free_slots < self.available_slots() {
+            // Just grab the free entry
+            let allocation = unsafe {
+                *list.free_addrs.as_mut_ptr()
+                    .add(list.free_slots)
+            };
+
+            // Update the number of free slots
+            list.free_slots += 1;
+
+            return Some(allocation);
+        }
+
+        // If no free slots are available, use the head of the list as the
+        // allocation
+        let allocation = self.head;
+
+        // Update the head to point to the next entry
+        self.head = list.next;
+
+        Some(unsafe { self.wipe_metadata(allocation as *mut u8) })
+    }
+
+    /// Scrub the metadata out of a popped block `vaddr` which held it, if
+    /// this freelist scrubs its blocks. Returns `vaddr`
+    unsafe fn wipe_metadata(&self, vaddr: *mut u8) -> *mut u8 {
+        if let Some(pattern) = self.scrub {
+            scrub(unsafe { core::slice::from_raw_parts_mut(vaddr, self.size) },
+                  pattern);
+        }
+        vaddr
+    }
+
+    /// Put a block back onto the free list
+    ///
+    /// # Safety
+    ///
+    /// `vaddr` must point to a block of `size()` bytes, aligned to a pointer,
+    /// which is owned by the freelist from now on
+    pub unsafe fn push(&mut self, vaddr: *mut u8) {
+        // Scrub the block before any of the metadata is written into it
+        if let Some(pattern) = self.scrub {
+            scrub(unsafe { core::slice::from_raw_parts_mut(vaddr, self.size) },
+                  pattern);
+        }
+
+        // For allocations that can't hold our stack-based freelist metadata,
+        // use a linked list.
+        if self.size <= size_of::<FreeListNode>() {
+            // Write the old head into the newly freed address
+            let vaddr = vaddr as *mut FreeListNode;
+            unsafe { (*vaddr).next = self.head; }
+
+            // Update the head
+            self.head = vaddr as usize;
+            return;
+        }
+
+        // Check if there is room for this allocation in the free stack,
+        // or if we need to create a new stack
+        let check = self.is_empty() ||
+            unsafe { (*(self.head as *const FreeListNode)).free_slots == 0 };
+        if check {
+            // No free slots, create a new stack out of the freed vaddr
+            let list = unsafe { &mut *(vaddr as *mut FreeListNode) };
+
+            // Set the number of free slots to the maximum size, as all
+            // entries are free in the stack
+            list.free_slots = self.available_slots();
+
+            // Update the next to point to the old head
+            list.next = self.head;
+
+            // Establish this as the new free list head
+            self.head = vaddr as usize;
+            return;
+        }
+
+        // There's room in the current stack, just throw us in there
+        let list = unsafe { &mut *(self.head as *mut FreeListNode) };
+
+        // Decrement the number of free slots
+        list.free_slots -= 1;
+
+        // Store our newly freed virtual address into this slot
+        unsafe {
+            *list.free_addrs.as_mut_ptr()
+                .add(list.free_slots) = vaddr;
+        }
+    }
+}
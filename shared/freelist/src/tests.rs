@@ -0,0 +1,77 @@
+extern crate std;
+
+use super::*;
+
+use std::vec;
+use std::vec::Vec;
+
+/// Fake memory holding `count` blocks of `size` bytes, aligned to a pointer
+fn fake_memory(size: usize, count: usize) -> Vec<u64> {
+    vec![0x1122_3344_5566_7788; size * count / size_of::<u64>()]
+}
+
+/// Push all of the blocks in `memory` onto `list`, returning their addresses
+fn push_all(list: &mut FreeList, memory: &mut [u64]) -> Vec<*mut u8> {
+    let size = list.size();
+    let base = memory.as_mut_ptr() as *mut u8;
+    let count = size_of_val(memory) / size;
+    let blocks: Vec<_> = (0..count)
+        .map(|idx| unsafe { base.add(idx * size) })
+        .collect();
+    for &block in &blocks {
+        unsafe { list.push(block); }
+    }
+    blocks
+}
+
+#[test]
+fn freelist_pops_pushed_blocks() {
+    // Both the linked list and the stack of free addresses give back all of
+    // the blocks, and nothing else
+    for size in [8, 16, 32, 64] {
+        let mut memory = fake_memory(size, 16);
+        let mut list = FreeList::new(size);
+        let mut blocks = push_all(&mut list, &mut memory);
+
+        let mut popped: Vec<_> =
+            core::iter::from_fn(|| unsafe { list.pop() }).collect();
+        assert!(list.is_empty());
+        blocks.sort();
+        popped.sort();
+        assert_eq!(popped, blocks);
+    }
+}
+
+#[test]
+fn freelist_scrubs_freed_blocks() {
+    for size in [8, 16, 32, 64] {
+        let mut memory = fake_memory(size, 16);
+        let mut list = FreeList::new_scrubbed(size, 0xA5);
+        push_all(&mut list, &mut memory);
+
+        // Every byte of every block popped again holds the pattern, including
+        // the ones the freelist metadata was written to
+        while let Some(block) = unsafe { list.pop() } {
+            let block = unsafe { core::slice::from_raw_parts(block, size) };
+            assert!(block.iter().all(|&byte| byte == 0xA5));
+        }
+    }
+}
+
+#[test]
+fn freelist_scrubs_reused_blocks() {
+    let mut memory = fake_memory(32, 4);
+    let mut list = FreeList::new_scrubbed(32, 0xA5);
+    push_all(&mut list, &mut memory);
+
+    // Data written to an allocation is gone once it's freed and allocated
+    // again
+    let block = unsafe { list.pop() }.unwrap();
+    unsafe {
+        core::ptr::write_bytes(block, 0x42, 32);
+        list.push(block);
+    }
+    let block = unsafe { list.pop() }.unwrap();
+    let block = unsafe { core::slice::from_raw_parts(block, 32) };
+    assert_eq!(block, [0xA5; 32]);
+}
@@ -0,0 +1,67 @@
+//! Internet checksums, as specified by RFC 1071
+
+use core::net::IpAddr;
+
+/// Compute a ones-complement checksum over the provided byte slice.
+pub fn checksum(bytes: &[u8]) -> u16 {
+    let mut checksum: u32 = 0;
+
+    // Process all 2-byte chunks
+    for chunk in bytes.chunks_exact(2) {
+        let word = u16::from_be_bytes([chunk[0], chunk[1]]);
+        checksum = checksum.wrapping_add(word as u32);
+    }
+
+    // Handle final byte (low byte) if length is odd
+    if let Some(&last_byte) = bytes.chunks_exact(2).remainder().first() {
+        checksum = checksum.wrapping_add((last_byte as u32) << 8);
+    }
+
+    // Fold carries
+    let checksum = (checksum & 0xFFFF).wrapping_add(checksum >> 16);
+    let checksum = (checksum & 0xFFFF).wrapping_add(checksum >> 16);
+    checksum as u16
+}
+
+/// Fold the carries of a ones-complement sum accumulator and complement it
+/// into a transport layer checksum.
+///
+/// A computed checksum of `0` is returned as `0xFFFF`, as `0` means that no
+/// checksum is present in UDP.
+pub fn finalize_checksum(acc: u32) -> u16 {
+    let acc = (acc & 0xFFFF) + (acc >> 16);
+    let acc = (acc & 0xFFFF) + (acc >> 16);
+    let checksum = !(acc as u16);
+    if checksum == 0 { 0xFFFF } else { checksum }
+}
+
+/// Compute the ones-complement sum of the pseudo-header which is covered
+/// by the TCP and UDP checksums. `len` is the size of the transport layer
+/// header and payload
+///
+/// Panics if the `src` and `dst` IP versions don't match
+pub fn pseudo_header_checksum(src: &IpAddr, dst: &IpAddr, proto: u8,
+                              len: u32) -> u16 {
+    let mut ph = [0u8; 40];
+    let pseudo_header = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            // IPv4 pseudo-header (12 bytes)
+            ph[0..4].copy_from_slice(&src.octets());
+            ph[4..8].copy_from_slice(&dst.octets());
+            ph[9] = proto;
+            ph[10..12].copy_from_slice(&(len as u16).to_be_bytes());
+            &ph[0..12]
+        },
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            // IPv6 pseudo-header (40 bytes)
+            ph[0..16].copy_from_slice(&src.octets());
+            ph[16..32].copy_from_slice(&dst.octets());
+            ph[32..36].copy_from_slice(&len.to_be_bytes());
+            ph[39] = proto;
+            &ph[0..40]
+        },
+        _ => panic!("Pseudo-header with mismatched IP versions"),
+    };
+
+    checksum(pseudo_header)
+}
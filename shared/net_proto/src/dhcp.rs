@@ -0,0 +1,380 @@
+//! DHCP options and the parameters of the leases they grant
+
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+
+/// DHCP options
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub enum DhcpOption<'a> {
+    Pad,
+    SubnetMask(Ipv4Addr),
+    Router(Ipv4Addr),
+
+    /// Raw list of DNS servers, see `DhcpOption::dns_servers()`
+    DnsServers(&'a [u8]),
+    BroadcastIp(Ipv4Addr),
+    RequestedIp(Ipv4Addr),
+    LeaseTime(u32),
+    MessageType(MessageType),
+    ServerIp(Ipv4Addr),
+    ParameterRequestList(&'a [u8]),
+    RenewalTime(u32),
+    Unknown(u8, &'a [u8]),
+    End,
+}
+
+/// Mapping of DHCP options to their IDs
+#[derive(Debug, PartialEq, Eq)]
+#[repr(u8)]
+#[allow(missing_docs)]
+pub enum DhcpOptionId {
+    Pad                  = 0,
+    SubnetMask           = 1,
+    Router               = 3,
+    DnsServer            = 6,
+    BroadcastIp          = 28,
+    RequestedIp          = 50,
+    LeaseTime            = 51,
+    MessageType          = 53,
+    ServerIp             = 54,
+    ParameterRequestList = 55,
+    RenewalTime          = 58,
+    End                  = 255,
+}
+
+impl TryFrom<u8> for DhcpOptionId {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0   => Ok(Self::Pad),
+            1   => Ok(Self::SubnetMask),
+            3   => Ok(Self::Router),
+            6   => Ok(Self::DnsServer),
+            28  => Ok(Self::BroadcastIp),
+            50  => Ok(Self::RequestedIp),
+            51  => Ok(Self::LeaseTime),
+            53  => Ok(Self::MessageType),
+            54  => Ok(Self::ServerIp),
+            55  => Ok(Self::ParameterRequestList),
+            58  => Ok(Self::RenewalTime),
+            255 => Ok(Self::End),
+            _   => Err(()),
+        }
+    }
+}
+
+impl<'a> DhcpOption<'a> {
+    /// Parse a DHCP option from a raw message, updating the message pointer to
+    /// reflect the number of parsed bytes
+    pub fn parse(ptr: &mut &'a [u8]) -> Option<Self> {
+        let code = *ptr.first()?;
+
+        // Handle single-byte options first
+        if let Ok(DhcpOptionId::Pad) = DhcpOptionId::try_from(code) {
+            *ptr = &ptr[1..];
+            return Some(Self::Pad);
+        }
+
+        if let Ok(DhcpOptionId::End) = DhcpOptionId::try_from(code) {
+            *ptr = &ptr[1..];
+            return Some(Self::End);
+        }
+
+        // Handle variable-length options
+        let len = *ptr.get(1)? as usize;
+        let payload = ptr.get(2..2 + len)?;
+
+        let option = match DhcpOptionId::try_from(code) {
+            Ok(id) => match id {
+                DhcpOptionId::SubnetMask => {
+                    let bytes: [u8; 4] = payload.try_into().ok()?;
+                    Self::SubnetMask(Ipv4Addr::from(u32::from_be_bytes(bytes)))
+                }
+                DhcpOptionId::Router => {
+                    // The routers are listed in order of preference, so only
+                    // the first one is used
+                    let bytes: [u8; 4] = payload.get(..4)?.try_into().ok()?;
+                    Self::Router(Ipv4Addr::from(u32::from_be_bytes(bytes)))
+                }
+                DhcpOptionId::DnsServer => {
+                    // Ignore a malformed trailing remainder of the list
+                    let len = payload.len() - payload.len() % 4;
+                    Self::DnsServers(&payload[..len])
+                }
+                DhcpOptionId::BroadcastIp => {
+                    let bytes: [u8; 4] = payload.try_into().ok()?;
+                    Self::BroadcastIp(Ipv4Addr::from(u32::from_be_bytes(bytes)))
+                }
+                DhcpOptionId::RequestedIp => {
+                    let bytes: [u8; 4] = payload.try_into().ok()?;
+                    Self::RequestedIp(Ipv4Addr::from(u32::from_be_bytes(bytes)))
+                }
+                DhcpOptionId::LeaseTime => {
+                    let bytes: [u8; 4] = payload.try_into().ok()?;
+                    Self::LeaseTime(u32::from_be_bytes(bytes))
+                }
+                DhcpOptionId::MessageType => {
+                    let byte: u8 = payload.first().copied()?;
+                    Self::MessageType(MessageType::from(byte))
+                }
+                DhcpOptionId::ServerIp => {
+                    let bytes: [u8; 4] = payload.try_into().ok()?;
+                    Self::ServerIp(Ipv4Addr::from(u32::from_be_bytes(bytes)))
+                }
+                DhcpOptionId::ParameterRequestList => {
+                    Self::ParameterRequestList(payload)
+                }
+                DhcpOptionId::RenewalTime => {
+                    let bytes: [u8; 4] = payload.try_into().ok()?;
+                    Self::RenewalTime(u32::from_be_bytes(bytes))
+                }
+                _ => unreachable!(), // Handled single-byte variants already
+            },
+            Err(_) => Self::Unknown(code, payload),
+        };
+
+        *ptr = &ptr[2 + len..];
+        Some(option)
+    }
+
+    /// Serialize a DHCP option by appending it to `buffer`
+    pub fn serialize(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Self::Pad => buffer.push(DhcpOptionId::Pad as u8),
+            Self::End => buffer.push(DhcpOptionId::End as u8),
+            Self::SubnetMask(addr) =>
+                Self::push_ip_option(buffer, DhcpOptionId::SubnetMask, addr),
+            Self::Router(addr) =>
+                Self::push_ip_option(buffer, DhcpOptionId::Router, addr),
+            Self::DnsServers(data) => {
+                buffer.push(DhcpOptionId::DnsServer as u8);
+                buffer.push(data.len() as u8);
+                buffer.extend_from_slice(data);
+            }
+            Self::BroadcastIp(addr) =>
+                Self::push_ip_option(buffer, DhcpOptionId::BroadcastIp, addr),
+            Self::RequestedIp(addr) =>
+                Self::push_ip_option(buffer, DhcpOptionId::RequestedIp, addr),
+            Self::ServerIp(addr) =>
+                Self::push_ip_option(buffer, DhcpOptionId::ServerIp, addr),
+            Self::LeaseTime(time) =>
+                Self::push_u32_option(buffer, DhcpOptionId::LeaseTime, *time),
+            Self::RenewalTime(time) =>
+                Self::push_u32_option(buffer, DhcpOptionId::RenewalTime, *time),
+            Self::MessageType(typ) => {
+                buffer.push(DhcpOptionId::MessageType as u8);
+                buffer.push(1);
+                buffer.push(*typ as u8);
+            }
+            Self::ParameterRequestList(data) => {
+                buffer.push(DhcpOptionId::ParameterRequestList as u8);
+                buffer.push(data.len() as u8);
+                buffer.extend_from_slice(data);
+            }
+            Self::Unknown(code, data) => {
+                buffer.push(*code);
+                buffer.push(data.len() as u8);
+                buffer.extend_from_slice(data);
+            }
+        }
+    }
+
+    /// Returns the DNS servers listed in a `DnsServers` option, in order of
+    /// preference. Returns nothing for any other option
+    pub fn dns_servers(&self) -> impl Iterator<Item = Ipv4Addr> + 'a {
+        let data: &'a [u8] = match self {
+            Self::DnsServers(data) => data,
+            _ => &[],
+        };
+
+        data.chunks_exact(4).map(|ip| {
+            Ipv4Addr::from(u32::from_be_bytes(ip.try_into().unwrap()))
+        })
+    }
+
+    // Helper functions for common patterns
+
+    fn push_ip_option(buffer: &mut Vec<u8>, code: DhcpOptionId, ip: &Ipv4Addr) {
+        buffer.push(code as u8);
+        buffer.push(4);
+        buffer.extend_from_slice(&ip.octets());
+    }
+
+    fn push_u32_option(buffer: &mut Vec<u8>, code: DhcpOptionId, value: u32) {
+        buffer.push(code as u8);
+        buffer.push(4);
+        buffer.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// DHCP client-server message type
+///
+/// [Source](https://datatracker.ietf.org/doc/html/rfc2131#section-3.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[allow(missing_docs)]
+pub enum MessageType {
+    Discover = 1,
+    Offer    = 2,
+    Request  = 3,
+    Ack      = 5,
+    Unsupported,
+}
+
+impl From<u8> for MessageType {
+    fn from(val: u8) -> Self {
+        match val {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            5 => Self::Ack,
+            _ => Self::Unsupported,
+        }
+    }
+}
+
+/// Parse the raw DHCP `options` of a message, up to the first malformed one
+pub fn parse_options(mut options: &[u8]) -> Vec<DhcpOption<'_>> {
+    let mut parsed = Vec::new();
+    while !options.is_empty() {
+        match DhcpOption::parse(&mut options) {
+            Some(option) => parsed.push(option),
+            None => break,
+        }
+    }
+    parsed
+}
+
+/// The parameters of a lease granted by a DHCPACK. The times are in seconds
+/// from the reception of the ACK, the infinite lease time being all ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ack {
+    pub broadcast_ip: Option<Ipv4Addr>,
+    pub subnet_mask:  Option<Ipv4Addr>,
+    pub gateway:      Option<Ipv4Addr>,
+    pub dns:          Option<Ipv4Addr>,
+
+    /// T1, after which the lease should be renewed
+    pub renewal_time: Option<u32>,
+
+    /// T2, after which the renewal is broadcast
+    pub rebinding_time: Option<u32>,
+
+    /// Time after which the lease is no longer valid
+    pub lease_time: Option<u32>,
+}
+
+impl Ack {
+    /// Get the parameters of the lease granted by the `options` of a DHCP
+    /// reply. Returns `None` if the reply isn't an ACK
+    pub fn from_options(options: &[DhcpOption]) -> Option<Self> {
+        // We're looking for an ACK now
+        options.iter()
+            .find(|&x| *x == DhcpOption::MessageType(MessageType::Ack))?;
+
+        // Save the broadcast IP
+        let broadcast_ip = options.iter().find_map(|x| {
+            if let DhcpOption::BroadcastIp(ip) = x {
+                Some(*ip)
+            } else { None }
+        });
+
+        // Save the subnet_mask
+        let subnet_mask = options.iter().find_map(|x| {
+            if let DhcpOption::SubnetMask(ip) = x {
+                Some(*ip)
+            } else { None }
+        });
+
+        // Save the most preferred router as the gateway
+        let gateway = options.iter().find_map(|x| {
+            if let DhcpOption::Router(ip) = x {
+                Some(*ip)
+            } else { None }
+        });
+
+        // Save the most preferred DNS server
+        let dns = options.iter().find_map(|x| x.dns_servers().next());
+
+        // Save the lease and the renewal times
+        let lease_time = options.iter().find_map(|x| {
+            if let DhcpOption::LeaseTime(time) = x {
+                Some(*time)
+            } else { None }
+        });
+        let renewal_time = options.iter().find_map(|x| {
+            if let DhcpOption::RenewalTime(time) = x {
+                Some(*time)
+            } else { None }
+        });
+
+        // T1 and T2 default to 50% and 87.5% of the lease time
+        let t1 = renewal_time.or(lease_time.map(|time| time / 2));
+        let t2 = lease_time.map(|time| (time as u64 * 7 / 8) as u32);
+
+        Some(Self {
+            broadcast_ip,
+            subnet_mask,
+            gateway,
+            dns,
+            renewal_time: t1,
+            rebinding_time: t2,
+            lease_time,
+        })
+    }
+}
+
+/// TSC deadlines of the lease times of an `Ack`, see `Deadlines::new()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadlines {
+    /// TSC deadline of T1, after which the lease should be renewed
+    pub renew_at: u64,
+
+    /// TSC deadline of T2, after which the renewal is broadcast
+    pub rebind_at: u64,
+
+    /// TSC deadline after which the lease is no longer valid
+    pub expires_at: u64,
+}
+
+impl Deadlines {
+    /// Get the deadlines of the lease granted by `ack`, received at the TSC
+    /// value `now` on a TSC running at `tsc_mhz`
+    pub fn new(ack: &Ack, now: u64, tsc_mhz: u64) -> Self {
+        Self {
+            renew_at:   deadline(ack.renewal_time, now, tsc_mhz),
+            rebind_at:  deadline(ack.rebinding_time, now, tsc_mhz),
+            expires_at: deadline(ack.lease_time, now, tsc_mhz),
+        }
+    }
+
+    /// Returns whether T1 has passed at `now` and the lease should be renewed
+    pub fn needs_renewal(&self, now: u64) -> bool {
+        now >= self.renew_at
+    }
+
+    /// Returns whether T2 has passed at `now` and the renewal should be
+    /// broadcast
+    pub fn needs_rebinding(&self, now: u64) -> bool {
+        now >= self.rebind_at
+    }
+
+    /// Returns whether the lease has expired at `now`
+    pub fn expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+}
+
+/// Returns the TSC deadline `secs` seconds after `now` on a TSC running at
+/// `tsc_mhz`. `None` and the infinite lease time (all ones) never pass
+fn deadline(secs: Option<u32>, now: u64, tsc_mhz: u64) -> u64 {
+    match secs {
+        Some(secs) if secs != u32::MAX => {
+            let ticks = (secs as u64 * 1_000_000).saturating_mul(tsc_mhz);
+            now.saturating_add(ticks)
+        }
+        _ => u64::MAX,
+    }
+}
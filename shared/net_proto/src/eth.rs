@@ -0,0 +1,75 @@
+//! Ethernet headers
+
+use crate::ParseError;
+
+/// Size of the Ethernet header
+pub const HEADER_LEN: usize = 14;
+
+/// The MAC address of a NIC
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Mac(pub [u8; 6]);
+
+impl Mac {
+    /// MAC address used for broadcasts
+    pub const BROADCAST: Mac = Mac([0xFF; 6]);
+
+    pub const ZERO: Mac = Mac([0; 6]);
+}
+
+/// Ethernet payload types (EtherTypes) known to the network stack
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EthType {
+    Ipv4 = 0x0800,
+    Arp  = 0x0806,
+    Ipv6 = 0x86DD,
+}
+
+impl EthType {
+    /// Get the known EtherType of the raw `eth_type`
+    pub fn from_raw(eth_type: u16) -> Option<Self> {
+        match eth_type {
+            x if x == Self::Ipv4 as u16 => Some(Self::Ipv4),
+            x if x == Self::Arp  as u16 => Some(Self::Arp),
+            x if x == Self::Ipv6 as u16 => Some(Self::Ipv6),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed Ethernet header
+#[derive(Debug)]
+pub struct Parsed<'a> {
+    /// Destination device MAC
+    pub dst_mac: Mac,
+
+    /// Source device MAC
+    pub src_mac: Mac,
+
+    /// Type of the ethernet payload
+    pub eth_type: u16,
+
+    /// Raw byte payload
+    pub payload: &'a [u8],
+}
+
+impl Parsed<'_> {
+    /// Returns the type of the payload, or `None` if it's not a type known to
+    /// the network stack
+    pub fn ethertype(&self) -> Option<EthType> {
+        EthType::from_raw(self.eth_type)
+    }
+}
+
+/// Parse the Ethernet header of `frame`
+pub fn parse(frame: &[u8]) -> Result<Parsed<'_>, ParseError> {
+    let (header, payload) = frame.split_at_checked(HEADER_LEN)
+        .ok_or(ParseError::TruncatedPacket)?;
+
+    let dst_mac = Mac(header[0x0..0x6].try_into().unwrap());
+    let src_mac = Mac(header[0x6..0xC].try_into().unwrap());
+    let eth_type = u16::from_be_bytes([header[0xC], header[0xD]]);
+
+    Ok(Parsed { dst_mac, src_mac, eth_type, payload })
+}
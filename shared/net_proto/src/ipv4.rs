@@ -0,0 +1,376 @@
+//! IPv4 headers, fragmentation and reassembly
+
+use core::net::Ipv4Addr;
+
+use crate::ParseError;
+
+/// Size of the IPv4 header without options
+pub const HEADER_LEN: usize = 20;
+
+/// Offset of the total length in the IPv4 header
+const TOTAL_LEN: usize = 2;
+
+/// Offset of the time to live in the IPv4 header
+const TTL: usize = 8;
+
+/// Offset of the protocol in the IPv4 header
+const PROTOCOL: usize = 9;
+
+/// Offset of the header checksum in the IPv4 header
+const CHECKSUM: usize = 10;
+
+/// Reserved bit of the flags and fragment offset field
+const FLAG_RESERVED: u16 = 1 << 15;
+
+/// "More fragments" bit of the flags and fragment offset field
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+
+/// Mask of the fragment offset (in 8 byte blocks) in the flags and fragment
+/// offset field
+const FRAG_OFFSET_MASK: u16 = 0x1FFF;
+
+/// Protocol number of TCP
+const PROTOCOL_TCP: u8 = 0x06;
+
+/// Protocol number of UDP
+const PROTOCOL_UDP: u8 = 0x11;
+
+/// Largest payload an IPv4 datagram can carry
+const MAX_PAYLOAD_LEN: usize = u16::MAX as usize - HEADER_LEN;
+
+/// Number of 8 byte blocks in the largest IPv4 payload
+const MAX_PAYLOAD_BLOCKS: usize = MAX_PAYLOAD_LEN.div_ceil(8);
+
+/// A parsed IPv4 header and payload
+#[derive(Debug)]
+pub struct Parsed<'a> {
+    /// Source IP address
+    pub src_ip: Ipv4Addr,
+
+    /// Destination IP address
+    pub dst_ip: Ipv4Addr,
+
+    /// IP payload protocol
+    pub protocol: u8,
+
+    /// Time to live of the datagram
+    pub ttl: u8,
+
+    /// Differentiated services code point of the datagram
+    pub dscp: u8,
+
+    /// Identification of the datagram this packet belongs to
+    pub id: u16,
+
+    /// Whether more fragments of the datagram follow this one
+    pub more_fragments: bool,
+
+    /// Offset of the payload of this fragment in the datagram in bytes
+    pub frag_offset: usize,
+
+    /// Raw bytes of the header
+    pub header: &'a [u8],
+
+    /// Raw byte payload of the IP packet
+    pub payload: &'a [u8],
+}
+
+impl Parsed<'_> {
+    /// Whether this packet is a fragment of a larger datagram
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments || self.frag_offset != 0
+    }
+
+    /// Returns whether the header checksum matches the header
+    pub fn verify_checksum(&self) -> bool {
+        // The ones-complement sum of a header including its checksum is all
+        // ones
+        crate::checksum(self.header) == 0xFFFF
+    }
+}
+
+/// Parse the IPv4 header at the start of `bytes`, accepting fragments of
+/// larger datagrams. The header checksum is verified if `verify_checksum` is
+/// set
+pub fn parse(bytes: &[u8], verify_checksum: bool)
+        -> Result<Parsed<'_>, ParseError> {
+    // Get the header. This will always be at least 20 bytes without options
+    let header = bytes.get(..HEADER_LEN).ok_or(ParseError::InvalidIpHeader)?;
+    let word = |idx: usize| u16::from_be_bytes([header[idx], header[idx + 1]]);
+    let addr = |idx: usize| Ipv4Addr::new(header[idx], header[idx + 1],
+                                          header[idx + 2], header[idx + 3]);
+
+    // Verify the version and the header length
+    if (header[0] >> 4)  != 4 {
+        return Err(ParseError::UnsupportedVersion);
+    }
+    if (header[0] & 0xF) != 5 {
+        return Err(ParseError::IpOptionsUnsupported);
+    }
+
+    // Verify the checksum of the header
+    if verify_checksum && crate::checksum(header) != 0xFFFF {
+        return Err(ParseError::BadChecksum);
+    }
+
+    // Get the flags and the fragment offset and make sure the reserved bit
+    // is clear
+    let flags = word(6);
+    if (flags & FLAG_RESERVED) != 0 {
+        return Err(ParseError::InvalidIpHeader);
+    }
+
+    // Validate the total length of the header and the payload
+    let total_length = word(TOTAL_LEN) as usize;
+    if total_length < header.len() || total_length > bytes.len() {
+        return Err(ParseError::InvalidLength);
+    }
+
+    Ok(Parsed {
+        src_ip: addr(12),
+        dst_ip: addr(16),
+        protocol: header[PROTOCOL],
+        ttl: header[TTL],
+        dscp: header[1] >> 2,
+        id: word(4),
+        more_fragments: (flags & FLAG_MORE_FRAGMENTS) != 0,
+        frag_offset: (flags & FRAG_OFFSET_MASK) as usize * 8,
+        header,
+        payload: &bytes[HEADER_LEN..total_length],
+    })
+}
+
+/// Create an IPv4 header without options for a datagram from `src` to `dst`.
+///
+/// The time to live defaults to 64 and the DSCP to 0. The protocol, length and
+/// checksum are zeroed, to be filled in with `set_protocol()` and `finalize()`
+pub fn header(src: &Ipv4Addr, dst: &Ipv4Addr) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+
+    // Ip version 4 and 20 byte header length
+    header[0] = 4 << 4 | 5;
+
+    // 64 TTL. Identification, flags and fragment offset are all zero
+    header[TTL] = 64;
+
+    // Source and destination IPs
+    header[12..16].copy_from_slice(&src.octets());
+    header[16..20].copy_from_slice(&dst.octets());
+    header
+}
+
+/// Set the transport protocol of the payload of the IPv4 `header`
+pub fn set_protocol(header: &mut [u8], protocol: u8) {
+    header[PROTOCOL] = protocol;
+}
+
+/// Set the time to live of the IPv4 `header`
+pub fn set_ttl(header: &mut [u8], ttl: u8) {
+    header[TTL] = ttl;
+}
+
+/// Set the 6-bit differentiated services code point of the IPv4 `header`,
+/// leaving the ECN bits clear
+pub fn set_dscp(header: &mut [u8], dscp: u8) {
+    header[1] = (dscp & 0x3F) << 2;
+}
+
+/// Finalize the IPv4 `header`, writing in the total length of the header and
+/// the `payload_len` bytes of payload. The checksum is computed unless the NIC
+/// inserts it, in which case it's left zeroed for the NIC
+///
+/// Panics if the total length overflows
+pub fn finalize(header: &mut [u8], payload_len: u16, checksum: bool) {
+    let size = (header.len() as u16).checked_add(payload_len)
+        .expect("totale packet size len overflow");
+    header[TOTAL_LEN..TOTAL_LEN + 2].copy_from_slice(&size.to_be_bytes());
+
+    if checksum {
+        write_checksum(header);
+    }
+}
+
+/// Calculates and writes the checksum of a raw IPv4 `header`
+pub fn write_checksum(header: &mut [u8]) {
+    // The checksum has to be zero while it's being calculated
+    header[CHECKSUM..CHECKSUM + 2].fill(0);
+    let checksum = !crate::checksum(header);
+    header[CHECKSUM..CHECKSUM + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Complete the TCP or UDP checksum of an IPv4 `datagram` which was left to the
+/// NIC. The NIC only sees a single fragment at a time, so it can't compute a
+/// checksum spanning the whole datagram
+pub fn finish_transport_checksum(datagram: &mut [u8]) {
+    // Get the offset of the checksum in the transport header
+    let csum = match datagram.get(PROTOCOL) {
+        Some(&PROTOCOL_TCP) => 16,
+        Some(&PROTOCOL_UDP) => 6,
+        _ => return,
+    };
+    let Some(segment) = datagram.get_mut(HEADER_LEN..) else { return; };
+    let Some(seed) = segment.get(csum..csum + 2) else { return; };
+
+    // The builders seed the checksum with the pseudo-header sum. A zero means
+    // that no checksum is wanted, as is the case with UDP over IPv4
+    if seed == [0, 0] { return; }
+
+    // Sum up the segment, seed included, as the NIC would have
+    let sum = crate::checksum(segment) as u32;
+    let checksum = crate::finalize_checksum(sum);
+    segment[csum..csum + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Split the `payload` of the IPv4 datagram with the `header` into fragments
+/// which fit into the `mtu`, all identified by `id`. The header and payload of
+/// each fragment are handed to `emit`, along with whether it's the last one
+pub fn fragment<F>(header: &[u8], payload: &[u8], mtu: usize, id: u16,
+                   mut emit: F)
+where
+    F: FnMut(&[u8; HEADER_LEN], &[u8], bool),
+{
+    // All fragments but the last one must be a multiple of 8 bytes
+    let frag_len = (mtu - HEADER_LEN) & !7;
+    let count = payload.len().div_ceil(frag_len);
+
+    for (ii, data) in payload.chunks(frag_len).enumerate() {
+        let last = ii + 1 == count;
+
+        // Create the header of this fragment
+        let mut frag = [0u8; HEADER_LEN];
+        frag.copy_from_slice(&header[..HEADER_LEN]);
+        let len = (HEADER_LEN + data.len()) as u16;
+        let mut flags = ((ii * frag_len / 8) as u16) & FRAG_OFFSET_MASK;
+        if !last { flags |= FLAG_MORE_FRAGMENTS; }
+        frag[TOTAL_LEN..TOTAL_LEN + 2].copy_from_slice(&len.to_be_bytes());
+        frag[4..6].copy_from_slice(&id.to_be_bytes());
+        frag[6..8].copy_from_slice(&flags.to_be_bytes());
+        write_checksum(&mut frag);
+
+        emit(&frag, data, last);
+    }
+}
+
+/// Source, destination, identification and protocol of a datagram, which
+/// identify the fragments belonging to it
+pub type ReassemblyKey = (Ipv4Addr, Ipv4Addr, u16, u8);
+
+/// A datagram which is being reassembled from its fragments in a `buffer`.
+///
+/// The buffer starts with the headers of the datagram, the IPv4 header last,
+/// followed by the payload. The largest datagram which can be reassembled is
+/// limited by the size of the buffer
+pub struct Reassembly<B: AsMut<[u8]>> {
+    /// Source, destination, identification and protocol of the datagram
+    key: ReassemblyKey,
+
+    /// The buffer the datagram is reassembled in
+    buffer: B,
+
+    /// Offset of the payload in the buffer
+    payload_offset: usize,
+
+    /// Bitmap of the 8 byte blocks of the payload which have been received
+    received: [u64; MAX_PAYLOAD_BLOCKS.div_ceil(64)],
+
+    /// Size of the payload, known once the last fragment has been received
+    total: Option<usize>,
+
+    /// Time after which the reassembly is dropped
+    deadline: u64,
+}
+
+impl<B: AsMut<[u8]>> Reassembly<B> {
+    /// Start a reassembly of a datagram in `buffer`, copying over the `headers`
+    /// of one of its fragments, which end with the IPv4 header. The reassembly
+    /// should be dropped once the time passes the `deadline`
+    ///
+    /// Panics if the `headers` don't fit into the `buffer`
+    pub fn new(key: ReassemblyKey, mut buffer: B, headers: &[u8],
+               deadline: u64) -> Self {
+        buffer.as_mut()[..headers.len()].copy_from_slice(headers);
+
+        Self {
+            key,
+            buffer,
+            payload_offset: headers.len(),
+            received: [0; MAX_PAYLOAD_BLOCKS.div_ceil(64)],
+            total: None,
+            deadline,
+        }
+    }
+
+    /// Get the key of the datagram being reassembled
+    pub fn key(&self) -> ReassemblyKey {
+        self.key
+    }
+
+    /// Get the time after which the reassembly is dropped
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /// Take the buffer out of a reassembly which is being dropped
+    pub fn into_buffer(self) -> B {
+        self.buffer
+    }
+
+    /// Put the `payload` of a fragment at `offset` into the datagram. `more`
+    /// signifies whether more fragments follow this one
+    ///
+    /// Returns `None` if the fragment is invalid or doesn't fit into the
+    /// reassembly buffer
+    pub fn insert(&mut self, offset: usize, more: bool, payload: &[u8])
+            -> Option<()> {
+        // Make sure the fragment fits into the buffer
+        let end = offset.checked_add(payload.len())?;
+        let buffer = self.buffer.as_mut();
+        if end > MAX_PAYLOAD_LEN || end > buffer.len() - self.payload_offset {
+            return None;
+        }
+
+        // All fragments but the last one must be a multiple of 8 bytes
+        if more && !payload.len().is_multiple_of(8) { return None; }
+
+        // The last fragment determines the size of the datagram
+        if !more { self.total = Some(end); }
+
+        // Copy in the payload
+        let start = self.payload_offset + offset;
+        buffer[start..start + payload.len()].copy_from_slice(payload);
+
+        // Mark the blocks as received
+        for block in offset / 8..end.div_ceil(8) {
+            self.received[block / 64] |= 1 << (block % 64);
+        }
+
+        Some(())
+    }
+
+    /// Whether all fragments of the datagram have been received
+    pub fn is_complete(&self) -> bool {
+        self.total.is_some_and(|total| {
+            (0..total.div_ceil(8)).all(|block| {
+                self.received[block / 64] & (1 << (block % 64)) != 0
+            })
+        })
+    }
+
+    /// Finish the reassembly, returning the buffer with the whole datagram and
+    /// the length of the headers and the payload in it
+    ///
+    /// Panics if the reassembly isn't complete
+    pub fn finish(mut self) -> (B, usize) {
+        let total = self.total.expect("Finished an incomplete reassembly");
+        let len = (HEADER_LEN + total) as u16;
+
+        // Fix up the IPv4 header to describe the whole datagram
+        let header = &mut self.buffer.as_mut()
+            [self.payload_offset - HEADER_LEN..self.payload_offset];
+        header[TOTAL_LEN..TOTAL_LEN + 2].copy_from_slice(&len.to_be_bytes());
+        header[6..8].fill(0);
+        write_checksum(header);
+
+        (self.buffer, self.payload_offset + total)
+    }
+}
@@ -0,0 +1,60 @@
+//! Network protocol and NIC ring logic which doesn't depend on the hardware
+//! or on the packet buffers of the kernel, operating on plain byte slices
+
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(test)]
+mod tests;
+
+mod checksum;
+pub use checksum::*;
+
+pub mod dhcp;
+pub mod eth;
+pub mod ipv4;
+pub mod route;
+pub mod rx_ring;
+pub mod tcp;
+
+/// Errors that can occur while parsing network packet headers
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// Indicates the packet is too short to contain the required data for the
+    /// given field.
+    TruncatedPacket,
+
+    /// The MAC address bytes could not be properly converted into a 6-byte
+    /// array
+    InvalidMacAddress,
+
+    /// Attempted to parse a big-endian `u16` but got an error
+    InvalidWord,
+
+    /// Attempted to parse a big-endian `u32` but got an error
+    InvalidDword,
+
+    /// The Ethernet frame indicates a version we do not support
+    UnsupportedVersion,
+
+    /// The IP header is either missing or too short to be valid
+    InvalidIpHeader,
+
+    /// The IP header included options which we do not support
+    IpOptionsUnsupported,
+
+    /// Attempted to parse an IP packet but got invalid protocol
+    InvalidIpProtocol,
+
+    /// The IP packet is a fragment of a larger datagram which has to be
+    /// reassembled before its payload can be parsed
+    Fragmented,
+
+    /// The total packet length is invalid (too short or longer than
+    /// available data)
+    InvalidLength,
+
+    /// The IP header checksum didn't match the header
+    BadChecksum,
+}
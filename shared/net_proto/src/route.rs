@@ -0,0 +1,73 @@
+//! IPv4 configuration of an interface and the routing decisions based on it
+
+use core::net::Ipv4Addr;
+
+use crate::dhcp::Ack;
+
+/// The IPv4 configuration of an interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    /// The address of the interface
+    pub addr:        Ipv4Addr,
+
+    /// The mask of the subnet the interface is on
+    pub subnet_mask: Option<Ipv4Addr>,
+
+    /// The broadcast address of the subnet
+    pub broadcast:   Option<Ipv4Addr>,
+
+    /// The router through which destinations outside of the subnet are
+    /// reached
+    pub gateway:     Option<Ipv4Addr>,
+}
+
+/// Where a packet for a destination has to be sent, see `Ipv4Config::route()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NextHop {
+    /// The destination is a broadcast address, no address has to be resolved
+    Broadcast,
+
+    /// The packet is sent to the host with this IP, either the destination
+    /// itself or the gateway
+    Host(Ipv4Addr),
+}
+
+impl Ipv4Config {
+    /// Create the configuration of `addr` leased by a DHCP `ack`
+    pub fn from_ack(addr: Ipv4Addr, ack: &Ack) -> Self {
+        Self {
+            addr,
+            subnet_mask: ack.subnet_mask,
+            broadcast:   ack.broadcast_ip,
+            gateway:     ack.gateway,
+        }
+    }
+
+    /// Returns whether `ip` is within the subnet of this configuration.
+    /// Without a subnet mask, all addresses are considered to be on the subnet
+    pub fn on_subnet(&self, ip: Ipv4Addr) -> bool {
+        let mask = self.subnet_mask.map_or(0, |mask| mask.to_bits());
+        (ip.to_bits() & mask) == (self.addr.to_bits() & mask)
+    }
+
+    /// Returns whether `ip` is a broadcast address for this configuration
+    pub fn is_broadcast(&self, ip: Ipv4Addr) -> bool {
+        ip.is_broadcast() || self.broadcast == Some(ip)
+    }
+
+    /// Returns the IP of the next hop towards `ip`, which is either `ip`
+    /// itself if it's on the subnet, or the gateway. Returns `None` if `ip`
+    /// isn't on the subnet and there is no gateway
+    pub fn next_hop(&self, ip: Ipv4Addr) -> Option<Ipv4Addr> {
+        if self.on_subnet(ip) { Some(ip) } else { self.gateway }
+    }
+
+    /// Decide where a packet for `dst` has to be sent. Returns `None` if
+    /// there's no route to `dst`
+    pub fn route(&self, dst: Ipv4Addr) -> Option<NextHop> {
+        // Broadcasts don't need to be resolved, everything else is sent to
+        // the next hop
+        if self.is_broadcast(dst) { return Some(NextHop::Broadcast); }
+        self.next_hop(dst).map(NextHop::Host)
+    }
+}
@@ -0,0 +1,108 @@
+//! Legacy receive descriptor rings of Intel NICs
+
+/// Intel NIC receive descriptor
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct RxDescriptor {
+    pub addr:     u64,
+    pub len:      u16,
+    pub checksum: u16,
+    pub status:   u8,
+    pub errors:   u8,
+    pub special:  u16,
+}
+
+/// Errors reported by the NIC for a received frame, as the raw error bits of
+/// its RX descriptor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RxError(pub u8);
+
+/// A buffer the NIC can receive frames into
+pub trait RxBuffer {
+    /// Get the physical address the NIC writes the frame to
+    fn phys_addr(&self) -> u64;
+
+    /// Set the length of the frame received into the buffer
+    fn set_len(&mut self, len: usize);
+}
+
+/// A ring of receive descriptors `descs`, each of them with the buffer at the
+/// same index in `buffers`
+pub struct RxRing<'a, B: RxBuffer> {
+    /// The descriptors shared with the NIC
+    pub descs: &'a mut [RxDescriptor],
+
+    /// Receive buffers corresponding to their descriptors
+    pub buffers: &'a mut [B],
+
+    /// Index of the descriptor which is the next one to get a frame from the
+    /// NIC
+    pub head: &'a mut usize,
+}
+
+impl<B: RxBuffer> RxRing<'_, B> {
+    /// Take the next received frame out of the ring, handing the buffer
+    /// returned by `replacement` to the NIC in its place. `tail` is called
+    /// with the index of the descriptor handed back to the NIC, which is to be
+    /// written to its tail register.
+    ///
+    /// If there's no frame on the line or `replacement` returns `None`, the
+    /// ring is left untouched. Frames received with errors are dropped,
+    /// handing their buffers straight back to the NIC, and the errors are
+    /// returned.
+    pub fn pop(&mut self, replacement: impl FnOnce() -> Option<B>,
+               tail: impl FnOnce(usize)) -> Result<Option<B>, RxError> {
+        let head = *self.head;
+        let desc = &self.descs[head];
+
+        // The NIC writes the descriptors behind our back
+        let (status, errors, len) = unsafe {
+            (core::ptr::read_volatile(&desc.status),
+             core::ptr::read_volatile(&desc.errors),
+             core::ptr::read_volatile(&desc.len) as usize)
+        };
+
+        // Check if there's a frame on the line and bail out if not
+        if status & 1 == 0 { return Ok(None); }
+
+        // Drop the frame if the NIC reported errors for it, reusing its
+        // buffer for the descriptor
+        if errors != 0 {
+            let phys_addr = self.buffers[head].phys_addr();
+            self.advance(phys_addr, tail);
+            return Err(RxError(errors));
+        }
+
+        // Get a new buffer for this descriptor
+        let Some(mut buffer) = replacement() else { return Ok(None) };
+        let phys_addr = buffer.phys_addr();
+
+        // Swap in the new buffer with the old one
+        core::mem::swap(&mut buffer, &mut self.buffers[head]);
+        self.advance(phys_addr, tail);
+
+        // Set the length of the frame and return it
+        buffer.set_len(len);
+        Ok(Some(buffer))
+    }
+
+    /// Hand the descriptor at the head of the ring back to the NIC with the
+    /// buffer at `phys_addr`, and move on to the next descriptor
+    fn advance(&mut self, phys_addr: u64, tail: impl FnOnce(usize)) {
+        let head = *self.head;
+
+        // Put this descriptor back for use by the NIC
+        unsafe {
+            core::ptr::write_volatile(
+                &mut self.descs[head],
+                RxDescriptor { addr: phys_addr, ..Default::default() });
+        }
+
+        // Let the NIC know this buffer is available again
+        tail(head);
+
+        // Increment the head
+        *self.head = (head + 1) % self.descs.len();
+    }
+}
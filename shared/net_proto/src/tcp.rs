@@ -0,0 +1,173 @@
+//! The receiving side of TCP connections
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+/// Maximum number of out of order segments buffered per connection
+const MAX_OUT_OF_ORDER: usize = 16;
+
+/// What happened to a segment taken in by [`Receiver::receive`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Received {
+    /// The segment carried no data
+    Empty,
+
+    /// The payload was put into the window, followed by the buffered segments
+    /// it made contiguous with the received data
+    InOrder,
+
+    /// The segment arrived ahead of the next expected byte. It was buffered,
+    /// unless it didn't fit into the window or the buffer
+    OutOfOrder,
+
+    /// The segment only carried data that was received already, or it didn't
+    /// fit into the window, so it was dropped
+    Dropped,
+}
+
+impl Received {
+    /// Whether the segment has to be acknowledged.
+    ///
+    /// Segments which aren't the next expected one are acknowledged as well,
+    /// telling the remote end which byte we expect. Duplicate ACKs sent for
+    /// out of order segments trigger its fast retransmit
+    pub fn needs_ack(self) -> bool {
+        self != Received::Empty
+    }
+}
+
+/// The receiving side of a TCP connection
+pub struct Receiver {
+    /// TCP receive window
+    window: VecDeque<u8>,
+
+    /// Maximum number of bytes held in the receive window
+    window_size: usize,
+
+    /// Segments received ahead of the next expected sequence number, keyed by
+    /// their sequence numbers
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+
+    /// The sequence number of the next byte expected from the remote end
+    ack: u32,
+}
+
+impl Receiver {
+    /// Create an empty receiver holding at most `window_size` bytes
+    pub fn new(window_size: usize) -> Self {
+        Self {
+            window: VecDeque::with_capacity(window_size),
+            window_size,
+            out_of_order: BTreeMap::new(),
+            ack: 0,
+        }
+    }
+
+    /// Get the sequence number of the next byte expected from the remote end
+    pub fn ack(&self) -> u32 {
+        self.ack
+    }
+
+    /// Expect the next byte from the remote end at `ack`, e.g. after a SYN
+    pub fn set_ack(&mut self, ack: u32) {
+        self.ack = ack;
+    }
+
+    /// Set the maximum number of bytes held in the receive window.
+    ///
+    /// Bytes already in a window that's being shrunk are kept
+    pub fn set_window_size(&mut self, size: usize) {
+        let len = self.window.len();
+        self.window.reserve(size.saturating_sub(len));
+        self.window_size = size;
+    }
+
+    /// Whether there's no received data waiting to be read
+    pub fn is_empty(&self) -> bool {
+        self.window.is_empty()
+    }
+
+    /// Move as much of the received data as fits into `buf`, returning the
+    /// number of bytes moved
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.window.len());
+        for (dst, src) in buf.iter_mut().zip(self.window.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+
+    /// Get the number of bytes of the receive window that are still free, as
+    /// advertised to the remote end
+    pub fn free_window(&self) -> u16 {
+        self.window_size.saturating_sub(self.window.len())
+            .min(u16::MAX as usize) as u16
+    }
+
+    /// Buffer the `payload` of a segment with `seq` which arrived ahead of the
+    /// next expected sequence number.
+    ///
+    /// Segments reaching past the free window and segments arriving while the
+    /// buffer is full are dropped
+    fn buffer_out_of_order(&mut self, seq: u32, payload: &[u8]) {
+        // Drop segments which don't fit into the advertised window
+        let offset = seq.wrapping_sub(self.ack) as usize;
+        let end = offset.saturating_add(payload.len());
+        if end > self.free_window() as usize { return; }
+
+        // Drop the segment if the buffer is full
+        if self.out_of_order.len() >= MAX_OUT_OF_ORDER
+                && !self.out_of_order.contains_key(&seq) {
+            return;
+        }
+
+        self.out_of_order.insert(seq, payload.to_vec());
+    }
+
+    /// Move the buffered segments which are now contiguous with the received
+    /// data into the window, advancing the ack
+    fn flush_out_of_order(&mut self) {
+        while let Some(payload) = self.out_of_order.remove(&self.ack) {
+            if payload.len() > self.free_window() as usize { break; }
+
+            self.window.extend(&payload);
+            self.ack = self.ack.wrapping_add(payload.len() as u32);
+        }
+
+        // Drop the segments which start in data that has already been received.
+        // Retransmissions will cover them if they carried anything new
+        let ack = self.ack;
+        self.out_of_order.retain(|&seq, _| (seq.wrapping_sub(ack) as i32) > 0);
+    }
+
+    /// Take in the `payload` of a segment with `seq`. Segments which arrive
+    /// ahead of the next expected byte are buffered until the gap before them
+    /// is filled
+    pub fn receive(&mut self, seq: u32, payload: &[u8]) -> Received {
+        if payload.is_empty() { return Received::Empty; }
+
+        // Buffer segments which arrive out of order and drop the ones which
+        // start in data that has already been received
+        let offset = seq.wrapping_sub(self.ack) as i32;
+        if offset < 0 { return Received::Dropped; }
+        if offset > 0 {
+            self.buffer_out_of_order(seq, payload);
+            return Received::OutOfOrder;
+        }
+
+        // Drop packets that exceed our window; the remote side should never
+        // send more than that.
+        if payload.len() > self.free_window() as usize {
+            return Received::Dropped;
+        }
+
+        // Save the data into our window and update the ack to indicate we
+        // read the bytes
+        self.window.extend(payload);
+        self.ack = self.ack.wrapping_add(payload.len() as u32);
+
+        // Deliver the buffered segments which follow this one
+        self.flush_out_of_order();
+        Received::InOrder
+    }
+}
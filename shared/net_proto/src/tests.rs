@@ -0,0 +1,756 @@
+extern crate std;
+
+use super::*;
+
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The example data of RFC 1071, section 3
+const RFC1071_DATA: [u8; 8] = [0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7];
+
+/// An IPv4 header with a valid checksum of `0xB861`
+const IPV4_HEADER: [u8; 20] = [
+    0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+    0xB8, 0x61, 0xC0, 0xA8, 0x00, 0x01, 0xC0, 0xA8, 0x00, 0xC7,
+];
+
+#[test]
+fn checksum_rfc1071_example() {
+    // The sum is 0x2DDF0, which folds into 0xDDF2
+    assert_eq!(checksum(&RFC1071_DATA), 0xDDF2);
+    assert_eq!(finalize_checksum(0x2DDF0), !0xDDF2);
+
+    // The sum is independent of the byte order of the words, RFC 1071 1.2.(B)
+    let swapped: std::vec::Vec<u8> = RFC1071_DATA.chunks(2)
+        .flat_map(|x| [x[1], x[0]])
+        .collect();
+    assert_eq!(checksum(&swapped), 0xDDF2u16.swap_bytes());
+}
+
+#[test]
+fn checksum_odd_length() {
+    // The last byte is padded with a zero, RFC 1071 4.1
+    assert_eq!(checksum(&RFC1071_DATA[..7]), checksum(&[
+        0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0x00,
+    ]));
+    assert_eq!(checksum(&[0xAB]), 0xAB00);
+    assert_eq!(checksum(&[]), 0);
+}
+
+#[test]
+fn checksum_ipv4_header() {
+    // A header including its checksum sums to all ones
+    assert_eq!(checksum(&IPV4_HEADER), 0xFFFF);
+
+    // Zeroing the checksum field gives the checksum back
+    let mut header = IPV4_HEADER;
+    header[10..12].fill(0);
+    assert_eq!(!checksum(&header), 0xB861);
+}
+
+#[test]
+fn finalize_checksum_folds_carries() {
+    assert_eq!(finalize_checksum(0), 0xFFFF);
+    assert_eq!(finalize_checksum(0x1234), !0x1234);
+
+    // Both carries have to be folded, 0x1FFFE folds into 0xFFFF
+    assert_eq!(finalize_checksum(0x2_0001), !0x0003);
+    assert_eq!(finalize_checksum(0xFFFF_FFFF), 0xFFFF);
+}
+
+#[test]
+fn finalize_checksum_never_zero() {
+    // A checksum of zero means that UDP has no checksum, so all ones is
+    // transmitted instead. Both are zero in ones-complement
+    assert_eq!(finalize_checksum(0xFFFF), 0xFFFF);
+    assert_eq!(finalize_checksum(0x1_FFFE), 0xFFFF);
+}
+
+#[test]
+fn pseudo_header_ipv4() {
+    let src = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let dst = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    // 0x0A00 + 0x0001 + 0x0A00 + 0x0002 + 0x0011 + 0x000C
+    assert_eq!(pseudo_header_checksum(&src, &dst, 17, 12), 0x1420);
+
+    // The addresses are summed up in any order
+    assert_eq!(pseudo_header_checksum(&dst, &src, 17, 12), 0x1420);
+}
+
+#[test]
+fn pseudo_header_ipv6() {
+    let src = IpAddr::V6(Ipv6Addr::new(0xFE80, 0, 0, 0, 0, 0, 0, 1));
+    let dst = IpAddr::V6(Ipv6Addr::new(0xFE80, 0, 0, 0, 0, 0, 0, 2));
+
+    // 2 * 0xFE80 + 0x0001 + 0x0002 + 0x0014 + 0x0006, with the carry folded
+    assert_eq!(pseudo_header_checksum(&src, &dst, 6, 20), 0xFD1E);
+}
+
+#[test]
+fn transport_checksum_verifies() {
+    let src = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let dst = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    // UDP from port 68 to 67 with a 4 byte payload and a zeroed checksum
+    let mut segment = [0x00, 0x44, 0x00, 0x43, 0x00, 0x0C, 0x00, 0x00,
+                       0xDE, 0xAD, 0xBE, 0xEF];
+    let acc = pseudo_header_checksum(&src, &dst, 17, 12) as u32
+        + checksum(&segment) as u32;
+    let csum = finalize_checksum(acc);
+    segment[6..8].copy_from_slice(&csum.to_be_bytes());
+
+    // The receiver sums up everything including the checksum into all ones
+    let acc = pseudo_header_checksum(&src, &dst, 17, 12) as u32
+        + checksum(&segment) as u32;
+    assert_eq!(finalize_checksum(acc), 0xFFFF);
+}
+
+#[test]
+#[should_panic(expected = "mismatched IP versions")]
+fn pseudo_header_mismatched_versions() {
+    let src = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let dst = IpAddr::V6(Ipv6Addr::LOCALHOST);
+    pseudo_header_checksum(&src, &dst, 17, 8);
+}
+
+/// `IPV4_HEADER` followed by its 95 byte UDP datagram, zeroed
+fn ipv4_datagram() -> std::vec::Vec<u8> {
+    let mut datagram = IPV4_HEADER.to_vec();
+    datagram.resize(0x73, 0);
+    datagram
+}
+
+#[test]
+fn ipv4_parse_valid_header() {
+    let datagram = ipv4_datagram();
+    let ip = ipv4::parse(&datagram, true).unwrap();
+    assert_eq!(ip.src_ip, Ipv4Addr::new(192, 168, 0, 1));
+    assert_eq!(ip.dst_ip, Ipv4Addr::new(192, 168, 0, 199));
+    assert_eq!(ip.protocol, 17);
+    assert_eq!(ip.ttl, 64);
+    assert_eq!(ip.payload.len(), 0x73 - ipv4::HEADER_LEN);
+    assert!(ip.verify_checksum());
+
+    // The "don't fragment" flag is set, this isn't a fragment
+    assert!(!ip.is_fragment());
+}
+
+#[test]
+fn ipv4_parse_corrupted_header() {
+    // Flip a bit of the destination address
+    let mut datagram = ipv4_datagram();
+    datagram[19] ^= 0x01;
+    assert_eq!(ipv4::parse(&datagram, true).unwrap_err(),
+        ParseError::BadChecksum);
+
+    // The header is still accepted if the NIC verified the checksum already
+    let ip = ipv4::parse(&datagram, false).unwrap();
+    assert_eq!(ip.dst_ip, Ipv4Addr::new(192, 168, 0, 198));
+    assert!(!ip.verify_checksum());
+}
+
+#[test]
+fn ipv4_parse_invalid_headers() {
+    let datagram = ipv4_datagram();
+    assert_eq!(ipv4::parse(&datagram[..19], false).unwrap_err(),
+        ParseError::InvalidIpHeader);
+
+    // The total length reaches past the end of the data
+    assert_eq!(ipv4::parse(&datagram[..0x72], false).unwrap_err(),
+        ParseError::InvalidLength);
+
+    // Options and IPv6 aren't supported
+    let mut options = datagram.clone();
+    options[0] = 0x46;
+    assert_eq!(ipv4::parse(&options, false).unwrap_err(),
+        ParseError::IpOptionsUnsupported);
+    let mut ipv6 = datagram.clone();
+    ipv6[0] = 0x65;
+    assert_eq!(ipv4::parse(&ipv6, false).unwrap_err(),
+        ParseError::UnsupportedVersion);
+}
+
+#[test]
+fn ipv4_header_defaults() {
+    let src = Ipv4Addr::new(10, 0, 0, 1);
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+    let mut datagram = ipv4::header(&src, &dst).to_vec();
+    ipv4::finalize(&mut datagram, 0, true);
+
+    let ip = ipv4::parse(&datagram, true).unwrap();
+    assert_eq!((ip.src_ip, ip.dst_ip), (src, dst));
+    assert_eq!((ip.ttl, ip.dscp), (64, 0));
+    assert!(ip.payload.is_empty());
+}
+
+#[test]
+fn ipv4_finalized_header_carries_ttl() {
+    let src = Ipv4Addr::new(10, 0, 0, 1);
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+    let mut datagram = ipv4::header(&src, &dst).to_vec();
+    ipv4::set_protocol(&mut datagram, 17);
+    ipv4::set_ttl(&mut datagram, 5);
+    ipv4::set_dscp(&mut datagram, 46);
+    ipv4::finalize(&mut datagram[..ipv4::HEADER_LEN], 8, true);
+    datagram.extend_from_slice(&[0; 8]);
+
+    // The checksum covers the configured fields
+    let ip = ipv4::parse(&datagram, true).unwrap();
+    assert_eq!(ip.ttl, 5);
+    assert_eq!(ip.dscp, 46);
+    assert_eq!(ip.protocol, 17);
+    assert_eq!(ip.payload.len(), 8);
+
+    // The DSCP leaves the ECN bits clear
+    assert_eq!(datagram[1], 46 << 2);
+}
+
+#[test]
+fn ipv4_finalize_leaves_checksum_to_nic() {
+    let src = Ipv4Addr::new(10, 0, 0, 1);
+    let mut header = ipv4::header(&src, &src);
+    ipv4::finalize(&mut header, 0, false);
+    assert_eq!(header[10..12], [0, 0]);
+    assert!(ipv4::parse(&header, false).is_ok());
+}
+
+/// Build an IPv4 datagram from 10.0.0.1 to 10.0.0.2 carrying UDP with `len`
+/// bytes of payload. The UDP checksum is seeded with the pseudo-header sum,
+/// as the builders do when the NIC is to insert the checksum
+fn offloaded_udp_datagram(len: usize) -> std::vec::Vec<u8> {
+    let src = Ipv4Addr::new(10, 0, 0, 1);
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+    let udp_len = 8 + len as u16;
+
+    let mut datagram = ipv4::header(&src, &dst).to_vec();
+    ipv4::set_protocol(&mut datagram, 17);
+    ipv4::finalize(&mut datagram, udp_len, true);
+
+    // Ports 9 to 9, followed by the seeded checksum
+    let seed = pseudo_header_checksum(&IpAddr::V4(src), &IpAddr::V4(dst), 17,
+                                      udp_len as u32);
+    datagram.extend_from_slice(&[0, 9, 0, 9]);
+    datagram.extend_from_slice(&udp_len.to_be_bytes());
+    datagram.extend_from_slice(&seed.to_be_bytes());
+    datagram.extend((0..len).map(|x| x as u8));
+    datagram
+}
+
+/// Returns whether the UDP checksum of the IPv4 `datagram` is valid
+fn udp_checksum_valid(datagram: &[u8]) -> bool {
+    let ip = ipv4::parse(datagram, true).unwrap();
+    let sum = pseudo_header_checksum(&IpAddr::V4(ip.src_ip),
+        &IpAddr::V4(ip.dst_ip), 17, ip.payload.len() as u32) as u32
+        + checksum(ip.payload) as u32;
+    (sum & 0xFFFF) + (sum >> 16) == 0xFFFF
+}
+
+#[test]
+fn ipv4_finish_transport_checksum() {
+    let mut datagram = offloaded_udp_datagram(100);
+    assert!(!udp_checksum_valid(&datagram));
+    ipv4::finish_transport_checksum(&mut datagram);
+    assert!(udp_checksum_valid(&datagram));
+
+    // A zero checksum means that UDP has no checksum and is left alone
+    let mut datagram = offloaded_udp_datagram(100);
+    datagram[26..28].fill(0);
+    ipv4::finish_transport_checksum(&mut datagram);
+    assert_eq!(datagram[26..28], [0, 0]);
+}
+
+#[test]
+fn ipv4_fragment_offloaded_datagram() {
+    let mut datagram = offloaded_udp_datagram(1500);
+    ipv4::finish_transport_checksum(&mut datagram);
+    let ip = ipv4::parse(&datagram, true).unwrap();
+
+    let mut fragments = std::vec::Vec::new();
+    ipv4::fragment(ip.header, ip.payload, 576, 0x1234, |header, data, last| {
+        let mut frag = header.to_vec();
+        frag.extend_from_slice(data);
+        fragments.push((frag, last));
+    });
+
+    // 1508 bytes of UDP split into 552 byte fragments
+    assert_eq!(fragments.len(), 3);
+    let mut offset = 0;
+    let mut payload = std::vec::Vec::new();
+    for (ii, (frag, last)) in fragments.iter().enumerate() {
+        assert!(frag.len() <= 576);
+        assert_eq!(*last, ii == fragments.len() - 1);
+
+        // Every fragment has a valid header describing its place in the
+        // datagram
+        let ip = ipv4::parse(frag, true).unwrap();
+        assert!(ip.is_fragment());
+        assert_eq!(ip.id, 0x1234);
+        assert_eq!(ip.frag_offset, offset);
+        assert_eq!(ip.more_fragments, !last);
+        assert_eq!(ip.protocol, 17);
+        if !last {
+            assert!(ip.payload.len().is_multiple_of(8));
+        }
+
+        offset += ip.payload.len();
+        payload.extend_from_slice(ip.payload);
+    }
+
+    // Nothing was lost, the checksum still spans the whole datagram
+    assert_eq!(payload, &datagram[ipv4::HEADER_LEN..]);
+}
+
+/// Split an offloaded UDP datagram with `len` bytes of payload into fragments
+/// of at most `mtu` bytes, returning the datagram and its fragments
+fn fragmented_udp_datagram(len: usize, mtu: usize)
+        -> (std::vec::Vec<u8>, std::vec::Vec<std::vec::Vec<u8>>) {
+    let mut datagram = offloaded_udp_datagram(len);
+    ipv4::finish_transport_checksum(&mut datagram);
+    let ip = ipv4::parse(&datagram, true).unwrap();
+
+    let mut fragments = std::vec::Vec::new();
+    ipv4::fragment(ip.header, ip.payload, mtu, 0x1234, |header, data, _| {
+        let mut frag = header.to_vec();
+        frag.extend_from_slice(data);
+        fragments.push(frag);
+    });
+    (datagram, fragments)
+}
+
+/// Start a reassembly in a buffer of `size` bytes from the first `fragment`
+fn start_reassembly(fragment: &[u8], size: usize)
+        -> ipv4::Reassembly<std::vec::Vec<u8>> {
+    let ip = ipv4::parse(fragment, true).unwrap();
+    let key = (ip.src_ip, ip.dst_ip, ip.id, ip.protocol);
+    ipv4::Reassembly::new(key, std::vec![0; size], ip.header, 1000)
+}
+
+/// Insert the `fragment` into the `reassembly`
+fn insert_fragment(reassembly: &mut ipv4::Reassembly<std::vec::Vec<u8>>,
+                   fragment: &[u8]) -> Option<()> {
+    let ip = ipv4::parse(fragment, true).unwrap();
+    reassembly.insert(ip.frag_offset, ip.more_fragments, ip.payload)
+}
+
+#[test]
+fn ipv4_reassemble_out_of_order() {
+    let (datagram, fragments) = fragmented_udp_datagram(1500, 576);
+    let mut reassembly = start_reassembly(&fragments[1], 2048);
+    assert_eq!(reassembly.key().2, 0x1234);
+    assert_eq!(reassembly.deadline(), 1000);
+
+    // The last fragment gives the size, but the datagram has holes
+    for ii in [2, 1] {
+        insert_fragment(&mut reassembly, &fragments[ii]).unwrap();
+        assert!(!reassembly.is_complete());
+    }
+    insert_fragment(&mut reassembly, &fragments[0]).unwrap();
+    assert!(reassembly.is_complete());
+
+    // The header describes the whole datagram, which is no longer a fragment
+    let (buffer, len) = reassembly.finish();
+    let ip = ipv4::parse(&buffer[..len], true).unwrap();
+    assert!(!ip.is_fragment());
+    assert_eq!(ip.id, 0x1234);
+    assert_eq!(ip.payload, &datagram[ipv4::HEADER_LEN..]);
+    assert!(udp_checksum_valid(&buffer[..len]));
+}
+
+#[test]
+fn ipv4_reassemble_duplicate_fragments() {
+    let (datagram, fragments) = fragmented_udp_datagram(100, 60);
+    let mut reassembly = start_reassembly(&fragments[0], 256);
+    for fragment in fragments.iter().chain(fragments.iter()) {
+        insert_fragment(&mut reassembly, fragment).unwrap();
+    }
+
+    // The fragments overlap fully, the payload is the same
+    let (buffer, len) = reassembly.finish();
+    assert_eq!(len, datagram.len());
+    assert_eq!(&buffer[ipv4::HEADER_LEN..len], &datagram[ipv4::HEADER_LEN..]);
+}
+
+#[test]
+fn ipv4_reassemble_rejects_invalid_fragments() {
+    let (_, fragments) = fragmented_udp_datagram(1500, 576);
+    // Room for the payload of the first two fragments only
+    let size = ipv4::HEADER_LEN + 2 * 552;
+    let mut reassembly = start_reassembly(&fragments[0], size);
+
+    // Only the last fragment may have a length which isn't a multiple of 8
+    assert!(reassembly.insert(0, true, &[0; 12]).is_none());
+    assert!(reassembly.insert(0, false, &[0; 12]).is_some());
+
+    // Fragments reaching beyond the buffer are dropped
+    assert!(insert_fragment(&mut reassembly, &fragments[1]).is_some());
+    assert!(insert_fragment(&mut reassembly, &fragments[2]).is_none());
+    assert!(reassembly.insert(usize::MAX, false, &[0; 8]).is_none());
+}
+
+#[test]
+#[should_panic(expected = "Finished an incomplete reassembly")]
+fn ipv4_finish_incomplete_reassembly() {
+    let (_, fragments) = fragmented_udp_datagram(1500, 576);
+    let mut reassembly = start_reassembly(&fragments[0], 2048);
+    insert_fragment(&mut reassembly, &fragments[0]).unwrap();
+    reassembly.finish();
+}
+
+/// A receive buffer which only remembers its address and frame length
+#[derive(Debug, PartialEq)]
+struct FakeRxBuffer {
+    addr: u64,
+    len: usize,
+}
+
+impl rx_ring::RxBuffer for FakeRxBuffer {
+    fn phys_addr(&self) -> u64 { self.addr }
+    fn set_len(&mut self, len: usize) { self.len = len; }
+}
+
+/// A ring of 4 descriptors with buffers at `0x1000 * (idx + 1)`
+fn rx_ring_parts() -> ([rx_ring::RxDescriptor; 4], [FakeRxBuffer; 4]) {
+    let buffers = core::array::from_fn(|ii| {
+        FakeRxBuffer { addr: 0x1000 * (ii as u64 + 1), len: 0 }
+    });
+    let descs = core::array::from_fn(|ii| rx_ring::RxDescriptor {
+        addr: buffers[ii].addr,
+        ..Default::default()
+    });
+    (descs, buffers)
+}
+
+#[test]
+fn rx_ring_pop_received_frame() {
+    let (mut descs, mut buffers) = rx_ring_parts();
+    let mut head = 0;
+    let mut tail = None;
+
+    // Nothing was received yet, the replacement isn't taken
+    let mut ring = rx_ring::RxRing {
+        descs: &mut descs, buffers: &mut buffers, head: &mut head,
+    };
+    let popped = ring.pop(|| panic!("Replacement taken"), |_| unreachable!());
+    assert_eq!(popped, Ok(None));
+
+    // Without a replacement, the frame stays in the ring
+    ring.descs[0].status = 1;
+    ring.descs[0].len = 60;
+    assert_eq!(ring.pop(|| None, |_| unreachable!()), Ok(None));
+
+    // The frame is swapped for the replacement
+    let replacement = FakeRxBuffer { addr: 0x9000, len: 0 };
+    let popped = ring.pop(|| Some(replacement), |idx| tail = Some(idx));
+    assert_eq!(popped, Ok(Some(FakeRxBuffer { addr: 0x1000, len: 60 })));
+    assert_eq!(tail, Some(0));
+    assert_eq!(head, 1);
+    assert_eq!((descs[0].addr, descs[0].status, descs[0].len), (0x9000, 0, 0));
+    assert_eq!(buffers[0].addr, 0x9000);
+}
+
+#[test]
+fn rx_ring_drop_errored_frame() {
+    let (mut descs, mut buffers) = rx_ring_parts();
+    let mut head = 3;
+    let mut tail = None;
+
+    // A frame with a CRC error
+    descs[3].status = 1;
+    descs[3].errors = 1;
+    descs[3].len = 60;
+
+    // The frame is dropped without taking a replacement for it
+    let mut ring = rx_ring::RxRing {
+        descs: &mut descs, buffers: &mut buffers, head: &mut head,
+    };
+    let popped = ring.pop(|| panic!("Replacement taken"),
+                          |idx| tail = Some(idx));
+    assert_eq!(popped, Err(rx_ring::RxError(1)));
+
+    // The descriptor was handed back with the same buffer and the head
+    // wrapped around
+    assert_eq!(tail, Some(3));
+    assert_eq!(head, 0);
+    assert_eq!((descs[3].addr, descs[3].status, descs[3].errors),
+               (0x4000, 0, 0));
+    assert_eq!(buffers[3], FakeRxBuffer { addr: 0x4000, len: 0 });
+}
+
+#[test]
+fn tcp_receive_reordered_segments() {
+    let mut rx = tcp::Receiver::new(64);
+    rx.set_ack(1000);
+
+    // Deliver the segments 1, 3 and 2
+    assert_eq!(rx.receive(1000, b"one "), tcp::Received::InOrder);
+    assert_eq!(rx.receive(1008, b"three"), tcp::Received::OutOfOrder);
+    assert_eq!(rx.ack(), 1004);
+    assert_eq!(rx.receive(1004, b"two "), tcp::Received::InOrder);
+    assert_eq!(rx.ack(), 1013);
+
+    let mut buf = [0; 32];
+    let len = rx.read(&mut buf);
+    assert_eq!(&buf[..len], b"one two three");
+    assert!(rx.is_empty());
+}
+
+#[test]
+fn tcp_receive_acks_unexpected_segments() {
+    let mut rx = tcp::Receiver::new(16);
+    rx.set_ack(u32::MAX - 1);
+
+    // Pure ACKs don't need to be acknowledged
+    assert_eq!(rx.receive(u32::MAX - 1, b""), tcp::Received::Empty);
+    assert!(!tcp::Received::Empty.needs_ack());
+
+    // Data is acknowledged across the sequence number wrap, whether it's
+    // expected, old, ahead or outside of the window
+    assert_eq!(rx.receive(u32::MAX - 1, b"abcd"), tcp::Received::InOrder);
+    assert_eq!(rx.ack(), 2);
+    assert_eq!(rx.receive(u32::MAX - 1, b"abcd"), tcp::Received::Dropped);
+    assert_eq!(rx.receive(6, b"ghij"), tcp::Received::OutOfOrder);
+    assert_eq!(rx.receive(2, &[0; 13]), tcp::Received::Dropped);
+    for received in [tcp::Received::InOrder, tcp::Received::OutOfOrder,
+                     tcp::Received::Dropped] {
+        assert!(received.needs_ack());
+    }
+
+    // Segments past the free window aren't buffered
+    assert_eq!(rx.free_window(), 12);
+    assert_eq!(rx.receive(14, b"xy"), tcp::Received::OutOfOrder);
+    assert_eq!(rx.receive(2, b"ef"), tcp::Received::InOrder);
+    assert_eq!(rx.ack(), 4);
+
+    // Filling the gap delivers the buffered segment
+    assert_eq!(rx.receive(4, b"gh"), tcp::Received::InOrder);
+    assert_eq!(rx.ack(), 10);
+}
+
+#[test]
+fn tcp_receive_window_size() {
+    let mut rx = tcp::Receiver::new(8);
+    assert_eq!(rx.receive(0, b"abcdef"), tcp::Received::InOrder);
+    assert_eq!(rx.free_window(), 2);
+
+    // Shrinking the window keeps the received data
+    rx.set_window_size(4);
+    assert_eq!(rx.free_window(), 0);
+    let mut buf = [0; 4];
+    assert_eq!(rx.read(&mut buf), 4);
+    assert_eq!(&buf, b"abcd");
+    assert_eq!(rx.free_window(), 2);
+
+    // The advertised window is capped without window scaling
+    rx.set_window_size(1 << 20);
+    assert_eq!(rx.free_window(), u16::MAX);
+}
+
+/// DHCP options of an ACK listing two routers, two DNS servers followed by a
+/// truncated one and a lease time of an hour
+const DHCP_ACK_OPTIONS: &[u8] = &[
+    53, 1, 5,
+    1, 4, 255, 255, 255, 0,
+    3, 8, 10, 0, 0, 1, 10, 0, 0, 2,
+    6, 10, 10, 0, 0, 53, 1, 1, 1, 1, 9, 9,
+    51, 4, 0, 0, 0x0E, 0x10,
+    255,
+];
+
+#[test]
+fn dhcp_parse_ack() {
+    let options = dhcp::parse_options(DHCP_ACK_OPTIONS);
+    assert_eq!(options.len(), 6);
+    assert_eq!(options[5], dhcp::DhcpOption::End);
+
+    // The first router is preferred as the gateway
+    let router = dhcp::DhcpOption::Router(Ipv4Addr::new(10, 0, 0, 1));
+    assert_eq!(options[2], router);
+    let ack = dhcp::Ack::from_options(&options).unwrap();
+    assert_eq!(ack.gateway, Some(Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(ack.subnet_mask, Some(Ipv4Addr::new(255, 255, 255, 0)));
+    assert_eq!(ack.broadcast_ip, None);
+
+    // Both DNS servers are listed, the truncated remainder is ignored and the
+    // first server is preferred
+    let servers: std::vec::Vec<_> = options[3].dns_servers().collect();
+    assert_eq!(servers, [Ipv4Addr::new(10, 0, 0, 53),
+                         Ipv4Addr::new(1, 1, 1, 1)]);
+    assert_eq!(ack.dns, Some(Ipv4Addr::new(10, 0, 0, 53)));
+    assert_eq!(options[0].dns_servers().count(), 0);
+
+    // T1 and T2 default to 50% and 87.5% of the lease time
+    assert_eq!(ack.lease_time, Some(3600));
+    assert_eq!(ack.renewal_time, Some(1800));
+    assert_eq!(ack.rebinding_time, Some(3150));
+
+    // The router survives a round trip, only the preferred one is kept
+    let mut buffer = std::vec::Vec::new();
+    options[2].serialize(&mut buffer);
+    assert_eq!(buffer, [3, 4, 10, 0, 0, 1]);
+}
+
+#[test]
+fn dhcp_parse_non_ack() {
+    // An offer doesn't grant a lease
+    let mut raw = DHCP_ACK_OPTIONS.to_vec();
+    raw[2] = dhcp::MessageType::Offer as u8;
+    assert!(dhcp::Ack::from_options(&dhcp::parse_options(&raw)).is_none());
+
+    // Options are parsed up to the first truncated one
+    let options = dhcp::parse_options(&DHCP_ACK_OPTIONS[..12]);
+    assert_eq!(options.len(), 2);
+}
+
+/// DHCP options of an ACK granting a lease for `secs` seconds
+fn dhcp_ack_for(secs: u32) -> dhcp::Ack {
+    let mut raw = std::vec![53, 1, 5, 51, 4];
+    raw.extend_from_slice(&secs.to_be_bytes());
+    dhcp::Ack::from_options(&dhcp::parse_options(&raw)).unwrap()
+}
+
+#[test]
+fn dhcp_zero_second_lease() {
+    // A lease of no time has to be renewed and is over as soon as it's
+    // received
+    let now = 1_000_000;
+    let deadlines = dhcp::Deadlines::new(&dhcp_ack_for(0), now, 3000);
+    assert!(deadlines.needs_renewal(now));
+    assert!(deadlines.needs_rebinding(now));
+    assert!(deadlines.expired(now));
+}
+
+#[test]
+fn dhcp_infinite_lease() {
+    // An infinite lease never expires, even when the TSC is about to wrap
+    let now = 1_000_000;
+    let deadlines = dhcp::Deadlines::new(&dhcp_ack_for(u32::MAX), now, 3000);
+    assert_eq!(deadlines.expires_at, u64::MAX);
+    assert!(!deadlines.expired(now));
+    assert!(!deadlines.expired(u64::MAX - 1));
+
+    // An hour long lease expires after an hour
+    let deadlines = dhcp::Deadlines::new(&dhcp_ack_for(3600), now, 3000);
+    let hour = 3600 * 1_000_000 * 3000;
+    assert!(!deadlines.expired(now + hour - 1));
+    assert!(deadlines.expired(now + hour));
+    assert!(deadlines.needs_renewal(now + hour / 2));
+}
+
+/// Configuration of an interface on 192.168.1.0/24 routing through
+/// 192.168.1.1
+const IPV4_CONFIG: route::Ipv4Config = route::Ipv4Config {
+    addr:        Ipv4Addr::new(192, 168, 1, 10),
+    subnet_mask: Some(Ipv4Addr::new(255, 255, 255, 0)),
+    broadcast:   Some(Ipv4Addr::new(192, 168, 1, 255)),
+    gateway:     Some(Ipv4Addr::new(192, 168, 1, 1)),
+};
+
+#[test]
+fn route_on_and_off_subnet() {
+    // Hosts on the subnet are reached directly
+    let local = Ipv4Addr::new(192, 168, 1, 20);
+    assert!(IPV4_CONFIG.on_subnet(local));
+    assert_eq!(IPV4_CONFIG.route(local), Some(route::NextHop::Host(local)));
+
+    // Everything else goes through the gateway
+    let remote = Ipv4Addr::new(8, 8, 8, 8);
+    assert!(!IPV4_CONFIG.on_subnet(remote));
+    assert_eq!(IPV4_CONFIG.route(remote),
+               Some(route::NextHop::Host(Ipv4Addr::new(192, 168, 1, 1))));
+
+    // Both the limited and the subnet broadcasts are broadcast
+    for ip in [Ipv4Addr::BROADCAST, Ipv4Addr::new(192, 168, 1, 255)] {
+        assert_eq!(IPV4_CONFIG.route(ip), Some(route::NextHop::Broadcast));
+    }
+}
+
+#[test]
+fn route_without_gateway() {
+    // Without a gateway, only the subnet can be reached
+    let config = route::Ipv4Config { gateway: None, ..IPV4_CONFIG };
+    let local = Ipv4Addr::new(192, 168, 1, 20);
+    assert_eq!(config.route(local), Some(route::NextHop::Host(local)));
+    assert_eq!(config.route(Ipv4Addr::new(8, 8, 8, 8)), None);
+
+    // Without a subnet mask, every address is considered to be on the subnet
+    let config = route::Ipv4Config { subnet_mask: None, ..config };
+    let remote = Ipv4Addr::new(8, 8, 8, 8);
+    assert_eq!(config.route(remote), Some(route::NextHop::Host(remote)));
+}
+
+#[test]
+fn route_config_from_ack() {
+    // The configuration reports what the lease granted
+    let options = dhcp::parse_options(DHCP_ACK_OPTIONS);
+    let ack = dhcp::Ack::from_options(&options).unwrap();
+    let addr = Ipv4Addr::new(10, 0, 0, 10);
+    let config = route::Ipv4Config::from_ack(addr, &ack);
+    assert_eq!(config.addr, addr);
+    assert_eq!(config.subnet_mask, Some(Ipv4Addr::new(255, 255, 255, 0)));
+    assert_eq!(config.gateway, Some(Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(config.broadcast, None);
+
+    // And routes through the leased router
+    assert_eq!(config.route(Ipv4Addr::new(8, 8, 8, 8)),
+               Some(route::NextHop::Host(Ipv4Addr::new(10, 0, 0, 1))));
+}
+
+/// Build an Ethernet frame from 02:00:00:00:00:01 to the broadcast address
+/// with `eth_type` and `payload`
+fn eth_frame(eth_type: u16, payload: &[u8]) -> std::vec::Vec<u8> {
+    let mut frame = std::vec![0xFF; 6];
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    frame.extend_from_slice(&eth_type.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[test]
+fn eth_parse_arp_frame() {
+    // An ARP request for 10.0.0.1 from 10.0.0.2
+    let arp = [
+        0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01,
+        0x02, 0, 0, 0, 0, 0x01, 10, 0, 0, 2,
+        0, 0, 0, 0, 0, 0, 10, 0, 0, 1,
+    ];
+    let frame = eth_frame(0x0806, &arp);
+
+    let eth = eth::parse(&frame).unwrap();
+    assert_eq!(eth.dst_mac, eth::Mac::BROADCAST);
+    assert_eq!(eth.src_mac, eth::Mac([0x02, 0, 0, 0, 0, 0x01]));
+    assert_eq!(eth.eth_type, 0x0806);
+    assert_eq!(eth.ethertype(), Some(eth::EthType::Arp));
+    assert_eq!(eth.payload, &arp[..]);
+}
+
+#[test]
+fn eth_parse_ipv4_frame() {
+    let datagram = ipv4_datagram();
+    let frame = eth_frame(0x0800, &datagram);
+
+    let eth = eth::parse(&frame).unwrap();
+    assert_eq!(eth.ethertype(), Some(eth::EthType::Ipv4));
+    assert_eq!(eth.payload, &datagram[..]);
+    assert!(ipv4::parse(eth.payload, true).is_ok());
+}
+
+#[test]
+fn eth_parse_unknown_and_truncated_frames() {
+    // Unknown EtherTypes are parsed, but not recognized
+    let frame = eth_frame(0x88CC, &[1, 2, 3]);
+    let eth = eth::parse(&frame).unwrap();
+    assert_eq!(eth.eth_type, 0x88CC);
+    assert_eq!(eth.ethertype(), None);
+    assert_eq!(eth.payload, &[1, 2, 3]);
+
+    // A frame with just the header has an empty payload
+    let frame = eth_frame(0x86DD, &[]);
+    let eth = eth::parse(&frame).unwrap();
+    assert_eq!(eth.ethertype(), Some(eth::EthType::Ipv6));
+    assert!(eth.payload.is_empty());
+
+    // Frames shorter than the header are rejected
+    assert_eq!(eth::parse(&frame[..eth::HEADER_LEN - 1]).unwrap_err(),
+               ParseError::TruncatedPacket);
+    assert_eq!(eth::parse(&[]).unwrap_err(), ParseError::TruncatedPacket);
+}
@@ -1,7 +1,11 @@
-//! Routines for creating and manipulating 4-level x86_64 page tables
+//! Routines for creating and manipulating 4-level and 5-level x86_64 page
+//! tables
 
 #![no_std]
 
+#[cfg(test)]
+mod tests;
+
 // The code here adheres to the Intel spec. A large portion of it was taken from
 // Brandon -- it's good and I wouldn't write it any different (apart from the
 // parts which I have indeed rewritten :D).
@@ -17,10 +21,39 @@ pub const PAGE_WRITE: u64 = 1 << 1;
 /// Page table flag indicating this page or table is accessible by userspace
 pub const PAGE_USER: u64 = 1 << 2;
 
+/// Page table flag indicating that writes to memory described by this page or
+/// table should be written through the cache
+pub const PAGE_WRITE_THROUGH: u64 = 1 << 3;
+
 /// Page table flag indicating that accesses to memory described by this page or
 /// table should be uncached
 pub const PAGE_CACHE_DISABLE: u64 = 1 << 4;
 
+/// Page table flag selecting the upper half of the PAT for a 4-KiB page
+pub const PAGE_PAT_4K: u64 = 1 << 7;
+
+/// Page table flag selecting the upper half of the PAT for a large page
+pub const PAGE_PAT_LARGE: u64 = 1 << 12;
+
+/// The page attribute table layout assumed by `MemoryType`. This has to be
+/// loaded into the `IA32_PAT` MSR (see `cpu::set_pat()`) before write-combining
+/// memory is mapped in.
+///
+/// The lower half is the power-on default, so write-back, write-through and
+/// uncached memory work even if the PAT has never been programmed:
+///
+/// | Index | PAT | PCD | PWT | Memory type |
+/// |-------|-----|-----|-----|-------------|
+/// | 0     | 0   | 0   | 0   | WB          |
+/// | 1     | 0   | 0   | 1   | WT          |
+/// | 2     | 0   | 1   | 0   | UC-         |
+/// | 3     | 0   | 1   | 1   | UC          |
+/// | 4     | 1   | 0   | 0   | WC          |
+/// | 5     | 1   | 0   | 1   | WT          |
+/// | 6     | 1   | 1   | 0   | UC-         |
+/// | 7     | 1   | 1   | 1   | UC          |
+pub const PAT: u64 = 0x0007_0401_0007_0406;
+
 /// Page table flag indicating this page entry is a large page
 pub const PAGE_SIZE: u64 = 1 << 7;
 
@@ -28,37 +61,211 @@ pub const PAGE_SIZE: u64 = 1 << 7;
 pub const PAGE_NXE: u64 = 1 << 63;
 
 
+/// Implement the alignment and arithmetic helpers shared by the address types.
+/// `$name` is used in the docs ("physical" or "virtual")
+macro_rules! addr_helpers {
+    ($addr:ident, $name:literal) => {
+        impl $addr {
+            #[doc = concat!("Returns whether the ", $name,
+                " address is aligned to `page_type`")]
+            pub fn is_aligned_to_page(&self, page_type: PageType) -> bool {
+                self.is_aligned(page_type as u64)
+            }
+
+            #[doc = concat!("Returns whether the ", $name,
+                " address is aligned to `val`")]
+            pub fn is_aligned(&self, val: u64) -> bool {
+                (self.0 & (!(val - 1))) == self.0
+            }
+
+            /// Returns this address offset by `off` bytes, or `None` on
+            /// overflow
+            pub fn checked_add(self, off: u64) -> Option<Self> {
+                self.0.checked_add(off).map(Self)
+            }
+
+            /// Returns this address rounded up to `align`, which has to be a
+            /// power of two.
+            ///
+            /// Panics if the rounding overflows
+            #[track_caller]
+            pub fn align_up(self, align: u64) -> Self {
+                debug_assert!(align.is_power_of_two());
+                self.checked_add(align - 1)
+                    .expect("Overflow when aligning an address up")
+                    .align_down(align)
+            }
+
+            /// Returns this address rounded down to `align`, which has to be a
+            /// power of two
+            pub fn align_down(self, align: u64) -> Self {
+                debug_assert!(align.is_power_of_two());
+                Self(self.0 & !(align - 1))
+            }
+
+            /// Returns the offset of this address into a page of `page_type`
+            pub fn offset_in_page(self, page_type: PageType) -> u64 {
+                self.0 & (page_type as u64 - 1)
+            }
+        }
+
+        /// Formats the bare address in upper case hex with a `0x` prefix
+        ///
+        /// ```
+        #[doc = concat!("# use page_table::", stringify!($addr), ";")]
+        #[doc = concat!("let addr = ", stringify!($addr), "(0x1000);")]
+        /// assert_eq!(format!("{addr}"), "0x1000");
+        /// ```
+        impl core::fmt::Display for $addr {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                write!(f, "{:#X}", self.0)
+            }
+        }
+
+        /// Formats the bare address in lower case hex
+        ///
+        /// ```
+        #[doc = concat!("# use page_table::", stringify!($addr), ";")]
+        #[doc = concat!("let addr = ", stringify!($addr), "(0xBEEF);")]
+        /// assert_eq!(format!("{addr:x}"), "beef");
+        /// assert_eq!(format!("{addr:#010x}"), "0x0000beef");
+        /// ```
+        impl core::fmt::LowerHex for $addr {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                core::fmt::LowerHex::fmt(&self.0, f)
+            }
+        }
+
+        /// Formats the bare address in upper case hex
+        ///
+        /// ```
+        #[doc = concat!("# use page_table::", stringify!($addr), ";")]
+        #[doc = concat!("let addr = ", stringify!($addr), "(0xbeef);")]
+        /// assert_eq!(format!("{addr:X}"), "BEEF");
+        /// ```
+        impl core::fmt::UpperHex for $addr {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                core::fmt::UpperHex::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
 /// Strongly typed physical address.
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
 pub struct PhysAddr(pub u64);
 
-impl PhysAddr {
-    /// Returns whether the physical address is aligned to `page_type`
-    pub fn is_aligned_to_page(&self, page_type: PageType) -> bool {
-        self.is_aligned(page_type as u64)
-    }
-
-    /// Returns whether the physical address is aligned to `val`
-    pub fn is_aligned(&self, val: u64) -> bool {
-        (self.0 & (!(val - 1))) == self.0
-    }
-}
+addr_helpers!(PhysAddr, "physical");
 
 /// A strongly typed virtual address.
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
 pub struct VirtAddr(pub u64);
 
+addr_helpers!(VirtAddr, "virtual");
+
 impl VirtAddr {
-    /// Returns whether the virtual address is aligned to `page_type`
-    pub fn is_aligned_to_page(&self, page_type: PageType) -> bool {
-        self.is_aligned(page_type as u64)
+    /// Returns the PML4, PDP, PD and PT indices of this address, in that
+    /// order. The offset into the page can be obtained by
+    /// [`VirtAddr::offset_in_page`]
+    ///
+    /// ```
+    /// # use page_table::{VirtAddr, PageType};
+    /// let vaddr = VirtAddr(0xFFFF_8123_4567_89AB);
+    /// assert_eq!(vaddr.page_indices(), [0x102, 0x8D, 0x2B, 0x78]);
+    /// assert_eq!(vaddr.offset_in_page(PageType::Page4K), 0x9AB);
+    /// ```
+    pub fn page_indices(self) -> [u16; 4] {
+        [39, 30, 21, 12].map(|shift| ((self.0 >> shift) & 0x1FF) as u16)
+    }
+
+    /// Returns whether this address is canonical with 4-level paging, i.e.
+    /// whether it's outside of the non-canonical hole in the middle of the
+    /// address space
+    ///
+    /// ```
+    /// # use page_table::VirtAddr;
+    /// assert!( VirtAddr(0x0000_7FFF_FFFF_FFFF).is_canonical());
+    /// assert!(!VirtAddr(0x0000_8000_0000_0000).is_canonical());
+    /// assert!(!VirtAddr(0xFFFF_7FFF_FFFF_FFFF).is_canonical());
+    /// assert!( VirtAddr(0xFFFF_8000_0000_0000).is_canonical());
+    /// ```
+    pub fn is_canonical(self) -> bool {
+        self.is_canonical_for(PagingLevels::Four)
+    }
+
+    /// Returns whether this address is canonical with `levels` of paging
+    ///
+    /// ```
+    /// # use page_table::{VirtAddr, PagingLevels};
+    /// let five = PagingLevels::Five;
+    /// assert!( VirtAddr(0x0000_8000_0000_0000).is_canonical_for(five));
+    /// assert!( VirtAddr(0x00FF_FFFF_FFFF_FFFF).is_canonical_for(five));
+    /// assert!(!VirtAddr(0x0100_0000_0000_0000).is_canonical_for(five));
+    /// assert!(!VirtAddr(0xFEFF_FFFF_FFFF_FFFF).is_canonical_for(five));
+    /// assert!( VirtAddr(0xFF00_0000_0000_0000).is_canonical_for(five));
+    /// ```
+    pub fn is_canonical_for(self, levels: PagingLevels) -> bool {
+        self.canonicalize_for(levels) == self
+    }
+
+    /// Returns this address in its canonical form with 4-level paging, by
+    /// sign extending bit 47 into the bits above it
+    ///
+    /// ```
+    /// # use page_table::VirtAddr;
+    /// let vaddr = VirtAddr(0x0000_8000_0000_1000);
+    /// assert_eq!(vaddr.canonicalize(), VirtAddr(0xFFFF_8000_0000_1000));
+    /// assert_eq!(VirtAddr(0x1000).canonicalize(), VirtAddr(0x1000));
+    /// ```
+    pub fn canonicalize(self) -> VirtAddr {
+        self.canonicalize_for(PagingLevels::Four)
+    }
+
+    /// Returns this address in its canonical form with `levels` of paging
+    pub fn canonicalize_for(self, levels: PagingLevels) -> VirtAddr {
+        VirtAddr(cpu::canonicalize_address(levels.canonical_bits(), self.0))
     }
+}
+
+/// A window of virtual memory starting at `base`, which linearly maps the
+/// first `size` bytes of physical memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    /// Virtual address at which physical address 0 is mapped
+    pub base: u64,
 
-    /// Returns whether the virtual address is aligned to `val`
-    pub fn is_aligned(&self, val: u64) -> bool {
-        (self.0 & (!(val - 1))) == self.0
+    /// Number of bytes of physical memory mapped by the window
+    pub size: u64,
+}
+
+impl Window {
+    /// Get the virtual address of `paddr` in the window, or `None` if it's
+    /// outside of the window
+    pub fn to_virt(&self, paddr: PhysAddr) -> Option<VirtAddr> {
+        if paddr.0 >= self.size { return None; }
+        paddr.0.checked_add(self.base).map(VirtAddr)
+    }
+
+    /// Get the virtual address of the `size` bytes of physical memory at
+    /// `paddr` in the window, or `None` if they're empty, their end overflows
+    /// or they don't fit into the window
+    pub fn range_to_virt(&self, paddr: PhysAddr, size: u64)
+            -> Option<VirtAddr> {
+        // Make sure the address doesn't overflow
+        let end = size.checked_sub(1)?.checked_add(paddr.0)?;
+
+        // Make sure we fit in the window
+        if end >= self.size { return None; }
+        self.to_virt(paddr)
+    }
+
+    /// Get the physical address `vaddr` maps to in the window, or `None` if
+    /// it's outside of the window
+    pub fn to_phys(&self, vaddr: VirtAddr) -> Option<PhysAddr> {
+        let paddr = vaddr.0.checked_sub(self.base)?;
+        (paddr < self.size).then_some(PhysAddr(paddr))
     }
 }
 
@@ -72,17 +279,60 @@ impl VirtAddr {
 pub trait PhysMem {
     /// Get a virtual address to memory which constains the raw physical memory
     /// at `paddr` for `size` bytes
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure that reading the memory doesn't race with
+    /// mutable accesses to it
     unsafe fn translate(&mut self, paddr: PhysAddr, size: usize)
         -> Option<*const u8>;
 
     /// Get a virtual address to memory which constains the raw physical memory
     /// at `paddr` for `size` bytes, with mutable access
+    ///
+    /// # Safety
+    ///
+    /// The caller must own the memory, making sure that no other accesses to
+    /// it race with the ones through the returned pointer
     unsafe fn translate_mut(&mut self, paddr: PhysAddr, size: usize)
         -> Option<*mut u8>;
 
+    /// Get a virtual address to device memory (e.g. MMIO registers) at
+    /// `paddr` for `size` bytes, with mutable access.
+    ///
+    /// Unlike `translate()` and `translate_mut()`, which are meant for normal
+    /// memory such as the page tables, the memory returned here should be
+    /// mapped uncacheable and must only be accessed with volatile reads and
+    /// writes, so that the compiler doesn't reorder or elide the accesses.
+    /// Defaults to `translate_mut()` for implementations which can't tell the
+    /// two apart
+    ///
+    /// # Safety
+    ///
+    /// The caller must own the device at `paddr`, and only access the
+    /// returned memory with volatile reads and writes valid for the device
+    unsafe fn translate_volatile(&mut self, paddr: PhysAddr, size: usize)
+            -> Option<*mut u8> {
+        unsafe { self.translate_mut(paddr, size) }
+    }
+
     /// Allocate physical memory with a requested `layout`
     fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr>;
 
+    /// Allocate `pages` 4-KiB pages of physical memory.
+    ///
+    /// The returned memory is guaranteed to be physically contiguous and 4-KiB
+    /// aligned, which makes it usable for DMA buffers. It can be freed with
+    /// `free_phys()` using the same layout as the one requested here.
+    fn alloc_phys_contiguous(&mut self, pages: usize) -> Option<PhysAddr> {
+        let size = pages.checked_mul(4096)?;
+        self.alloc_phys(Layout::from_size_align(size, 4096).ok()?)
+    }
+
+    /// Free physical memory at `paddr` previously allocated by `alloc_phys()`
+    /// with the same `layout`
+    fn free_phys(&mut self, paddr: PhysAddr, layout: Layout);
+
     /// Same as `alloc_phys()` but the memory will be zeroed out
     fn alloc_phys_zeroed(&mut self, layout: Layout) -> Option<PhysAddr> {
         // Allocate the memory
@@ -116,6 +366,56 @@ pub enum Error {
 
     /// Attempted to map in an unaligned address
     AddressUnaligned,
+
+    /// Attempted to create a mapping which is empty or overflows the address
+    /// space
+    InvalidSize,
+
+    /// Physical memory couldn't be allocated for a page or a page table
+    OutOfMemory,
+
+    /// Physical memory couldn't be translated to be accessed
+    TranslationFailed,
+}
+
+/// Memory types which can be selected for a page through the PAT, assuming the
+/// layout described by `PAT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Cached reads and writes
+    WriteBack,
+
+    /// Cached reads, writes go straight to memory
+    WriteThrough,
+
+    /// Uncached reads and writes
+    Uncached,
+
+    /// Uncached reads, writes are combined in a buffer before they are
+    /// written to memory
+    WriteCombining,
+}
+
+impl MemoryType {
+    /// Returns the PAT index of this memory type
+    fn pat_index(&self) -> u64 {
+        match self {
+            MemoryType::WriteBack      => 0,
+            MemoryType::WriteThrough   => 1,
+            MemoryType::Uncached       => 3,
+            MemoryType::WriteCombining => 4,
+        }
+    }
+
+    /// Returns the memory type at `index` in the PAT
+    fn from_pat_index(index: u64) -> Self {
+        match index & 0b111 {
+            0     => MemoryType::WriteBack,
+            1 | 5 => MemoryType::WriteThrough,
+            4     => MemoryType::WriteCombining,
+            _     => MemoryType::Uncached,
+        }
+    }
 }
 
 /// Paging memory access permissions.
@@ -124,7 +424,7 @@ pub enum Error {
 ///
 /// Note: The `read` permission is not included because all present pages are
 /// implicitly readable.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Permissions {
     /// Allows write access to the memory page
     pub write: bool,
@@ -135,32 +435,64 @@ pub struct Permissions {
     /// Allows access to the memory page from user mode
     pub user: bool,
 
-    /// Disables caching for the memory page
-    pub cache: bool,
+    /// Caching behavior of the memory page
+    pub memory_type: MemoryType,
 }
 
 impl Permissions {
     /// Returns a new instance with the specified access rights.
     ///
-    /// The page will be assumed to be cached by default.
+    /// The page will be write-back cached. Every mapping the bootloader
+    /// creates goes through here, so all of them are write-back; use
+    /// `uncached()` for device memory.
     pub fn new(write: bool, execute: bool, user: bool) -> Self {
-        Self { write, execute, user, cache: false }
+        Self { write, execute, user, memory_type: MemoryType::WriteBack }
     }
 
     /// Returns a new instance with the specified access rights,
     /// ensuring the page is uncached
     pub fn uncached(write: bool, execute: bool, user: bool) -> Self {
-        Self { write, execute, user, cache: true }
+        Self { write, execute, user, memory_type: MemoryType::Uncached }
     }
 
-    /// Computes the corresponding bitmask for the current permission set.
+    /// Computes the corresponding bitmask for the current permission set for
+    /// a page of `page_type`.
     ///
     /// This bitmask can be used to configure hardware page tables.
-    fn bits(&self) -> u64 {
-        0 | if self.write   { PAGE_WRITE } else { 0 }
-          | if self.user    { PAGE_USER  } else { 0 }
-          | if self.execute { 0 } else { PAGE_NXE }
-          | if self.cache   { 0 } else { PAGE_CACHE_DISABLE }
+    pub fn bits(&self, page_type: PageType) -> u64 {
+        // Get the memory type bits
+        let index = self.memory_type.pat_index();
+        let memory_type =
+              if (index & 0b001) != 0 { PAGE_WRITE_THROUGH } else { 0 }
+            | if (index & 0b010) != 0 { PAGE_CACHE_DISABLE } else { 0 }
+            | if (index & 0b100) != 0 { page_type.pat_bit() } else { 0 };
+
+        memory_type
+            | if self.write   { PAGE_WRITE } else { 0 }
+            | if self.user    { PAGE_USER  } else { 0 }
+            | if self.execute { 0 } else { PAGE_NXE }
+    }
+
+    /// Decodes the permission set from a `raw` page table entry mapping a
+    /// page of `page_type`. All bits unrelated to permissions are ignored.
+    ///
+    /// This is the inverse of `bits()` for the four memory types `bits()`
+    /// produces. The UC- entries of the PAT at indices 2 and 6 are never
+    /// produced and decode as `MemoryType::Uncached`, so they don't
+    /// round-trip.
+    pub fn from_bits(raw: u64, page_type: PageType) -> Self {
+        // Get the PAT index of the page
+        let index =
+              if (raw & PAGE_WRITE_THROUGH) != 0 { 0b001 } else { 0 }
+            | if (raw & PAGE_CACHE_DISABLE) != 0 { 0b010 } else { 0 }
+            | if (raw & page_type.pat_bit()) != 0 { 0b100 } else { 0 };
+
+        Self {
+            write:       (raw & PAGE_WRITE) != 0,
+            user:        (raw & PAGE_USER)  != 0,
+            execute:     (raw & PAGE_NXE)   == 0,
+            memory_type: MemoryType::from_pat_index(index),
+        }
     }
 }
 
@@ -174,10 +506,87 @@ pub enum PageType {
 }
 
 impl PageType {
+    /// Returns the mask of the offset bits within a page of this type
+    ///
+    /// ```
+    /// # use page_table::PageType;
+    /// assert_eq!(PageType::Page4K.mask(), 0xFFF);
+    /// assert_eq!(PageType::Page2M.mask(), 0x1F_FFFF);
+    /// assert_eq!(PageType::Page1G.mask(), 0x3FFF_FFFF);
+    /// ```
+    pub const fn mask(self) -> u64 {
+        self as u64 - 1
+    }
+
+    /// Returns the size of the whole pages of this type needed to hold `size`
+    /// bytes, i.e. `size` rounded up to a multiple of the page size
+    ///
+    /// ```
+    /// # use page_table::PageType;
+    /// assert_eq!(PageType::Page4K.span(1), 0x1000);
+    /// assert_eq!(PageType::Page2M.span(0x1000), 0x20_0000);
+    /// ```
+    pub const fn span(self, size: usize) -> usize {
+        size.div_ceil(self as usize) * self as usize
+    }
+
+    /// Returns the number of page table entries walked to reach a page of this
+    /// type, including the entry mapping the page itself
+    ///
+    /// ```
+    /// # use page_table::PageType;
+    /// assert_eq!(PageType::Page4K.depth(), 4);
+    /// assert_eq!(PageType::Page2M.depth(), 3);
+    /// assert_eq!(PageType::Page1G.depth(), 2);
+    /// ```
+    pub const fn depth(self) -> usize {
+        match self {
+            PageType::Page4K => 4,
+            PageType::Page2M => 3,
+            PageType::Page1G => 2,
+        }
+    }
+
+    /// Returns the type of the page mapped by an entry in the table at `level`
+    /// (0 being the PML4 and 3 the page table), where `large` is whether the
+    /// `PAGE_SIZE` bit of the entry is set.
+    ///
+    /// Returns `None` if the entry points to another table or can't map a
+    /// page at all.
+    ///
+    /// ```
+    /// # use page_table::PageType;
+    /// assert_eq!(PageType::from_level(3, false), Some(PageType::Page4K));
+    /// assert_eq!(PageType::from_level(2, true),  Some(PageType::Page2M));
+    /// assert_eq!(PageType::from_level(1, true),  Some(PageType::Page1G));
+    /// assert_eq!(PageType::from_level(2, false), None);
+    /// assert_eq!(PageType::from_level(0, true),  None);
+    ///
+    /// use PageType::*;
+    /// for page_type in [Page4K, Page2M, Page1G] {
+    ///     let large = page_type != Page4K;
+    ///     let level = page_type.depth() - 1;
+    ///     assert_eq!(PageType::from_level(level, large), Some(page_type));
+    /// }
+    /// ```
+    pub const fn from_level(level: usize, large: bool) -> Option<Self> {
+        match (level, large) {
+            (1, true) => Some(PageType::Page1G),
+            (2, true) => Some(PageType::Page2M),
+            (3, _)    => Some(PageType::Page4K),
+            _         => None,
+        }
+    }
+
     /// Returns the `PAGE_SIZE` bit if this page type is not 4-KiB
     fn size_bit(&self) -> u64 {
         if *self == PageType::Page4K { 0 } else { PAGE_SIZE }
     }
+
+    /// Returns the bit selecting the upper half of the PAT for this page type
+    fn pat_bit(&self) -> u64 {
+        if *self == PageType::Page4K { PAGE_PAT_4K } else { PAGE_PAT_LARGE }
+    }
 }
 
 /// Request for a new page table mapping
@@ -213,9 +622,55 @@ impl MapRequest {
     }
 }
 
+/// The number of levels of a page table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PagingLevels {
+    /// 4-level paging with 48-bit virtual addresses
+    #[default]
+    Four = 4,
+
+    /// 5-level paging with 57-bit virtual addresses. This requires CR4.LA57,
+    /// which the bootloader must set before switching to such a table, and
+    /// can only be used if `cpu::Features::la57` is supported
+    Five = 5,
+}
+
+impl PagingLevels {
+    /// Returns the number of high bits which have to be the same in a
+    /// canonical virtual address
+    ///
+    /// ```
+    /// # use page_table::PagingLevels;
+    /// assert_eq!(PagingLevels::Four.canonical_bits(), 16);
+    /// assert_eq!(PagingLevels::Five.canonical_bits(), 7);
+    /// ```
+    pub const fn canonical_bits(self) -> usize {
+        match self {
+            PagingLevels::Four => 16,
+            PagingLevels::Five => 7,
+        }
+    }
+
+    /// Returns the number of entries walked to reach a page of `page_type`,
+    /// including the entry mapping the page itself
+    fn depth(self, page_type: PageType) -> usize {
+        page_type.depth() + self as usize - 4
+    }
+
+    /// Returns the number of the top levels of a 5-level walk which aren't
+    /// present with these levels
+    fn skipped(self) -> usize {
+        5 - self as usize
+    }
+}
+
 /// The paging components of a page table mapping.
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Mapping {
+    /// Physical address of the Page Map Level 5 entry, only present with
+    /// 5-level paging
+    pub pml5e: Option<PhysAddr>,
+
     /// Physical address of the Page Map Level 4 entry
     pub pml4e: Option<PhysAddr>,
 
@@ -242,12 +697,20 @@ impl Mapping {
         /// Size of a page table entry
         const ES: u64 = core::mem::size_of::<u64>() as u64;
 
-        Some(VirtAddr(cpu::canonicalize_address(16,
+        // Get the levels the page was walked with
+        let levels = if self.pml5e.is_some() {
+            PagingLevels::Five
+        } else {
+            PagingLevels::Four
+        };
+
+        Some(VirtAddr(
+            ((self.pml5e.unwrap_or(PhysAddr(0)).0 & 0xFFF) / ES) << 48 |
             ((self.pml4e.unwrap_or(PhysAddr(0)).0 & 0xFFF) / ES) << 39 |
             ((self.pdpe .unwrap_or(PhysAddr(0)).0 & 0xFFF) / ES) << 30 |
             ((self.pde  .unwrap_or(PhysAddr(0)).0 & 0xFFF) / ES) << 21 |
             ((self.pte  .unwrap_or(PhysAddr(0)).0 & 0xFFF) / ES) << 12
-        )))
+        ).canonicalize_for(levels))
     }
 
     /// Returns the size of this page
@@ -262,40 +725,74 @@ impl Mapping {
 
     /// Returns the components of `vaddr`
     fn get_indices(vaddr: VirtAddr) -> [u64; 4] {
-        [
-            (vaddr.0 >> 39) & 0x1FF,
-            (vaddr.0 >> 30) & 0x1FF,
-            (vaddr.0 >> 21) & 0x1FF,
-            (vaddr.0 >> 12) & 0x1FF,
-        ]
+        vaddr.page_indices().map(u64::from)
+    }
+
+    /// Returns the components of `vaddr` with 5-level paging, starting with
+    /// the PML5 index
+    fn get_indices_la57(vaddr: VirtAddr) -> [u64; 5] {
+        let [pml4, pdp, pd, pt] = Self::get_indices(vaddr);
+        [(vaddr.0 >> 48) & 0x1FF, pml4, pdp, pd, pt]
     }
 }
 
 /// A 64-bit x86 page table
 #[derive(Debug, Clone, PartialEq)]
-#[repr(transparent)]
 pub struct PageTable {
     /// The physical address of the top-level page table. This is typically the
     /// value in `cr3`
     table: PhysAddr,
+
+    /// The number of levels of this page table
+    levels: PagingLevels,
 }
 
 impl PageTable {
-    /// Create a new empty page table, allocating it in physical memory using
-    /// `phys_mem`
+    /// Create a new empty 4-level page table, allocating it in physical memory
+    /// using `phys_mem`
     pub fn new<P: PhysMem>(phys_mem: &mut P) -> Option<Self> {
+        Self::with_levels(phys_mem, PagingLevels::Four)
+    }
+
+    /// Create a new empty page table with `levels`, allocating it in physical
+    /// memory using `phys_mem`
+    pub fn with_levels<P: PhysMem>(phys_mem: &mut P, levels: PagingLevels)
+            -> Option<Self> {
         // Allocate the root level table
         let table = phys_mem.alloc_phys_zeroed(
             Layout::from_size_align(4096, 4096).unwrap())?;
 
-        Some(PageTable { table })
+        Some(PageTable { table, levels })
     }
 
     /// Returns a `PageTable` struct with the value of CR3 as the table address
+    /// and the number of levels selected by CR4.LA57
+    ///
+    /// # Safety
+    ///
+    /// The returned page table is the one this core is running on. Changing
+    /// it can unmap or change the memory in use, so the caller must make sure
+    /// it isn't modified behind the back of its owner
     pub unsafe fn from_cr3() -> Self {
         let mut cr3 = PhysAddr(0);
         unsafe { core::arch::asm!("mov {}, cr3", out(reg) cr3.0) }
-        Self { table: cr3 }
+
+        let levels = if (cpu::read_cr4() & cpu::CR4_LA57) != 0 {
+            PagingLevels::Five
+        } else {
+            PagingLevels::Four
+        };
+        Self { table: cr3, levels }
+    }
+
+    /// Returns the physical address of the top-level page table
+    pub fn addr(&self) -> PhysAddr {
+        self.table
+    }
+
+    /// Returns the number of levels of this page table
+    pub fn levels(&self) -> PagingLevels {
+        self.levels
     }
 
     /// Translate a virtual address in this page table into its components.
@@ -311,32 +808,40 @@ impl PageTable {
     ///
     /// This is the internal function and shouldn't be used unless necessary.
     /// Use `translate()` instead.
+    ///
+    /// # Safety
+    ///
+    /// The page table must be valid and translatable by `phys_mem`, and
+    /// mustn't be modified while it's being walked
     pub unsafe fn components_inner<P: PhysMem>(&mut self, phys_mem: &mut P,
             vaddr: VirtAddr) -> Result<Mapping, Error> {
         // Start with an empty mapping
         let mut ret = Mapping::default();
 
         // Make sure the address is canonical
-        if cpu::canonicalize_address(16, vaddr.0) != vaddr.0 {
+        if !vaddr.is_canonical_for(self.levels) {
             return Err(Error::AddressNotCanonical);
         }
 
-        // Get the components of the address
-        let indices = Mapping::get_indices(vaddr);
+        // Get the components of the address. The depth is counted from the
+        // PML5, which is skipped with 4-level paging
+        let indices = Mapping::get_indices_la57(vaddr);
 
         // Get the address of the page table
         let mut table = self.table;
 
-        for (depth, &index) in indices.iter().enumerate() {
+        for (depth, &index) in
+                indices.iter().enumerate().skip(self.levels.skipped()) {
             // Get the physical address of the page table entry
             let ptp = PhysAddr(table.0 + index * size_of::<u64>() as u64);
 
             // Fill in the address of the entry we are decoding
             match depth {
-                0 => ret.pml4e = Some(ptp),
-                1 => ret.pdpe  = Some(ptp),
-                2 => ret.pde   = Some(ptp),
-                3 => ret.pte   = Some(ptp),
+                0 => ret.pml5e = Some(ptp),
+                1 => ret.pml4e = Some(ptp),
+                2 => ret.pdpe  = Some(ptp),
+                3 => ret.pde   = Some(ptp),
+                4 => ret.pte   = Some(ptp),
                 _ => unreachable!(),
             }
 
@@ -356,18 +861,14 @@ impl PageTable {
             table = PhysAddr(ent & 0xffffffffff000);
 
             // Check if this is the page mapping and not pointing to a table
-            if depth == 3 || (ent & PAGE_SIZE) != 0 {
-                // Page size bit is not valid (reserved as 0) for the PML4E,
-                // return out the partially walked table
-                if depth == 0 { break; }
-
-                // Determine the mask for this page size
-                let page_mask = match depth {
-                    1 => PageType::Page1G as u64 - 1,
-                    2 => PageType::Page2M as u64 - 1,
-                    3 => PageType::Page4K as u64 - 1,
-                    _ => unreachable!(),
-                };
+            if depth == 4 || (ent & PAGE_SIZE) != 0 {
+                // Determine the mask for this page size. Page size bit is not
+                // valid (reserved as 0) for the PML5E and PML4E, return out
+                // the partially walked table
+                let Some(page_type) = depth.checked_sub(1).and_then(|level| {
+                    PageType::from_level(level, (ent & PAGE_SIZE) != 0)
+                }) else { break; };
+                let page_mask = page_type.mask();
 
                 // At this point, the page is valid, mask off all bits that
                 // arent part of the address
@@ -390,7 +891,7 @@ impl PageTable {
     /// Create a 4-KiB page table entry within this page table, initializing all
     /// memory to 0.
     pub fn map<P: PhysMem>(&mut self, phys_mem: &mut P, request: MapRequest)
-            -> Option<()> {
+            -> Result<(), Error> {
         self.map_init(phys_mem, request, None::<fn(u64) -> u8>)
     }
 
@@ -404,16 +905,18 @@ impl PageTable {
         phys_mem: &mut P,
         request: MapRequest,
         init: Option<F>
-    ) -> Option<()> {
+    ) -> Result<(), Error> {
         let vaddr = request.vaddr.0;
 
         // Make sure the virtual address is aligned to the page size request
-        if request.size <= 0 || (vaddr & (request.page_type as u64 - 1)) != 0 {
-            return None;
+        if request.size == 0 { return Err(Error::InvalidSize); }
+        if (vaddr & (request.page_type as u64 - 1)) != 0 {
+            return Err(Error::AddressUnaligned);
         }
 
         // Compute the end virtual address of this mapping
-        let end_vaddr = vaddr.checked_add(request.size - 1)?;
+        let end_vaddr = vaddr.checked_add(request.size - 1)
+            .ok_or(Error::InvalidSize)?;
 
         // Get the page size for this mapping
         let page_size = request.page_type as u64 as usize;
@@ -422,11 +925,12 @@ impl PageTable {
         for vaddr in (vaddr..=end_vaddr).step_by(page_size) {
             // Allocate the page
             let page = phys_mem.alloc_phys(
-                Layout::from_size_align(page_size, page_size).unwrap())?;
+                Layout::from_size_align(page_size, page_size).unwrap())
+                .ok_or(Error::OutOfMemory)?;
 
             // Create the page table entry
             let entry = page.0 | PAGE_PRESENT
-                | request.permissions.bits()
+                | request.permissions.bits(request.page_type)
                 | request.page_type.size_bit();
 
             // If there is an initialization function, use it to initialize the
@@ -434,7 +938,8 @@ impl PageTable {
             if let Some(init) = &init {
                 // Create a slice from this page's physical memory
                 let slice = unsafe {
-                    let bytes = phys_mem.translate_mut(page, page_size)?;
+                    let bytes = phys_mem.translate_mut(page, page_size)
+                        .ok_or(Error::TranslationFailed)?;
                     core::slice::from_raw_parts_mut(bytes, page_size)
                 };
 
@@ -444,21 +949,206 @@ impl PageTable {
                 }
             }
 
-            // Add this mapping to the page table
+            // Add this mapping to the page table.
+            // XXX: On failure, anything we mapped so far will be leaked
             unsafe {
-                if self.map_raw(phys_mem, VirtAddr(vaddr), request.page_type,
-                                entry).is_err() {
-                    // XXX: Failed to map; anything we did will be leaked
-                    return None;
-                }
+                self.map_raw(phys_mem, VirtAddr(vaddr), request.page_type,
+                             entry)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Map `size` bytes of physically contiguous memory starting at `paddr` to
+    /// `vaddr`, using pages of `page_type` with `perms`.
+    ///
+    /// Both addresses must be aligned to `page_type`, and `size` is rounded up
+    /// to it. The memory isn't allocated.
+    /// XXX: On failure, anything mapped so far stays mapped
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure that mapping the memory is sound, i.e. that
+    /// it isn't owned by anything else which the mapping could break
+    pub unsafe fn map_range<P: PhysMem>(
+        &mut self,
+        phys_mem: &mut P,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: u64,
+        page_type: PageType,
+        perms: Permissions,
+    ) -> Result<(), Error> {
+        // Make sure the addresses are aligned to the page size
+        if !vaddr.is_aligned_to_page(page_type)
+                || !paddr.is_aligned_to_page(page_type) {
+            return Err(Error::AddressUnaligned);
+        }
+
+        // Make sure neither of the ranges overflows
+        if size == 0 { return Err(Error::InvalidSize); }
+        let size = size.checked_next_multiple_of(page_type as u64)
+            .ok_or(Error::InvalidSize)?;
+        vaddr.checked_add(size - 1).ok_or(Error::InvalidSize)?;
+        paddr.checked_add(size - 1).ok_or(Error::InvalidSize)?;
+
+        // Get the bits shared by all the entries
+        let bits = PAGE_PRESENT | perms.bits(page_type) | page_type.size_bit();
+
+        // Map in each page
+        for offset in (0..size).step_by(page_type as usize) {
+            unsafe {
+                self.map_raw(phys_mem, VirtAddr(vaddr.0 + offset), page_type,
+                             (paddr.0 + offset) | bits)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the page mapped at `vaddr` from this page table, returning the
+    /// physical address and the size of the page that was mapped in, or `None`
+    /// if there was no page mapped at `vaddr`.
+    ///
+    /// Page tables which are left without any entries are freed. If `free` is
+    /// set, the page itself is freed as well.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may access the page through `vaddr` after it's unmapped, or
+    /// through any other mapping after it's freed. The caller is responsible
+    /// for invalidating the TLB entries of the page
+    pub unsafe fn unmap<P: PhysMem>(
+            &mut self, phys_mem: &mut P, vaddr: VirtAddr, free: bool)
+            -> Result<Option<(PhysAddr, PageType)>, Error> {
+        // Determine the state of the existing mapping
+        let mapping = self.components(phys_mem, vaddr)?;
+
+        // Nothing to do if there is no page mapped in
+        let (page, _, _) = match mapping.page {
+            Some(page) => page,
+            None       => return Ok(None),
+        };
+        let page_type = mapping.page_type().unwrap();
+
+        // Get all of the current mapping tables, starting at the root table
+        let entries = [
+            mapping.pml5e,
+            mapping.pml4e,
+            mapping.pdpe,
+            mapping.pde,
+            mapping.pte,
+        ];
+        let entries = &entries[self.levels.skipped()..];
+
+        // Get the number of the entries based on the page type
+        let depth = self.levels.depth(page_type);
+
+        // Remove the page from the table
+        unsafe {
+            let ptr = phys_mem.translate_mut(entries[depth - 1].unwrap(),
+                core::mem::size_of::<u64>()).unwrap();
+            core::ptr::write(ptr as *mut u64, 0);
+        }
+
+        // Walk up the tables, updating the reference counts and freeing the
+        // tables which are no longer in use. The root table is never freed
+        for idx in (1..depth).rev() {
+            // Get access to the entry with the reference count of the table
+            // we have removed an entry from
+            let ptr = unsafe {
+                phys_mem.translate_mut(entries[idx - 1].unwrap(),
+                                       core::mem::size_of::<u64>())
+                    .unwrap()
+            };
+
+            // Read the entry
+            let nent = unsafe { core::ptr::read(ptr as *const u64) };
+
+            // Tables without a reference count haven't been created by us.
+            // Don't touch them
+            let in_use = (nent >> 52) & 0x3ff;
+            if in_use == 0 { break; }
+
+            // If the table is still in use, just update the reference count
+            if in_use > 1 {
+                let nent = (nent & !(0x3FF << 52)) | ((in_use - 1) << 52);
+                unsafe { core::ptr::write(ptr as *mut u64, nent); }
+                break;
             }
+
+            // The table is empty. Remove it from the table above and free it
+            unsafe { core::ptr::write(ptr as *mut u64, 0); }
+            phys_mem.free_phys(PhysAddr(nent & 0xffffffffff000),
+                Layout::from_size_align(4096, 4096).unwrap());
+        }
+
+        // Free the page if requested
+        if free {
+            let page_size = page_type as u64 as usize;
+            phys_mem.free_phys(page,
+                Layout::from_size_align(page_size, page_size).unwrap());
         }
 
-        Some(())
+        Ok(Some((page, page_type)))
+    }
+
+    /// Change the permissions of the page mapped at `vaddr` to `perms`,
+    /// returning the permissions it had before, or `None` if there was no page
+    /// mapped at `vaddr`.
+    ///
+    /// The whole page is changed, even if it's a large page.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may rely on the permissions being taken away, e.g. code still
+    /// writing to a page which becomes read-only. The caller is responsible
+    /// for invalidating the TLB entries of the page
+    pub unsafe fn protect<P: PhysMem>(
+            &mut self, phys_mem: &mut P, vaddr: VirtAddr, perms: Permissions)
+            -> Result<Option<Permissions>, Error> {
+        // Determine the state of the existing mapping
+        let mapping = self.components(phys_mem, vaddr)?;
+
+        // Nothing to do if there is no page mapped in
+        let Some((_, _, raw)) = mapping.page else { return Ok(None); };
+        let page_type = mapping.page_type().unwrap();
+
+        // Get the entry mapping the page. It's always the last one walked
+        let entry = [mapping.pte, mapping.pde, mapping.pdpe]
+            .into_iter().flatten().next().unwrap();
+
+        // Replace the permission bits of the entry
+        let mask = PAGE_WRITE | PAGE_USER | PAGE_NXE | PAGE_WRITE_THROUGH
+            | PAGE_CACHE_DISABLE | page_type.pat_bit();
+        let new = (raw & !mask) | perms.bits(page_type);
+        unsafe {
+            let ptr = phys_mem.translate_mut(entry, size_of::<u64>())
+                .ok_or(Error::TranslationFailed)?;
+            core::ptr::write(ptr as *mut u64, new);
+        }
+
+        Ok(Some(Permissions::from_bits(raw, page_type)))
+    }
+
+    /// Returns an iterator over all of the pages mapped in this page table,
+    /// yielding the virtual address, type and raw page table entry of each
+    /// page in the order of their entries in the tables
+    pub fn iter_mappings<'a, P: PhysMem>(&self, phys_mem: &'a mut P)
+            -> Mappings<'a, P> {
+        let mut stack = [(PhysAddr(0), 0); 5];
+        stack[0] = (self.table, 0);
+        Mappings { phys_mem, levels: self.levels, stack, depth: 1 }
     }
 
     /// Map a `vaddr` to a raw page table entry `raw`, using the page size
     /// specified by `page_type`
+    ///
+    /// # Safety
+    ///
+    /// `raw` is written to the page table as is, so the caller must make sure
+    /// that mapping the physical address it holds with its bits is sound
     pub unsafe fn map_raw<P: PhysMem>(
             &mut self, phys_mem: &mut P, vaddr: VirtAddr,
             page_type: PageType, raw: u64) -> Result<(), Error> {
@@ -474,20 +1164,18 @@ impl PageTable {
         // Don't re-map pages
         if mapping.page.is_some() { return Err(Error::MappedAlready); }
 
-        // Get all of the current mapping tables
+        // Get all of the current mapping tables, starting at the root table
         let mut entries = [
+            mapping.pml5e,
             mapping.pml4e,
             mapping.pdpe,
             mapping.pde,
             mapping.pte,
         ];
+        let entries = &mut entries[self.levels.skipped()..];
 
         // Get the number of the entries based on the page type
-        let depth = match page_type {
-            PageType::Page4K => 4,
-            PageType::Page2M => 3,
-            PageType::Page1G => 2,
-        };
+        let depth = self.levels.depth(page_type);
 
         // Don't map a large page over a table containing smaller pages
         if entries.get(depth).map_or(false, |x| x.is_some()) {
@@ -495,10 +1183,11 @@ impl PageTable {
         }
 
         // After this point, the mapping _must_ be done
-        assert!(mapping.pml4e.is_some());
+        assert!(entries[0].is_some());
 
-        // Get the components of the address
-        let indices = Mapping::get_indices(vaddr);
+        // Get the components of the address, starting at the root table
+        let indices = Mapping::get_indices_la57(vaddr);
+        let indices = &indices[self.levels.skipped()..];
 
         // Create page tables as needed while walking to the final page
         for idx in 1..depth {
@@ -507,7 +1196,8 @@ impl PageTable {
 
             // Allocate a new empty table
             let table = phys_mem.alloc_phys_zeroed(
-                Layout::from_size_align(4096, 4096).unwrap()).unwrap();
+                Layout::from_size_align(4096, 4096).unwrap())
+                .ok_or(Error::OutOfMemory)?;
 
             // Convert the address of the page table entry where we need
             // to insert the new table
@@ -579,3 +1269,77 @@ impl PageTable {
         Ok(())
     }
 }
+
+/// Iterator over the pages mapped in a page table, created by
+/// `PageTable::iter_mappings()`
+pub struct Mappings<'a, P: PhysMem> {
+    /// Access to the physical memory of the tables
+    phys_mem: &'a mut P,
+
+    /// The number of levels of the page table
+    levels: PagingLevels,
+
+    /// The tables being walked, starting at the root table, along with the
+    /// index of the next entry to visit in each of them
+    stack: [(PhysAddr, u64); 5],
+
+    /// The number of the tables being walked
+    depth: usize,
+}
+
+impl<P: PhysMem> Iterator for Mappings<'_, P> {
+    type Item = (VirtAddr, PageType, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        /// Number of entries in a table
+        const ENTRIES: u64 = 512;
+
+        /// Shift of the address bits indexing each level, starting at PML5
+        const SHIFTS: [u64; 5] = [48, 39, 30, 21, 12];
+
+        while self.depth > 0 {
+            // Get the next entry in the current table
+            let (table, index) = self.stack[self.depth - 1];
+            if index == ENTRIES {
+                // Done with this table, get back to the one above it
+                self.depth -= 1;
+                continue;
+            }
+            self.stack[self.depth - 1].1 += 1;
+
+            // Read the entry
+            let ptp = PhysAddr(table.0 + index * size_of::<u64>() as u64);
+            let ent = unsafe {
+                let vad = self.phys_mem.translate(ptp, size_of::<u64>())?;
+                core::ptr::read(vad as *const u64)
+            };
+            if (ent & PAGE_PRESENT) == 0 { continue; }
+
+            // Get the level of this table, counted from the PML5
+            let level = self.depth - 1 + self.levels.skipped();
+
+            // Descend into the next table, unless the entry maps a page
+            let page_type = level.checked_sub(1).and_then(|level| {
+                PageType::from_level(level, (ent & PAGE_SIZE) != 0)
+            });
+            let Some(page_type) = page_type else {
+                self.stack[self.depth] = (PhysAddr(ent & 0xffffffffff000), 0);
+                self.depth += 1;
+                continue;
+            };
+
+            // Compute the virtual address of the page from the indices of
+            // the walked entries
+            let skipped = self.levels.skipped();
+            let vaddr = self.stack[..self.depth].iter().enumerate()
+                .fold(0, |acc, (depth, &(_, next))| {
+                    acc | ((next - 1) << SHIFTS[depth + skipped])
+                });
+            let vaddr = VirtAddr(vaddr).canonicalize_for(self.levels);
+
+            return Some((vaddr, page_type, ent));
+        }
+
+        None
+    }
+}
@@ -0,0 +1,354 @@
+extern crate std;
+
+use super::*;
+
+use std::vec;
+use std::vec::Vec;
+
+/// All page types a permission set can be encoded for
+const PAGE_TYPES: [PageType; 3] =
+    [PageType::Page4K, PageType::Page2M, PageType::Page1G];
+
+/// All memory types selectable through the PAT
+const MEMORY_TYPES: [MemoryType; 4] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::Uncached,
+    MemoryType::WriteCombining,
+];
+
+/// Returns all possible permission sets
+fn all_permissions() -> impl Iterator<Item = Permissions> {
+    (0..8).flat_map(|x| MEMORY_TYPES.into_iter().map(move |memory_type| {
+        Permissions {
+            write:   (x & 0b001) != 0,
+            execute: (x & 0b010) != 0,
+            user:    (x & 0b100) != 0,
+            memory_type,
+        }
+    }))
+}
+
+#[test]
+fn permissions_round_trip() {
+    for page_type in PAGE_TYPES {
+        for perms in all_permissions() {
+            let bits = perms.bits(page_type);
+            assert_eq!(Permissions::from_bits(bits, page_type), perms,
+                "{perms:?} as {page_type:?}");
+        }
+    }
+}
+
+#[test]
+fn permissions_ignore_unrelated_bits() {
+    for page_type in PAGE_TYPES {
+        for perms in all_permissions() {
+            // Present, 1-GiB aligned physical address
+            let raw = perms.bits(page_type) | PAGE_PRESENT | 0x1234_4000_0000;
+            assert_eq!(Permissions::from_bits(raw, page_type), perms);
+        }
+    }
+}
+
+#[test]
+fn permissions_uc_minus_decodes_as_uncached() {
+    for page_type in PAGE_TYPES {
+        // PAT indices 2 and 6 are UC-, selected by PCD without PWT
+        for pat in [0, page_type.pat_bit()] {
+            let raw = PAGE_CACHE_DISABLE | pat;
+            let perms = Permissions::from_bits(raw, page_type);
+            assert_eq!(perms.memory_type, MemoryType::Uncached);
+
+            // Encoding it again selects UC at index 3 instead
+            let caching = PAGE_WRITE_THROUGH | PAGE_CACHE_DISABLE
+                | page_type.pat_bit();
+            assert_eq!(perms.bits(page_type) & caching,
+                PAGE_WRITE_THROUGH | PAGE_CACHE_DISABLE);
+        }
+    }
+}
+
+#[test]
+fn permissions_new_is_write_back() {
+    let perms = Permissions::new(true, false, false);
+    assert_eq!(perms.memory_type, MemoryType::WriteBack);
+    for page_type in PAGE_TYPES {
+        let caching = PAGE_WRITE_THROUGH | PAGE_CACHE_DISABLE
+            | page_type.pat_bit();
+        assert_eq!(perms.bits(page_type) & caching, 0);
+    }
+}
+
+/// Physical memory backed by a host allocation. Physical addresses are offsets
+/// into the allocation and are handed out by a bump allocator
+struct MockPhysMem {
+    /// Backing memory, as 4-KiB pages
+    memory: Vec<[u64; 512]>,
+
+    /// Next physical address to be allocated
+    next: u64,
+
+    /// Layouts of all allocations made so far
+    allocations: Vec<Layout>,
+}
+
+impl MockPhysMem {
+    /// Create physical memory of `pages` 4-KiB pages. The first page is never
+    /// allocated, so a null physical address can't be handed out
+    fn new(pages: usize) -> Self {
+        Self { memory: vec![[0; 512]; pages], next: 4096, allocations: vec![] }
+    }
+
+    /// Get the size of the physical memory in bytes
+    fn size(&self) -> u64 {
+        self.memory.len() as u64 * 4096
+    }
+}
+
+impl PhysMem for MockPhysMem {
+    unsafe fn translate(&mut self, paddr: PhysAddr, size: usize)
+            -> Option<*const u8> {
+        unsafe { self.translate_mut(paddr, size).map(|x| x as *const u8) }
+    }
+
+    unsafe fn translate_mut(&mut self, paddr: PhysAddr, size: usize)
+            -> Option<*mut u8> {
+        // Make sure the whole range is backed
+        let end = paddr.0.checked_add(size as u64)?;
+        if end > self.size() { return None; }
+
+        let base = self.memory.as_mut_ptr() as *mut u8;
+        Some(unsafe { base.add(paddr.0 as usize) })
+    }
+
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        let paddr = PhysAddr(self.next).align_up(layout.align() as u64);
+        let end = paddr.checked_add(layout.size() as u64)?;
+        if end.0 > self.size() { return None; }
+
+        self.next = end.0;
+        self.allocations.push(layout);
+        Some(paddr)
+    }
+
+    fn free_phys(&mut self, _paddr: PhysAddr, _layout: Layout) {
+        // Memory is never reused
+    }
+}
+
+#[test]
+fn alloc_phys_contiguous() {
+    let mut pmem = MockPhysMem::new(16);
+
+    // The pages are requested as a single page aligned allocation
+    let paddr = pmem.alloc_phys_contiguous(3).unwrap();
+    assert!(paddr.is_aligned_to_page(PageType::Page4K));
+    assert_eq!(pmem.allocations,
+        [Layout::from_size_align(3 * 4096, 4096).unwrap()]);
+
+    // The next allocation starts after all of the pages
+    let next = pmem.alloc_phys_contiguous(1).unwrap();
+    assert!(next.0 >= paddr.0 + 3 * 4096);
+}
+
+#[test]
+fn alloc_phys_contiguous_failure() {
+    let mut pmem = MockPhysMem::new(4);
+
+    // Not enough memory
+    assert_eq!(pmem.alloc_phys_contiguous(4), None);
+
+    // The size overflows, nothing is requested at all
+    assert_eq!(pmem.alloc_phys_contiguous(usize::MAX), None);
+    assert_eq!(pmem.allocations.len(), 0);
+}
+
+#[test]
+fn align_at_page_boundaries() {
+    for page_type in PAGE_TYPES {
+        let size = page_type as u64;
+        let page = PhysAddr(3 * size);
+
+        // Aligned addresses stay where they are
+        assert_eq!(page.align_up(size), page);
+        assert_eq!(page.align_down(size), page);
+
+        // One byte past the boundary
+        let after = PhysAddr(page.0 + 1);
+        assert_eq!(after.align_up(size), PhysAddr(page.0 + size));
+        assert_eq!(after.align_down(size), page);
+
+        // One byte before the boundary
+        let before = PhysAddr(page.0 - 1);
+        assert_eq!(before.align_up(size), page);
+        assert_eq!(before.align_down(size), PhysAddr(page.0 - size));
+
+        // Offsets into the page
+        assert_eq!(page.offset_in_page(page_type), 0);
+        assert_eq!(after.offset_in_page(page_type), 1);
+        assert_eq!(before.offset_in_page(page_type), size - 1);
+    }
+}
+
+#[test]
+fn align_virtual_addresses() {
+    let vaddr = VirtAddr(0xFFFF_8000_0000_1001);
+    assert_eq!(vaddr.align_up(4096), VirtAddr(0xFFFF_8000_0000_2000));
+    assert_eq!(vaddr.align_down(4096), VirtAddr(0xFFFF_8000_0000_1000));
+    assert_eq!(VirtAddr(0).align_up(4096), VirtAddr(0));
+}
+
+#[test]
+fn align_up_top_of_address_space() {
+    let last = PhysAddr(u64::MAX - 4095);
+    assert_eq!(last.align_up(4096), last);
+    assert_eq!(last.checked_add(4096), None);
+    assert_eq!(last.checked_add(4095), Some(PhysAddr(u64::MAX)));
+}
+
+#[test]
+#[should_panic(expected = "Overflow when aligning an address up")]
+fn align_up_overflow() {
+    PhysAddr(u64::MAX - 4094).align_up(4096);
+}
+
+/// Bits of a large page table entry holding the physical address of the page
+const LARGE_PAGE_ADDR: u64 = 0x000F_FFFF_FFE0_0000;
+
+#[test]
+fn map_range_large_pages() {
+    let mut pmem = MockPhysMem::new(16);
+    let mut table = PageTable::new(&mut pmem).unwrap();
+
+    // Map 4 MiB with 2 MiB pages
+    let vaddr = VirtAddr(0xFFFF_8000_0020_0000);
+    let paddr = PhysAddr(0x4000_0000);
+    let perms = Permissions::new(true, false, false);
+    unsafe {
+        table.map_range(&mut pmem, vaddr, paddr, 4 * 1024 * 1024,
+            PageType::Page2M, perms).unwrap();
+    }
+
+    // Exactly two consecutive pages are mapped
+    let mappings: Vec<_> = table.iter_mappings(&mut pmem).collect();
+    assert_eq!(mappings.len(), 2);
+    for (idx, &(va, page_type, raw)) in mappings.iter().enumerate() {
+        let offset = idx as u64 * PageType::Page2M as u64;
+        assert_eq!(va, VirtAddr(vaddr.0 + offset));
+        assert_eq!(page_type, PageType::Page2M);
+        assert_eq!(raw & LARGE_PAGE_ADDR, paddr.0 + offset);
+        assert_eq!(Permissions::from_bits(raw, page_type), perms);
+    }
+
+    // Addresses within the second page translate into it
+    let mapping = table.components(&mut pmem,
+        VirtAddr(vaddr.0 + 0x30_1234)).unwrap();
+    assert_eq!(mapping.page_type(), Some(PageType::Page2M));
+    assert_eq!(mapping.page.map(|(page, offset, _)| (page, offset)),
+        Some((PhysAddr(paddr.0 + 0x20_0000), 0x10_1234)));
+}
+
+#[test]
+fn map_range_rounds_up_size() {
+    let mut pmem = MockPhysMem::new(16);
+    let mut table = PageTable::new(&mut pmem).unwrap();
+
+    // 3 MiB need two 2 MiB pages
+    unsafe {
+        table.map_range(&mut pmem, VirtAddr(0x20_0000), PhysAddr(0x20_0000),
+            3 * 1024 * 1024, PageType::Page2M,
+            Permissions::new(true, false, false)).unwrap();
+    }
+    assert_eq!(table.iter_mappings(&mut pmem).count(), 2);
+}
+
+#[test]
+fn map_range_unaligned() {
+    let mut pmem = MockPhysMem::new(16);
+    let mut table = PageTable::new(&mut pmem).unwrap();
+    let perms = Permissions::new(true, false, false);
+
+    // 4-KiB aligned addresses can't be mapped with 2 MiB pages
+    let res = unsafe {
+        table.map_range(&mut pmem, VirtAddr(0x20_0000), PhysAddr(0x1000),
+            4 * 1024 * 1024, PageType::Page2M, perms)
+    };
+    assert!(matches!(res, Err(Error::AddressUnaligned)));
+
+    let res = unsafe {
+        table.map_range(&mut pmem, VirtAddr(0x1000), PhysAddr(0x20_0000),
+            4 * 1024 * 1024, PageType::Page2M, perms)
+    };
+    assert!(matches!(res, Err(Error::AddressUnaligned)));
+    assert_eq!(table.iter_mappings(&mut pmem).count(), 0);
+}
+
+#[test]
+fn page_span_of_packet_buffers() {
+    // Control packets, standard frames, the default packets and jumbo frames
+    // are backed by whole 4-KiB pages
+    for (size, span) in [(64, 0x1000), (1518, 0x1000), (4096, 0x1000),
+                         (4097, 0x2000), (9018, 0x3000)] {
+        assert_eq!(PageType::Page4K.span(size), span);
+    }
+
+    // Nothing needs no pages
+    assert_eq!(PageType::Page4K.span(0), 0);
+    assert_eq!(PageType::Page1G.span(1), 0x4000_0000);
+}
+
+/// A 1 GiB window mapped at 0xFFFF_8000_0000_0000
+const WINDOW: Window = Window { base: 0xFFFF_8000_0000_0000, size: 1 << 30 };
+
+#[test]
+fn window_round_trip() {
+    // Addresses in the window translate back and forth
+    for paddr in [0, 0x1234_5678, (1 << 30) - 1] {
+        let vaddr = WINDOW.to_virt(PhysAddr(paddr)).unwrap();
+        assert_eq!(vaddr, VirtAddr(WINDOW.base + paddr));
+        assert_eq!(WINDOW.to_phys(vaddr), Some(PhysAddr(paddr)));
+    }
+}
+
+#[test]
+fn window_rejects_outside_addresses() {
+    // Physical memory past the window isn't mapped
+    assert_eq!(WINDOW.to_virt(PhysAddr(1 << 30)), None);
+    assert_eq!(WINDOW.to_virt(PhysAddr(u64::MAX)), None);
+
+    // Neither are the virtual addresses around the window
+    assert_eq!(WINDOW.to_phys(VirtAddr(WINDOW.base - 1)), None);
+    assert_eq!(WINDOW.to_phys(VirtAddr(WINDOW.base + (1 << 30))), None);
+
+    // A window reaching past the end of the address space can't map it
+    let window = Window { base: u64::MAX - 0xFFF, size: 0x2000 };
+    assert_eq!(window.to_virt(PhysAddr(0xFFF)), Some(VirtAddr(u64::MAX)));
+    assert_eq!(window.to_virt(PhysAddr(0x1000)), None);
+}
+
+#[test]
+fn window_range_boundaries() {
+    use shared_data::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
+    let window = Window {
+        base: KERNEL_PHYS_WINDOW_BASE,
+        size: KERNEL_PHYS_WINDOW_SIZE,
+    };
+
+    // A range ending right at the end of the window fits
+    let paddr = PhysAddr(KERNEL_PHYS_WINDOW_SIZE - 0x1000);
+    assert_eq!(window.range_to_virt(paddr, 0x1000),
+               Some(VirtAddr(KERNEL_PHYS_WINDOW_BASE + paddr.0)));
+    assert!(window.range_to_virt(PhysAddr(0), KERNEL_PHYS_WINDOW_SIZE)
+        .is_some());
+
+    // One byte more doesn't
+    assert_eq!(window.range_to_virt(paddr, 0x1001), None);
+    assert_eq!(window.range_to_virt(PhysAddr(0), KERNEL_PHYS_WINDOW_SIZE + 1),
+               None);
+
+    // Neither do empty ranges and ranges whose end overflows
+    assert_eq!(window.range_to_virt(PhysAddr(0), 0), None);
+    assert_eq!(window.range_to_virt(PhysAddr(u64::MAX), 2), None);
+    assert_eq!(window.range_to_virt(PhysAddr(0x1000), u64::MAX), None);
+}
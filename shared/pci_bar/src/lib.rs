@@ -0,0 +1,101 @@
+//! Decoding of the raw values of PCI base address registers (BARs)
+//!
+//! The size of a BAR is found by writing all ones to it and reading it back.
+//! The bits which stay clear give away the size, see `BarBits::size()`
+
+#![no_std]
+
+#[cfg(test)]
+mod tests;
+
+/// The bitness of a BAR
+#[derive(Debug, PartialEq)]
+#[allow(missing_docs)]
+pub enum BarBits {
+    Bit32,
+    Bit64,
+}
+
+impl BarBits {
+    /// Return the bitness of `bar`. I/O space BARs are always 32-bit
+    pub fn from_bar(bar: u32) -> Self {
+        if BarType::from_bar(bar) == BarType::Io { return Self::Bit32; }
+
+        match (bar >> 1) & 0b11 {
+            0b10 => Self::Bit64,
+            _    => Self::Bit32,
+        }
+    }
+
+    /// Based on the bitness of the memory BAR `bar0`, returns a whole `u64`
+    /// value from either `bar0` (if 32-bit), or in `bar1 << 32 | bar0` (if
+    /// 64-bit) with the BAR type bits masked off. The upper half of a 64-bit
+    /// BAR holds nothing but address bits
+    pub fn u64(bar0: u32, bar1: u32) -> u64 {
+        // Mask off the type bits
+        let lower  = (bar0 & !0b1111) as u64;
+        let higher = bar1 as u64;
+
+        // Return the u64 based on bitness
+        match Self::from_bar(bar0) {
+            Self::Bit32 => lower,
+            Self::Bit64 => (higher << 32) | lower,
+        }
+    }
+
+    /// Returns the size of the memory BAR `bar0` from the values `sizing0`
+    /// and `sizing1` read back from it and the BAR after it after writing all
+    /// ones to them. `sizing1` is only used for 64-bit BARs.
+    ///
+    /// Returns `None` if the BAR isn't implemented
+    pub fn size(bar0: u32, sizing0: u32, sizing1: u32) -> Option<u64> {
+        // The bits above a 32-bit BAR can't be written
+        let mask = match Self::from_bar(bar0) {
+            Self::Bit32 => (sizing0 & !0b1111) as u64 | !0u64 << 32,
+            Self::Bit64 => Self::u64(sizing0, sizing1),
+        };
+
+        // The lowest writable bit is the size
+        (mask != 0 && mask != !0u64 << 32).then(|| !mask + 1)
+    }
+
+    /// Returns the base I/O port of the I/O space BAR `bar`, with the BAR type
+    /// bits masked off. Ports are 16-bit, so the upper bits are discarded
+    pub fn io_port(bar: u32) -> u16 {
+        (bar & !0b11) as u16
+    }
+
+    /// Returns the number of ports decoded by an I/O space BAR from the value
+    /// `sizing` read back from it after writing all ones to it. The upper 16
+    /// bits are allowed to read back as zeroes.
+    ///
+    /// Returns `None` if the BAR isn't implemented
+    pub fn io_size(sizing: u32) -> Option<u16> {
+        let mask = Self::io_port(sizing);
+        (mask != 0).then(|| !mask + 1)
+    }
+}
+
+/// The memory type of a BAR
+#[derive(Debug, PartialEq)]
+#[allow(missing_docs)]
+pub enum BarType {
+    Io,
+    Memory,
+}
+
+impl BarType {
+    /// Return the type of `bar`
+    pub fn from_bar(bar: u32) -> Self {
+        match bar & 0b1 {
+            0 => Self::Memory,
+            _ => Self::Io,
+        }
+    }
+
+    /// Whether the memory of the memory BAR `bar` is prefetchable, i.e. reads
+    /// have no side effects. Always `false` for I/O space BARs
+    pub fn prefetchable(bar: u32) -> bool {
+        Self::from_bar(bar) == Self::Memory && (bar & 0b1000) != 0
+    }
+}
@@ -0,0 +1,54 @@
+use super::*;
+
+#[test]
+fn bar_64bit_prefetchable_pair() {
+    // A prefetchable 64-bit memory BAR at 0x1_2345_0000, with 64 KiB of
+    // writable bits below its base
+    let (bar0, bar1) = (0x2345_000C, 0x1);
+    assert_eq!(BarType::from_bar(bar0), BarType::Memory);
+    assert_eq!(BarBits::from_bar(bar0), BarBits::Bit64);
+    assert!(BarType::prefetchable(bar0));
+
+    // Both halves make up the base and the size
+    assert_eq!(BarBits::u64(bar0, bar1), 0x1_2345_0000);
+    assert_eq!(BarBits::size(bar0, 0xFFFF_000C, 0xFFFF_FFFF), Some(0x10000));
+
+    // The size can reach above 4 GiB
+    assert_eq!(BarBits::size(bar0, 0x0000_000C, 0xFFFF_FFFE),
+               Some(0x2_0000_0000));
+
+    // Nothing writable means the BAR isn't implemented
+    assert_eq!(BarBits::size(bar0, 0xC, 0), None);
+}
+
+#[test]
+fn bar_32bit_memory() {
+    // A non-prefetchable 32-bit memory BAR at 0xFEBC_0000
+    let bar0 = 0xFEBC_0000;
+    assert_eq!(BarType::from_bar(bar0), BarType::Memory);
+    assert_eq!(BarBits::from_bar(bar0), BarBits::Bit32);
+    assert!(!BarType::prefetchable(bar0));
+
+    // The next BAR is ignored for both the base and the size
+    assert_eq!(BarBits::u64(bar0, 0xFFFF_FFFF), 0xFEBC_0000);
+    assert_eq!(BarBits::size(bar0, 0xFFFE_0000, 0xFFFF_FFFF), Some(0x20000));
+    assert_eq!(BarBits::size(bar0, 0, 0xFFFF_FFFF), None);
+}
+
+#[test]
+fn bar_io_space() {
+    // An I/O BAR decoding 32 ports at 0xC020
+    let bar = 0xC021;
+    assert_eq!(BarType::from_bar(bar), BarType::Io);
+    assert_eq!(BarBits::from_bar(bar), BarBits::Bit32);
+    assert!(!BarType::prefetchable(bar));
+
+    // The type bits and the upper half are masked off
+    assert_eq!(BarBits::io_port(bar), 0xC020);
+    assert_eq!(BarBits::io_port(0xABCD_C023), 0xC020);
+
+    // The upper half may read back as zeroes when sizing
+    assert_eq!(BarBits::io_size(0xFFFF_FFE1), Some(32));
+    assert_eq!(BarBits::io_size(0x0000_FFE1), Some(32));
+    assert_eq!(BarBits::io_size(0x1), None);
+}
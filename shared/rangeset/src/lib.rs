@@ -6,6 +6,8 @@
 #[cfg(test)]
 mod tests;
 
+pub mod shards;
+
 use core::cmp;
 
 /// Errors returned by the range-based routines
@@ -27,6 +29,28 @@ pub enum Error {
 
     /// An attempt was made to allocate memory not aligned to a power of 2
     WrongAlignment(u64),
+
+    /// An attempt was made to reserve a [`Range`] which isn't fully present
+    /// in the [`RangeSet`]
+    AlreadyReserved(Range),
+}
+
+/// Policy used to pick the entry an allocation is made from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum AllocPolicy {
+    /// Use the first entry the allocation fits into. This stops scanning as
+    /// soon as an entry fits, but tends to fragment the start of the set
+    FirstFit,
+
+    /// Use the smallest entry the allocation fits into. This scans every
+    /// entry, but keeps the large entries intact for large allocations
+    #[default]
+    BestFit,
+
+    /// Use the largest entry. This scans every entry, but leaves the largest
+    /// possible remainder behind, so small allocations don't split up the
+    /// entries which would fit medium sized ones
+    WorstFit,
 }
 
 /// An inclusive range. `RangeInclusive` doesn't implement `Copy`, so it's not
@@ -111,6 +135,11 @@ impl RangeSet {
         &self.ranges[..self.in_use as usize]
     }
 
+    /// Returns an iterator over the used entries in a `RangeSet`
+    pub fn iter(&self) -> core::iter::Copied<core::slice::Iter<'_, Range>> {
+        self.entries().iter().copied()
+    }
+
     /// Compute the size of the range covered by this rangeset
     pub fn len(&self) -> Option<u64> {
         self.entries().iter().try_fold(0u64, |acc, x| {
@@ -123,6 +152,19 @@ impl RangeSet {
         self.in_use == 0
     }
 
+    /// Returns the largest range in the set, the lowest one if there are
+    /// several of the same size
+    pub fn largest(&self) -> Option<Range> {
+        // `max_by_key` returns the last maximum, so iterate from the top
+        self.iter().rev().max_by_key(|entry| entry.end - entry.start)
+    }
+
+    /// Returns the smallest range in the set, the lowest one if there are
+    /// several of the same size
+    pub fn smallest(&self) -> Option<Range> {
+        self.iter().min_by_key(|entry| entry.end - entry.start)
+    }
+
     /// Delete the range at `idx`
     fn delete(&mut self, idx: usize) -> Result<(), Error> {
         // Make sure we don't index out of bounds
@@ -137,13 +179,46 @@ impl RangeSet {
 
         // Decrement the number of valid ranges
         self.in_use -= 1;
+        self.check_invariant();
         Ok(())
     }
 
+    /// Assert that the entries are valid, strictly ascending and that they
+    /// neither overlap nor touch each other
+    #[cfg(debug_assertions)]
+    fn check_invariant(&self) {
+        assert!(self.in_use as usize <= self.ranges.len(),
+            "RangeSet uses more entries than it has");
+
+        for entry in self.entries() {
+            assert!(entry.start <= entry.end,
+                "RangeSet contains an invalid range {entry:?}");
+        }
+
+        for pair in self.entries().windows(2) {
+            let separated = pair[0].end.checked_add(1)
+                .is_some_and(|end| end < pair[1].start);
+            assert!(separated,
+                "RangeSet entries {:?} and {:?} are unsorted or overlap",
+                pair[0], pair[1]);
+        }
+    }
+
+    /// The invariant is only checked in debug builds
+    #[cfg(not(debug_assertions))]
+    #[inline(always)]
+    fn check_invariant(&self) {}
+
+    /// Insert the single `value` into the `RangeSet`. This is the same as
+    /// inserting a range with `start == end == value`
+    pub fn insert_value(&mut self, value: u64) -> Result<(), Error> {
+        self.insert(Range::new(value, value)?)
+    }
+
     /// Insert a new range into the `RangeSet` while keeping it sorted.
     ///
-    /// If the range overlaps with an existing range, both ranges will be merged
-    /// into one.
+    /// If the range overlaps or touches an existing range, on either side of
+    /// it, both ranges will be merged into one.
     pub fn insert(&mut self, mut range: Range) -> Result<(), Error> {
         let mut idx = 0;
         while idx < self.in_use as usize {
@@ -159,7 +234,7 @@ impl RangeSet {
             }
 
             // If the ranges don't overlap/touch, break
-            if range.end < entry.start { break; }
+            if range.end.saturating_add(1) < entry.start { break; }
 
             // At this point, there is some overlap/touch: merge the ranges
             range.start = cmp::min(entry.start, range.start);
@@ -182,9 +257,67 @@ impl RangeSet {
         // Insert the range
         self.ranges[idx] = range;
         self.in_use += 1;
+        self.check_invariant();
         Ok(())
     }
 
+    /// Insert a new range into the `RangeSet` like [`RangeSet::insert`], but
+    /// make space by dropping the smallest range if the set is full.
+    ///
+    /// Touching and overlapping ranges are merged by `insert` already, so
+    /// this only drops anything once the set holds the maximum number of
+    /// separate ranges. The smallest of the ranges in the set and `range` is
+    /// the one dropped, which may be `range` itself. Merging the ranges over
+    /// the gaps between them instead would claim memory which isn't part of
+    /// the set, so under-reporting is preferred.
+    ///
+    /// Returns the dropped range, if any.
+    pub fn insert_or_drop_smallest(&mut self, range: Range)
+            -> Result<Option<Range>, Error> {
+        match self.insert(range) {
+            Err(Error::RangeSetOverflow) => {},
+            other => return other.map(|_| None),
+        }
+
+        // The set is full and untouched. Find its smallest range
+        let (idx, smallest) = self.entries().iter().copied().enumerate()
+            .min_by_key(|(_, entry)| entry.end - entry.start)
+            .ok_or(Error::RangeSetOverflow)?;
+
+        // Drop the new range if it's the smallest one
+        if range.end - range.start <= smallest.end - smallest.start {
+            return Ok(Some(range));
+        }
+
+        // Otherwise make space for it
+        self.delete(idx)?;
+        self.insert(range)?;
+        Ok(Some(smallest))
+    }
+
+    /// Insert every range of `other` into this `RangeSet`, merging
+    /// overlapping and touching ranges.
+    ///
+    /// If this set runs out of space, `Error::RangeSetOverflow` is returned.
+    /// The ranges inserted up to that point stay in the set, so it may end up
+    /// only partially merged.
+    pub fn merge_from(&mut self, other: &RangeSet) -> Result<(), Error> {
+        other.entries().iter().try_for_each(|&range| self.insert(range))
+    }
+
+    /// Returns a new `RangeSet` containing only the parts of the ranges in
+    /// this set which are also present in `other`
+    pub fn intersect(&self, other: &RangeSet) -> Result<RangeSet, Error> {
+        let mut ret = RangeSet::new();
+        for entry in self.entries() {
+            for overlap in other.entries().iter()
+                    .filter_map(|range| entry.overlaps(range)) {
+                ret.insert(overlap)?;
+            }
+        }
+        Ok(ret)
+    }
+
     /// Remove a `range` from this `RangeSet`.
     ///
     /// Any range overlapping with `range` will be trimmed. Any range that is
@@ -228,13 +361,44 @@ impl RangeSet {
             } else {
                 // The range is fully contained within this entry;
                 // split the entry in two and skip the new entry
-                idx += 1 * self.split_entry(idx, range)? as usize;
+                idx += self.split_entry(idx, range)? as usize;
             }
             idx += 1;
         }
+        self.check_invariant();
         Ok(any_removed)
     }
 
+    /// Remove the single `value` from this `RangeSet`. This is the same as
+    /// removing a range with `start == end == value`.
+    ///
+    /// Returns `Ok(true)` if the value was in the set, otherwise `Ok(false)`
+    pub fn remove_value(&mut self, value: u64) -> Result<bool, Error> {
+        self.remove(Range::new(value, value)?)
+    }
+
+    /// Reserve the exact `range`, removing it from this `RangeSet`, which is
+    /// treated as a set of free regions.
+    ///
+    /// Returns `Error::AlreadyReserved` if any part of `range` is missing from
+    /// the set. The set is left untouched if an error is returned.
+    pub fn reserve(&mut self, range: Range) -> Result<(), Error> {
+        // Make sure the range is valid
+        if range.start > range.end {
+            return Err(Error::InvalidRange(range));
+        }
+
+        // Touching entries are always merged, so a fully free range has to be
+        // contained within a single entry
+        if !self.entries().iter().any(|entry| entry.contains(&range)) {
+            return Err(Error::AlreadyReserved(range));
+        }
+
+        // Only the containing entry is altered, which fails before modifying
+        // it if the set is out of space for a split
+        self.remove(range).map(|_| ())
+    }
+
     /// Split an entry into two when the `range` is fully contained within the
     /// entry at `idx`, making sure there is enough space in the rangeset for
     /// both entries. Returns `true` if an entry was in fact split and another
@@ -277,6 +441,7 @@ impl RangeSet {
             range.end.saturating_add(1), entry.end)?;
         self.in_use += 1;
 
+        self.check_invariant();
         Ok(true)
     }
 
@@ -294,6 +459,65 @@ impl RangeSet {
         align: u64,
         regions: Option<&RangeSet>
     ) -> Result<Option<u64>, Error> {
+        self.allocate_with(size, align, regions, AllocPolicy::BestFit)
+    }
+
+    /// Allocate `size` bytes of memory with `align` requirements, preferring to
+    /// allocate from `regions` and picking the entry to allocate from by
+    /// `policy`.
+    ///
+    /// Best-fit minimizes fragmentation but always scans the whole set;
+    /// first-fit is faster for hot paths which don't care where the memory
+    /// comes from. An allocation from `regions` is always preferred, so with
+    /// `regions`, first-fit only stops early on an entry within them.
+    ///
+    /// Returns the pointer to the allocated memory. Errors are returned the
+    /// same way as by [`RangeSet::allocate_prefer`].
+    pub fn allocate_with(
+        &mut self,
+        size: u64,
+        align: u64,
+        regions: Option<&RangeSet>,
+        policy: AllocPolicy,
+    ) -> Result<Option<u64>, Error> {
+        // The pointer is the start of the region rounded up to the alignment
+        let align_mask = align.wrapping_sub(1);
+        Ok(self.allocate_region_with(size, align, regions, policy)?
+            .map(|region| (region.start + align_mask) & !align_mask))
+    }
+
+    /// Allocate `size` bytes of memory with `align` requirements, preferring to
+    /// allocate from `regions`.
+    ///
+    /// Returns the whole inclusive [`Range`] removed from the set, which can be
+    /// passed back to [`RangeSet::insert`] to free the allocation. The range
+    /// may start with padding required by the alignment; the pointer to the
+    /// allocated memory is its start rounded up to `align`.
+    ///
+    /// Errors are returned the same way as by [`RangeSet::allocate_prefer`].
+    pub fn allocate_region_prefer(
+        &mut self,
+        size: u64,
+        align: u64,
+        regions: Option<&RangeSet>
+    ) -> Result<Option<Range>, Error> {
+        self.allocate_region_with(size, align, regions, AllocPolicy::BestFit)
+    }
+
+    /// Allocate `size` bytes of memory with `align` requirements, preferring to
+    /// allocate from `regions` and picking the entry to allocate from by
+    /// `policy`.
+    ///
+    /// Returns the whole inclusive [`Range`] removed from the set. See
+    /// [`RangeSet::allocate_region_prefer`] and [`RangeSet::allocate_with`]
+    /// for details.
+    pub fn allocate_region_with(
+        &mut self,
+        size: u64,
+        align: u64,
+        regions: Option<&RangeSet>,
+        policy: AllocPolicy,
+    ) -> Result<Option<Range>, Error> {
         // Don't allow 0-sized allocations
         if size == 0 { return Err(Error::ZeroSizedAllocation); }
 
@@ -305,6 +529,10 @@ impl RangeSet {
 
         // Go through each range and see if an allocation can fit into it
         let mut allocation = None;
+
+        // Size of the entry the allocation is made from. The smaller the
+        // entry, the better the fit, unless the policy is worst-fit
+        let mut fit = u64::MAX;
         'search: for entry in self.entries() {
             // Calculate the padding
             let padding = (align - (entry.start & align_mask)) & align_mask;
@@ -352,35 +580,46 @@ impl RangeSet {
 
                         // Make sure the allocation fits in the current
                         // addressable address space
-                        let max_addr = core::usize::MAX as u64;
+                        let max_addr = usize::MAX as u64;
                         if aligned > max_addr || alc_end > max_addr {
                             continue 'search;
                         }
 
                         // We know the allocation can be satisfied starting
                         // at `aligned`
-                        allocation = Some((aligned, alc_end, aligned));
+                        allocation = Some((aligned, alc_end));
                         break 'search;
                     }
                 }
             }
 
-            // Compute the "best" allocation size to date
-            let prev_size = allocation.map(|(start, end, _)| end - start);
+            // Update the allocation if this entry fits it better
+            let entry_size = entry.end - entry.start;
+            let better = match policy {
+                AllocPolicy::FirstFit => false,
+                AllocPolicy::BestFit  => entry_size < fit,
+                AllocPolicy::WorstFit => entry_size > fit,
+            };
+            if allocation.is_none() || better {
+                allocation = Some((start, end));
+                fit = entry_size;
+            }
 
-            if allocation.is_none() || prev_size.unwrap() > end - start {
-                // Update the allocation to the new best size
-                allocation = Some((start, end, start + padding));
+            // First-fit is done with the first fitting entry, unless a
+            // preferred region might still come up
+            if policy == AllocPolicy::FirstFit && regions.is_none() {
+                break 'search;
             }
         }
 
-        Ok(allocation.map(|(start, end, ptr)| {
+        Ok(allocation.map(|(start, end)| {
             // Remove this range from the available set; it should be properly
             // validated at this point
-            self.remove(Range { start, end }).unwrap();
+            let region = Range { start, end };
+            self.remove(region).unwrap();
 
-            // Return out the pointer!
-            ptr
+            // Return out the region!
+            region
         }))
     }
 
@@ -395,4 +634,52 @@ impl RangeSet {
             -> Result<Option<u64>, Error> {
         self.allocate_prefer(size, align, None)
     }
+
+    /// Allocate `size` bytes of memory with `align` requirements from the
+    /// largest range in the set which fits it (worst-fit).
+    ///
+    /// Unlike [`RangeSet::allocate`], this keeps the ranges which fit the
+    /// allocation tightly intact. Errors are returned the same way.
+    pub fn take_largest(&mut self, size: u64, align: u64)
+            -> Result<Option<u64>, Error> {
+        self.allocate_with(size, align, None, AllocPolicy::WorstFit)
+    }
+
+    /// Allocate `size` bytes of memory with `align` requirements.
+    ///
+    /// Returns the whole inclusive [`Range`] removed from the set. See
+    /// [`RangeSet::allocate_region_prefer`] for details.
+    pub fn allocate_region(&mut self, size: u64, align: u64)
+            -> Result<Option<Range>, Error> {
+        self.allocate_region_prefer(size, align, None)
+    }
+}
+
+impl Default for RangeSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> IntoIterator for &'a RangeSet {
+    type Item = Range;
+    type IntoIter = core::iter::Copied<core::slice::Iter<'a, Range>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Collect ranges into a `RangeSet`, merging overlapping and touching ranges.
+///
+/// Collection stops at the first range that fails to be inserted, returning
+/// its error (e.g. `Error::RangeSetOverflow`)
+impl FromIterator<Range> for Result<RangeSet, Error> {
+    fn from_iter<I: IntoIterator<Item = Range>>(iter: I) -> Self {
+        let mut set = RangeSet::new();
+        for range in iter {
+            set.insert(range)?;
+        }
+        Ok(set)
+    }
 }
@@ -0,0 +1,108 @@
+//! Free memory split into shards with a lock each, so that cores allocating
+//! from different shards (e.g. different NUMA nodes) don't contend on a single
+//! lock.
+//!
+//! Lock order: a shard lock and the lock of the global free memory are never
+//! held at the same time, nor are two shard locks. Allocations which spill
+//! over to another set release the lock of the previous one first.
+
+use spinlock::{SpinLock, InterruptState};
+
+use crate::{RangeSet, Range, Error, AllocPolicy};
+
+/// Free memory of a single domain, such as a NUMA node
+pub struct Shard<I: InterruptState> {
+    /// All of the memory of the domain, used to return freed memory to it
+    domain: RangeSet,
+
+    /// Free memory of the domain
+    free: SpinLock<RangeSet, I>,
+}
+
+impl<I: InterruptState> Shard<I> {
+    /// Create a shard of the memory `domain`, moving the parts of it which are
+    /// free in `global` out of it and into the shard
+    pub fn carve(domain: RangeSet, global: &mut RangeSet)
+            -> Result<Self, Error> {
+        let mut free = RangeSet::new();
+        for range in domain.iter() {
+            while let Some(overlap) = global.entries().iter()
+                    .find_map(|entry| entry.overlaps(&range)) {
+                global.remove(overlap)?;
+                free.insert(overlap)?;
+            }
+        }
+
+        Ok(Self { domain, free: SpinLock::new(free) })
+    }
+
+    /// Get all of the memory of the domain, free or not
+    pub fn domain(&self) -> &RangeSet {
+        &self.domain
+    }
+
+    /// Whether the whole `range` belongs to the domain of this shard
+    pub fn owns(&self, range: &Range) -> bool {
+        self.domain.entries().iter().any(|entry| entry.contains(range))
+    }
+
+    /// Allocate `size` bytes aligned to `align` from the free memory of this
+    /// shard, picked by `policy`
+    pub fn allocate(&self, size: u64, align: u64, policy: AllocPolicy)
+            -> Option<u64> {
+        self.free.lock().allocate_with(size, align, None, policy).ok()?
+    }
+
+    /// Return the `range` to the free memory of this shard
+    pub fn free(&self, range: Range) -> Result<(), Error> {
+        self.free.lock().insert(range)
+    }
+
+    /// Returns the number of free bytes in this shard, or `None` if the lock
+    /// is held or the size overflows
+    pub fn free_bytes_try(&self) -> Option<u64> {
+        self.free.try_lock()?.len()
+    }
+}
+
+/// Allocate `size` bytes aligned to `align` from the `shards`, picking the
+/// memory within each of them by `policy`.
+///
+/// The `local` shard is tried first, followed by the `global` allocation and
+/// the rest of the shards in order.
+pub fn allocate<I: InterruptState>(
+    shards: &[Shard<I>],
+    local: Option<usize>,
+    size: u64,
+    align: u64,
+    policy: AllocPolicy,
+    global: impl FnOnce() -> Option<u64>,
+) -> Option<u64> {
+    // Try the local shard first
+    let local = local.and_then(|idx| shards.get(idx).map(|shard| (idx, shard)));
+    if let Some(allocation) = local
+            .and_then(|(_, shard)| shard.allocate(size, align, policy)) {
+        return Some(allocation);
+    }
+
+    // Fall back to the global free memory
+    if let Some(allocation) = global() { return Some(allocation); }
+
+    // Spill over to the other shards
+    shards.iter().enumerate()
+        .filter(|&(idx, _)| local.is_none_or(|(local, _)| local != idx))
+        .find_map(|(_, shard)| shard.allocate(size, align, policy))
+}
+
+/// Return the `range` to the shard whose domain it belongs to, or to the
+/// `global` free memory if it doesn't belong to any of them
+pub fn free<I: InterruptState>(
+    shards: &[Shard<I>],
+    range: Range,
+    global: impl FnOnce(Range) -> Result<(), Error>,
+) -> Result<(), Error> {
+    match shards.iter().find(|shard| shard.owns(&range)) {
+        Some(shard) => shard.free(range),
+        None => global(range),
+    }
+}
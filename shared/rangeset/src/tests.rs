@@ -1,5 +1,11 @@
+extern crate std;
+
 use super::*;
 
+use spinlock::DummyInterruptState;
+
+use shards::Shard;
+
 const DEFAULT_RS: RangeSet = RangeSet::new();
 
 #[test]
@@ -22,7 +28,7 @@ fn range_new_invalid() {
 fn range_contains() {
     let range1 = Range::new(5, 15).unwrap();
     let range2 = Range::new(7, 10).unwrap();
-    assert_eq!(range1.contains(&range2), true);
+    assert!(range1.contains(&range2));
 }
 
 #[test]
@@ -30,10 +36,10 @@ fn range_contains_edge_cases() {
     let range1 = Range::new(5, 15).unwrap();
 
     let range3 = Range::new(15, 15).unwrap();
-    assert_eq!(range1.contains(&range3), true);
+    assert!(range1.contains(&range3));
 
     let range4 = Range::new(16, 16).unwrap();
-    assert_eq!(range1.contains(&range4), false);
+    assert!(!range1.contains(&range4));
 }
 
 #[test]
@@ -110,6 +116,28 @@ fn rangeset_insert_touching() {
     assert_eq!(entries[0].end, 15);
 }
 
+#[test]
+fn rangeset_insert_touching_before() {
+    // A range ending right before an entry is merged into it
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(11, 15).unwrap()).unwrap();
+    rangeset.insert(Range::new(5, 10).unwrap()).unwrap();
+    assert_eq!(rangeset.entries(), &[Range::new(5, 15).unwrap()]);
+
+    // A range filling the gap between two entries joins all three
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(0, 4).unwrap()).unwrap();
+    rangeset.insert(Range::new(11, 15).unwrap()).unwrap();
+    rangeset.insert(Range::new(5, 10).unwrap()).unwrap();
+    assert_eq!(rangeset.entries(), &[Range::new(0, 15).unwrap()]);
+
+    // Ranges with a gap between them stay apart
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(12, 15).unwrap()).unwrap();
+    rangeset.insert(Range::new(5, 10).unwrap()).unwrap();
+    assert_eq!(rangeset.entries().len(), 2);
+}
+
 #[test]
 fn rangeset_remove() {
     let mut rangeset = DEFAULT_RS.clone();
@@ -144,7 +172,7 @@ fn rangeset_remove_noop() {
 
     let entries = rangeset.entries();
     assert_eq!(entries.len(), 1);
-    assert_eq!(rangeset.in_use, entries.len());
+    assert_eq!(rangeset.in_use as usize, entries.len());
     assert_eq!(entries[0].start, 5);
     assert_eq!(entries[0].end, 15);
 }
@@ -182,7 +210,7 @@ fn rangeset_split_entry() {
     rangeset.split_entry(0, Range::new(15, 20).unwrap()).unwrap();
 
     let entries = rangeset.entries();
-    assert_eq!(rangeset.in_use, entries.len());
+    assert_eq!(rangeset.in_use as usize, entries.len());
     assert_eq!(entries.len(), 2);
     assert_eq!(entries[0], Range { start: 10, end: 14 });
     assert_eq!(entries[1], Range { start: 21, end: 30 });
@@ -214,10 +242,500 @@ fn rangeset_len_edge_cases() {
     assert_eq!(rangeset.len(), Some(0));
 
     // Test with large range
-    rangeset.insert(Range::new(0, usize::MAX - 1).unwrap()).unwrap();
-    assert_eq!(rangeset.len(), Some(usize::MAX));
+    rangeset.insert(Range::new(0, u64::MAX - 1).unwrap()).unwrap();
+    assert_eq!(rangeset.len(), Some(u64::MAX));
 
     // Test with overlapping range
-    rangeset.insert(Range::new(usize::MAX / 2, usize::MAX - 1).unwrap()).unwrap();
-    assert_eq!(rangeset.len(), Some(usize::MAX));  // Should remain the same, as the range overlaps
+    rangeset.insert(Range::new(u64::MAX / 2, u64::MAX - 1).unwrap()).unwrap();
+    assert_eq!(rangeset.len(), Some(u64::MAX));  // Should remain the same, as the range overlaps
+}
+
+#[test]
+fn rangeset_reserve() {
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(0x1000, 0x4fff).unwrap()).unwrap();
+
+    // Reserve a region from the middle of the free range
+    rangeset.reserve(Range::new(0x2000, 0x2fff).unwrap()).unwrap();
+    assert_eq!(rangeset.entries(), &[
+        Range { start: 0x1000, end: 0x1fff },
+        Range { start: 0x3000, end: 0x4fff },
+    ]);
+
+    // Reserving the same region again must fail
+    let range = Range::new(0x2000, 0x2fff).unwrap();
+    assert_eq!(rangeset.reserve(range), Err(Error::AlreadyReserved(range)));
+}
+
+#[test]
+fn rangeset_reserve_partial_overlap() {
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(0x1000, 0x1fff).unwrap()).unwrap();
+    rangeset.insert(Range::new(0x3000, 0x3fff).unwrap()).unwrap();
+    let entries = rangeset.entries().to_vec();
+
+    // Overlapping the start, the end, and spanning a reserved hole between
+    // two free ranges must all be rejected without altering the set
+    for (start, end) in [
+        (0x0800, 0x17ff),
+        (0x1800, 0x27ff),
+        (0x1000, 0x3fff),
+        (0x5000, 0x5fff),
+    ] {
+        let range = Range::new(start, end).unwrap();
+        assert_eq!(rangeset.reserve(range), Err(Error::AlreadyReserved(range)));
+        assert_eq!(rangeset.entries(), &entries[..]);
+    }
+
+    // Reserving whole free ranges exactly succeeds
+    rangeset.reserve(Range::new(0x1000, 0x1fff).unwrap()).unwrap();
+    rangeset.reserve(Range::new(0x3000, 0x3fff).unwrap()).unwrap();
+    assert!(rangeset.is_empty());
+}
+
+#[test]
+fn rangeset_allocate_free_stress() {
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(0x1000, 0x100_0fff).unwrap()).unwrap();
+    let start_len = rangeset.len();
+
+    // Outstanding allocations as `(address, size)`
+    let mut allocs = [(0u64, 0u64); 32];
+
+    // Simple LCG, so the allocation pattern is deterministic
+    let mut seed = 0x1337u64;
+    let mut rand = || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        seed >> 33
+    };
+
+    for _ in 0..10_000 {
+        // Pick a slot, freeing whatever is allocated in it
+        let slot = &mut allocs[rand() as usize % 32];
+        if slot.1 != 0 {
+            rangeset.insert(Range::new(slot.0, slot.0 + slot.1 - 1).unwrap())
+                .unwrap();
+            *slot = (0, 0);
+        }
+
+        // Allocate a new region of 1 to 16 pages into the slot
+        let size = (rand() % 16 + 1) * 0x1000;
+        let addr = rangeset.allocate(size, 0x1000).unwrap().unwrap();
+        *slot = (addr, size);
+    }
+
+    // Free everything that's still allocated
+    for &(addr, size) in allocs.iter().filter(|x| x.1 != 0) {
+        rangeset.insert(Range::new(addr, addr + size - 1).unwrap()).unwrap();
+    }
+
+    assert_eq!(rangeset.len(), start_len);
+    assert_eq!(rangeset.entries(), &[Range { start: 0x1000, end: 0x100_0fff }]);
+}
+
+#[test]
+fn rangeset_allocate_region_roundtrip() {
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(0x1234, 0x5fff).unwrap()).unwrap();
+    let entries = rangeset.entries().to_vec();
+
+    // The region includes the padding required by the alignment
+    let region = rangeset.allocate_region(0x100, 0x1000).unwrap().unwrap();
+    assert_eq!(region, Range { start: 0x1234, end: 0x20ff });
+
+    let region2 = rangeset.allocate_region(0x10, 0x10).unwrap().unwrap();
+    assert_eq!(region2, Range { start: 0x2100, end: 0x210f });
+
+    // Inserting the regions back restores the set
+    rangeset.insert(region).unwrap();
+    rangeset.insert(region2).unwrap();
+    assert_eq!(rangeset.entries(), &entries[..]);
+}
+
+#[test]
+fn rangeset_merge_from() {
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(0x1000, 0x1fff).unwrap()).unwrap();
+    rangeset.insert(Range::new(0x3000, 0x3fff).unwrap()).unwrap();
+
+    let mut other = DEFAULT_RS.clone();
+    other.insert(Range::new(0x2000, 0x2fff).unwrap()).unwrap();
+    other.insert(Range::new(0x4000, 0x4fff).unwrap()).unwrap();
+
+    // The adjacent ranges of both sets coalesce into one
+    rangeset.merge_from(&other).unwrap();
+    assert_eq!(rangeset.entries(), &[Range { start: 0x1000, end: 0x4fff }]);
+    assert_eq!(other.entries().len(), 2);
+}
+
+#[test]
+fn rangeset_insert_remove_fuzz() {
+    /// Number of values tracked by the model of the set
+    const UNIVERSE: usize = 1024;
+
+    let mut rangeset = DEFAULT_RS.clone();
+
+    // Model of the set, tracking whether each value is present
+    let mut model = [false; UNIVERSE];
+
+    // Simple LCG, so the operations are deterministic
+    let mut seed = 0xC0FFEEu64;
+    let mut rand = || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        seed >> 33
+    };
+
+    for _ in 0..20_000 {
+        // Pick a random range of up to 64 values
+        let start = rand() % UNIVERSE as u64;
+        let end = (start + rand() % 64).min(UNIVERSE as u64 - 1);
+        let range = Range::new(start, end).unwrap();
+
+        // Insert or remove it from both the set and the model
+        let insert = rand() % 2 == 0;
+        if insert {
+            rangeset.insert(range).unwrap();
+        } else {
+            rangeset.remove(range).unwrap();
+        }
+        model[start as usize..=end as usize].fill(insert);
+
+        // The entries have to stay sorted and separated...
+        rangeset.check_invariant();
+
+        // ...and describe exactly the values in the model
+        let mut expected = [false; UNIVERSE];
+        for entry in rangeset.entries() {
+            expected[entry.start as usize..=entry.end as usize].fill(true);
+        }
+        assert_eq!(expected, model);
+    }
+}
+
+#[test]
+fn rangeset_intersect() {
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(0x1000, 0x1fff).unwrap()).unwrap();
+    rangeset.insert(Range::new(0x3000, 0x3fff).unwrap()).unwrap();
+    rangeset.insert(Range::new(0x8000, 0x8fff).unwrap()).unwrap();
+
+    let mut other = DEFAULT_RS.clone();
+    other.insert(Range::new(0x1800, 0x37ff).unwrap()).unwrap();
+    other.insert(Range::new(0x5000, 0x5fff).unwrap()).unwrap();
+
+    // Only the overlaps remain, ranges present in only one set are dropped
+    let intersection = rangeset.intersect(&other).unwrap();
+    assert_eq!(intersection.entries(), &[
+        Range { start: 0x1800, end: 0x1fff },
+        Range { start: 0x3000, end: 0x37ff },
+    ]);
+    assert_eq!(other.intersect(&rangeset).unwrap().entries(),
+        intersection.entries());
+
+    // Intersecting with an empty set leaves nothing
+    assert!(rangeset.intersect(&DEFAULT_RS).unwrap().is_empty());
+}
+
+#[test]
+fn rangeset_alloc_policy() {
+    // A fragmented set with a large entry first and a tight one later
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(0x1000, 0x8fff).unwrap()).unwrap();
+    for start in (0x10000..0x20000).step_by(0x2000) {
+        rangeset.insert(Range::new(start, start + 0xfff).unwrap()).unwrap();
+    }
+    rangeset.insert(Range::new(0x30000, 0x30fff).unwrap()).unwrap();
+    let entries = rangeset.entries().to_vec();
+
+    // First-fit takes the first entry the allocation fits into
+    let mut first = rangeset.clone();
+    let addr = first.allocate_with(0x1000, 0x1000, None, AllocPolicy::FirstFit);
+    assert_eq!(addr, Ok(Some(0x1000)));
+
+    // Best-fit takes the tightest one, which is the first exact fit
+    let mut best = rangeset.clone();
+    let addr = best.allocate_with(0x1000, 0x1000, None, AllocPolicy::BestFit);
+    assert_eq!(addr, Ok(Some(0x10000)));
+
+    // First-fit still prefers the requested regions
+    let mut regions = DEFAULT_RS.clone();
+    regions.insert(Range::new(0x30000, 0x30fff).unwrap()).unwrap();
+    let addr = rangeset.allocate_with(0x1000, 0x1000, Some(&regions),
+        AllocPolicy::FirstFit);
+    assert_eq!(addr, Ok(Some(0x30000)));
+
+    // Both policies leave the rest of the set intact
+    rangeset.insert(Range::new(0x30000, 0x30fff).unwrap()).unwrap();
+    assert_eq!(rangeset.entries(), &entries[..]);
+}
+
+#[test]
+fn rangeset_insert_or_drop_smallest() {
+    // Touching ranges are merged, so they never overflow the set
+    let mut rangeset = DEFAULT_RS.clone();
+    for idx in 0..300u64 {
+        let range = Range::new(idx * 0x1000, idx * 0x1000 + 0xfff).unwrap();
+        assert_eq!(rangeset.insert_or_drop_smallest(range), Ok(None));
+    }
+    assert_eq!(rangeset.entries(), &[Range { start: 0, end: 0x12_bfff }]);
+
+    // Separate ranges overflow the set. Range `idx` is `idx + 1` pages large,
+    // so the smallest ones get dropped
+    let mut rangeset = DEFAULT_RS.clone();
+    let capacity = rangeset.ranges.len() as u64;
+    let mut dropped = 0;
+    for idx in 0..300u64 {
+        let start = idx * 0x100_0000;
+        let range = Range::new(start, start + (idx + 1) * 0x1000 - 1).unwrap();
+        if let Some(range) = rangeset.insert_or_drop_smallest(range).unwrap() {
+            assert_eq!(range.start, dropped * 0x100_0000);
+            dropped += 1;
+        }
+    }
+    assert_eq!(dropped, 300 - capacity);
+    assert_eq!(rangeset.entries().len() as u64, capacity);
+    assert_eq!(rangeset.entries()[0].start, (300 - capacity) * 0x100_0000);
+
+    // A range smaller than all of the others is dropped itself
+    let range = Range::new(0xffff_0000_0000, 0xffff_0000_0000).unwrap();
+    assert_eq!(rangeset.insert_or_drop_smallest(range), Ok(Some(range)));
+    assert_eq!(rangeset.entries().len() as u64, capacity);
+}
+
+#[test]
+fn rangeset_collect() {
+    // Overlapping and touching ranges are coalesced
+    let rangeset: RangeSet = [
+        Range::new(0x10, 0x1f).unwrap(),
+        Range::new(0x18, 0x2f).unwrap(),
+        Range::new(0x30, 0x3f).unwrap(),
+        Range::new(0x50, 0x5f).unwrap(),
+    ].into_iter().collect::<Result<_, _>>().unwrap();
+    assert_eq!(rangeset.entries(), &[
+        Range { start: 0x10, end: 0x3f },
+        Range { start: 0x50, end: 0x5f },
+    ]);
+
+    // Iterating a set yields its entries by value
+    let mut count = 0;
+    for (range, entry) in (&rangeset).into_iter().zip(rangeset.entries()) {
+        assert_eq!(range, *entry);
+        count += 1;
+    }
+    assert_eq!(count, rangeset.entries().len());
+
+    // Collecting the entries of a set gives the same set back
+    let copy: RangeSet = rangeset.iter().collect::<Result<_, _>>().unwrap();
+    assert_eq!(copy.entries(), rangeset.entries());
+
+    // Overflow is propagated
+    let overflow = (0..300u64)
+        .map(|x| Range::new(x * 0x2000, x * 0x2000 + 0xfff).unwrap())
+        .collect::<Result<RangeSet, _>>();
+    assert_eq!(overflow.unwrap_err(), Error::RangeSetOverflow);
+}
+
+#[test]
+fn rangeset_insert_remove_value() {
+    let mut by_value = RangeSet::new();
+    let mut by_range = RangeSet::new();
+    let single = |x| Range::new(x, x).unwrap();
+
+    // Inserting single values merges them the same way as ranges
+    for value in [5, 7, 6, 10] {
+        by_value.insert_value(value).unwrap();
+        by_range.insert(single(value)).unwrap();
+    }
+    assert_eq!(by_value.entries(), by_range.entries());
+    assert_eq!(by_value.entries(), &[
+        Range { start: 5,  end: 7  },
+        Range { start: 10, end: 10 },
+    ]);
+
+    // Removing single values splits and drops ranges the same way as ranges,
+    // including when the value isn't in the set
+    for value in [6, 10, 8] {
+        assert_eq!(by_value.remove_value(value),
+                   by_range.remove(single(value)));
+        assert_eq!(by_value.entries(), by_range.entries());
+    }
+    assert_eq!(by_value.entries(), &[
+        Range { start: 5, end: 5 },
+        Range { start: 7, end: 7 },
+    ]);
+    assert_eq!(by_value.remove_value(5), Ok(true));
+    assert_eq!(by_value.remove_value(5), Ok(false));
+}
+
+#[test]
+fn rangeset_largest_smallest() {
+    let mut rangeset = DEFAULT_RS.clone();
+    assert_eq!(rangeset.largest(), None);
+    assert_eq!(rangeset.smallest(), None);
+
+    // Two entries share the largest and the smallest sizes, the lowest one is
+    // returned
+    for (start, end) in [(0x0, 0xff), (0x1000, 0x4fff), (0x6000, 0x60ff),
+                         (0x8000, 0xbfff)] {
+        rangeset.insert(Range::new(start, end).unwrap()).unwrap();
+    }
+    assert_eq!(rangeset.largest(), Some(Range::new(0x1000, 0x4fff).unwrap()));
+    assert_eq!(rangeset.smallest(), Some(Range::new(0x0, 0xff).unwrap()));
+}
+
+#[test]
+fn rangeset_take_largest() {
+    // A tight entry first, the largest entry in the middle and a medium one
+    // at the end
+    let mut rangeset = DEFAULT_RS.clone();
+    rangeset.insert(Range::new(0x1000, 0x1fff).unwrap()).unwrap();
+    rangeset.insert(Range::new(0x10000, 0x1ffff).unwrap()).unwrap();
+    rangeset.insert(Range::new(0x30000, 0x33fff).unwrap()).unwrap();
+
+    // Best-fit places the allocation into the tight entry, worst-fit into the
+    // largest one
+    let mut best = rangeset.clone();
+    assert_eq!(best.allocate(0x1000, 0x1000), Ok(Some(0x1000)));
+    let mut worst = rangeset.clone();
+    assert_eq!(worst.take_largest(0x1000, 0x1000), Ok(Some(0x10000)));
+
+    // Worst-fit keeps going to the largest entry, until the medium one is
+    // the largest
+    for addr in (0x11000..0x1d000).step_by(0x1000) {
+        assert_eq!(worst.take_largest(0x1000, 0x1000), Ok(Some(addr)));
+    }
+    assert_eq!(worst.largest(), Some(Range::new(0x30000, 0x33fff).unwrap()));
+    assert_eq!(worst.take_largest(0x1000, 0x1000), Ok(Some(0x30000)));
+
+    // Entries the allocation doesn't fit into are never used, and the
+    // argument errors are the same as for `allocate`
+    assert_eq!(worst.take_largest(0x10000, 0x1000), Ok(None));
+    assert_eq!(worst.take_largest(0, 0x1000),
+               Err(Error::ZeroSizedAllocation));
+    assert_eq!(worst.take_largest(0x1000, 3), Err(Error::WrongAlignment(3)));
+}
+
+/// Free memory split into two NUMA domains of 64 KiB, only half of the first
+/// one being free, and 64 KiB of memory outside of both of them
+fn sharded_memory() -> (RangeSet, [Shard<DummyInterruptState>; 2]) {
+    let mut global = DEFAULT_RS.clone();
+    for (start, end) in [(0x0, 0x7fff), (0x10000, 0x1ffff),
+                         (0x100000, 0x10ffff)] {
+        global.insert(Range::new(start, end).unwrap()).unwrap();
+    }
+
+    let domain = |start, end| {
+        let mut domain = DEFAULT_RS.clone();
+        domain.insert(Range::new(start, end).unwrap()).unwrap();
+        domain
+    };
+    let shards = [
+        Shard::carve(domain(0x0, 0xffff), &mut global).unwrap(),
+        Shard::carve(domain(0x10000, 0x1ffff), &mut global).unwrap(),
+    ];
+    (global, shards)
+}
+
+#[test]
+fn shards_carve_free_memory() {
+    let (global, shards) = sharded_memory();
+
+    // Only the free memory of the domains was moved into the shards
+    assert_eq!(global.entries(), &[Range::new(0x100000, 0x10ffff).unwrap()]);
+    assert_eq!(shards[0].free_bytes_try(), Some(0x8000));
+    assert_eq!(shards[1].free_bytes_try(), Some(0x10000));
+
+    // The domains are kept whole
+    assert_eq!(shards[0].domain().len(), Some(0x10000));
+    assert!(shards[0].owns(&Range::new(0x8000, 0xffff).unwrap()));
+    assert!(!shards[0].owns(&Range::new(0xf000, 0x10fff).unwrap()));
+}
+
+#[test]
+fn shards_allocate_order() {
+    let (mut global, shards) = sharded_memory();
+    let mut allocate = |local| {
+        shards::allocate(&shards, local, 0x8000, 0x1000, AllocPolicy::BestFit,
+                         || global.allocate(0x8000, 0x1000).ok()?)
+    };
+
+    // The local shard is used first, followed by the global free memory and
+    // the other shard
+    assert_eq!(allocate(Some(0)), Some(0x0));
+    assert_eq!(allocate(Some(0)), Some(0x100000));
+    assert_eq!(allocate(Some(0)), Some(0x108000));
+    assert_eq!(allocate(Some(0)), Some(0x10000));
+
+    // Without a valid local shard, the global free memory is tried first
+    assert_eq!(allocate(None), Some(0x18000));
+    assert_eq!(allocate(Some(2)), None);
+}
+
+#[test]
+fn shards_free_to_owner() {
+    let (mut global, shards) = sharded_memory();
+    let mut free = |start, end| {
+        shards::free(&shards, Range::new(start, end).unwrap(),
+                     |range| global.insert(range))
+    };
+
+    // Memory of a domain goes back to its shard, even if it wasn't free when
+    // the shard was carved out
+    free(0x8000, 0x8fff).unwrap();
+    free(0x200000, 0x200fff).unwrap();
+
+    // Ranges straddling the domains don't belong to either of them
+    free(0x1f000, 0x20fff).unwrap();
+
+    assert_eq!(shards[0].free_bytes_try(), Some(0x9000));
+    assert_eq!(shards[1].free_bytes_try(), Some(0x10000));
+    assert_eq!(global.len(), Some(0x10000 + 0x1000 + 0x2000));
+}
+
+/// Allocate and free 4 KiB pages from `threads` threads at once, each of them
+/// using the shard `shard_of(thread)`. Returns the time it took
+fn alloc_storm(threads: usize, shard_of: fn(usize) -> usize)
+        -> std::time::Duration {
+    /// Number of pages allocated and freed by each thread
+    const ROUNDS: usize = 100_000;
+
+    // One shard of 16 MiB per thread
+    let mut global = DEFAULT_RS.clone();
+    let shards = (0..threads).map(|ii| {
+        let start = ii as u64 * 0x100_0000;
+        let range = Range::new(start, start + 0xff_ffff).unwrap();
+        global.insert(range).unwrap();
+        let mut domain = DEFAULT_RS.clone();
+        domain.insert(range).unwrap();
+        Shard::<DummyInterruptState>::carve(domain, &mut global).unwrap()
+    }).collect::<std::vec::Vec<_>>();
+
+    let start = std::time::Instant::now();
+    std::thread::scope(|scope| {
+        for thread in 0..threads {
+            let shards = &shards;
+            scope.spawn(move || {
+                for _ in 0..ROUNDS {
+                    let addr = shards::allocate(shards, Some(shard_of(thread)),
+                        0x1000, 0x1000, AllocPolicy::FirstFit, || None)
+                        .unwrap();
+                    let page = Range::new(addr, addr + 0xfff).unwrap();
+                    shards::free(shards, page, |_| unreachable!()).unwrap();
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+/// Compares all cores allocating under a single lock with each of them having
+/// its own shard. Run with `cargo test --release -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn shards_alloc_storm() {
+    let threads = std::thread::available_parallelism().map_or(4, |x| x.get());
+    let single = alloc_storm(threads, |_| 0);
+    let sharded = alloc_storm(threads, |thread| thread);
+    std::println!("{threads} threads: {single:?} on a single shard, \
+        {sharded:?} with a shard per thread");
 }
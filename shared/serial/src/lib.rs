@@ -7,6 +7,27 @@ use cpu;
 /// Addresses of the legacy serial ports that are to be used by this driver
 pub const PORT_ADDRESSES: [u16; 4] = [0x2F8, 0x3F8, 0x2E8, 0x3E8];
 
+/// The baud rate `SerialDriver::init()` sets the ports to
+pub const DEFAULT_BAUD: u32 = 28800;
+
+/// The base clock of the UART, which is divided by the baud divisor
+const UART_CLOCK: u32 = 115200;
+
+/// The number of times the loopback test is attempted before a port is
+/// considered absent. Slow virtual UARTs might not echo the byte back on the
+/// first try
+const LOOPBACK_ATTEMPTS: usize = 8;
+
+/// Errors returned by the serial driver
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerialError {
+    /// The baud rate can't be represented by a divisor of the UART clock
+    InvalidBaud(u32),
+
+    /// None of the ports in `PORT_ADDRESSES` passed the loopback test
+    NoPortsDetected,
+}
+
 /// The serial driver implementation for COM ports defined by `PORT_ADDRESSES`
 #[derive(Clone, Debug)]
 pub struct SerialDriver {
@@ -15,9 +36,42 @@ pub struct SerialDriver {
 }
 
 impl SerialDriver {
-    /// Initialize the serial ports on the system to 28800n1. This should only
-    /// ever be called once, therefore it is marked as unsafe.
+    /// Initialize the serial ports on the system to 28800n1.
+    ///
+    /// The driver is returned even if no port was detected, in which case
+    /// all writes are discarded. Use `try_init()` to detect that case.
+    ///
+    /// # Safety
+    ///
+    /// This reprograms the serial ports, so it should only ever be called
+    /// once, and nothing else may be using the ports
     pub unsafe fn init() -> Self {
+        unsafe { Self::init_divisor((UART_CLOCK / DEFAULT_BAUD) as u16) }
+    }
+
+    /// Initialize the serial ports on the system to `baud`n1, returning an
+    /// error if the baud rate is invalid or no port was detected.
+    ///
+    /// # Safety
+    ///
+    /// Same as `init()`
+    pub unsafe fn try_init(baud: u32) -> Result<Self, SerialError> {
+        // The divisor has to be a whole, non-zero 16-bit number
+        let divisor = UART_CLOCK.checked_div(baud)
+            .filter(|&div| div != 0 && div * baud == UART_CLOCK)
+            .and_then(|div| u16::try_from(div).ok())
+            .ok_or(SerialError::InvalidBaud(baud))?;
+
+        let driver = unsafe { Self::init_divisor(divisor) };
+        if driver.ports.iter().all(Option::is_none) {
+            return Err(SerialError::NoPortsDetected);
+        }
+        Ok(driver)
+    }
+
+    /// Initialize the serial ports on the system with the baud `divisor` of
+    /// the UART clock
+    unsafe fn init_divisor(divisor: u16) -> Self {
         // Create a new serial port driver
         let mut driver = Self {
             ports: [None; PORT_ADDRESSES.len()],
@@ -34,8 +88,8 @@ impl SerialDriver {
 
                 // Divisor = 115200 / divisor;
                 // low byte and high byte of the divisor, respectively
-                cpu::out8(port, 0x04);
-                cpu::out8(port + 1, 0x00);
+                cpu::out8(port, divisor as u8);
+                cpu::out8(port + 1, (divisor >> 8) as u8);
 
                 // 8 bits, no parity, one stop bit
                 cpu::out8(port + 3, 0x03);
@@ -48,11 +102,14 @@ impl SerialDriver {
                 // Set it to loopback mode
                 cpu::out8(port + 4, 0x1E);
 
-                // Send a byte
-                cpu::out8(port, 0xAE);
+                // Send a byte and check if it's returned back, retrying a
+                // few times for slow UARTs
+                let passed = (0..LOOPBACK_ATTEMPTS).any(|_| {
+                    cpu::out8(port, 0xAE);
+                    cpu::in8(port) == 0xAE
+                });
 
-                // Check if the byte is returned back
-                if cpu::in8(port) == 0xAE {
+                if passed {
                     // It is -- set the port back to normal mode
                     cpu::out8(port + 4, 0x0F);
 
@@ -63,7 +120,7 @@ impl SerialDriver {
         }
 
         // Drain all the ports of inbound bytes
-        while let Some(_) = driver.read_byte() {}
+        while driver.read_byte().is_some() {}
         driver
     }
 
@@ -11,7 +11,7 @@ pub use constants::*;
 pub use trampoline::*;
 
 use core::sync::atomic::{AtomicU64, AtomicBool, Ordering};
-use spinlock::{SpinLock, InterruptState};
+use spinlock::{SpinLock, SpinLockGuard, InterruptState};
 use oncelock::OnceLock;
 use serial::SerialDriver;
 use rangeset::RangeSet;
@@ -49,8 +49,45 @@ pub struct SdtTable {
     pub base: PhysAddr,
 }
 
+/// Magic value at the start of `Shared`
+pub const SHARED_MAGIC: u64 = u64::from_le_bytes(*b"eliseSHR");
+
+/// Version of the layout of `Shared`. This has to be bumped whenever the
+/// layout of `Shared` or of any of the types it holds changes
+pub const SHARED_ABI_VERSION: u64 = 1;
+
+/// Check whether the `Shared` at `shared` was created by a bootloader built
+/// with the same `SHARED_ABI_VERSION` as the caller.
+///
+/// Only the header of the struct is read, so this can be called before the
+/// rest of it is trusted
+///
+/// # Safety
+///
+/// `shared` must point to at least two readable and aligned `u64`s
+pub unsafe fn shared_abi_matches(shared: *const u8) -> bool {
+    let header = shared as *const u64;
+    unsafe {
+        header.read() == SHARED_MAGIC &&
+            header.add(1).read() == SHARED_ABI_VERSION
+    }
+}
+
 /// Data structure shared between the kernel and the bootloader
+///
+/// The bootloader and the kernel are built separately, and the kernel uses
+/// the bootloader's `Shared` in place. Both of them must therefore be built
+/// with the same layout of this struct, which is why it starts with
+/// `SHARED_MAGIC` and `SHARED_ABI_VERSION` at fixed offsets. The kernel checks
+/// them with `shared_abi_matches()` before touching anything else
+#[repr(C)]
 pub struct Shared<I: InterruptState> {
+    /// Always `SHARED_MAGIC`. This must stay the first field
+    magic: u64,
+
+    /// Always `SHARED_ABI_VERSION`. This must stay the second field
+    abi_version: u64,
+
     /// Whether the kernel is rebooting completely
     pub rebooting: AtomicBool,
 
@@ -94,12 +131,20 @@ pub struct Shared<I: InterruptState> {
     /// A snapshot of the bootloader after the bootloader has been initialized
     /// to its permanent state.
     bootloader: OnceLock<BootloaderState>,
+
+    /// Barrier used to park cores during a soft reboot.
+    ///
+    /// The low 32 bits are the number of cores which have arrived at the
+    /// barrier and the high 32 bits are the generation of the barrier
+    ap_barrier: AtomicU64,
 }
 
 impl<I: InterruptState> Shared<I> {
     /// Creates an empty structure for shared data
     pub const fn new() -> Self {
         Self {
+            magic:        SHARED_MAGIC,
+            abi_version:  SHARED_ABI_VERSION,
             rebooting:    AtomicBool::new(true),
             serial:       SpinLock::new_no_preempt(None),
             print_lock:   SpinLock::new_no_preempt(()),
@@ -109,6 +154,26 @@ impl<I: InterruptState> Shared<I> {
             next_stack:   AtomicU64::new(KERNEL_STACK_BASE),
             acpi_sdt:     OnceLock::new(),
             bootloader:   OnceLock::new(),
+            ap_barrier:   AtomicU64::new(0),
+        }
+    }
+
+    /// Write `bytes` to the serial ports under the print lock, such that they
+    /// aren't interleaved with other messages.
+    ///
+    /// Does nothing if the serial driver hasn't been initialized yet.
+    pub fn serial_write(&self, bytes: &[u8]) {
+        let _lock = self.print_lock.lock();
+        self.serial_write_raw(bytes);
+    }
+
+    /// Write `bytes` to the serial ports without taking the print lock. This
+    /// is meant for callers which already hold the print lock.
+    ///
+    /// Does nothing if the serial driver hasn't been initialized yet.
+    pub fn serial_write_raw(&self, bytes: &[u8]) {
+        if let Some(serial) = self.serial.lock().as_mut() {
+            serial.write(bytes);
         }
     }
 
@@ -117,6 +182,16 @@ impl<I: InterruptState> Shared<I> {
         &self.free_memory
     }
 
+    /// Attempt to lock the free memory without waiting for it, returning
+    /// `None` if it's held by someone else.
+    ///
+    /// NMI and panic handlers must use this rather than `free_memory().lock()`,
+    /// as the core they interrupted might be the one holding the lock
+    pub fn free_memory_try(&self)
+            -> Option<SpinLockGuard<'_, Option<RangeSet>, I>> {
+        self.free_memory.try_lock()
+    }
+
     /// Returns a reference to the kernel image pointer lock
     pub fn kernel_image(&self) -> &SpinLock<Option<Elf<'static>>, I> {
         &self.kernel_image
@@ -127,6 +202,16 @@ impl<I: InterruptState> Shared<I> {
         &self.kernel_pt
     }
 
+    /// Attempt to lock the kernel page table without waiting for it, returning
+    /// `None` if it's held by someone else.
+    ///
+    /// NMI and panic handlers must use this rather than `kernel_pt().lock()`,
+    /// as the core they interrupted might be the one holding the lock
+    pub fn kernel_pt_try(&self)
+            -> Option<SpinLockGuard<'_, Option<PageTable>, I>> {
+        self.kernel_pt.try_lock()
+    }
+
     /// Returns a reference to the bootloader snapshot
     pub fn bootloader(&self) -> &OnceLock<BootloaderState> {
         &self.bootloader
@@ -149,6 +234,41 @@ impl<I: InterruptState> Shared<I> {
         &self.acpi_sdt
     }
 
+    /// Arrive at the core barrier and wait until `total_cores` cores have
+    /// arrived at it or until the cores are released by `release_aps()`
+    pub fn rendezvous(&self, total_cores: u64) {
+        // Check in and get the generation of the barrier we've arrived at
+        let generation = self.ap_barrier.fetch_add(1, Ordering::SeqCst) >> 32;
+
+        // Wait for everyone else
+        loop {
+            let barrier = self.ap_barrier.load(Ordering::SeqCst);
+            if barrier >> 32 != generation ||
+                    (barrier & 0xFFFF_FFFF) >= total_cores {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Release all cores waiting at the core barrier and reset it, so it can
+    /// be used again
+    pub fn release_aps(&self) {
+        // Bump the generation and clear the number of arrived cores
+        self.ap_barrier.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+            Some(((x >> 32).wrapping_add(1) & 0xFFFF_FFFF) << 32)
+        }).unwrap();
+    }
+
+    /// Reset the core barrier to its initial state, dropping any cores which
+    /// have arrived at it after it has been released.
+    ///
+    /// This must only be called once no core can be waiting at the barrier,
+    /// such as after all APs have been INITed
+    pub fn reset_barrier(&self) {
+        self.ap_barrier.store(0, Ordering::SeqCst);
+    }
+
     /// Check whether the kernel wants a full reboot; for the bootloader, this
     /// means that the kernel image has to be reloaded
     pub fn is_rebooting(&self) -> bool {
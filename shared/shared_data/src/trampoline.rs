@@ -1,17 +1,43 @@
-use page_table::{VirtAddr, PageTable, PhysAddr};
+use page_table::{VirtAddr, PhysAddr};
+
+use crate::BootloaderState;
 
 /// The trampoline function. This has to be identical to the function specified
-/// in trampoline.asm
+/// in trampoline.asm. `table` is the address of the top-level page table which
+/// is loaded into CR3
 pub type Trampoline = unsafe extern "sysv64" fn(
     entry: VirtAddr,
     stack: VirtAddr,
-    table: PageTable,
+    table: PhysAddr,
     shared: PhysAddr,
 ) -> !;
 
 /// Returns a pointer to the trampoline.
 ///
+/// # Safety
+///
 /// The trampoline must be mapped in the current page table at `TRAMPOLINE_ADDR`
 pub unsafe fn get_trampoline() -> Trampoline {
     unsafe { core::mem::transmute(crate::TRAMPOLINE_ADDR) }
 }
+
+/// Jump back to the bootloader described by the `state` snapshot, switching to
+/// its page table and stack.
+///
+/// # Safety
+///
+/// This must be called with interrupts disabled, as no interrupt handlers
+/// remain valid once the page table is switched. The trampoline must be mapped
+/// in the current page table at `TRAMPOLINE_ADDR` and the physical memory
+/// described by `state` must still be mapped in and untouched, as it's used as
+/// is by the bootloader.
+pub unsafe fn return_to_bootloader(state: &BootloaderState) -> ! {
+    // The bootloader keeps its own pointer to the shared struct, we don't need
+    // to pass it
+    let shared = PhysAddr(0);
+
+    unsafe {
+        let tramp = get_trampoline();
+        tramp(state.entry, state.stack, state.page_table.addr(), shared)
+    }
+}
@@ -1,12 +1,55 @@
 //! A mutex-like spinlock implementation
+//!
+//! With the `hold_time` feature, every guard records the TSC when the lock is
+//! acquired and the location which acquired it. When a guard is dropped after
+//! holding the lock for longer than the threshold, the hold is reported
+//! through `InterruptState::long_hold()`. The threshold defaults to
+//! `DEFAULT_HOLD_THRESHOLD` cycles and can be changed at runtime with
+//! `set_hold_threshold()`. Without the feature, none of this is compiled in.
 
 #![no_std]
 
+#[cfg(test)]
+mod tests;
+
 use core::marker::PhantomData;
 use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "hold_time")]
+use core::sync::atomic::{AtomicBool, AtomicU64};
+#[cfg(feature = "hold_time")]
+use core::panic::Location;
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 
+/// Default number of TSC cycles a lock can be held for before the hold is
+/// reported. About 30 ms on a 3 GHz TSC
+#[cfg(feature = "hold_time")]
+pub const DEFAULT_HOLD_THRESHOLD: u64 = 100_000_000;
+
+/// Number of TSC cycles a lock can be held for before the hold is reported
+#[cfg(feature = "hold_time")]
+static HOLD_THRESHOLD: AtomicU64 = AtomicU64::new(DEFAULT_HOLD_THRESHOLD);
+
+/// Set while a long hold is being reported, so the locks taken by the report
+/// itself don't get reported in turn
+#[cfg(feature = "hold_time")]
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Set the number of TSC cycles any lock can be held for before the hold is
+/// reported. `u64::MAX` disables the reports
+#[cfg(feature = "hold_time")]
+pub fn set_hold_threshold(cycles: u64) {
+    HOLD_THRESHOLD.store(cycles, Ordering::Relaxed);
+}
+
+/// Maximum number of pauses between two checks of a contended lock in
+/// `SpinLock::lock()`
+const MAX_BACKOFF: usize = 64;
+
+/// Maximum number of pauses between two checks of a contended lock in
+/// `SpinLock::lock()` for every waiter queued before us
+const BACKOFF_PER_WAITER: usize = 8;
+
 /// A dummy structure which can be used to implement an interrupt ignoring
 /// `SpinLock`
 pub struct DummyInterruptState;
@@ -38,6 +81,76 @@ pub trait InterruptState {
     /// A lock which does not allow interrupting was released, and thus
     /// interrupts can be enabled.
     fn exit_lock();
+
+    /// A lock acquired at `location` was held for `cycles` TSC cycles, which
+    /// is longer than the hold threshold. The lock has already been released.
+    ///
+    /// Locks taken here are not reported. Ignores the hold by default
+    #[cfg(feature = "hold_time")]
+    fn long_hold(location: &'static Location<'static>, cycles: u64) {
+        let _ = (location, cycles);
+    }
+}
+
+/// Counter of the nested requests to have interrupts disabled.
+///
+/// Interrupts have to stay disabled until as many requests have been released
+/// as have been made
+pub struct InterruptDisables(AtomicUsize);
+
+impl InterruptDisables {
+    /// Create a new counter without any outstanding requests
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    /// Record a request to have interrupts disabled
+    #[track_caller]
+    pub fn disable(&self) {
+        let x = self.0.fetch_add(1, Ordering::SeqCst);
+        x.checked_add(1).expect("Overflow on disable interrupts outstanding");
+    }
+
+    /// Release a request to have interrupts disabled. Returns `true` if it was
+    /// the last outstanding one, and the interrupts can be enabled again
+    #[track_caller]
+    pub fn enable(&self) -> bool {
+        let x = self.0.fetch_sub(1, Ordering::SeqCst);
+        x.checked_sub(1).expect("Overflow on enable interrupts outstanding");
+        x == 1
+    }
+
+    /// Returns the number of outstanding requests
+    pub fn outstanding(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for InterruptDisables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Guard which keeps the interrupts disabled through `I` while it's alive.
+///
+/// The guards nest the same way as `InterruptState::enter_lock()` and
+/// `InterruptState::exit_lock()` do
+pub struct InterruptGuard<I: InterruptState>(PhantomData<I>);
+
+impl<I: InterruptState> InterruptGuard<I> {
+    /// Disable interrupts until the returned guard is dropped
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        I::enter_lock();
+        Self(PhantomData)
+    }
+}
+
+impl<I: InterruptState> Drop for InterruptGuard<I> {
+    fn drop(&mut self) {
+        I::exit_lock();
+    }
 }
 
 /// A spinlock-guarded inner-mutable variable
@@ -108,16 +221,105 @@ impl<T: ?Sized, I: InterruptState> SpinLock<T, I> {
         // Get a ticket
         let ticket = self.ticket.fetch_add(1, Ordering::SeqCst);
 
-        // Spin until we're free to use the value
-        while ticket != self.release.load(Ordering::SeqCst) {
-            core::hint::spin_loop();
+        // Spin until we're free to use the value. Every failed check doubles
+        // the number of pauses before the next one, so contended waiters don't
+        // keep hammering the cache line of `release`. The pauses are capped by
+        // our distance from the front of the queue: waiters far back can't
+        // get the lock soon anyway, but the next one in line must notice the
+        // release quickly, as the lock sits idle until it does. The order in
+        // which the tickets are served is unaffected
+        let mut backoff = 1;
+        loop {
+            let release = self.release.load(Ordering::SeqCst);
+            let distance = ticket.wrapping_sub(release);
+            if distance == 0 { break; }
+
+            for _ in 0..backoff {
+                core::hint::spin_loop();
+            }
+            backoff = (backoff * 2)
+                .min(distance.saturating_mul(BACKOFF_PER_WAITER))
+                .min(MAX_BACKOFF);
         }
 
-        SpinLockGuard::<T, I> {
-            lock: self,
+        SpinLockGuard::new(self)
+    }
+
+    /// Run `f` with exclusive access to the variable guarded by this spinlock.
+    ///
+    /// The lock is released as soon as `f` returns, so the critical section
+    /// can't outlive the closure and no guard has to be dropped by hand
+    #[track_caller]
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut guard = self.lock();
+        f(&mut guard)
+    }
+
+    /// Attempt to acquire exclusive access to the variable guarded by this
+    /// spinlock without spinning, returning `None` if it's held or contended.
+    ///
+    /// As this never waits for the lock, it can't deadlock against the core
+    /// it's running on. It's therefore allowed in any context, including NMI
+    /// and panic handlers, which must not use `lock()`.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T, I>> {
+        // Disable interrupts if needed
+        if self.disable_interrupts {
+            I::enter_lock();
+        }
+
+        if self.try_take_ticket() {
+            Some(SpinLockGuard::new(self))
+        } else {
+            if self.disable_interrupts { I::exit_lock(); }
+            None
+        }
+    }
+
+    /// Attempt to acquire exclusive access to the variable guarded by this
+    /// spinlock, giving up once the TSC passes `tsc_deadline`.
+    ///
+    /// Abandoning an already taken ticket would leave the lock stuck, as its
+    /// release would never come. Instead, a ticket is only taken with a CAS
+    /// when it's the one being served, i.e. when the lock is free and no one
+    /// is queued for it. This is not fair: a core waiting in `lock_timeout()`
+    /// can be starved by cores queued with `lock()`, so this is meant for
+    /// paths which have to survive a dead lock holder, not for hot paths.
+    #[track_caller]
+    pub fn lock_timeout(&self, tsc_deadline: u64)
+            -> Option<SpinLockGuard<'_, T, I>> {
+        // Make sure we don't use a non-preemptable lock during an interrupt.
+        assert!(self.disable_interrupts || !I::in_interrupt(),
+            "Attempted to take a non-preemptable lock in an interrupt");
+
+        // Disable interrupts if needed
+        if self.disable_interrupts {
+            I::enter_lock();
+        }
+
+        loop {
+            if self.try_take_ticket() {
+                return Some(SpinLockGuard::new(self));
+            }
+
+            // Give up once the deadline has passed
+            if unsafe { core::arch::x86_64::_rdtsc() } >= tsc_deadline {
+                if self.disable_interrupts { I::exit_lock(); }
+                return None;
+            }
+
+            core::hint::spin_loop();
         }
     }
 
+    /// Take a ticket only if it's the one being served, i.e. when the lock is
+    /// free and no one is queued for it. Returns whether the ticket was taken
+    fn try_take_ticket(&self) -> bool {
+        let release = self.release.load(Ordering::SeqCst);
+        self.ticket.compare_exchange(release, release.wrapping_add(1),
+            Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
     /// Return a raw pointer to the internal locked value, bypassing the lock
     pub unsafe fn shatter(&self) -> *mut T {
         self.value.get()
@@ -127,13 +329,48 @@ impl<T: ?Sized, I: InterruptState> SpinLock<T, I> {
 /// A guard which implements `Drop` so the locks can be released based on scope
 pub struct SpinLockGuard<'a, T: ?Sized, I: InterruptState> {
     lock: &'a SpinLock<T, I>,
+
+    /// Location which acquired the lock
+    #[cfg(feature = "hold_time")]
+    location: &'static Location<'static>,
+
+    /// TSC when the lock was acquired
+    #[cfg(feature = "hold_time")]
+    acquired: u64,
+}
+
+impl<'a, T: ?Sized, I: InterruptState> SpinLockGuard<'a, T, I> {
+    /// Create a guard of the just acquired `lock`
+    #[track_caller]
+    fn new(lock: &'a SpinLock<T, I>) -> Self {
+        Self {
+            lock,
+            #[cfg(feature = "hold_time")]
+            location: Location::caller(),
+            #[cfg(feature = "hold_time")]
+            acquired: unsafe { core::arch::x86_64::_rdtsc() },
+        }
+    }
 }
 
 impl<'a, T: ?Sized, I: InterruptState> Drop for SpinLockGuard<'a, T, I> {
     fn drop(&mut self) {
+        // Get the hold time before releasing the lock
+        #[cfg(feature = "hold_time")]
+        let held = unsafe { core::arch::x86_64::_rdtsc() }
+            .saturating_sub(self.acquired);
+
         // Release the lock
         self.lock.release.fetch_add(1, Ordering::SeqCst);
 
+        // Report the hold if it was too long, unless it was taken by a report
+        #[cfg(feature = "hold_time")]
+        if held > HOLD_THRESHOLD.load(Ordering::Relaxed) &&
+                !REPORTING.swap(true, Ordering::Acquire) {
+            I::long_hold(self.location, held);
+            REPORTING.store(false, Ordering::Release);
+        }
+
         // Enable interrupts if needed
         if self.lock.disable_interrupts { I::exit_lock(); }
     }
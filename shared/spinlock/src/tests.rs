@@ -0,0 +1,160 @@
+extern crate std;
+
+use super::*;
+
+use core::sync::atomic::{AtomicBool, AtomicIsize};
+
+type Lock<T> = SpinLock<T, DummyInterruptState>;
+
+/// Number of locks held by `CountingInterruptState` locks which haven't been
+/// released yet
+static HELD: AtomicIsize = AtomicIsize::new(0);
+
+/// An interrupt state which counts the non-preemptable locks being held
+struct CountingInterruptState;
+
+impl InterruptState for CountingInterruptState {
+    fn in_interrupt() -> bool { false }
+    fn in_exception() -> bool { false }
+    fn enter_lock() { HELD.fetch_add(1, Ordering::SeqCst); }
+    fn exit_lock() { HELD.fetch_sub(1, Ordering::SeqCst); }
+}
+
+#[test]
+fn with_releases_on_return() {
+    let lock = Lock::new(5);
+    assert_eq!(lock.with(|val| { *val += 1; *val }), 6);
+    assert!(lock.try_lock().is_some());
+    assert_eq!(*lock.lock(), 6);
+}
+
+#[test]
+fn with_releases_on_early_return() {
+    let lock = Lock::new(Some(5));
+    let find = |lock: &Lock<Option<u32>>| lock.with(|val| {
+        let val = (*val)?;
+        Some(val * 2)
+    });
+
+    assert_eq!(find(&lock), Some(10));
+    lock.with(|val| *val = None);
+    assert_eq!(find(&lock), None);
+    assert!(lock.try_lock().is_some());
+}
+
+#[test]
+fn with_releases_on_panic() {
+    let lock = Lock::new(0);
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        lock.with(|val| {
+            *val = 1;
+            panic!("Panicking with the lock held");
+        })
+    }));
+    assert!(res.is_err());
+    assert!(lock.try_lock().is_some());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test]
+fn with_exits_lock() {
+    let lock = SpinLock::<u32, CountingInterruptState>::new_no_preempt(0);
+    lock.with(|_| assert_eq!(HELD.load(Ordering::SeqCst), 1));
+    assert_eq!(HELD.load(Ordering::SeqCst), 0);
+}
+
+/// Requests to have interrupts disabled by `NestingInterruptState` guards
+static DISABLES: InterruptDisables = InterruptDisables::new();
+
+/// Whether `NestingInterruptState` has interrupts enabled
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// An interrupt state which disables interrupts in a nesting manner
+struct NestingInterruptState;
+
+impl InterruptState for NestingInterruptState {
+    fn in_interrupt() -> bool { false }
+    fn in_exception() -> bool { false }
+    fn enter_lock() {
+        DISABLES.disable();
+        ENABLED.store(false, Ordering::SeqCst);
+    }
+    fn exit_lock() {
+        if DISABLES.enable() { ENABLED.store(true, Ordering::SeqCst); }
+    }
+}
+
+#[test]
+fn nested_interrupt_guards() {
+    let outer = InterruptGuard::<NestingInterruptState>::new();
+    let inner = InterruptGuard::<NestingInterruptState>::new();
+    assert_eq!(DISABLES.outstanding(), 2);
+    assert!(!ENABLED.load(Ordering::SeqCst));
+
+    // Interrupts stay disabled while the outer guard is alive
+    drop(inner);
+    assert_eq!(DISABLES.outstanding(), 1);
+    assert!(!ENABLED.load(Ordering::SeqCst));
+
+    // They're enabled again only once the outer guard drops
+    drop(outer);
+    assert_eq!(DISABLES.outstanding(), 0);
+    assert!(ENABLED.load(Ordering::SeqCst));
+}
+
+#[test]
+#[should_panic(expected = "Overflow on enable interrupts outstanding")]
+fn unbalanced_interrupt_enable() {
+    InterruptDisables::new().enable();
+}
+
+/// Returns the TSC deadline `cycles` cycles from now
+fn deadline(cycles: u64) -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() + cycles }
+}
+
+#[test]
+fn lock_timeout_free() {
+    let lock = Lock::new(5);
+
+    // A free lock is taken even if the deadline has passed already
+    assert_eq!(lock.lock_timeout(0).map(|x| *x), Some(5));
+    assert_eq!(lock.lock_timeout(deadline(1_000_000)).map(|x| *x), Some(5));
+}
+
+#[test]
+fn lock_timeout_held() {
+    let lock = Lock::new(5);
+
+    // Give up while the lock is held
+    let guard = lock.lock();
+    assert!(lock.lock_timeout(deadline(1_000_000)).is_none());
+    drop(guard);
+
+    // Giving up must not leave a ticket behind
+    assert!(lock.try_lock().is_some());
+    assert_eq!(lock.lock_timeout(deadline(1_000_000)).map(|x| *x), Some(5));
+}
+
+#[test]
+fn lock_timeout_queued() {
+    let lock = std::sync::Arc::new(Lock::new(0));
+
+    // Queue a waiter behind the holder
+    let guard = lock.lock();
+    let waiter = {
+        let lock = lock.clone();
+        std::thread::spawn(move || *lock.lock() += 1)
+    };
+    while lock.ticket.load(Ordering::SeqCst) != 2 {
+        std::thread::yield_now();
+    }
+
+    // The lock can't be taken while someone is queued for it
+    assert!(lock.lock_timeout(deadline(1_000_000)).is_none());
+
+    // The queued waiter still gets it once it's released
+    drop(guard);
+    waiter.join().unwrap();
+    assert_eq!(*lock.lock(), 1);
+}
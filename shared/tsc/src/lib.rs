@@ -0,0 +1,143 @@
+//! Timekeeping in TSC ticks which doesn't read the TSC itself. The current
+//! TSC value is passed in by the caller
+
+#![no_std]
+
+#[cfg(test)]
+mod tests;
+
+/// Convert `us` microseconds to TSC ticks at `tsc_mhz`, saturating instead of
+/// overflowing
+pub const fn us_to_ticks(us: u64, tsc_mhz: u64) -> u64 {
+    us.saturating_mul(tsc_mhz)
+}
+
+/// Convert `ticks` TSC ticks at `tsc_mhz` to whole microseconds
+pub const fn ticks_to_us(ticks: u64, tsc_mhz: u64) -> u64 {
+    ticks / tsc_mhz
+}
+
+/// Get the TSC value `us` microseconds after `now`, saturating at the end of
+/// time
+pub const fn deadline(now: u64, us: u64, tsc_mhz: u64) -> u64 {
+    now.saturating_add(us_to_ticks(us, tsc_mhz))
+}
+
+/// Spin until `rdtsc` reaches `deadline` and return the last TSC value read
+pub fn spin_until(deadline: u64, mut rdtsc: impl FnMut() -> u64) -> u64 {
+    loop {
+        let now = rdtsc();
+        if now >= deadline { return now; }
+        core::hint::spin_loop();
+    }
+}
+
+/// Measures the TSC ticks elapsed since it was started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stopwatch {
+    /// TSC at the time the stopwatch was started
+    start: u64,
+}
+
+impl Stopwatch {
+    /// Start a new stopwatch at the TSC value `now`
+    pub const fn new(now: u64) -> Self {
+        Self { start: now }
+    }
+
+    /// Get the number of TSC ticks elapsed at `now`. This is 0 if `now` is
+    /// before the start, which happens when the TSCs of the cores drift apart
+    pub const fn elapsed_ticks(&self, now: u64) -> u64 {
+        now.saturating_sub(self.start)
+    }
+
+    /// Get the number of microseconds elapsed at `now`
+    pub const fn elapsed_us(&self, now: u64, tsc_mhz: u64) -> u64 {
+        ticks_to_us(self.elapsed_ticks(now), tsc_mhz)
+    }
+}
+
+/// Maximum number of timers pending in a single wheel at once
+pub const MAX_TIMERS: usize = 32;
+
+/// A callback scheduled to run once its deadline has passed
+#[derive(Clone, Copy)]
+struct Timer {
+    /// TSC value after which the callback is run
+    deadline: u64,
+
+    /// The function to call
+    callback: fn(),
+
+    /// Generation of the slot this timer was scheduled in, used to tell stale
+    /// IDs apart
+    generation: u64,
+}
+
+/// Identifies a timer scheduled in a `TimerWheel`, see `TimerWheel::cancel()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    /// Slot of the timer in the wheel
+    slot: usize,
+
+    /// Generation of the timer
+    generation: u64,
+}
+
+/// Error returned when scheduling a timer in a wheel whose slots are all in
+/// use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WheelFull;
+
+/// Pending timers.
+///
+/// The timers are held in a fixed number of slots, so that they can be
+/// serviced by interrupt handlers which can't allocate
+#[derive(Default)]
+pub struct TimerWheel {
+    /// Slots of the pending timers
+    timers: [Option<Timer>; MAX_TIMERS],
+
+    /// Generation of the next scheduled timer
+    generation: u64,
+}
+
+impl TimerWheel {
+    /// Create a new wheel without any timers
+    pub const fn new() -> Self {
+        Self { timers: [None; MAX_TIMERS], generation: 0 }
+    }
+
+    /// Schedule `callback` to run `ticks` TSC ticks after `now`
+    pub fn schedule(&mut self, now: u64, ticks: u64, callback: fn())
+            -> Result<TimerId, WheelFull> {
+        let (slot, timer) = self.timers.iter_mut().enumerate()
+            .find(|(_, timer)| timer.is_none())
+            .ok_or(WheelFull)?;
+
+        self.generation += 1;
+        let generation = self.generation;
+        let deadline = now.saturating_add(ticks);
+        *timer = Some(Timer { deadline, callback, generation });
+        Ok(TimerId { slot, generation })
+    }
+
+    /// Cancel the timer `id`. Returns whether it was still pending
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        let timer = &mut self.timers[id.slot];
+        if timer.is_some_and(|timer| timer.generation == id.generation) {
+            *timer = None;
+            return true;
+        }
+        false
+    }
+
+    /// Remove the earliest timer whose deadline is before `now` and return
+    /// its callback
+    pub fn pop_expired(&mut self, now: u64) -> Option<fn()> {
+        let timer = self.timers.iter_mut()
+            .filter(|timer| timer.is_some_and(|timer| timer.deadline <= now))
+            .min_by_key(|timer| timer.unwrap().deadline)?;
+        timer.take().map(|timer| timer.callback)
+    }
+}
@@ -0,0 +1,120 @@
+use super::*;
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// TSC rate used by the tests
+const TSC_MHZ: u64 = 3_000;
+
+#[test]
+fn sleep_within_tolerance() {
+    // A fake TSC which advances by an odd number of ticks on every read
+    const STEP: u64 = 997;
+    let tsc = Cell::new(1_000_000);
+    let rdtsc = || tsc.replace(tsc.get() + STEP);
+
+    let start = rdtsc();
+    let watch = Stopwatch::new(start);
+    let end = spin_until(deadline(start, 100, TSC_MHZ), rdtsc);
+
+    // The sleep lasts at least as long as requested, and overshoots by less
+    // than a single step of the TSC
+    let ticks = watch.elapsed_ticks(end);
+    assert!(ticks >= us_to_ticks(100, TSC_MHZ));
+    assert!(ticks < us_to_ticks(100, TSC_MHZ) + STEP);
+    assert_eq!(watch.elapsed_us(end, TSC_MHZ), 100);
+}
+
+#[test]
+fn time_conversions_saturate() {
+    // Microseconds are whole, partial ones are dropped
+    assert_eq!(us_to_ticks(5, TSC_MHZ), 15_000);
+    assert_eq!(ticks_to_us(15_000, TSC_MHZ), 5);
+    assert_eq!(ticks_to_us(14_999, TSC_MHZ), 4);
+
+    // Huge durations and deadlines saturate instead of wrapping around
+    assert_eq!(us_to_ticks(u64::MAX, TSC_MHZ), u64::MAX);
+    assert_eq!(deadline(u64::MAX - 10, 1, TSC_MHZ), u64::MAX);
+    assert_eq!(deadline(10, u64::MAX, TSC_MHZ), u64::MAX);
+
+    // A TSC read before the start, as on another core, counts as no time
+    let watch = Stopwatch::new(1_000);
+    assert_eq!(watch.elapsed_ticks(999), 0);
+    assert_eq!(watch.elapsed_us(0, TSC_MHZ), 0);
+    assert_eq!(watch.elapsed_us(4_000, TSC_MHZ), 1);
+}
+
+#[test]
+fn timer_fires_after_deadline() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    let mut wheel = TimerWheel::new();
+    wheel.schedule(1000, 500, || { FIRED.fetch_add(1, Ordering::Relaxed); })
+        .unwrap();
+
+    // Nothing fires before the deadline
+    assert!(wheel.pop_expired(1000).is_none());
+    assert!(wheel.pop_expired(1499).is_none());
+
+    // The timer fires once the deadline is reached, and only once
+    wheel.pop_expired(1500).unwrap()();
+    assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+    assert!(wheel.pop_expired(u64::MAX).is_none());
+}
+
+#[test]
+fn timers_fire_in_deadline_order() {
+    static ORDER: AtomicUsize = AtomicUsize::new(0);
+    let mut wheel = TimerWheel::new();
+    wheel.schedule(0, 20, || { ORDER.fetch_add(2, Ordering::Relaxed); })
+        .unwrap();
+    wheel.schedule(0, 10, || { ORDER.fetch_add(1, Ordering::Relaxed); })
+        .unwrap();
+
+    // The earliest expired timer fires first
+    wheel.pop_expired(30).unwrap()();
+    assert_eq!(ORDER.load(Ordering::Relaxed), 1);
+    wheel.pop_expired(30).unwrap()();
+    assert_eq!(ORDER.load(Ordering::Relaxed), 3);
+
+    // Deadlines past the end of time saturate instead of firing right away
+    wheel.schedule(u64::MAX - 1, 10, || {}).unwrap();
+    assert!(wheel.pop_expired(u64::MAX - 1).is_none());
+}
+
+#[test]
+fn timer_cancel_stale_id() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    let mut wheel = TimerWheel::new();
+    let callback = || { FIRED.fetch_add(1, Ordering::Relaxed); };
+
+    // A cancelled timer doesn't fire
+    let first = wheel.schedule(0, 10, callback).unwrap();
+    assert!(wheel.cancel(first));
+    assert!(!wheel.cancel(first));
+    assert!(wheel.pop_expired(10).is_none());
+
+    // A new timer reuses the slot with a new generation, so the stale ID
+    // can't cancel it
+    let second = wheel.schedule(0, 10, callback).unwrap();
+    assert_ne!(first, second);
+    assert!(!wheel.cancel(first));
+    wheel.pop_expired(10).unwrap()();
+    assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+
+    // A fired timer can't be cancelled either
+    assert!(!wheel.cancel(second));
+}
+
+#[test]
+fn timer_wheel_full() {
+    let mut wheel = TimerWheel::new();
+    let ids: [TimerId; MAX_TIMERS] =
+        core::array::from_fn(|_| wheel.schedule(0, 10, || {}).unwrap());
+
+    // All of the slots are in use
+    assert_eq!(wheel.schedule(0, 10, || {}), Err(WheelFull));
+
+    // Cancelling a timer frees its slot up
+    assert!(wheel.cancel(ids[7]));
+    assert!(wheel.schedule(0, 10, || {}).is_ok());
+}
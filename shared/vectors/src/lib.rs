@@ -0,0 +1,181 @@
+//! Identifiers of the x86 interrupt vectors and the tables dispatching them
+//! to their handlers
+
+#![no_std]
+
+#[cfg(test)]
+mod tests;
+
+/// Legacy ISA interrupt identifiers
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Copy, Clone)]
+#[repr(u8)]
+pub enum InterruptId {
+    DivideBy0 = 0x00,
+    // Reserved = 0x01,
+    NonMaskableInterrupt = 0x02,
+    Breakpoint,
+    Overflow,
+    BoundsRangeExceeded,
+    InvalidOpcode,
+    DeviceNotAvailable,
+    DoubleFault,
+    CoprocessorSegmentOverrun,
+    InvalidTSS,
+    SegmentNotPresent,
+    StackSegmentFault,
+    GeneralProtectionFault,
+    PageFault,
+    // Reserved = 0x0F,
+    X87FPUError = 0x10,
+    AlignmentCheck,
+    MachineCheck,
+    SIMDFloatingPointException,
+    VirtualizationException,
+    ControlProtection,
+
+    // Reserved hole
+    Reserved = 0x16,
+    LastReserved = 0x1F,
+
+    // Kernel definable interrupts start at 0x20
+    SoftRebootTimer = 0x20,
+
+    // Vectors allocated at runtime through `Interrupts::allocate()`
+    Dynamic(u8),
+}
+
+impl InterruptId {
+    /// First vector which can be dynamically allocated
+    pub const FIRST_DYNAMIC: u8 = 0x21;
+
+    /// Last vector which can be dynamically allocated. `0xFF` is left out as
+    /// it's used as the spurious interrupt vector
+    pub const LAST_DYNAMIC: u8 = 0xFE;
+
+    /// Returns whether this interrupt is a CPU exception
+    pub fn is_exception(self) -> bool {
+        u8::from(self) < 0x20
+    }
+
+    /// Returns whether this interrupt is reserved and must not be used.
+    ///
+    /// Dynamic vectors below `FIRST_DYNAMIC` are reserved as well, as they
+    /// would alias the fixed vectors
+    pub fn is_reserved(self) -> bool {
+        match self {
+            Self::Reserved | Self::LastReserved => true,
+            Self::Dynamic(vector) => vector < Self::FIRST_DYNAMIC,
+            _ => false,
+        }
+    }
+}
+
+impl From<InterruptId> for u8 {
+    fn from(id: InterruptId) -> Self {
+        match id {
+            InterruptId::Dynamic(vector) => vector,
+
+            // The enum is `repr(u8)`, so the discriminant is stored as the
+            // first byte of the value
+            _ => unsafe { *(&id as *const InterruptId as *const u8) },
+        }
+    }
+}
+
+impl From<u8> for InterruptId {
+    fn from(val: u8) -> Self {
+        match val {
+            // Well defined IDT entries
+            0x00 => Self::DivideBy0,
+            0x02 => Self::NonMaskableInterrupt,
+            0x03 => Self::Breakpoint,
+            0x04 => Self::Overflow,
+            0x05 => Self::BoundsRangeExceeded,
+            0x06 => Self::InvalidOpcode,
+            0x07 => Self::DeviceNotAvailable,
+            0x08 => Self::DoubleFault,
+            0x09 => Self::CoprocessorSegmentOverrun,
+            0x0A => Self::InvalidTSS,
+            0x0B => Self::SegmentNotPresent,
+            0x0C => Self::StackSegmentFault,
+            0x0D => Self::GeneralProtectionFault,
+            0x0E => Self::PageFault,
+            0x10 => Self::X87FPUError,
+            0x11 => Self::AlignmentCheck,
+            0x12 => Self::MachineCheck,
+            0x13 => Self::SIMDFloatingPointException,
+            0x14 => Self::VirtualizationException,
+            0x15 => Self::ControlProtection,
+
+            // Kernel defined IDT entries
+            0x20 => Self::SoftRebootTimer,
+
+            // Reserved entries which must not be used
+            0x01 | 0x0F | 0x16..=0x1F => Self::Reserved,
+
+            // Everything else (0x21..=0xFF) is definable at runtime
+            _ => Self::Dynamic(val),
+        }
+    }
+}
+
+impl From<InterruptId> for usize {
+    fn from(val: InterruptId) -> Self {
+        u8::from(val) as usize
+    }
+}
+
+/// The handlers of each of the interrupt vectors, along with a default
+/// handler for the vectors without one
+pub struct DispatchTable<H: Copy> {
+    /// Handlers registered for each of the vectors
+    handlers: [Option<H>; 256],
+
+    /// Handler for the vectors without a registered handler
+    default: Option<H>,
+}
+
+impl<H: Copy> DispatchTable<H> {
+    /// Create a new table without any handlers
+    pub const fn new() -> Self {
+        Self { handlers: [None; 256], default: None }
+    }
+
+    /// Register `handler` for `id`. Returns `false` without replacing it if a
+    /// handler is registered for `id` already
+    pub fn insert(&mut self, id: InterruptId, handler: H) -> bool {
+        let slot = &mut self.handlers[usize::from(id)];
+        if slot.is_some() { return false; }
+        *slot = Some(handler);
+        true
+    }
+
+    /// Unregister the handler of `id`
+    pub fn remove(&mut self, id: InterruptId) {
+        self.handlers[usize::from(id)] = None;
+    }
+
+    /// Returns the handler registered for `id`, if any. The default handler
+    /// isn't considered
+    pub fn get(&self, id: InterruptId) -> Option<H> {
+        self.handlers[usize::from(id)]
+    }
+
+    /// Set the handler for the vectors without a registered handler,
+    /// replacing the previous one
+    pub fn set_default(&mut self, handler: H) {
+        self.default = Some(handler);
+    }
+
+    /// Returns the handler to dispatch `id` to. This is the handler registered
+    /// for `id` if there is one, and the default handler otherwise
+    pub fn lookup(&self, id: InterruptId) -> Option<H> {
+        self.get(id).or(self.default)
+    }
+}
+
+impl<H: Copy> Default for DispatchTable<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,59 @@
+use super::*;
+
+#[test]
+fn interrupt_id_round_trip() {
+    // Vectors past the fixed ones are dynamic and convert back unchanged
+    for vector in [0x21, 0x80, 0xFF] {
+        let id = InterruptId::from(vector);
+        assert_eq!(id, InterruptId::Dynamic(vector));
+        assert_eq!(u8::from(id), vector);
+        assert_eq!(usize::from(id), vector as usize);
+        assert!(!id.is_exception());
+        assert!(!id.is_reserved());
+    }
+
+    // Fixed vectors convert to their variant and back
+    assert_eq!(InterruptId::from(0x0E), InterruptId::PageFault);
+    assert_eq!(u8::from(InterruptId::PageFault), 0x0E);
+    assert_eq!(InterruptId::from(0x20), InterruptId::SoftRebootTimer);
+    assert_eq!(u8::from(InterruptId::SoftRebootTimer), 0x20);
+    assert!(InterruptId::PageFault.is_exception());
+
+    // Every vector except for the reserved ones survives the round trip
+    for vector in 0..=u8::MAX {
+        let id = InterruptId::from(vector);
+        if id == InterruptId::Reserved {
+            assert!(matches!(vector, 0x01 | 0x0F | 0x16..=0x1F));
+            continue;
+        }
+        assert_eq!(u8::from(id), vector);
+    }
+}
+
+#[test]
+fn dispatch_falls_back_to_default() {
+    let mut table = DispatchTable::new();
+    let registered = InterruptId::from(0x21);
+    let unregistered = InterruptId::from(0x80);
+
+    // Nothing is dispatched without any handlers
+    assert_eq!(table.lookup(registered), None);
+
+    // Only the registered vector is dispatched before there's a default
+    assert!(table.insert(registered, "registered"));
+    assert_eq!(table.lookup(registered), Some("registered"));
+    assert_eq!(table.lookup(unregistered), None);
+
+    // The default handler is consulted for the unregistered vector, while the
+    // registered handler still takes precedence
+    table.set_default("default");
+    assert_eq!(table.lookup(unregistered), Some("default"));
+    assert_eq!(table.lookup(registered), Some("registered"));
+    assert_eq!(table.get(unregistered), None);
+
+    // Registered handlers aren't replaced, and fall back once removed
+    assert!(!table.insert(registered, "replaced"));
+    assert_eq!(table.lookup(registered), Some("registered"));
+    table.remove(registered);
+    assert_eq!(table.lookup(registered), Some("default"));
+}